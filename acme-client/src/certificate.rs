@@ -1,7 +1,274 @@
-use openssl::x509::X509;
+use crate::crypto::SupportedKey;
+use crate::csr::CsrIdentifier;
+use crate::keys::PrivateKey;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::Id;
+use openssl::stack::Stack;
+use openssl::x509::{X509, X509NameRef, X509VerifyResult};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-// TODO: remove allow
-#[allow(dead_code)]
+#[derive(Clone)]
 pub struct Certificate {
-    x509: X509
-}
\ No newline at end of file
+    x509: X509,
+}
+
+impl Certificate {
+    pub fn from_x509(x509: X509) -> Self {
+        Certificate { x509 }
+    }
+
+    /// Parses the first certificate of a PEM bundle.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(Certificate {
+            x509: X509::from_pem(pem)?,
+        })
+    }
+
+    pub fn from_der(der: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(Certificate {
+            x509: X509::from_der(der)?,
+        })
+    }
+
+    pub fn x509(&self) -> &X509 {
+        &self.x509
+    }
+
+    pub fn to_pem(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.x509.to_pem()?)
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.x509.to_der()?)
+    }
+
+    /// notBefore as unix seconds.
+    pub fn not_before(&self) -> Result<i64, Box<dyn Error>> {
+        unix_seconds(self.x509.not_before())
+    }
+
+    /// notAfter as unix seconds.
+    pub fn not_after(&self) -> Result<i64, Box<dyn Error>> {
+        unix_seconds(self.x509.not_after())
+    }
+
+    /// Serial number as upper case hex, the same format the CA shows in its logs.
+    pub fn serial(&self) -> Result<String, Box<dyn Error>> {
+        Ok(self.x509.serial_number().to_bn()?.to_hex_str()?.to_string())
+    }
+
+    pub fn subject_alt_names(&self) -> Vec<CsrIdentifier> {
+        let Some(names) = self.x509.subject_alt_names() else {
+            return Vec::new();
+        };
+        names
+            .iter()
+            .filter_map(|name| {
+                if let Some(dns) = name.dnsname() {
+                    return Some(CsrIdentifier::Dns(dns.to_lowercase()));
+                }
+                name.ipaddress().and_then(ip_from_bytes).map(CsrIdentifier::Ip)
+            })
+            .collect()
+    }
+
+    pub fn subject(&self) -> String {
+        name_to_string(self.x509.subject_name())
+    }
+
+    pub fn subject_common_name(&self) -> Option<String> {
+        common_name(self.x509.subject_name())
+    }
+
+    pub fn issuer(&self) -> String {
+        name_to_string(self.x509.issuer_name())
+    }
+
+    pub fn issuer_common_name(&self) -> Option<String> {
+        common_name(self.x509.issuer_name())
+    }
+
+    /// Key type of the certified public key, `None` if it's not one we can issue for.
+    pub fn key_type(&self) -> Result<Option<SupportedKey>, Box<dyn Error>> {
+        let key = self.x509.public_key()?;
+        let key_type = match key.id() {
+            Id::RSA => match key.bits() {
+                2048 => Some(SupportedKey::Rsa2048),
+                4096 => Some(SupportedKey::Rsa4096),
+                _ => None,
+            },
+            Id::EC => match key.ec_key()?.group().curve_name() {
+                Some(Nid::X9_62_PRIME256V1) => Some(SupportedKey::EcP256),
+                Some(Nid::SECP384R1) => Some(SupportedKey::EcP384),
+                Some(Nid::SECP521R1) => Some(SupportedKey::EcP521),
+                _ => None,
+            },
+            Id::ED25519 => Some(SupportedKey::Ed25519),
+            _ => None,
+        };
+        Ok(key_type)
+    }
+
+    /// Base64 SHA-256 of the SubjectPublicKeyInfo, the `pin-sha256` value of RFC 7469.
+    pub fn spki_sha256(&self) -> Result<String, Box<dyn Error>> {
+        let spki = self.x509.public_key()?.public_key_to_der()?;
+        Ok(STANDARD.encode(openssl::sha::sha256(&spki)))
+    }
+
+    /// Hex SHA-256 over the whole DER certificate.
+    pub fn sha256_fingerprint(&self) -> Result<String, Box<dyn Error>> {
+        Ok(hex::encode(self.x509.digest(MessageDigest::sha256())?))
+    }
+
+    pub fn authority_key_id(&self) -> Option<String> {
+        self.x509.authority_key_id().map(|id| hex::encode(id.as_slice()))
+    }
+
+    pub fn subject_key_id(&self) -> Option<String> {
+        self.x509.subject_key_id().map(|id| hex::encode(id.as_slice()))
+    }
+
+    pub fn is_self_signed(&self) -> bool {
+        self.is_issued_by(self)
+    }
+
+    /// Checks both names and, if present, that the AKI of `self` matches the issuer's SKI.
+    pub fn is_issued_by(&self, issuer: &Certificate) -> bool {
+        issuer.x509.issued(&self.x509) == X509VerifyResult::OK
+    }
+
+    pub fn matches_key(&self, key: &PrivateKey) -> Result<bool, Box<dyn Error>> {
+        Ok(self.x509.public_key()?.public_eq(&key.k))
+    }
+
+    /// Seconds of validity left relative to `now` (unix seconds), negative once expired.
+    pub fn seconds_left(&self, now: i64) -> Result<i64, Box<dyn Error>> {
+        Ok(self.not_after()? - now)
+    }
+}
+
+/// A leaf certificate followed by the intermediates the CA sent along with it.
+#[derive(Clone)]
+pub struct CertificateChain {
+    leaf: Certificate,
+    intermediates: Vec<Certificate>,
+}
+
+impl CertificateChain {
+    /// Parses a `application/pem-certificate-chain` body, the first certificate is the leaf.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut certificates = X509::stack_from_pem(pem)?.into_iter().map(Certificate::from_x509);
+        let leaf = certificates.next().ok_or("PEM bundle does not contain any certificates")?;
+        Ok(CertificateChain {
+            leaf,
+            intermediates: certificates.collect(),
+        })
+    }
+
+    pub fn from_certificates(leaf: Certificate, intermediates: Vec<Certificate>) -> Self {
+        CertificateChain { leaf, intermediates }
+    }
+
+    pub fn leaf(&self) -> &Certificate {
+        &self.leaf
+    }
+
+    pub fn intermediates(&self) -> &[Certificate] {
+        &self.intermediates
+    }
+
+    /// The last certificate of the chain, the one closest to the root.
+    pub fn top(&self) -> &Certificate {
+        self.intermediates.last().unwrap_or(&self.leaf)
+    }
+
+    /// Issuer CN of the top of the chain, i.e. the root the chain is anchored in.
+    pub fn root_issuer_common_name(&self) -> Option<String> {
+        self.top().issuer_common_name()
+    }
+
+    /// Every certificate has to be issued by the one that follows it.
+    pub fn is_ordered(&self) -> bool {
+        let mut current = &self.leaf;
+        for next in &self.intermediates {
+            if !current.is_issued_by(next) {
+                return false;
+            }
+            current = next;
+        }
+        true
+    }
+
+    pub fn cert_pem(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.leaf.to_pem()
+    }
+
+    /// Intermediates only.
+    pub fn chain_pem(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut pem = Vec::new();
+        for certificate in &self.intermediates {
+            pem.extend(certificate.to_pem()?);
+        }
+        Ok(pem)
+    }
+
+    /// Leaf followed by the intermediates.
+    pub fn fullchain_pem(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut pem = self.cert_pem()?;
+        pem.extend(self.chain_pem()?);
+        Ok(pem)
+    }
+
+    /// PKCS#12 bundle with the key, the leaf and the intermediates as CA certificates.
+    pub fn to_pkcs12(&self, key: &PrivateKey, name: &str, password: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut ca = Stack::new()?;
+        for certificate in &self.intermediates {
+            ca.push(certificate.x509.clone())?;
+        }
+        let pkcs12 = Pkcs12::builder()
+            .name(name)
+            .pkey(&key.k)
+            .cert(&self.leaf.x509)
+            .ca(ca)
+            .build2(password)?;
+        Ok(pkcs12.to_der()?)
+    }
+}
+
+fn unix_seconds(time: &Asn1TimeRef) -> Result<i64, Box<dyn Error>> {
+    let epoch = Asn1Time::from_unix(0)?;
+    let diff = epoch.diff(time)?;
+    Ok(diff.days as i64 * 86_400 + diff.secs as i64)
+}
+
+fn common_name(name: &X509NameRef) -> Option<String> {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|cn| cn.to_string())
+}
+
+fn name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            let value = entry.data().as_utf8().ok()?;
+            Some(format!("{}={}", key, value))
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?))),
+        _ => None,
+    }
+}
@@ -1,2 +1,5 @@
 pub mod account;
-pub mod directory;
\ No newline at end of file
+pub mod certificate;
+pub mod directory;
+pub mod order;
+pub mod session;
\ No newline at end of file
@@ -0,0 +1,87 @@
+use crate::comms::session::{AcmeSession, AsyncResult};
+use crate::jws::{JWS, JWSHeader};
+use crate::keys::PrivateKey;
+use reqwest::header::LOCATION;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+
+/// The account object of RFC 8555 section 7.1.2, as far as acme-sentry uses it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountObject {
+    pub status: String,
+    #[serde(default)]
+    pub contact: Vec<String>,
+}
+
+/// `mailto:` contact URLs of `emails`.
+pub fn contacts(emails: &[String]) -> Vec<String> {
+    emails.iter().map(|email| format!("mailto:{}", email)).collect()
+}
+
+/// Registers the key of an [unregistered](AcmeSession::unregistered) session and agrees to
+/// the terms of service, returns the account URL. If the key is registered already the CA
+/// hands out the existing account.
+pub async fn register_account(
+    session: &AcmeSession,
+    new_account_url: &str,
+    contact: &[String],
+) -> AsyncResult<(String, AccountObject)> {
+    let payload = json!({"termsOfServiceAgreed": true, "contact": contact});
+    let response = session.post(new_account_url, payload).await?;
+    let account_url = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .ok_or("newAccount did not return the account URL")?;
+    Ok((account_url, response.json::<AccountObject>().await?))
+}
+
+/// The session's account as the CA knows it.
+pub async fn fetch_account(session: &AcmeSession) -> AsyncResult<AccountObject> {
+    let account_url = registered(session)?;
+    let response = session.post_as_get(account_url).await?;
+    Ok(response.json::<AccountObject>().await?)
+}
+
+/// Replaces the contact URLs of the session's account.
+pub async fn update_account(session: &AcmeSession, contact: &[String]) -> AsyncResult<AccountObject> {
+    let account_url = registered(session)?;
+    let response = session.post(account_url, json!({"contact": contact})).await?;
+    Ok(response.json::<AccountObject>().await?)
+}
+
+/// Deactivates the session's account for good, the CA refuses any later request of it.
+pub async fn deactivate_account(session: &AcmeSession) -> AsyncResult<AccountObject> {
+    let account_url = registered(session)?;
+    let response = session.post(account_url, json!({"status": "deactivated"})).await?;
+    Ok(response.json::<AccountObject>().await?)
+}
+
+/// Moves the session's account over to `new_key`, the session's key is useless afterwards.
+pub async fn change_key(session: &AcmeSession, key_change_url: &str, new_key: &PrivateKey) -> AsyncResult<()> {
+    let account_url = registered(session)?;
+    let inner = key_change_payload(account_url, session.key(), new_key, key_change_url).map_err(|e| e.to_string())?;
+    session.post(key_change_url, inner).await?;
+    Ok(())
+}
+
+/// The inner JWS of a key change (RFC 8555 section 7.3.5), signed by `new_key` and sent
+/// as the payload of a request signed by the old one.
+pub fn key_change_payload(
+    account_url: &str,
+    old_key: &PrivateKey,
+    new_key: &PrivateKey,
+    key_change_url: &str,
+) -> Result<Value, Box<dyn Error>> {
+    let header = JWSHeader::with_jwk(new_key.kt.get_key_alg(), None, key_change_url.to_string());
+    let payload = json!({"account": account_url, "oldKey": old_key.get_jwk()?});
+    JWS::with_header_and_payload(header, payload).finalize_flattened(new_key)
+}
+
+fn registered(session: &AcmeSession) -> AsyncResult<&str> {
+    session
+        .account_url()
+        .ok_or_else(|| "The session isn't signed by a registered account".into())
+}
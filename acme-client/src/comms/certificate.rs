@@ -0,0 +1,164 @@
+use crate::certificate::{Certificate, CertificateChain};
+use crate::comms::session::{AcmeSession, AsyncResult};
+use crate::encoding::encode_b64;
+use reqwest::Url;
+use reqwest::header::{HeaderMap, LINK};
+use serde_json::{json, Value};
+
+const PEM_CHAIN: &str = "application/pem-certificate-chain";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkHeader {
+    pub url: String,
+    pub rel: String,
+}
+
+/// Parses `Link: <url>;rel="alternate", <url2>;rel="up"` style values (RFC 8288).
+pub fn parse_link_header(value: &str) -> Vec<LinkHeader> {
+    value
+        .split(',')
+        .filter_map(|link| {
+            let mut parts = link.split(';');
+            let url = parts.next()?.trim().strip_prefix('<')?.strip_suffix('>')?;
+            let rel = parts.find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("rel") {
+                    Some(value.trim().trim_matches('"').to_string())
+                } else {
+                    None
+                }
+            })?;
+            Some(LinkHeader {
+                url: url.to_string(),
+                rel,
+            })
+        })
+        .collect()
+}
+
+/// All `rel="alternate"` links of a response, resolved against the request url.
+pub fn alternate_links(headers: &HeaderMap, base: &Url) -> Vec<String> {
+    headers
+        .get_all(LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(parse_link_header)
+        .filter(|link| link.rel == "alternate")
+        .filter_map(|link| base.join(link.url.as_str()).ok())
+        .map(|url| url.to_string())
+        .collect()
+}
+
+/// Chain preference, matched the same way certbot's `--preferred-chain` works.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreferredChain {
+    /// CN of the issuer of the top-most certificate in the chain.
+    IssuerCommonName(String),
+    /// SHA-256 fingerprint (hex) of any certificate above the leaf.
+    ///
+    /// Only the certificates the CA sends are compared, the self-signed root a chain ends in
+    /// usually isn't one of them. Such a root is matched through a cross-signed copy the chain
+    /// carries or not at all, [`IssuerCommonName`](Self::IssuerCommonName) matches it by name.
+    RootFingerprint(String),
+}
+
+impl PreferredChain {
+    /// A 64 character hex string (colons allowed) is a fingerprint, anything else an issuer CN.
+    pub fn parse(value: &str) -> Self {
+        let stripped = value.replace(':', "").to_lowercase();
+        if stripped.len() == 64 && stripped.chars().all(|c| c.is_ascii_hexdigit()) {
+            PreferredChain::RootFingerprint(stripped)
+        } else {
+            PreferredChain::IssuerCommonName(value.trim().to_string())
+        }
+    }
+
+    pub fn matches(&self, chain: &CertificateChain) -> bool {
+        match self {
+            PreferredChain::IssuerCommonName(cn) => chain.root_issuer_common_name().as_deref() == Some(cn.as_str()),
+            PreferredChain::RootFingerprint(fingerprint) => chain
+                .intermediates()
+                .iter()
+                .any(|cert| cert.sha256_fingerprint().is_ok_and(|f| &f == fingerprint)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DownloadedChain {
+    pub url: String,
+    pub chain: CertificateChain,
+    pub is_default: bool,
+}
+
+impl DownloadedChain {
+    /// Issuer CN of the top of the chain, stored alongside the certificate.
+    pub fn issuer(&self) -> Option<String> {
+        self.chain.root_issuer_common_name()
+    }
+}
+
+/// Picks the first chain matching `preferred`, `chains[0]` is expected to be the default
+/// one and is used if nothing matches.
+pub fn select_chain(chains: Vec<DownloadedChain>, preferred: Option<&PreferredChain>) -> Option<DownloadedChain> {
+    let index = preferred
+        .and_then(|preferred| chains.iter().position(|c| preferred.matches(&c.chain)))
+        .unwrap_or(0);
+    chains.into_iter().nth(index)
+}
+
+/// Downloads the default chain and every advertised alternate before selecting one.
+///
+/// Alternates that fail to download or parse are skipped, the default chain is not optional.
+pub async fn download_certificate(
+    session: &AcmeSession,
+    certificate_url: &str,
+    preferred: Option<&PreferredChain>,
+) -> AsyncResult<DownloadedChain> {
+    let (default, alternates) = fetch_chain(session, certificate_url).await?;
+    let mut chains = vec![DownloadedChain {
+        url: certificate_url.to_string(),
+        chain: default,
+        is_default: true,
+    }];
+    if preferred.is_some() {
+        for url in alternates {
+            if let Ok((chain, _)) = fetch_chain(session, url.as_str()).await {
+                chains.push(DownloadedChain {
+                    url,
+                    chain,
+                    is_default: false,
+                });
+            }
+        }
+    }
+    select_chain(chains, preferred).ok_or_else(|| "No certificate chain could be downloaded".into())
+}
+
+/// Revokes `certificate` (RFC 8555 section 7.6), `reason` is a CRL reason code.
+pub async fn revoke_certificate(
+    session: &AcmeSession,
+    revoke_url: &str,
+    certificate: &Certificate,
+    reason: Option<u32>,
+) -> AsyncResult<()> {
+    session.post(revoke_url, revocation_payload(certificate, reason).map_err(|e| e.to_string())?).await?;
+    Ok(())
+}
+
+pub fn revocation_payload(certificate: &Certificate, reason: Option<u32>) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut payload = json!({"certificate": encode_b64(&certificate.to_der()?)});
+    if let Some(reason) = reason {
+        payload["reason"] = json!(reason);
+    }
+    Ok(payload)
+}
+
+async fn fetch_chain(session: &AcmeSession, url: &str) -> AsyncResult<(CertificateChain, Vec<String>)> {
+    let base = Url::parse(url)?;
+    let response = session.post_as_get_accepting(url, PEM_CHAIN).await?;
+    let alternates = alternate_links(response.headers(), &base);
+    let body = response.bytes().await?;
+    let chain = CertificateChain::from_pem(&body).map_err(|e| e.to_string())?;
+    Ok((chain, alternates))
+}
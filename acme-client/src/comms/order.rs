@@ -0,0 +1,115 @@
+use crate::comms::session::{AcmeSession, AsyncResult};
+use crate::csr::{CertificateSigningRequest, CsrIdentifier};
+use crate::jwk::GenericJWK;
+use crate::keys::PrivateKey;
+use reqwest::header::LOCATION;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+
+/// An identifier as the order and authorization objects carry it, e.g. `dns`/`example.org`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identifier {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+/// The order object of RFC 8555 section 7.1.3.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderObject {
+    pub status: String,
+    pub identifiers: Vec<Identifier>,
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    /// Set once the order is `valid`.
+    pub certificate: Option<String>,
+    /// The problem document of an `invalid` order.
+    pub error: Option<Value>,
+}
+
+/// The authorization object of RFC 8555 section 7.1.4.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizationObject {
+    pub status: String,
+    pub identifier: Identifier,
+    #[serde(default)]
+    pub challenges: Vec<ChallengeObject>,
+    #[serde(default)]
+    pub wildcard: bool,
+}
+
+/// The challenge object of RFC 8555 section 7.1.5, `token` is set for the challenge types
+/// of section 8.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChallengeObject {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    pub status: String,
+    pub token: Option<String>,
+    pub error: Option<Value>,
+}
+
+/// The `newOrder` payload, `profile` selects one of the CA's profiles (draft-ietf-acme-profiles).
+pub fn new_order_payload(identifiers: &[CsrIdentifier], profile: Option<&str>) -> Value {
+    let identifiers: Vec<Value> = identifiers
+        .iter()
+        .map(|identifier| json!({"type": identifier.acme_type(), "value": identifier.value()}))
+        .collect();
+    let mut payload = json!({"identifiers": identifiers});
+    if let Some(profile) = profile {
+        payload["profile"] = json!(profile);
+    }
+    payload
+}
+
+/// Places an order for `identifiers`, returns the order URL.
+pub async fn new_order(
+    session: &AcmeSession,
+    new_order_url: &str,
+    identifiers: &[CsrIdentifier],
+    profile: Option<&str>,
+) -> AsyncResult<(String, OrderObject)> {
+    let response = session.post(new_order_url, new_order_payload(identifiers, profile)).await?;
+    let order_url = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .ok_or("newOrder did not return the order URL")?;
+    Ok((order_url, response.json::<OrderObject>().await?))
+}
+
+pub async fn fetch_order(session: &AcmeSession, order_url: &str) -> AsyncResult<OrderObject> {
+    let response = session.post_as_get(order_url).await?;
+    Ok(response.json::<OrderObject>().await?)
+}
+
+pub async fn fetch_authorization(session: &AcmeSession, authorization_url: &str) -> AsyncResult<AuthorizationObject> {
+    let response = session.post_as_get(authorization_url).await?;
+    Ok(response.json::<AuthorizationObject>().await?)
+}
+
+/// Tells the CA the challenge is ready to be validated.
+pub async fn respond_to_challenge(session: &AcmeSession, challenge_url: &str) -> AsyncResult<ChallengeObject> {
+    let response = session.post(challenge_url, json!({})).await?;
+    Ok(response.json::<ChallengeObject>().await?)
+}
+
+/// Sends the CSR of a `ready` order, the order moves on to `processing` or `valid`.
+pub async fn finalize_order(
+    session: &AcmeSession,
+    finalize_url: &str,
+    csr: &CertificateSigningRequest,
+) -> AsyncResult<OrderObject> {
+    let payload = json!({"csr": csr.to_finalize_value().map_err(|e| e.to_string())?});
+    let response = session.post(finalize_url, payload).await?;
+    Ok(response.json::<OrderObject>().await?)
+}
+
+/// `token || '.' || base64url(JWK thumbprint of the account key)`, RFC 8555 section 8.1.
+pub fn key_authorization(token: &str, account_key: &PrivateKey) -> Result<String, Box<dyn Error>> {
+    let thumbprint = GenericJWK::from_value(account_key.get_jwk()?)?.thumbprint()?;
+    Ok(format!("{}.{}", token, thumbprint))
+}
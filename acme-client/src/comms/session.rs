@@ -0,0 +1,141 @@
+use crate::jws::{JWS, JWSHeader};
+use crate::keys::PrivateKey;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::{Client, Response};
+use serde_json::Value;
+use std::error::Error;
+use std::sync::Mutex;
+
+/// Errors crossing an `.await` have to be `Send`, anyhow picks these up as well.
+pub type AsyncResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const REPLAY_NONCE: &str = "Replay-Nonce";
+
+/// Signed requests on behalf of an existing account, or of a key that is about to become
+/// one, see [`AcmeSession::unregistered`].
+///
+/// Keeps the last `Replay-Nonce` the server handed out, so only the first request
+/// has to go through `newNonce`.
+pub struct AcmeSession {
+    client: Client,
+    key: PrivateKey,
+    account_url: Option<String>,
+    new_nonce_url: String,
+    nonce: Mutex<Option<String>>,
+}
+
+impl AcmeSession {
+    pub fn new(
+        key: PrivateKey,
+        account_url: String,
+        new_nonce_url: String,
+        accept_invalid_certs: bool,
+    ) -> AsyncResult<Self> {
+        let client = Client::builder()
+            .danger_accept_invalid_certs(accept_invalid_certs)
+            .build()?;
+        Ok(AcmeSession {
+            client,
+            key,
+            account_url: Some(account_url),
+            new_nonce_url,
+            nonce: Mutex::new(None),
+        })
+    }
+
+    /// Requests are signed with the `jwk` of `key` instead of an account URL, the way
+    /// `newAccount` wants them.
+    pub fn unregistered(key: PrivateKey, new_nonce_url: String, accept_invalid_certs: bool) -> AsyncResult<Self> {
+        let client = Client::builder()
+            .danger_accept_invalid_certs(accept_invalid_certs)
+            .build()?;
+        Ok(AcmeSession {
+            client,
+            key,
+            account_url: None,
+            new_nonce_url,
+            nonce: Mutex::new(None),
+        })
+    }
+
+    pub fn key(&self) -> &PrivateKey {
+        &self.key
+    }
+
+    pub fn account_url(&self) -> Option<&str> {
+        self.account_url.as_deref()
+    }
+
+    pub async fn post_as_get(&self, url: &str) -> AsyncResult<Response> {
+        self.send(url, None, None).await
+    }
+
+    /// POST-as-GET with an explicit `Accept`, e.g. `application/pem-certificate-chain`.
+    pub async fn post_as_get_accepting(&self, url: &str, accept: &str) -> AsyncResult<Response> {
+        self.send(url, None, Some(accept)).await
+    }
+
+    pub async fn post(&self, url: &str, payload: Value) -> AsyncResult<Response> {
+        self.send(url, Some(payload), None).await
+    }
+
+    async fn send(&self, url: &str, payload: Option<Value>, accept: Option<&str>) -> AsyncResult<Response> {
+        let nonce = self.next_nonce().await?;
+        let body = self.sign(url, nonce, payload)?;
+        let mut request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/jose+json")
+            .body(body);
+        if let Some(accept) = accept {
+            request = request.header(ACCEPT, accept);
+        }
+        let response = request.send().await?;
+        self.store_nonce(&response);
+        if !response.status().is_success() {
+            let status = response.status();
+            let problem = response.text().await.unwrap_or_default();
+            return Err(format!("ACME server returned {} for {}: {}", status, url, problem).into());
+        }
+        Ok(response)
+    }
+
+    async fn next_nonce(&self) -> AsyncResult<String> {
+        let cached = self.nonce.lock().unwrap().take();
+        if let Some(nonce) = cached {
+            return Ok(nonce);
+        }
+        let response = self.client.head(self.new_nonce_url.as_str()).send().await?;
+        response
+            .headers()
+            .get(REPLAY_NONCE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+            .ok_or_else(|| "newNonce did not return a Replay-Nonce header".into())
+    }
+
+    fn store_nonce(&self, response: &Response) {
+        let nonce = response
+            .headers()
+            .get(REPLAY_NONCE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        if nonce.is_some() {
+            *self.nonce.lock().unwrap() = nonce;
+        }
+    }
+
+    fn sign(&self, url: &str, nonce: String, payload: Option<Value>) -> AsyncResult<String> {
+        let header = match &self.account_url {
+            Some(account_url) => JWSHeader::with_kid(self.key.kt.get_key_alg(), account_url.clone(), nonce, url.to_string()),
+            None => JWSHeader::with_jwk(self.key.kt.get_key_alg(), Some(nonce), url.to_string()),
+        };
+        let jws = match payload {
+            Some(payload) => JWS::with_header_and_payload(header, payload),
+            None => JWS::post_as_get(header),
+        };
+        jws.finalize_flattened(&self.key)
+            .map(|v| v.to_string())
+            .map_err(|e| e.to_string().into())
+    }
+}
@@ -50,6 +50,7 @@ impl SupportedKey {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(string: &str) -> Result<SupportedKey, Box<dyn Error>> {
         match string.to_lowercase().as_str() {
             "rsa-2048" => Ok(SupportedKey::Rsa2048),
@@ -121,7 +122,7 @@ impl Serialize for SupportedAlgorithm {
     where
         S: Serializer
     {
-        serializer.serialize_str(&self.to_string())
+        serializer.serialize_str(self.to_string())
     }
 }
 
@@ -136,6 +137,7 @@ impl SupportedAlgorithm {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(alg: &str) -> SupportedAlgorithm {
         match alg {
             "RS256" => SupportedAlgorithm::RS256,
@@ -1,40 +1,209 @@
+use crate::crypto::SupportedKey;
+use crate::encoding::encode_b64;
+use crate::keys::PrivateKey;
+use openssl::asn1::{Asn1Object, Asn1OctetString};
 use openssl::hash::MessageDigest;
-use openssl::pkey::{PKey, Private};
-use openssl::x509::{X509NameBuilder, X509Req, X509ReqBuilder};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Extension, X509NameBuilder, X509Req, X509ReqBuilder};
+use std::error::Error;
+use std::net::IpAddr;
+
+/// id-pe-tlsfeature, RFC 7633
+const TLS_FEATURE_OID: &str = "1.3.6.1.5.5.7.1.24";
+/// DER for `SEQUENCE { INTEGER 5 }` - status_request, a.k.a. "OCSP must-staple"
+const TLS_FEATURE_STATUS_REQUEST: &[u8] = &[0x30, 0x03, 0x02, 0x01, 0x05];
+
+/// An identifier that ends up in the subjectAltName of the CSR.
+///
+/// `Ip` covers RFC 8738 identifiers, everything else is a plain DNS name (wildcards included).
+#[derive(Debug, Clone, PartialEq)]
+pub enum CsrIdentifier {
+    Dns(String),
+    Ip(IpAddr),
+}
+
+impl CsrIdentifier {
+    /// Parses the identifier value the same way the ACME order does,
+    /// anything that looks like an ip address is treated as one.
+    pub fn parse(value: &str) -> Self {
+        match value.parse::<IpAddr>() {
+            Ok(ip) => CsrIdentifier::Ip(ip),
+            Err(_) => CsrIdentifier::Dns(value.to_lowercase()),
+        }
+    }
+
+    /// ACME identifier type, as used in the `newOrder` payload.
+    pub fn acme_type(&self) -> &'static str {
+        match self {
+            CsrIdentifier::Dns(_) => "dns",
+            CsrIdentifier::Ip(_) => "ip",
+        }
+    }
+
+    pub fn value(&self) -> String {
+        match self {
+            CsrIdentifier::Dns(name) => name.clone(),
+            CsrIdentifier::Ip(ip) => ip.to_string(),
+        }
+    }
+}
+
+struct CustomExtension {
+    oid: String,
+    critical: bool,
+    der: Vec<u8>,
+}
 
-// TODO: remove allow
-#[allow(dead_code)]
 pub struct CertificateSigningRequest {
-    x509req: X509Req
-}
-
-// TODO: remove allow
-#[allow(dead_code)]
-pub fn create_new_certificate_request(
-    country: &str,
-    state: Option<&str>,
-    city: &str,
-    organization: &str,
-    department: &str,
-    url: &str,
-    pkey: &PKey<Private>
-) -> Result<CertificateSigningRequest, Box<dyn std::error::Error>> {
-    let mut req_name = X509NameBuilder::new()?;
-    req_name.append_entry_by_text("C", &country)?;
-    if let Some(s) = state {
-        req_name.append_entry_by_text("ST", &s)?;
-    }
-    req_name.append_entry_by_text("L", &city)?;
-    req_name.append_entry_by_text("O", &organization)?;
-    req_name.append_entry_by_text("OU", &department)?;
-    req_name.append_entry_by_text("CN", &url)?;
-
-    let mut req_builder = X509ReqBuilder::new()?;
-
-    req_builder.set_subject_name(req_name.build().as_ref())?;
-    req_builder.sign(pkey, MessageDigest::sha256())?;
-
-    Ok(CertificateSigningRequest {
-        x509req: req_builder.build()
-    })
-}
\ No newline at end of file
+    x509req: X509Req,
+    identifiers: Vec<CsrIdentifier>,
+}
+
+impl CertificateSigningRequest {
+    pub fn to_der(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.x509req.to_der()?)
+    }
+
+    pub fn to_pem(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.x509req.to_pem()?)
+    }
+
+    /// The `csr` field of the finalize payload (base64url encoded DER without padding).
+    pub fn to_finalize_value(&self) -> Result<String, Box<dyn Error>> {
+        Ok(encode_b64(&self.to_der()?))
+    }
+
+    pub fn identifiers(&self) -> &[CsrIdentifier] {
+        &self.identifiers
+    }
+
+    pub fn x509_req(&self) -> &X509Req {
+        &self.x509req
+    }
+}
+
+/// Builder for the CSR that's sent when finalizing an order.
+///
+/// ACME CAs only look at the subjectAltName extension, the CN is optional and
+/// if it's set it has to be one of the identifiers.
+#[derive(Default)]
+pub struct CsrBuilder {
+    identifiers: Vec<CsrIdentifier>,
+    common_name: Option<String>,
+    must_staple: bool,
+    extensions: Vec<CustomExtension>,
+}
+
+impl CsrBuilder {
+    pub fn new() -> Self {
+        CsrBuilder::default()
+    }
+
+    pub fn dns(mut self, name: &str) -> Self {
+        self.push(CsrIdentifier::Dns(name.to_lowercase()));
+        self
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.push(CsrIdentifier::Ip(ip));
+        self
+    }
+
+    pub fn identifier(mut self, identifier: CsrIdentifier) -> Self {
+        self.push(identifier);
+        self
+    }
+
+    pub fn common_name(mut self, common_name: &str) -> Self {
+        self.common_name = Some(common_name.to_lowercase());
+        self
+    }
+
+    /// Adds the TLS feature extension (RFC 7633) requesting OCSP stapling.
+    pub fn must_staple(mut self, must_staple: bool) -> Self {
+        self.must_staple = must_staple;
+        self
+    }
+
+    /// Adds an arbitrary extension, `der` is the DER encoded extnValue content.
+    pub fn extension(mut self, oid: &str, critical: bool, der: Vec<u8>) -> Self {
+        self.extensions.push(CustomExtension {
+            oid: oid.to_string(),
+            critical,
+            der,
+        });
+        self
+    }
+
+    pub fn build(&self, key: &PrivateKey) -> Result<CertificateSigningRequest, Box<dyn Error>> {
+        if self.identifiers.is_empty() {
+            return Err("CSR requires at least one identifier".into());
+        }
+        let cn_listed = self
+            .common_name
+            .as_ref()
+            .is_none_or(|cn| self.identifiers.contains(&CsrIdentifier::parse(cn)));
+        if !cn_listed {
+            return Err("Common name has to be one of the requested identifiers".into());
+        }
+        let mut req_builder = X509ReqBuilder::new()?;
+        let mut req_name = X509NameBuilder::new()?;
+        if let Some(cn) = &self.common_name {
+            req_name.append_entry_by_text("CN", cn)?;
+        }
+        req_builder.set_subject_name(req_name.build().as_ref())?;
+        req_builder.set_pubkey(&key.k)?;
+
+        let mut extensions = Stack::<X509Extension>::new()?;
+        let mut san = SubjectAlternativeName::new();
+        for identifier in &self.identifiers {
+            match identifier {
+                CsrIdentifier::Dns(name) => san.dns(name),
+                CsrIdentifier::Ip(ip) => san.ip(ip.to_string().as_str()),
+            };
+        }
+        extensions.push(san.build(&req_builder.x509v3_context(None))?)?;
+        if self.must_staple {
+            extensions.push(Self::der_extension(
+                TLS_FEATURE_OID,
+                false,
+                TLS_FEATURE_STATUS_REQUEST,
+            )?)?;
+        }
+        for extension in &self.extensions {
+            extensions.push(Self::der_extension(
+                extension.oid.as_str(),
+                extension.critical,
+                extension.der.as_slice(),
+            )?)?;
+        }
+        req_builder.add_extensions(&extensions)?;
+        req_builder.sign(&key.k, signature_digest(&key.kt))?;
+
+        Ok(CertificateSigningRequest {
+            x509req: req_builder.build(),
+            identifiers: self.identifiers.clone(),
+        })
+    }
+
+    fn push(&mut self, identifier: CsrIdentifier) {
+        if !self.identifiers.contains(&identifier) {
+            self.identifiers.push(identifier);
+        }
+    }
+
+    fn der_extension(oid: &str, critical: bool, der: &[u8]) -> Result<X509Extension, Box<dyn Error>> {
+        let oid = Asn1Object::from_str(oid)?;
+        let contents = Asn1OctetString::new_from_bytes(der)?;
+        Ok(X509Extension::new_from_der(&oid, critical, &contents)?)
+    }
+}
+
+/// Ed25519 signs the message as-is, everything else uses the hash of the matching JWS algorithm.
+pub(crate) fn signature_digest(key_type: &SupportedKey) -> MessageDigest {
+    match key_type {
+        SupportedKey::Ed25519 => MessageDigest::null(),
+        kt => kt.get_key_alg().get_hash().get_digest(),
+    }
+}
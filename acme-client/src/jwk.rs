@@ -2,10 +2,11 @@ use std::error::Error;
 use openssl::bn::{BigNum, BigNumRef};
 use openssl::pkey::{PKey, Private, Public};
 use openssl::rsa::Rsa;
+use openssl::sha::sha256;
 use serde::Deserialize;
 use serde_json::Value;
 use crate::crypto::{SupportedAlgorithm, SupportedKey};
-use crate::encoding::decode_b64;
+use crate::encoding::{decode_b64, encode_b64};
 
 #[derive(Deserialize, Debug)]
 pub struct GenericJWK {
@@ -40,12 +41,41 @@ impl GenericJWK {
     pub fn from_value(jwk: Value) -> Result<Self, Box<dyn Error>> {
         Ok(serde_json::from_value(jwk)?)
     }
+    pub fn algorithm(&self) -> SupportedAlgorithm {
+        SupportedAlgorithm::from_str(self.alg.as_str())
+    }
+    /// Whether the JWK is the public half of `key`, only RSA keys can be parsed so far.
+    pub fn matches(&self, key: &PKey<Private>) -> Result<bool, Box<dyn Error>> {
+        Ok(self.parse_pub()?.public_eq(key))
+    }
     pub fn parse_pub(&self) -> Result<PKey<Public>, Box<dyn Error>> {
         match self.kty.as_str() {
             "RSA" => Ok(self.parse_rsa_pub()?),
-            _ => panic!("Unknown kty {}", self.kty),
+            kty => Err(format!("Parsing {} JWKs is not supported", kty).into()),
         }
     }
+    /// The JWK thumbprint of RFC 7638, base64url encoded: the SHA-256 of the required members
+    /// in lexicographic order, without whitespace.
+    pub fn thumbprint(&self) -> Result<String, Box<dyn Error>> {
+        let member = |name: &str, value: &Option<String>| {
+            value
+                .as_deref()
+                .map(|value| format!("\"{}\":\"{}\"", name, value))
+                .ok_or_else(|| format!("{} JWK without {}", self.kty, name))
+        };
+        let members = match self.kty.as_str() {
+            "RSA" => vec![member("e", &self.e)?, member("kty", &Some(self.kty.clone()))?, member("n", &self.n)?],
+            "EC" => vec![
+                member("crv", &self.crv)?,
+                member("kty", &Some(self.kty.clone()))?,
+                member("x", &self.x)?,
+                member("y", &self.y)?,
+            ],
+            "OKP" => vec![member("crv", &self.crv)?, member("kty", &Some(self.kty.clone()))?, member("x", &self.x)?],
+            kty => return Err(format!("Unknown kty {}", kty).into()),
+        };
+        Ok(encode_b64(&sha256(format!("{{{}}}", members.join(",")).as_bytes())))
+    }
     fn parse_rsa_pub(&self) -> Result<PKey<Public>, Box<dyn Error>> {
         let n_coordinate_bytes = decode_b64(self.n.clone().unwrap().as_str())?;
         let e_coordinate_bytes = decode_b64(self.e.clone().unwrap().as_str())?;
@@ -38,22 +38,52 @@ impl JWSHeader {
             url: None,
         }
     }
+    /// Header for requests signed by an existing account, `kid` is the account url.
+    pub fn with_kid(alg: SupportedAlgorithm, kid: String, nonce: String, url: String) -> Self {
+        JWSHeader {
+            alg,
+            kid: Some(kid),
+            jwk: None,
+            nonce: Some(nonce),
+            url: Some(url),
+        }
+    }
+    /// Header for requests signed by a key the CA doesn't know by an account yet, e.g.
+    /// `newAccount`. The inner JWS of a key change goes without a `nonce`.
+    pub fn with_jwk(alg: SupportedAlgorithm, nonce: Option<String>, url: String) -> Self {
+        JWSHeader {
+            alg,
+            kid: None,
+            jwk: None,
+            nonce,
+            url: Some(url),
+        }
+    }
     pub fn serialize_with_pkey(&self, pkey: &PrivateKey) -> Result<String, Box<dyn Error>> {
-        Ok(serde_json::to_string(&json!({
+        let mut header = json!({
             "alg": self.alg,
             "jwk": pkey.get_jwk()?,
-            "nonce": "placeholder",
-            "url": "",
-        }))?)
+            "url": self.url.as_deref().unwrap_or(""),
+        });
+        if let Some(nonce) = &self.nonce {
+            header["nonce"] = json!(nonce);
+        }
+        Ok(serde_json::to_string(&header)?)
     }
     pub fn serialize_with_kid(&self, kid: String) -> Result<String, Box<dyn Error>> {
         Ok(serde_json::to_string(&json!({
             "alg": self.alg,
             "kid": Some(kid),
-            "nonce": "placeholder",
-            "url": "",
+            "nonce": self.nonce.as_deref().unwrap_or("placeholder"),
+            "url": self.url.as_deref().unwrap_or(""),
         }))?)
     }
+    fn serialize(&self, pkey: &PrivateKey) -> Result<String, Box<dyn Error>> {
+        match &self.kid {
+            Some(kid) => self.serialize_with_kid(kid.clone()),
+            None => self.serialize_with_pkey(pkey),
+        }
+    }
 
     pub fn from_string(header: String) -> Result<Self, Box<dyn Error>> {
         Ok(serde_json::from_str(&header)?)
@@ -71,17 +101,40 @@ impl JWS {
             payload,
         }
     }
+    /// POST-as-GET (RFC 8555 section 6.3), the payload is sent as an empty string.
+    pub fn post_as_get(header: JWSHeader) -> Self {
+        JWS {
+            header,
+            payload: Value::Null,
+        }
+    }
     pub fn get_payload(&self) -> &Value {
         &self.payload
     }
     pub fn finalize(&self, pkey: &PrivateKey) -> Result<String, Box<dyn Error>> {
-        let encoded_header = encode_b64(self.header.serialize_with_pkey(&pkey)?.as_bytes());
-        let encoded_payload = encode_b64(self.payload.to_string().as_bytes());
+        let (header, payload, signature) = self.sign_parts(pkey)?;
+        Ok(format!("{}.{}.{}", header, payload, signature))
+    }
+    /// Flattened JSON serialization, the form ACME servers expect as `application/jose+json`.
+    pub fn finalize_flattened(&self, pkey: &PrivateKey) -> Result<Value, Box<dyn Error>> {
+        let (header, payload, signature) = self.sign_parts(pkey)?;
+        Ok(json!({
+            "protected": header,
+            "payload": payload,
+            "signature": signature,
+        }))
+    }
+    fn sign_parts(&self, pkey: &PrivateKey) -> Result<(String, String, String), Box<dyn Error>> {
+        let encoded_header = encode_b64(self.header.serialize(pkey)?.as_bytes());
+        let encoded_payload = match &self.payload {
+            Value::Null => String::new(),
+            payload => encode_b64(payload.to_string().as_bytes()),
+        };
         let jws_data = format!("{}.{}", encoded_header, encoded_payload);
         let signature = pkey.sign(&self.header, &jws_data)?;
-        Ok(format!("{}.{}", jws_data, encode_b64(signature.as_ref())))
+        Ok((encoded_header, encoded_payload, encode_b64(signature.as_ref())))
     }
-    pub fn parse(content: &String, key_fetcher: Box<dyn KeyFetcher>) -> Result<Option<JWS>, Box<dyn Error>> {
+    pub fn parse(content: &str, key_fetcher: Box<dyn KeyFetcher>) -> Result<Option<JWS>, Box<dyn Error>> {
         if count_occurrences(content, '.') != 2 {
             return Err("Error encountered when parsing JWS, JWS parts formatting is invalid".into())
         }
@@ -1,15 +1,14 @@
 use crate::crypto::SupportedKey;
 use crate::encoding::encode_b64;
 use crate::jws::JWSHeader;
-use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use openssl::bn::{BigNum, BigNumContext};
 use openssl::ec::{EcGroup, EcKey};
 use openssl::ecdsa::EcdsaSig;
 use openssl::pkey::{PKey, Private};
 use openssl::rsa::Rsa;
 use openssl::sign::Signer;
-use serde_json::{json, Value};
+use serde_json::json;
 use std::error::Error;
-use serde::Deserialize;
 use crate::jwk::fast_padded_coordinate_vector;
 
 pub struct PrivateKey {
@@ -124,13 +123,12 @@ impl PrivateKey {
         }
     }
 
+    /// PKCS#8 PEM, works for every supported key type.
     pub fn get_pem_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        
-        let a = self.k.ec_key()?.private_key_to_pem()?;
-        Ok(a)
+        Ok(self.k.private_key_to_pem_pkcs8()?)
     }
 
-    pub fn load_private_bytes(pem: &Vec<u8>, supported_key: SupportedKey) -> Result<Self, Box<dyn Error>> {
+    pub fn load_private_bytes(pem: &[u8], supported_key: SupportedKey) -> Result<Self, Box<dyn Error>> {
         let key = PKey::private_key_from_pem(pem)?;
         Ok(PrivateKey {
             kt: supported_key,
@@ -145,7 +143,7 @@ impl PrivateKey {
 
     fn sign_elliptic_curve(&self, header: &JWSHeader, data: &String) -> Result<Vec<u8>, Box<dyn Error>> {
         let hash = header.get_alg().get_hash().hash(data.as_bytes())?;
-        let signer = EcdsaSig::sign(&hash, &self.k.ec_key()?.as_ref())?;
+        let signer = EcdsaSig::sign(&hash, self.k.ec_key()?.as_ref())?;
         let coordinate_size = self.kt.get_coordinate_size();
         let mut r = fast_padded_coordinate_vector(signer.r(), coordinate_size);
         let s = fast_padded_coordinate_vector(signer.s(), coordinate_size);
@@ -2,10 +2,10 @@ pub mod encoding;
 pub mod crypto;
 pub mod keys;
 pub mod jws;
-mod csr;
-mod certificate;
+pub mod csr;
+pub mod certificate;
 pub mod comms;
-mod jwk;
+pub mod jwk;
 
 #[cfg(test)]
 mod test;
\ No newline at end of file
@@ -2,4 +2,7 @@ mod encoding;
 mod crypto;
 mod keys;
 mod jws;
-mod jwk;
\ No newline at end of file
+mod jwk;
+mod csr;
+mod certificate;
+mod comms;
\ No newline at end of file
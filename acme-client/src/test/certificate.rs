@@ -0,0 +1,159 @@
+use crate::certificate::{Certificate, CertificateChain};
+use crate::crypto::SupportedKey;
+use crate::csr::CsrIdentifier;
+use crate::keys::PrivateKey;
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, SubjectAlternativeName, SubjectKeyIdentifier,
+};
+use openssl::x509::{X509, X509Builder, X509NameBuilder};
+use std::net::{IpAddr, Ipv4Addr};
+
+const NOT_BEFORE: i64 = 1_700_000_000;
+const NOT_AFTER: i64 = 1_707_776_000;
+
+fn issue(cn: &str, key: &PrivateKey, issuer: Option<(&X509, &PrivateKey)>, serial: u32, ca: bool) -> X509 {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("O", "Sentry Test").unwrap();
+    name.append_entry_by_text("CN", cn).unwrap();
+    let name = name.build();
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&Asn1Integer::from_bn(&BigNum::from_u32(serial).unwrap()).unwrap())
+        .unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder
+        .set_issuer_name(issuer.map(|(c, _)| c.subject_name()).unwrap_or(&name))
+        .unwrap();
+    builder.set_pubkey(&key.k).unwrap();
+    builder.set_not_before(&Asn1Time::from_unix(NOT_BEFORE).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::from_unix(NOT_AFTER).unwrap()).unwrap();
+    if ca {
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+    } else {
+        let mut san = SubjectAlternativeName::new();
+        san.dns("example.org").dns("www.example.org").ip("192.0.2.7");
+        let san = san.build(&builder.x509v3_context(issuer.map(|(c, _)| c.as_ref()), None)).unwrap();
+        builder.append_extension(san).unwrap();
+    }
+    let ski = SubjectKeyIdentifier::new()
+        .build(&builder.x509v3_context(issuer.map(|(c, _)| c.as_ref()), None))
+        .unwrap();
+    builder.append_extension(ski).unwrap();
+    if issuer.is_some() {
+        let aki = AuthorityKeyIdentifier::new()
+            .keyid(true)
+            .build(&builder.x509v3_context(issuer.map(|(c, _)| c.as_ref()), None))
+            .unwrap();
+        builder.append_extension(aki).unwrap();
+    }
+    let signer = issuer.map(|(_, k)| k).unwrap_or(key);
+    builder.sign(&signer.k, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+pub(super) struct TestChain {
+    pub root: X509,
+    pub intermediate: X509,
+    pub leaf: X509,
+    pub leaf_key: PrivateKey,
+}
+
+fn test_chain() -> TestChain {
+    test_chain_with_root("Sentry Test Root")
+}
+
+pub(super) fn test_chain_with_root(root_cn: &str) -> TestChain {
+    let root_key = PrivateKey::from_supported_type(SupportedKey::EcP384).unwrap();
+    let intermediate_key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    let leaf_key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    let root = issue(root_cn, &root_key, None, 1, true);
+    let intermediate = issue("Sentry Test Intermediate", &intermediate_key, Some((&root, &root_key)), 2, true);
+    let leaf = issue("example.org", &leaf_key, Some((&intermediate, &intermediate_key)), 0xABCDEF, false);
+    TestChain { root, intermediate, leaf, leaf_key }
+}
+
+pub(super) fn bundle(certs: &[&X509]) -> Vec<u8> {
+    certs.iter().flat_map(|c| c.to_pem().unwrap()).collect()
+}
+
+#[test]
+fn test_certificate_inspection() {
+    let chain = test_chain();
+    let cert = Certificate::from_der(&chain.leaf.to_der().unwrap()).unwrap();
+    assert_eq!(cert.not_before().unwrap(), NOT_BEFORE);
+    assert_eq!(cert.not_after().unwrap(), NOT_AFTER);
+    assert_eq!(cert.seconds_left(NOT_AFTER - 10).unwrap(), 10);
+    assert_eq!(cert.serial().unwrap(), "ABCDEF");
+    assert_eq!(
+        cert.subject_alt_names(),
+        vec![
+            CsrIdentifier::Dns("example.org".to_string()),
+            CsrIdentifier::Dns("www.example.org".to_string()),
+            CsrIdentifier::Ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))),
+        ]
+    );
+    assert_eq!(cert.issuer_common_name().unwrap(), "Sentry Test Intermediate");
+    assert_eq!(cert.issuer(), "O=Sentry Test, CN=Sentry Test Intermediate");
+    assert_eq!(cert.subject_common_name().unwrap(), "example.org");
+    assert_eq!(cert.key_type().unwrap(), Some(SupportedKey::EcP256));
+    assert_eq!(cert.spki_sha256().unwrap().len(), 44);
+    assert_eq!(cert.sha256_fingerprint().unwrap().len(), 64);
+}
+
+#[test]
+fn test_certificate_key_identifiers() {
+    let chain = test_chain();
+    let leaf = Certificate::from_x509(chain.leaf);
+    let intermediate = Certificate::from_x509(chain.intermediate);
+    assert!(leaf.subject_key_id().is_some());
+    assert_eq!(leaf.authority_key_id(), intermediate.subject_key_id());
+    assert!(Certificate::from_x509(chain.root).authority_key_id().is_none());
+}
+
+#[test]
+fn test_certificate_matches_key() {
+    let chain = test_chain();
+    let cert = Certificate::from_x509(chain.leaf);
+    assert!(cert.matches_key(&chain.leaf_key).unwrap());
+    let other = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    assert!(!cert.matches_key(&other).unwrap());
+}
+
+#[test]
+fn test_chain_split_and_order() {
+    let chain = test_chain();
+    let parsed = CertificateChain::from_pem(&bundle(&[&chain.leaf, &chain.intermediate])).unwrap();
+    assert_eq!(parsed.leaf().subject_common_name().unwrap(), "example.org");
+    assert_eq!(parsed.intermediates().len(), 1);
+    assert!(parsed.is_ordered());
+    assert_eq!(parsed.root_issuer_common_name().unwrap(), "Sentry Test Root");
+    assert_eq!(parsed.chain_pem().unwrap(), chain.intermediate.to_pem().unwrap());
+    assert_eq!(parsed.fullchain_pem().unwrap(), bundle(&[&chain.leaf, &chain.intermediate]));
+
+    let with_root = CertificateChain::from_pem(&bundle(&[&chain.leaf, &chain.intermediate, &chain.root])).unwrap();
+    assert!(with_root.top().is_self_signed());
+    assert!(with_root.is_ordered());
+
+    let shuffled = CertificateChain::from_pem(&bundle(&[&chain.leaf, &chain.root, &chain.intermediate])).unwrap();
+    assert!(!shuffled.is_ordered());
+}
+
+#[test]
+fn test_chain_rejects_empty_bundle() {
+    assert!(CertificateChain::from_pem(b"").is_err());
+}
+
+#[test]
+fn test_chain_pkcs12() {
+    let chain = test_chain();
+    let parsed = CertificateChain::from_pem(&bundle(&[&chain.leaf, &chain.intermediate])).unwrap();
+    let der = parsed.to_pkcs12(&chain.leaf_key, "example.org", "secret").unwrap();
+    let bundle = openssl::pkcs12::Pkcs12::from_der(&der).unwrap().parse2("secret").unwrap();
+    assert!(bundle.pkey.unwrap().public_eq(&chain.leaf_key.k));
+    assert_eq!(bundle.cert.unwrap().to_der().unwrap(), chain.leaf.to_der().unwrap());
+    assert_eq!(bundle.ca.unwrap().len(), 1);
+}
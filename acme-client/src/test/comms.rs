@@ -0,0 +1,154 @@
+use crate::certificate::CertificateChain;
+use crate::comms::account::{contacts, key_change_payload};
+use crate::comms::certificate::{
+    alternate_links, parse_link_header, revocation_payload, select_chain, DownloadedChain, LinkHeader, PreferredChain,
+};
+use crate::comms::order::{key_authorization, new_order_payload, OrderObject};
+use crate::crypto::SupportedKey;
+use crate::csr::CsrIdentifier;
+use crate::encoding::{decode_b64, encode_b64};
+use crate::keys::PrivateKey;
+use crate::test::certificate::{bundle, test_chain_with_root};
+use reqwest::header::{HeaderMap, HeaderValue, LINK};
+use reqwest::Url;
+use serde_json::{json, Value};
+
+fn downloaded(root_cn: &str, url: &str, is_default: bool) -> (DownloadedChain, String) {
+    let chain = test_chain_with_root(root_cn);
+    let fingerprint = hex::encode(chain.root.digest(openssl::hash::MessageDigest::sha256()).unwrap());
+    let pem = bundle(&[&chain.leaf, &chain.intermediate, &chain.root]);
+    (
+        DownloadedChain {
+            url: url.to_string(),
+            chain: CertificateChain::from_pem(&pem).unwrap(),
+            is_default,
+        },
+        fingerprint,
+    )
+}
+
+#[test]
+fn test_parse_link_header() {
+    let links = parse_link_header(
+        r#"<https://ca.test/cert/1/1>;rel="alternate", <https://ca.test/dir>; rel=index"#,
+    );
+    assert_eq!(
+        links,
+        vec![
+            LinkHeader { url: "https://ca.test/cert/1/1".to_string(), rel: "alternate".to_string() },
+            LinkHeader { url: "https://ca.test/dir".to_string(), rel: "index".to_string() },
+        ]
+    );
+    assert!(parse_link_header("garbage").is_empty());
+}
+
+#[test]
+fn test_alternate_links_resolved() {
+    let mut headers = HeaderMap::new();
+    headers.append(LINK, HeaderValue::from_static(r#"</cert/1/1>;rel="alternate""#));
+    headers.append(LINK, HeaderValue::from_static(r#"<https://ca.test/dir>;rel="index""#));
+    headers.append(LINK, HeaderValue::from_static(r#"<https://other.test/cert/1/2>;rel="alternate""#));
+    let base = Url::parse("https://ca.test/cert/1").unwrap();
+    assert_eq!(
+        alternate_links(&headers, &base),
+        vec!["https://ca.test/cert/1/1".to_string(), "https://other.test/cert/1/2".to_string()]
+    );
+}
+
+#[test]
+fn test_preferred_chain_parse() {
+    assert_eq!(
+        PreferredChain::parse("ISRG Root X1"),
+        PreferredChain::IssuerCommonName("ISRG Root X1".to_string())
+    );
+    let fingerprint = "AB:".repeat(31) + "AB";
+    assert_eq!(
+        PreferredChain::parse(fingerprint.as_str()),
+        PreferredChain::RootFingerprint("ab".repeat(32))
+    );
+}
+
+#[test]
+fn test_select_chain() {
+    let (default, _) = downloaded("Default Root", "https://ca.test/cert/1", true);
+    let (alternate, alternate_fingerprint) = downloaded("Legacy Root", "https://ca.test/cert/1/1", false);
+    let chains = || vec![default.clone(), alternate.clone()];
+
+    let selected = select_chain(chains(), None).unwrap();
+    assert!(selected.is_default);
+
+    let by_cn = PreferredChain::IssuerCommonName("Legacy Root".to_string());
+    let selected = select_chain(chains(), Some(&by_cn)).unwrap();
+    assert_eq!(selected.url, "https://ca.test/cert/1/1");
+    assert_eq!(selected.issuer().unwrap(), "Legacy Root");
+
+    let by_fingerprint = PreferredChain::RootFingerprint(alternate_fingerprint);
+    assert!(!select_chain(chains(), Some(&by_fingerprint)).unwrap().is_default);
+
+    let unknown = PreferredChain::IssuerCommonName("Unknown Root".to_string());
+    assert!(select_chain(chains(), Some(&unknown)).unwrap().is_default);
+    assert!(select_chain(Vec::new(), Some(&unknown)).is_none());
+}
+
+fn decoded(part: &Value) -> Value {
+    serde_json::from_slice(&decode_b64(part.as_str().unwrap()).unwrap()).unwrap()
+}
+
+#[test]
+fn test_key_change_is_signed_by_the_new_key() {
+    let old_key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    let new_key = PrivateKey::from_supported_type(SupportedKey::EcP384).unwrap();
+    let inner = key_change_payload("https://ca.test/acct/1", &old_key, &new_key, "https://ca.test/key-change").unwrap();
+    let header = decoded(&inner["protected"]);
+    assert_eq!(header["jwk"], new_key.get_jwk().unwrap());
+    assert_eq!(header["url"], "https://ca.test/key-change");
+    assert!(header.get("nonce").is_none());
+    assert!(header.get("kid").is_none());
+    let payload = decoded(&inner["payload"]);
+    assert_eq!(payload, json!({"account": "https://ca.test/acct/1", "oldKey": old_key.get_jwk().unwrap()}));
+}
+
+#[test]
+fn test_revocation_payload() {
+    let (downloaded, _) = downloaded("Default Root", "https://ca.test/cert/1", true);
+    let leaf = downloaded.chain.leaf();
+    let payload = revocation_payload(leaf, Some(4)).unwrap();
+    assert_eq!(payload, json!({"certificate": encode_b64(&leaf.to_der().unwrap()), "reason": 4}));
+    assert!(revocation_payload(leaf, None).unwrap().get("reason").is_none());
+    assert_eq!(contacts(&["admin@example.org".to_string()]), vec!["mailto:admin@example.org"]);
+}
+
+#[test]
+fn test_new_order_payload() {
+    let identifiers = [CsrIdentifier::parse("Example.org"), CsrIdentifier::parse("192.0.2.1")];
+    assert_eq!(
+        new_order_payload(&identifiers, None),
+        json!({"identifiers": [{"type": "dns", "value": "example.org"}, {"type": "ip", "value": "192.0.2.1"}]})
+    );
+    assert_eq!(new_order_payload(&identifiers[..1], Some("shortlived"))["profile"], json!("shortlived"));
+}
+
+#[test]
+fn test_order_object_of_a_pending_order() {
+    let order: OrderObject = serde_json::from_value(json!({
+        "status": "pending",
+        "expires": "2026-10-26T00:00:00Z",
+        "identifiers": [{"type": "dns", "value": "example.org"}],
+        "authorizations": ["https://ca.test/authz/1"],
+        "finalize": "https://ca.test/order/1/finalize"
+    }))
+    .unwrap();
+    assert_eq!(order.identifiers[0].kind, "dns");
+    assert_eq!(order.certificate, None);
+}
+
+#[test]
+fn test_key_authorization_appends_the_thumbprint() {
+    let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    let authorization = key_authorization("token", &key).unwrap();
+    let (token, thumbprint) = authorization.split_once('.').unwrap();
+    assert_eq!(token, "token");
+    // base64url of a SHA-256
+    assert_eq!(decode_b64(thumbprint).unwrap().len(), 32);
+    assert_eq!(key_authorization("token", &key).unwrap(), authorization);
+}
@@ -0,0 +1,80 @@
+use crate::crypto::SupportedKey;
+use crate::csr::{CsrBuilder, CsrIdentifier};
+use crate::encoding::decode_b64;
+use crate::keys::PrivateKey;
+use openssl::nid::Nid;
+use openssl::x509::X509Req;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn test_csr_signed_for_every_key_type() {
+    for key_type in [
+        SupportedKey::Rsa2048,
+        SupportedKey::EcP256,
+        SupportedKey::EcP384,
+        SupportedKey::EcP521,
+        SupportedKey::Ed25519,
+    ] {
+        let key = PrivateKey::from_supported_type(key_type.clone()).unwrap();
+        let csr = CsrBuilder::new().dns("example.org").build(&key).unwrap();
+        let parsed = X509Req::from_der(&csr.to_der().unwrap()).unwrap();
+        let public = parsed.public_key().unwrap();
+        assert!(parsed.verify(&public).unwrap(), "signature invalid for {}", key_type);
+        assert!(public.public_eq(&key.k));
+    }
+}
+
+#[test]
+fn test_csr_identifiers_and_common_name() {
+    let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    let csr = CsrBuilder::new()
+        .dns("Example.org")
+        .dns("*.example.org")
+        .dns("example.org")
+        .ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+        .ip(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        .common_name("example.org")
+        .build(&key)
+        .unwrap();
+    assert_eq!(csr.identifiers().len(), 4);
+    let der = csr.to_der().unwrap();
+    assert!(contains(&der, b"*.example.org"));
+    assert!(contains(&der, &[192, 0, 2, 1]));
+    let parsed = X509Req::from_der(&der).unwrap();
+    let cn = parsed.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap();
+    assert_eq!(cn.data().as_utf8().unwrap().to_string(), "example.org");
+}
+
+#[test]
+fn test_csr_must_staple() {
+    let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    let staple = [0x30, 0x03, 0x02, 0x01, 0x05];
+    let without = CsrBuilder::new().dns("example.org").build(&key).unwrap();
+    assert!(!contains(&without.to_der().unwrap(), &staple));
+    let with = CsrBuilder::new().dns("example.org").must_staple(true).build(&key).unwrap();
+    assert!(contains(&with.to_der().unwrap(), &staple));
+}
+
+#[test]
+fn test_csr_rejects_invalid_requests() {
+    let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    assert!(CsrBuilder::new().build(&key).is_err());
+    assert!(CsrBuilder::new().dns("example.org").common_name("example.com").build(&key).is_err());
+}
+
+#[test]
+fn test_csr_finalize_value() {
+    let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    let csr = CsrBuilder::new()
+        .identifier(CsrIdentifier::parse("10.0.0.1"))
+        .build(&key)
+        .unwrap();
+    let value = csr.to_finalize_value().unwrap();
+    assert!(!value.contains('=') && !value.contains('+') && !value.contains('/'));
+    assert_eq!(decode_b64(&value).unwrap(), csr.to_der().unwrap());
+    assert_eq!(csr.identifiers()[0].acme_type(), "ip");
+}
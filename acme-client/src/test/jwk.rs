@@ -1,5 +1,41 @@
+use crate::crypto::{SupportedAlgorithm, SupportedKey};
+use crate::jwk::GenericJWK;
+use crate::keys::PrivateKey;
+use serde_json::json;
 
 #[test]
-pub fn t() {
-    
-}
\ No newline at end of file
+fn test_thumbprint_of_the_rfc_7638_example() {
+    let jwk = GenericJWK::from_value(json!({
+        "kty": "RSA",
+        "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+        "e": "AQAB",
+        "alg": "RS256",
+        "kid": "2011-04-29"
+    }))
+    .unwrap();
+    assert_eq!(jwk.thumbprint().unwrap(), "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+}
+
+#[test]
+fn test_thumbprint_needs_the_members_of_the_key_type() {
+    let jwk = GenericJWK::from_value(json!({"kty": "EC", "alg": "ES256", "crv": "P-256", "x": "AA"})).unwrap();
+    assert!(jwk.thumbprint().is_err());
+}
+
+#[test]
+fn test_jwk_matches_its_private_key_only() {
+    let key = PrivateKey::from_supported_type(SupportedKey::Rsa2048).unwrap();
+    let other = PrivateKey::from_supported_type(SupportedKey::Rsa2048).unwrap();
+    let jwk = GenericJWK::from_value(key.get_jwk().unwrap()).unwrap();
+    assert_eq!(jwk.algorithm().to_string(), SupportedAlgorithm::RS256.to_string());
+    assert!(jwk.matches(&key.k).unwrap());
+    assert!(!jwk.matches(&other.k).unwrap());
+}
+
+#[test]
+fn test_unsupported_key_types_are_errors() {
+    let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+    let jwk = GenericJWK::from_value(key.get_jwk().unwrap()).unwrap();
+    assert!(jwk.parse_pub().is_err());
+    assert!(jwk.matches(&key.k).is_err());
+}
@@ -83,6 +83,7 @@ fn test_rsa_thumbprint() {
     let n = encode_b64(&rsa.n().to_vec());
     let j: RsaJwk = serde_json::from_value(jwk).unwrap();
     assert_eq!(j.kty, "RSA");
+    assert_eq!(j.alg, "RS256");
     assert_eq!(j.e, e);
     assert_eq!(j.n, n);
 }
@@ -110,4 +111,13 @@ fn test_rsa_public_key_thumbprint() {
 
     assert_eq!(rsa.e(), r.e());
     assert_eq!(rsa.n(), r.n());
-}
\ No newline at end of file
+}
+#[test]
+fn test_pem_round_trip_for_every_key_type() {
+    for key_type in [SupportedKey::Rsa2048, SupportedKey::EcP384, SupportedKey::Ed25519] {
+        let key = PrivateKey::from_supported_type(key_type.clone()).unwrap();
+        let pem = key.get_pem_bytes().unwrap();
+        let loaded = PrivateKey::load_private_bytes(&pem, key_type).unwrap();
+        assert!(loaded.k.public_eq(&key.k));
+    }
+}
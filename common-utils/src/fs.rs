@@ -1,7 +1,99 @@
+use crate::InternalIdTooling;
 use std::error::Error;
 use std::fs;
-use std::path::{Path, PathBuf};
-use tracing::debug;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::fmt::{Display, Formatter};
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Mode and ownership applied to files written through [`FileSystem::write_to_file_with`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileOptions {
+    pub mode: u32,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+}
+
+impl FileOptions {
+    /// 0600, for private keys and anything containing them.
+    pub fn private() -> Self {
+        FileOptions {
+            mode: 0o600,
+            owner: None,
+            group: None,
+        }
+    }
+    /// 0644, for certificates.
+    pub fn public() -> Self {
+        FileOptions {
+            mode: 0o644,
+            owner: None,
+            group: None,
+        }
+    }
+    pub fn with_owner(mut self, owner: Option<u32>, group: Option<u32>) -> Self {
+        self.owner = owner;
+        self.group = group;
+        self
+    }
+}
+
+/// Resolves a user name (or numeric uid) through `/etc/passwd`.
+pub fn lookup_uid(user: &str) -> Result<u32, Box<dyn Error>> {
+    lookup_id(user, "/etc/passwd")
+}
+
+/// Resolves a group name (or numeric gid) through `/etc/group`.
+pub fn lookup_gid(group: &str) -> Result<u32, Box<dyn Error>> {
+    lookup_id(group, "/etc/group")
+}
+
+fn lookup_id(name: &str, database: &str) -> Result<u32, Box<dyn Error>> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    let content = fs::read_to_string(database)?;
+    content
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields.len() > 2 && fields[0] == name)
+        .and_then(|fields| fields[2].parse::<u32>().ok())
+        .ok_or_else(|| format!("{} not found in {}", name, database).into())
+}
+
+/// Paths rejected by [`FileSystem`], every path it hands out stays inside `base_dir`.
+#[derive(Debug)]
+pub enum FileSystemError {
+    /// The path contains a `..` component.
+    ParentTraversal(String),
+    /// The path resolves (possibly through a symlink) to somewhere outside `base_dir`.
+    OutsideBaseDir(PathBuf),
+    /// File names have to be a single, non-empty path component.
+    InvalidFileName(String),
+    Io(std::io::Error),
+}
+
+impl Display for FileSystemError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileSystemError::ParentTraversal(path) => write!(f, "Path {} contains a parent directory reference", path),
+            FileSystemError::OutsideBaseDir(path) => write!(f, "Path {} is outside of the base directory", path.display()),
+            FileSystemError::InvalidFileName(name) => write!(f, "Invalid file name: {:?}", name),
+            FileSystemError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for FileSystemError {}
+
+impl From<std::io::Error> for FileSystemError {
+    fn from(e: std::io::Error) -> Self {
+        FileSystemError::Io(e)
+    }
+}
 
 pub struct FileSystem {
     base_dir: PathBuf,
@@ -14,34 +106,253 @@ impl FileSystem {
             fs::create_dir_all(base_dir)?;
         }
         Ok(FileSystem {
-            base_dir: base_dir.to_path_buf(),
+            base_dir: base_dir.canonicalize()?,
         })
     }
-    pub fn sub_dir(&self, name: &str) -> PathBuf {
-        self.base_dir.join(name)
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+    /// Resolves `name` against `base_dir`. Absolute paths are accepted as long as they point
+    /// into `base_dir`, existing parts of the path are canonicalized so symlinks can't escape.
+    pub fn sub_dir(&self, name: &str) -> Result<PathBuf, FileSystemError> {
+        if Path::new(name).components().any(|c| c == Component::ParentDir) {
+            return Err(FileSystemError::ParentTraversal(name.to_string()));
+        }
+        self.contain(&self.base_dir.join(name))
     }
     pub fn ensure_sub_dir(&self, name: &str) -> Result<PathBuf, Box<dyn Error>> {
-        let target = self.sub_dir(name);
-        if !target.exists() { 
+        let target = self.sub_dir(name)?;
+        if !target.exists() {
             fs::create_dir_all(&target)?;
+            // re-check, a concurrently created symlink would have been followed
+            self.contain(&target)?;
         }
         Ok(target)
     }
     pub fn file_exists(&self, dir: PathBuf, file_name: &str) -> bool {
-        dir.join(file_name).exists()
+        self.file_path(dir.to_str().unwrap_or_default(), file_name)
+            .is_ok_and(|path| path.exists())
+    }
+    /// Rejects anything but a plain file name, e.g. `../x`, `a/b` or `..`.
+    pub fn validate_file_name(name: &str) -> Result<(), FileSystemError> {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) if !name.contains(['/', '\\', '\0']) => Ok(()),
+            _ => Err(FileSystemError::InvalidFileName(name.to_string())),
+        }
+    }
+    fn file_path(&self, sub_dir: &str, filename: &str) -> Result<PathBuf, FileSystemError> {
+        Self::validate_file_name(filename)?;
+        self.contain(&self.sub_dir(sub_dir)?.join(filename))
+    }
+    /// Canonicalizes the longest existing prefix of `path` and checks it's inside `base_dir`,
+    /// the rest of the path doesn't exist yet and can't contain symlinks.
+    fn contain(&self, path: &Path) -> Result<PathBuf, FileSystemError> {
+        let mut existing = path.to_path_buf();
+        let mut missing = Vec::new();
+        while existing.symlink_metadata().is_err() {
+            match existing.file_name() {
+                Some(name) => missing.push(name.to_os_string()),
+                None => return Err(FileSystemError::OutsideBaseDir(path.to_path_buf())),
+            }
+            existing.pop();
+        }
+        let mut resolved = existing.canonicalize()?;
+        if !resolved.starts_with(&self.base_dir) {
+            return Err(FileSystemError::OutsideBaseDir(path.to_path_buf()));
+        }
+        resolved.extend(missing.iter().rev());
+        Ok(resolved)
     }
     pub fn write_to_file(&self, sub_dir: &str, filename: &str, data: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
-        let dir = self.sub_dir(sub_dir);
-        let file_path = dir.join(filename);
-        debug!("Writing file: {}", file_path.display());
-        fs::write(&file_path, data)?;
+        self.write_to_file_with(sub_dir, filename, data, &FileOptions::public())
+    }
+    /// Writes into a temp file next to the target, fsyncs it and renames it into place,
+    /// a crash leaves either the old or the new content but never a truncated file.
+    pub fn write_to_file_with(
+        &self,
+        sub_dir: &str,
+        filename: &str,
+        data: &[u8],
+        options: &FileOptions,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let file_path = self.file_path(sub_dir, filename)?;
+        let dir = self.sub_dir(sub_dir)?;
+        let tmp_path = dir.join(format!(".{}.{}.tmp", filename, InternalIdTooling::new_compact_id()));
+        debug!("Writing file: {} (mode {:o})", file_path.display(), options.mode);
+        let result = Self::write_tmp(&tmp_path, data, options).and_then(|_| {
+            fs::rename(&tmp_path, &file_path)?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        #[cfg(unix)]
+        File::open(&dir)?.sync_all()?;
         Ok(file_path)
     }
+    fn write_tmp(tmp_path: &Path, data: &[u8], options: &FileOptions) -> Result<(), Box<dyn Error>> {
+        let mut open_options = OpenOptions::new();
+        open_options.write(true).create_new(true);
+        #[cfg(unix)]
+        open_options.mode(options.mode);
+        let mut file = open_options.open(tmp_path)?;
+        #[cfg(unix)]
+        {
+            // the mode passed to open is still filtered through the umask
+            file.set_permissions(fs::Permissions::from_mode(options.mode))?;
+            if options.owner.is_some() || options.group.is_some() {
+                std::os::unix::fs::fchown(&file, options.owner, options.group)?;
+            }
+        }
+        file.write_all(data)?;
+        file.sync_all()?;
+        Ok(())
+    }
+    /// Walks `base_dir` looking for private keys readable by anyone but the owner.
+    ///
+    /// With `fix` set the offending files are chmod-ed to 0600, either way they're returned.
+    pub fn audit_key_permissions(&self, fix: bool) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut findings = Vec::new();
+        let mut pending = vec![self.base_dir.clone()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    pending.push(entry.path());
+                } else if file_type.is_file() && Self::is_exposed_key(&entry.path())? {
+                    warn!("Key file {} is readable by other users", entry.path().display());
+                    if fix {
+                        #[cfg(unix)]
+                        fs::set_permissions(entry.path(), fs::Permissions::from_mode(0o600))?;
+                        info!("Permissions of {} have been set to 0600", entry.path().display());
+                    }
+                    findings.push(entry.path());
+                }
+            }
+        }
+        findings.sort();
+        Ok(findings)
+    }
+    #[cfg(unix)]
+    fn is_exposed_key(path: &Path) -> Result<bool, Box<dyn Error>> {
+        if fs::metadata(path)?.permissions().mode() & 0o077 == 0 {
+            return Ok(false);
+        }
+        let is_key = match path.extension().and_then(|e| e.to_str()) {
+            Some("p12") | Some("pfx") | Some("key") => true,
+            Some("pem") => fs::read(path)?.windows(11).any(|w| w == b"PRIVATE KEY"),
+            _ => false,
+        };
+        Ok(is_key)
+    }
+    #[cfg(not(unix))]
+    fn is_exposed_key(_path: &Path) -> Result<bool, Box<dyn Error>> {
+        Ok(false)
+    }
+    /// Points `sub_dir/link_name` at `target`, replacing an existing link in one rename
+    /// so readers never see the link missing. Symlinks along `target` are followed before
+    /// it's checked to stay inside `base_dir`.
+    pub fn symlink(&self, sub_dir: &str, link_name: &str, target: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        Self::validate_file_name(link_name)?;
+        let dir = self.sub_dir(sub_dir)?;
+        let link_path = dir.join(link_name);
+        let tmp_path = dir.join(format!(".{}.{}.tmp", link_name, InternalIdTooling::new_compact_id()));
+        self.contain(&resolve_path(&dir.join(target))?)
+            .map_err(|_| FileSystemError::OutsideBaseDir(target.to_path_buf()))?;
+        debug!("Linking {} -> {}", link_path.display(), target.display());
+        let result = Self::link_tmp(&dir, target, &tmp_path).and_then(|_| Ok(fs::rename(&tmp_path, &link_path)?));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        Ok(link_path)
+    }
+    #[cfg(unix)]
+    fn link_tmp(_dir: &Path, target: &Path, tmp_path: &Path) -> Result<(), Box<dyn Error>> {
+        std::os::unix::fs::symlink(target, tmp_path)?;
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    fn link_tmp(dir: &Path, target: &Path, tmp_path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::copy(dir.join(target), tmp_path)?;
+        Ok(())
+    }
+    pub fn list_files(&self, sub_dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let dir = self.sub_dir(sub_dir)?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+    /// Symlinks in `sub_dir`, whatever they point at.
+    pub fn list_links(&self, sub_dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let dir = self.sub_dir(sub_dir)?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_symlink() {
+                names.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+    /// Directories in `sub_dir`, symlinks to directories are left out.
+    pub fn list_dirs(&self, sub_dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let dir = self.sub_dir(sub_dir)?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
     pub fn read_from_file(&self, sub_dir: &str, filename: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-        let dir = self.sub_dir(sub_dir);
-        let file_path = dir.join(filename);
+        let file_path = self.file_path(sub_dir, filename)?;
         debug!("Reading file: {}", file_path.display());
         let vec = fs::read(&file_path)?;
         Ok(vec)
     }
-}
\ No newline at end of file
+}
+
+/// Where `path` points once it exists. Components are resolved one by one like the kernel
+/// does, existing ones are canonicalized before a following `..` is applied, the rest is
+/// resolved lexically. Relative paths are taken from the working directory.
+pub fn resolve_path(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let mut resolved = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            other => {
+                resolved.push(other);
+                if let Ok(canonical) = resolved.canonicalize() {
+                    resolved = canonical;
+                }
+            }
+        }
+    }
+    Ok(resolved)
+}
@@ -3,21 +3,111 @@ pub mod fs;
 #[cfg(test)]
 mod test;
 
+use std::collections::BTreeMap;
 use std::slice::Iter;
 use std::sync::OnceLock;
 use tracing::Level;
 use uuid::Uuid;
 
+/// Name of the CA a configuration with a single base url refers to.
+pub const DEFAULT_CA_NAME: &str = "default";
+
+/// A CA acme-sentry holds accounts with, certificates refer to it by `name`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateAuthority {
+    pub name: String,
+    pub directory_url: String,
+}
+
+/// How the CA validates control over the identifiers of a certificate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChallengeMethod {
+    Http01,
+    Dns01,
+    TlsAlpn01,
+}
+
+impl ChallengeMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeMethod::Http01 => "http-01",
+            ChallengeMethod::Dns01 => "dns-01",
+            ChallengeMethod::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "http-01" => Ok(ChallengeMethod::Http01),
+            "dns-01" => Ok(ChallengeMethod::Dns01),
+            "tls-alpn-01" => Ok(ChallengeMethod::TlsAlpn01),
+            other => Err(format!("Unknown challenge method {}, expected http-01, dns-01 or tls-alpn-01", other)),
+        }
+    }
+}
+
+/// Commands run around issuing a certificate.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CertificateHooks {
+    /// Before the order is placed.
+    pub pre: Option<String>,
+    /// After the order, whether it succeeded or not.
+    pub post: Option<String>,
+    /// Once the new certificate is live.
+    pub deploy: Option<String>,
+}
+
+/// A certificate declared in the configuration, the daemon keeps it issued and renewed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateDefinition {
+    /// Name of the certificate, its files end up under `live/<name>`.
+    pub name: String,
+    /// DNS names, wildcards and ip addresses, normalized like the ACME order has them.
+    pub identifiers: Vec<String>,
+    pub key_type: String,
+    pub challenge: ChallengeMethod,
+    /// Settings of the challenge method, e.g. the `webroot` of http-01.
+    pub challenge_settings: BTreeMap<String, String>,
+    /// Name of the CA to order from.
+    pub ca: String,
+    /// ACME profile to order, for CAs that offer several.
+    pub profile: Option<String>,
+    /// Seconds before expiry the certificate is renewed, unset renews once a third of its
+    /// lifetime is left.
+    pub renew_before: Option<u64>,
+    /// Replaces the global output formats for this certificate.
+    pub output_formats: Option<Vec<String>>,
+    pub hooks: CertificateHooks,
+}
+
 #[derive(Debug)]
 pub struct ApplicationConfig {
     pub application_mode: bool,
-    pub base_url: String,
+    pub certificate_authorities: Vec<CertificateAuthority>,
     pub base_dir: String,
     pub output_dir: String,
     pub user_id: String,
     pub user_email: String,
     pub key_type: String,
     pub logging_level: Option<Level>,
+    pub preferred_chain: Option<String>,
+    /// Skips the TLS certificate check of the CA, for test CAs like Pebble only.
+    pub accept_invalid_certs: bool,
+    pub output_formats: Vec<String>,
+    pub pkcs12_password: Option<String>,
+    pub file_owner: Option<String>,
+    pub file_group: Option<String>,
+    pub fix_key_permissions: bool,
+    pub directory_refresh: Option<String>,
+    pub missed_runs: String,
+    pub workers: usize,
+    pub max_ca_requests: usize,
+    /// Seconds a shutdown waits for queued jobs before leaving them for the next start.
+    pub shutdown_timeout: u64,
+    pub certificates: Vec<CertificateDefinition>,
+    /// Schedule the daemon reconciles `certificates` with the database at.
+    pub reconciliation: String,
 }
 
 pub static APPLICATION_CONFIG: OnceLock<ApplicationConfig> = OnceLock::new();
@@ -0,0 +1,396 @@
+use crate::database::DatabaseConnection;
+use crate::migrations::Migration;
+use crate::repository::now;
+use common_utils::fs::{FileOptions, FileSystem, resolve_path};
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+const MAGIC: &[u8; 4] = b"ASBK";
+const FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+const PBKDF2_ITERATIONS: usize = 600_000;
+const MANIFEST_ENTRY: &str = "manifest.json";
+const DATABASE_ENTRY: &str = "acme-sentry.db";
+
+/// A file of the backup, `path` is relative to the base directory it was taken from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    pub mode: u32,
+}
+
+/// What a backup contains, stored in the archive next to the data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub created_at: i64,
+    pub schema_version: i64,
+    /// Base directory the backup was taken from, absolute paths in the database start with it.
+    pub base_dir: String,
+    pub database_sha256: String,
+    pub files: Vec<BackupFile>,
+}
+
+/// Outcome of [`BackupArchive::restore`].
+#[derive(Debug)]
+pub struct RestoreSummary {
+    pub files: usize,
+    /// Schema version after the restored database has been migrated.
+    pub schema_version: i64,
+    /// Stored paths moved from the old base directory to the new one.
+    pub rewritten_paths: usize,
+}
+
+/// A consistent snapshot of the database together with the key and certificate files it
+/// refers to, written as one encrypted archive.
+///
+/// The archive is `ASBK`, a format version, the PBKDF2 salt and the AES-256-GCM nonce,
+/// followed by the encrypted entries and the tag. The header is authenticated as well.
+#[derive(Debug)]
+pub struct BackupArchive {
+    pub manifest: BackupManifest,
+    database: Vec<u8>,
+    files: Vec<Vec<u8>>,
+}
+
+impl BackupArchive {
+    /// Takes a snapshot of the database under `base_dir` and reads every file in the key and
+    /// user directories stored in it and in `extra_paths`, directories or single files. All of
+    /// them have to be inside `base_dir`.
+    pub fn create(base_dir: &str, extra_paths: &[&str]) -> Result<Self, Box<dyn Error>> {
+        let base = FileSystem::new(base_dir)?.base_dir().to_path_buf();
+        let snapshot_dir = base.join(format!(".backup-{}", std::process::id()));
+        fs::create_dir_all(&snapshot_dir)?;
+        let snapshot = snapshot_dir.join(DATABASE_ENTRY);
+        let result = DatabaseConnection::open(base_dir)
+            .and_then(|connection| connection.backup_to(&snapshot))
+            .and_then(|_| Self::from_snapshot(&base, &snapshot, extra_paths));
+        fs::remove_dir_all(&snapshot_dir)?;
+        result
+    }
+    fn from_snapshot(base: &Path, snapshot: &Path, extra_paths: &[&str]) -> Result<Self, Box<dyn Error>> {
+        let (schema_version, dirs) = referenced_dirs(snapshot, extra_paths)?;
+        if schema_version != Migration::latest() {
+            return Err(format!("Database is at schema version {}, start acme-sentry once to migrate it first", schema_version).into());
+        }
+        let mut found = BTreeMap::new();
+        for dir in dirs {
+            // relative to the working directory like the base directory, which is canonical
+            collect_files(base, &resolve_path(&dir)?, &mut found)?;
+        }
+        let database = fs::read(snapshot)?;
+        let mut files = Vec::new();
+        let mut entries = Vec::new();
+        for (path, absolute) in found {
+            let data = fs::read(&absolute)?;
+            entries.push(BackupFile {
+                path,
+                size: data.len() as u64,
+                sha256: sha256(&data),
+                mode: file_mode(&absolute)?,
+            });
+            files.push(data);
+        }
+        info!("Backup holds the database and {} file(s)", entries.len());
+        Ok(BackupArchive {
+            manifest: BackupManifest {
+                created_at: now(),
+                schema_version,
+                base_dir: base.to_string_lossy().to_string(),
+                database_sha256: sha256(&database),
+                files: entries,
+            },
+            database,
+            files,
+        })
+    }
+
+    pub fn encrypt(&self, passphrase: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut payload = Vec::new();
+        write_entry(&mut payload, MANIFEST_ENTRY, &serde_json::to_vec(&self.manifest)?);
+        write_entry(&mut payload, DATABASE_ENTRY, &self.database);
+        for (file, data) in self.manifest.files.iter().zip(&self.files) {
+            write_entry(&mut payload, file.path.as_str(), data);
+        }
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(FORMAT_VERSION);
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut salt)?;
+        rand_bytes(&mut nonce)?;
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&nonce);
+        let mut tag = [0; TAG_LEN];
+        let key = derive_key(passphrase, &salt)?;
+        let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &key, Some(&nonce), &header, &payload, &mut tag)?;
+        let mut archive = header;
+        archive.extend_from_slice(&ciphertext);
+        archive.extend_from_slice(&tag);
+        Ok(archive)
+    }
+
+    /// Decrypts an archive written by [`encrypt`](Self::encrypt) and checks every entry
+    /// against the checksums in the manifest.
+    pub fn decrypt(archive: &[u8], passphrase: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if archive.len() < HEADER_LEN + TAG_LEN || &archive[..MAGIC.len()] != MAGIC {
+            return Err("Not an acme-sentry backup".into());
+        }
+        if archive[MAGIC.len()] != FORMAT_VERSION {
+            return Err(format!("Unsupported backup format version {}", archive[MAGIC.len()]).into());
+        }
+        let (header, rest) = archive.split_at(HEADER_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+        let nonce = &header[MAGIC.len() + 1 + SALT_LEN..];
+        let key = derive_key(passphrase, salt)?;
+        let payload = decrypt_aead(Cipher::aes_256_gcm(), &key, Some(nonce), header, ciphertext, tag)
+            .map_err(|_| "Backup is corrupt or the passphrase is wrong")?;
+
+        let mut entries = read_entries(&payload)?.into_iter();
+        let manifest: BackupManifest = match entries.next() {
+            Some((name, data)) if name == MANIFEST_ENTRY => serde_json::from_slice(data)?,
+            _ => return Err("Backup has no manifest".into()),
+        };
+        let database = match entries.next() {
+            Some((name, data)) if name == DATABASE_ENTRY && sha256(data) == manifest.database_sha256 => data.to_vec(),
+            _ => return Err("Database in the backup is missing or damaged".into()),
+        };
+        let mut files = Vec::new();
+        for file in &manifest.files {
+            match entries.next() {
+                Some((name, data)) if name == file.path && sha256(data) == file.sha256 => files.push(data.to_vec()),
+                _ => return Err(format!("File {} in the backup is missing or damaged", file.path).into()),
+            }
+        }
+        if entries.next().is_some() {
+            return Err("Backup holds entries missing from its manifest".into());
+        }
+        Ok(BackupArchive { manifest, database, files })
+    }
+
+    /// Writes the backup into `base_dir`, migrates the database and moves the paths stored in
+    /// it from the old base directory to `base_dir`. Refuses to overwrite anything and to
+    /// restore a database newer than this build.
+    pub fn restore(&self, base_dir: &str) -> Result<RestoreSummary, Box<dyn Error>> {
+        if self.manifest.schema_version > Migration::latest() {
+            return Err(format!(
+                "Backup is at schema version {}, newer than the {} this build supports",
+                self.manifest.schema_version,
+                Migration::latest()
+            )
+            .into());
+        }
+        let system = FileSystem::new(base_dir)?;
+        let database = system.base_dir().join(DATABASE_ENTRY);
+        let mut targets = Vec::new();
+        for file in &self.manifest.files {
+            let target = system.sub_dir(file.path.as_str())?;
+            if target.exists() {
+                return Err(format!("{} exists already, restore into an empty base directory", target.display()).into());
+            }
+            targets.push(target);
+        }
+        if database.exists() {
+            return Err(format!("{} exists already, restore into an empty base directory", database.display()).into());
+        }
+        // a failed restore leaves the base directory as empty as it was found
+        let mut written = Vec::new();
+        let result = self.write_files(&system, &mut written).and_then(|_| self.migrate(&system));
+        if result.is_err() {
+            for path in written.iter().rev() {
+                let _ = fs::remove_file(path);
+            }
+        }
+        let (schema_version, rewritten_paths) = result?;
+        info!(
+            "Restored the database and {} file(s), {} stored path(s) moved to {}",
+            targets.len(),
+            rewritten_paths,
+            system.base_dir().display()
+        );
+        Ok(RestoreSummary {
+            files: targets.len(),
+            schema_version,
+            rewritten_paths,
+        })
+    }
+    /// Writes every file with the mode it was backed up with, then the database. The paths
+    /// are pushed to `written` as they're written, for [`restore`](Self::restore) to clean up.
+    fn write_files(&self, system: &FileSystem, written: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+        for (file, data) in self.manifest.files.iter().zip(&self.files) {
+            let path = Path::new(file.path.as_str());
+            let sub_dir = path.parent().and_then(Path::to_str).unwrap_or_default();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| format!("Backup holds an invalid path {}", file.path))?;
+            system.ensure_sub_dir(sub_dir)?;
+            let options = FileOptions {
+                mode: file.mode & 0o777,
+                owner: None,
+                group: None,
+            };
+            let target = system.write_to_file_with(sub_dir, name, data, &options)?;
+            debug!("Restored {}", target.display());
+            written.push(target);
+        }
+        written.push(system.write_to_file_with("", DATABASE_ENTRY, &self.database, &FileOptions::private())?);
+        Ok(())
+    }
+    /// Migrates the restored database and moves its paths to the new base directory, returns
+    /// the schema version and the number of paths moved.
+    fn migrate(&self, system: &FileSystem) -> Result<(i64, usize), Box<dyn Error>> {
+        let new_base = system.base_dir().to_string_lossy().to_string();
+        let connection = DatabaseConnection::open(new_base.as_str())?;
+        let schema_version = connection.migrate()?;
+        let rewritten_paths = connection.transaction(|connection| {
+            let mut rewritten = 0;
+            for (table, column) in [("acme_users", "key_path"), ("acme_users", "user_dump_path"), ("acme_accounts", "key_path")] {
+                rewritten += rewrite_prefix(connection, table, column, self.manifest.base_dir.as_str(), new_base.as_str())?;
+            }
+            Ok(rewritten)
+        })?;
+        Ok((schema_version, rewritten_paths))
+    }
+}
+
+/// Schema version of the snapshot and the directories holding the files it refers to.
+fn referenced_dirs(snapshot: &Path, extra_paths: &[&str]) -> Result<(i64, Vec<PathBuf>), Box<dyn Error>> {
+    let connection = sqlite::open(snapshot)?;
+    let mut statement = connection.prepare("PRAGMA user_version;")?;
+    statement.next()?;
+    let schema_version = statement.read::<i64, _>(0)?;
+    let mut dirs: Vec<PathBuf> = extra_paths.iter().map(PathBuf::from).collect();
+    if schema_version != Migration::latest() {
+        return Ok((schema_version, dirs));
+    }
+    let sql = r#"
+        SELECT key_path FROM acme_users
+        UNION SELECT user_dump_path FROM acme_users
+        UNION SELECT key_path FROM acme_accounts;
+        "#;
+    let mut statement = connection.prepare(sql)?;
+    while let sqlite::State::Row = statement.next()? {
+        dirs.push(PathBuf::from(statement.read::<String, _>(0)?));
+    }
+    Ok((schema_version, dirs))
+}
+
+/// Adds every regular file below `dir`, or `dir` itself if it's a file, to `found`, keyed by
+/// its path relative to `base`.
+fn collect_files(base: &Path, dir: &Path, found: &mut BTreeMap<String, PathBuf>) -> Result<(), Box<dyn Error>> {
+    let relative = dir
+        .strip_prefix(base)
+        .map_err(|_| format!("{} is outside of {}, it can't be backed up", dir.display(), base.display()))?;
+    if dir.is_file() {
+        found.insert(relative.to_string_lossy().to_string(), dir.to_path_buf());
+        return Ok(());
+    }
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        // symlinks aren't followed, they could point anywhere
+        if file_type.is_dir() {
+            collect_files(base, &entry.path(), found)?;
+        } else if file_type.is_file() {
+            let path = relative.join(entry.file_name()).to_string_lossy().to_string();
+            found.insert(path, entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `old_base` at the start of `column` with `new_base`, returns the rows changed.
+fn rewrite_prefix(
+    connection: &DatabaseConnection,
+    table: &str,
+    column: &str,
+    old_base: &str,
+    new_base: &str,
+) -> Result<usize, Box<dyn Error>> {
+    if old_base == new_base {
+        return Ok(0);
+    }
+    let sql = format!(
+        "UPDATE {table} SET {column} = ?2 || substr({column}, length(?1) + 1) \
+         WHERE {column} = ?1 OR substr({column}, 1, length(?1) + 1) = ?1 || '/' RETURNING 1;"
+    );
+    let mut statement = connection.prepare(sql.as_str())?;
+    statement.bind((1, old_base))?;
+    statement.bind((2, new_base))?;
+    let mut rewritten = 0;
+    while let sqlite::State::Row = statement.next()? {
+        rewritten += 1;
+    }
+    Ok(rewritten)
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<[u8; 32], Box<dyn Error>> {
+    if passphrase.is_empty() {
+        return Err("The backup passphrase must not be empty".into());
+    }
+    let mut key = [0; 32];
+    pbkdf2_hmac(passphrase, salt, PBKDF2_ITERATIONS, MessageDigest::sha256(), &mut key)?;
+    Ok(key)
+}
+
+fn write_entry(payload: &mut Vec<u8>, name: &str, data: &[u8]) {
+    payload.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    payload.extend_from_slice(name.as_bytes());
+    payload.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    payload.extend_from_slice(data);
+}
+
+/// An entry of the decrypted payload, its name and its data.
+type Entry<'a> = (&'a str, &'a [u8]);
+
+fn read_entries(mut payload: &[u8]) -> Result<Vec<Entry<'_>>, Box<dyn Error>> {
+    const TRUNCATED: &str = "Backup entry is truncated";
+    let mut entries = Vec::new();
+    while !payload.is_empty() {
+        let (name_len, rest) = payload.split_at_checked(4).ok_or(TRUNCATED)?;
+        let (name, rest) = rest
+            .split_at_checked(u32::from_be_bytes(name_len.try_into()?) as usize)
+            .ok_or(TRUNCATED)?;
+        let (data_len, rest) = rest.split_at_checked(8).ok_or(TRUNCATED)?;
+        let (data, rest) = rest
+            .split_at_checked(u64::from_be_bytes(data_len.try_into()?) as usize)
+            .ok_or(TRUNCATED)?;
+        entries.push((std::str::from_utf8(name)?, data));
+        payload = rest;
+    }
+    Ok(entries)
+}
+
+fn sha256(data: &[u8]) -> String {
+    hex::encode(openssl::sha::sha256(data))
+}
+
+#[cfg(unix)]
+fn file_mode(path: &Path) -> Result<u32, Box<dyn Error>> {
+    Ok(fs::metadata(path)?.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> Result<u32, Box<dyn Error>> {
+    Ok(0o600)
+}
+
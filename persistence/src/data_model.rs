@@ -3,41 +3,116 @@ use sqlite::{State, Statement};
 use acme_client::comms::directory::AcmeDirectoryApi;
 use common_utils::{CompareFields, FieldDiff};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AcmeUser {
     pub id: i64,
     pub user_id: String,
     pub key_type: String,
     pub key_path: String,
     pub user_dump_path: String,
+    /// Account URL from before accounts were kept per CA, see [`AcmeAccount`].
+    pub account_url: Option<String>,
 }
 
 impl AcmeUser {
     pub fn scan_statement(mut statement: Statement) -> Result<Self, Box<dyn Error>> {
         if let Ok(State::Row) = statement.next() {
-            let user = Self {
-                id: statement.read::<i64, _>("id")?,
-                user_id: statement.read::<String, _>("user_id")?,
-                key_type: statement.read::<String, _>("key_type")?,
-                key_path: statement.read::<String, _>("key_path")?,
-                user_dump_path: statement.read::<String, _>("user_dump_path")?,
-            };
-            return Ok(user)
+            return Self::read_row(&statement);
         }
         Err("Failed to execute statement".into())
     }
+    pub fn read_row(statement: &Statement) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            id: statement.read::<i64, _>("id")?,
+            user_id: statement.read::<String, _>("user_id")?,
+            key_type: statement.read::<String, _>("key_type")?,
+            key_path: statement.read::<String, _>("key_path")?,
+            user_dump_path: statement.read::<String, _>("user_dump_path")?,
+            account_url: statement.read::<Option<String>, _>("account_url")?,
+        })
+    }
+}
+
+/// Lifecycle of an account with a CA, `Pending` until it's registered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccountStatus {
+    Pending,
+    Valid,
+    Deactivated,
+    Revoked,
 }
 
-#[derive(Debug)]
+impl AccountStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Pending => "pending",
+            AccountStatus::Valid => "valid",
+            AccountStatus::Deactivated => "deactivated",
+            AccountStatus::Revoked => "revoked",
+        }
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value {
+            "pending" => Ok(AccountStatus::Pending),
+            "valid" => Ok(AccountStatus::Valid),
+            "deactivated" => Ok(AccountStatus::Deactivated),
+            "revoked" => Ok(AccountStatus::Revoked),
+            other => Err(format!("Unknown account status: {}", other).into()),
+        }
+    }
+}
+
+/// The account of a local user with one CA, `ca_name` is the name from the configuration.
+#[derive(Debug, Clone)]
+pub struct AcmeAccount {
+    pub account_id: i64,
+    pub user_id: i64,
+    pub ca_name: String,
+    pub directory_url: String,
+    pub key_path: String,
+    /// Set once the account is registered with the CA.
+    pub account_url: Option<String>,
+    pub status: AccountStatus,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl AcmeAccount {
+    pub fn scan_statement(mut statement: Statement) -> Result<Option<Self>, Box<dyn Error>> {
+        if let State::Row = statement.next()? {
+            return Ok(Some(Self::read_row(&statement)?));
+        }
+        Ok(None)
+    }
+    pub fn read_row(statement: &Statement) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            account_id: statement.read::<i64, _>("account_id")?,
+            user_id: statement.read::<i64, _>("user_id")?,
+            ca_name: statement.read::<String, _>("ca_name")?,
+            directory_url: statement.read::<String, _>("directory_url")?,
+            key_path: statement.read::<String, _>("key_path")?,
+            account_url: statement.read::<Option<String>, _>("account_url")?,
+            status: AccountStatus::from_str(statement.read::<String, _>("status")?.as_str())?,
+            created_at: statement.read::<i64, _>("created_at")?,
+            updated_at: statement.read::<i64, _>("updated_at")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct AcmeDirectory {
     pub directory_id: i64,
     pub user_id: String,
+    pub ca_name: String,
     pub key_change: String,
     pub new_authz: Option<String>,
     pub new_nonce: String,
     pub new_account: String,
     pub new_order: String,
     pub revoke_cert: String,
+    /// The directory's `meta` object as JSON, e.g. the terms of service.
+    pub meta: Option<String>,
 }
 impl CompareFields<AcmeDirectoryApi> for AcmeDirectory {
     fn compare_fields(&self, other: &AcmeDirectoryApi) -> Vec<FieldDiff> {
@@ -75,20 +150,374 @@ impl CompareFields<AcmeDirectoryApi> for AcmeDirectory {
 impl AcmeDirectory {
     pub fn scan_statement(mut statement: Statement) -> Result<Option<Self>, Box<dyn Error>> {
         if let Ok(State::Row) = statement.next() {
-            let dir = Self {
-                directory_id: statement.read::<i64, _>("directory_id")?,
-                user_id: statement.read::<String, _>("user_id")?,
-                key_change: statement.read::<String, _>("key_change")?,
-                new_nonce: statement.read::<String, _>("new_nonce")?,
-                new_account: statement.read::<String, _>("new_account")?,
-                new_order: statement.read::<String, _>("new_order")?,
-                new_authz: statement.read::<Option<String>, _>("new_authz")?,
-                revoke_cert: statement.read::<String, _>("revoke_cert")?,
-            };
-            return Ok(Some(dir));
+            return Ok(Some(Self::read_row(&statement)?));
         } else if let Ok(State::Done) = statement.next() {
             return Ok(None)
         }
         Err(statement.next().unwrap_err().into())
     }
-}
\ No newline at end of file
+    pub fn read_row(statement: &Statement) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            directory_id: statement.read::<i64, _>("directory_id")?,
+            user_id: statement.read::<String, _>("user_id")?,
+            ca_name: statement.read::<String, _>("ca_name")?,
+            key_change: statement.read::<String, _>("key_change")?,
+            new_nonce: statement.read::<String, _>("new_nonce")?,
+            new_account: statement.read::<String, _>("new_account")?,
+            new_order: statement.read::<String, _>("new_order")?,
+            new_authz: statement.read::<Option<String>, _>("new_authz")?,
+            revoke_cert: statement.read::<String, _>("revoke_cert")?,
+            meta: statement.read::<Option<String>, _>("meta")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AcmeCertificate {
+    pub certificate_id: i64,
+    pub user_id: i64,
+    /// The CA that issued the certificate.
+    pub ca_name: String,
+    pub name: String,
+    pub serial: String,
+    pub not_before: i64,
+    pub not_after: i64,
+    pub certificate_url: String,
+    pub chain_url: String,
+    pub chain_issuer: Option<String>,
+}
+
+impl AcmeCertificate {
+    pub fn scan_statement(mut statement: Statement) -> Result<Option<Self>, Box<dyn Error>> {
+        if let State::Row = statement.next()? {
+            return Ok(Some(Self::read_row(&statement)?));
+        }
+        Ok(None)
+    }
+    pub fn read_row(statement: &Statement) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            certificate_id: statement.read::<i64, _>("certificate_id")?,
+            user_id: statement.read::<i64, _>("user_id")?,
+            ca_name: statement.read::<String, _>("ca_name")?,
+            name: statement.read::<String, _>("name")?,
+            serial: statement.read::<String, _>("serial")?,
+            not_before: statement.read::<i64, _>("not_before")?,
+            not_after: statement.read::<i64, _>("not_after")?,
+            certificate_url: statement.read::<String, _>("certificate_url")?,
+            chain_url: statement.read::<String, _>("chain_url")?,
+            chain_issuer: statement.read::<Option<String>, _>("chain_issuer")?,
+        })
+    }
+}
+
+/// An order placed with the CA, `status` is the order status as reported by the CA.
+#[derive(Debug, Clone)]
+pub struct AcmeOrder {
+    pub order_id: i64,
+    pub user_id: i64,
+    pub order_url: String,
+    pub status: String,
+    pub identifiers: Vec<String>,
+    pub finalize_url: String,
+    /// Set once the order is valid and the certificate can be downloaded.
+    pub certificate_url: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl AcmeOrder {
+    pub fn scan_statement(mut statement: Statement) -> Result<Option<Self>, Box<dyn Error>> {
+        if let State::Row = statement.next()? {
+            return Ok(Some(Self::read_row(&statement)?));
+        }
+        Ok(None)
+    }
+    pub fn read_row(statement: &Statement) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            order_id: statement.read::<i64, _>("order_id")?,
+            user_id: statement.read::<i64, _>("user_id")?,
+            order_url: statement.read::<String, _>("order_url")?,
+            status: statement.read::<String, _>("status")?,
+            identifiers: serde_json::from_str(statement.read::<String, _>("identifiers")?.as_str())?,
+            finalize_url: statement.read::<String, _>("finalize_url")?,
+            certificate_url: statement.read::<Option<String>, _>("certificate_url")?,
+            created_at: statement.read::<i64, _>("created_at")?,
+            updated_at: statement.read::<i64, _>("updated_at")?,
+        })
+    }
+}
+
+/// Lifecycle of a row in the `jobs` table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    /// Failed, waiting for `run_at` before the next attempt.
+    Retrying,
+    Succeeded,
+    /// Out of attempts or failed with a permanent error, the dead-letter state.
+    Failed,
+    /// Cancelled through the scheduler handle, never retried.
+    Cancelled,
+    /// Not run because a job it depends on didn't succeed.
+    Skipped,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Retrying => "retrying",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::Skipped => "skipped",
+        }
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "retrying" => Ok(JobStatus::Retrying),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            "skipped" => Ok(JobStatus::Skipped),
+            other => Err(format!("Unknown job status: {}", other).into()),
+        }
+    }
+    /// Jobs in these states were interrupted and have to be picked up again at startup.
+    pub fn is_unfinished(&self) -> bool {
+        matches!(self, JobStatus::Queued | JobStatus::Running | JobStatus::Retrying)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JobRecord {
+    pub job_id: i64,
+    pub job_type: String,
+    pub payload: String,
+    pub status: JobStatus,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub run_at: Option<i64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl JobRecord {
+    pub fn scan_statement(mut statement: Statement) -> Result<Option<Self>, Box<dyn Error>> {
+        if let State::Row = statement.next()? {
+            return Ok(Some(Self::read_row(&statement)?));
+        }
+        Ok(None)
+    }
+    pub fn read_row(statement: &Statement) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            job_id: statement.read::<i64, _>("job_id")?,
+            job_type: statement.read::<String, _>("job_type")?,
+            payload: statement.read::<String, _>("payload")?,
+            status: JobStatus::from_str(statement.read::<String, _>("status")?.as_str())?,
+            attempts: statement.read::<i64, _>("attempts")?,
+            last_error: statement.read::<Option<String>, _>("last_error")?,
+            run_at: statement.read::<Option<i64>, _>("run_at")?,
+            created_at: statement.read::<i64, _>("created_at")?,
+            updated_at: statement.read::<i64, _>("updated_at")?,
+        })
+    }
+}
+
+/// A job that's submitted again and again, `schedule` is either a cron expression or an interval.
+#[derive(Debug, Clone)]
+pub struct RecurringJobRecord {
+    pub recurring_id: i64,
+    pub name: String,
+    pub job_type: String,
+    pub payload: String,
+    pub schedule: String,
+    pub missed_runs: String,
+    pub last_run_at: Option<i64>,
+    pub next_run_at: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl RecurringJobRecord {
+    pub fn scan_statement(mut statement: Statement) -> Result<Option<Self>, Box<dyn Error>> {
+        if let State::Row = statement.next()? {
+            return Ok(Some(Self::read_row(&statement)?));
+        }
+        Ok(None)
+    }
+    pub fn read_row(statement: &Statement) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            recurring_id: statement.read::<i64, _>("recurring_id")?,
+            name: statement.read::<String, _>("name")?,
+            job_type: statement.read::<String, _>("job_type")?,
+            payload: statement.read::<String, _>("payload")?,
+            schedule: statement.read::<String, _>("schedule")?,
+            missed_runs: statement.read::<String, _>("missed_runs")?,
+            last_run_at: statement.read::<Option<i64>, _>("last_run_at")?,
+            next_run_at: statement.read::<i64, _>("next_run_at")?,
+            created_at: statement.read::<i64, _>("created_at")?,
+            updated_at: statement.read::<i64, _>("updated_at")?,
+        })
+    }
+}
+
+/// What an entry in `audit_events` records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditKind {
+    UserCreated,
+    AccountCreated,
+    AccountRegistered,
+    AccountUpdated,
+    AccountDeactivated,
+    KeyRollover,
+    DirectoryRefreshed,
+    OrderCreated,
+    OrderUpdated,
+    ChallengeAttempted,
+    CertificateIssued,
+    CertificateRevoked,
+    ConfigChanged,
+    BackupCreated,
+    BackupRestored,
+}
+
+impl AuditKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditKind::UserCreated => "user-created",
+            AuditKind::AccountCreated => "account-created",
+            AuditKind::AccountRegistered => "account-registered",
+            AuditKind::AccountUpdated => "account-updated",
+            AuditKind::AccountDeactivated => "account-deactivated",
+            AuditKind::KeyRollover => "key-rollover",
+            AuditKind::DirectoryRefreshed => "directory-refreshed",
+            AuditKind::OrderCreated => "order-created",
+            AuditKind::OrderUpdated => "order-updated",
+            AuditKind::ChallengeAttempted => "challenge-attempted",
+            AuditKind::CertificateIssued => "certificate-issued",
+            AuditKind::CertificateRevoked => "certificate-revoked",
+            AuditKind::ConfigChanged => "config-changed",
+            AuditKind::BackupCreated => "backup-created",
+            AuditKind::BackupRestored => "backup-restored",
+        }
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value {
+            "user-created" => Ok(AuditKind::UserCreated),
+            "account-created" => Ok(AuditKind::AccountCreated),
+            "account-registered" => Ok(AuditKind::AccountRegistered),
+            "account-updated" => Ok(AuditKind::AccountUpdated),
+            "account-deactivated" => Ok(AuditKind::AccountDeactivated),
+            "key-rollover" => Ok(AuditKind::KeyRollover),
+            "directory-refreshed" => Ok(AuditKind::DirectoryRefreshed),
+            "order-created" => Ok(AuditKind::OrderCreated),
+            "order-updated" => Ok(AuditKind::OrderUpdated),
+            "challenge-attempted" => Ok(AuditKind::ChallengeAttempted),
+            "certificate-issued" => Ok(AuditKind::CertificateIssued),
+            "certificate-revoked" => Ok(AuditKind::CertificateRevoked),
+            "config-changed" => Ok(AuditKind::ConfigChanged),
+            "backup-created" => Ok(AuditKind::BackupCreated),
+            "backup-restored" => Ok(AuditKind::BackupRestored),
+            other => Err(format!("Unknown audit event kind: {}", other).into()),
+        }
+    }
+}
+
+/// An entry of the append-only audit log. `hash` covers the entry and `previous_hash`, so
+/// changing or dropping an entry breaks the chain from there on.
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub event_id: i64,
+    pub occurred_at: i64,
+    pub kind: AuditKind,
+    /// The local user the event belongs to, if any.
+    pub user_id: Option<String>,
+    /// What the event is about, e.g. the account, order or certificate URL.
+    pub subject: Option<String>,
+    /// JSON object with the details of the event.
+    pub details: String,
+    pub previous_hash: String,
+    pub hash: String,
+}
+
+impl AuditEvent {
+    /// `previous_hash` of the first entry.
+    pub const GENESIS_HASH: &'static str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    pub fn scan_statement(mut statement: Statement) -> Result<Option<Self>, Box<dyn Error>> {
+        if let State::Row = statement.next()? {
+            return Ok(Some(Self::read_row(&statement)?));
+        }
+        Ok(None)
+    }
+    pub fn read_row(statement: &Statement) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            event_id: statement.read::<i64, _>("event_id")?,
+            occurred_at: statement.read::<i64, _>("occurred_at")?,
+            kind: AuditKind::from_str(statement.read::<String, _>("kind")?.as_str())?,
+            user_id: statement.read::<Option<String>, _>("user_id")?,
+            subject: statement.read::<Option<String>, _>("subject")?,
+            details: statement.read::<String, _>("details")?,
+            previous_hash: statement.read::<String, _>("previous_hash")?,
+            hash: statement.read::<String, _>("hash")?,
+        })
+    }
+    /// Hex SHA-256 over the fields of an entry and the hash of the entry before it.
+    pub fn chain_hash(
+        previous_hash: &str,
+        occurred_at: i64,
+        kind: AuditKind,
+        user_id: Option<&str>,
+        subject: Option<&str>,
+        details: &str,
+    ) -> String {
+        let fields = serde_json::json!([previous_hash, occurred_at, kind.as_str(), user_id, subject, details]);
+        hex::encode(openssl::sha::sha256(fields.to_string().as_bytes()))
+    }
+    fn expected_hash(&self) -> String {
+        Self::chain_hash(
+            self.previous_hash.as_str(),
+            self.occurred_at,
+            self.kind,
+            self.user_id.as_deref(),
+            self.subject.as_deref(),
+            self.details.as_str(),
+        )
+    }
+    /// Checks that `events`, the whole log oldest first, form an unbroken chain and returns
+    /// how many entries were checked. The error names the first entry that doesn't fit.
+    pub fn verify_chain(events: &[AuditEvent]) -> Result<usize, Box<dyn Error>> {
+        let mut previous_hash = Self::GENESIS_HASH;
+        for event in events {
+            if event.previous_hash != previous_hash {
+                return Err(format!("Audit event {} does not follow the event before it", event.event_id).into());
+            }
+            if event.hash != event.expected_hash() {
+                return Err(format!("Audit event {} has been altered", event.event_id).into());
+            }
+            previous_hash = event.hash.as_str();
+        }
+        Ok(events.len())
+    }
+    /// The entry as one line of a JSON Lines export. `details` stays the stored text, the
+    /// hash is over exactly that, so the export can be verified on its own.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "event_id": self.event_id,
+            "occurred_at": self.occurred_at,
+            "kind": self.kind.as_str(),
+            "user_id": self.user_id,
+            "subject": self.subject,
+            "details": self.details,
+            "previous_hash": self.previous_hash,
+            "hash": self.hash,
+        })
+    }
+}
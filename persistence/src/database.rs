@@ -1,38 +1,39 @@
+use crate::migrations::Migration;
 use common_utils::{EnumIterator, APPLICATION_CONFIG};
 use sqlite::Statement;
+use sqlite3_sys as ffi;
 use std::error::Error;
-use std::fmt::{Display, Formatter};
+use std::ffi::CStr;
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::Deref;
+use std::path::Path;
 use std::slice::Iter;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use tracing::{debug, info};
 
 pub struct DatabaseConnection {
     connection: sqlite::Connection
 }
-// TODO: remove allow
-#[allow(dead_code)]
-#[derive(Debug)]
-enum PreFlightCheckList {
-    AcmeUsersTable,
-    AcmeUserDirectory,
-    AcmeUserOrders,
-    AcmeUserCertificates,
-}
 #[derive(Debug)]
 enum SqliteSettings {
     ForeignKeysEnabled,
-}
-#[derive(Debug)]
-enum KeyOperations {
-    GetKeyFromKeyId,
+    /// Readers don't block the writer and the other way around.
+    WriteAheadLog,
+    /// Waits for a competing writer instead of failing with `SQLITE_BUSY`.
+    BusyTimeout,
 }
 
-trait SqlStatement {
+pub(crate) trait SqlStatement {
     fn get_statement(&self) -> &'static str;
 }
 impl SqlStatement for SqliteSettings {
     fn get_statement(&self) -> &'static str {
         match self {
             SqliteSettings::ForeignKeysEnabled => "PRAGMA foreign_keys = ON;",
+            SqliteSettings::WriteAheadLog => "PRAGMA journal_mode = WAL;",
+            SqliteSettings::BusyTimeout => "PRAGMA busy_timeout = 5000;",
         }
     }
 }
@@ -41,70 +42,26 @@ impl Display for SqliteSettings {
         write!(f, "{:?}", self)
     }
 }
-impl Display for PreFlightCheckList {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
-impl Display for KeyOperations {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
-impl EnumIterator<PreFlightCheckList> for PreFlightCheckList {
-    fn iterator() -> Iter<'static, PreFlightCheckList> {
-        static PRE_FLIGHT_CHECK_LIST: &[PreFlightCheckList] = &[
-            PreFlightCheckList::AcmeUsersTable,
-            PreFlightCheckList::AcmeUserDirectory,
-        ];
-        PRE_FLIGHT_CHECK_LIST.iter()
-    }
-}
 impl EnumIterator<SqliteSettings> for SqliteSettings {
     fn iterator() -> Iter<'static, SqliteSettings> {
-        static SQLITE_SETTINGS: &[SqliteSettings] = &[SqliteSettings::ForeignKeysEnabled];
+        static SQLITE_SETTINGS: &[SqliteSettings] = &[
+            SqliteSettings::BusyTimeout,
+            SqliteSettings::WriteAheadLog,
+            SqliteSettings::ForeignKeysEnabled,
+        ];
         SQLITE_SETTINGS.iter()
     }
 }
-impl SqlStatement for PreFlightCheckList {
-    fn get_statement(&self) -> &'static str {
-        match self {
-            PreFlightCheckList::AcmeUsersTable => {
-                r#"
-                CREATE TABLE IF NOT EXISTS acme_users(
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    user_id TEXT(36) NOT NULL,
-                    key_type TEX(15) NOT NULL,
-                    key_path TEXT(256) NOT NULL,
-                    user_dump_path TEXT(256) NOT NULL
-                );
-            "#
-            }
-            PreFlightCheckList::AcmeUserDirectory => {
-                r#"
-                CREATE TABLE IF NOT EXISTS acme_users_directory(
-                    directory_id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    user_id INTEGER NOT NULL,
-                    new_nonce TEXT(512) NOT NULL,
-                    new_account TEXT(512),
-                    new_order TEXT(512) NOT NULL,
-                    new_authz TEXT(512) NOT NULL,
-                    revoke_cert TEXT(512) NOT NULL,
-                    key_change TEXT(512) NOT NULL,
-                    FOREIGN KEY (user_id) REFERENCES acme_users(id) ON DELETE RESTRICT
-                )
-            "#
-            }
-            PreFlightCheckList::AcmeUserOrders => r#"dudde"#,
-            PreFlightCheckList::AcmeUserCertificates => r#"dudde"#,
-        }
-    }
-}
 impl DatabaseConnection {
     pub fn get_connection() -> Result<DatabaseConnection, Box<dyn Error>> {
         let config = APPLICATION_CONFIG.get().unwrap();
-        debug!("Opening db at: {}/acme-sentry.db", config.base_dir.as_str());
-        let connection = sqlite::open((config.base_dir.to_owned() + "/acme-sentry.db").as_str())?;
+        Self::open(config.base_dir.as_str())
+    }
+
+    /// Opens `acme-sentry.db` under `base_dir`, independent of the global configuration.
+    pub fn open(base_dir: &str) -> Result<DatabaseConnection, Box<dyn Error>> {
+        debug!("Opening db at: {}/acme-sentry.db", base_dir);
+        let connection = sqlite::open((base_dir.to_owned() + "/acme-sentry.db").as_str())?;
         for settings in SqliteSettings::iterator() {
             debug!("Executing setting: {} for Sqlite", settings);
             connection.execute(settings.get_statement())?
@@ -113,16 +70,201 @@ impl DatabaseConnection {
         Ok(DatabaseConnection { connection })
     }
 
-    pub fn prepare(&self, prepared_statement: &str) -> Result<Statement, Box<dyn Error>> {
+    pub fn prepare(&self, prepared_statement: &str) -> Result<Statement<'_>, Box<dyn Error>> {
         Ok(self.connection.prepare(prepared_statement)?)
     }
 
+    /// Runs `work` in an immediate transaction, it's committed if `work` succeeds and rolled
+    /// back otherwise. Taking the write lock up front keeps a read followed by a write from
+    /// failing halfway through because another connection got to write first.
+    ///
+    /// A failed `COMMIT` (e.g. `SQLITE_BUSY` or a deferred constraint) leaves the transaction
+    /// open, it's rolled back as well so the connection can go back to the pool.
+    pub fn transaction<T>(
+        &self,
+        work: impl FnOnce(&DatabaseConnection) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        self.connection.execute("BEGIN IMMEDIATE;")?;
+        let result = work(self).and_then(|result| {
+            self.connection.execute("COMMIT;")?;
+            Ok(result)
+        });
+        if result.is_err() && self.in_transaction() {
+            self.connection.execute("ROLLBACK;")?;
+        }
+        result
+    }
+
+    /// Some errors roll the transaction back on their own, `ROLLBACK` would fail after them.
+    fn in_transaction(&self) -> bool {
+        // SAFETY: the handle is valid for as long as the connection lives
+        unsafe { ffi::sqlite3_get_autocommit(self.connection.as_raw()) == 0 }
+    }
+
+    /// Brings the schema up to date, see [`migrate`](Self::migrate).
     pub fn internal_structure_check(&self) -> Result<(), Box<dyn Error>> {
-        for pre_flight in PreFlightCheckList::iterator() {
-            debug!("Executing pre-flight script: {}", pre_flight);
-            self.connection.execute(pre_flight.get_statement())?;
+        self.migrate()?;
+        Ok(())
+    }
+
+    /// Copies the database to `destination` through sqlite's online backup API, the copy is a
+    /// consistent snapshot even while other connections keep writing.
+    pub fn backup_to(&self, destination: &Path) -> Result<(), Box<dyn Error>> {
+        let target = sqlite::open(destination)?;
+        let main = c"main";
+        // SAFETY: both handles outlive the backup, which is finished before returning
+        unsafe {
+            let backup = ffi::sqlite3_backup_init(target.as_raw(), main.as_ptr(), self.connection.as_raw(), main.as_ptr());
+            if backup.is_null() {
+                let message = CStr::from_ptr(ffi::sqlite3_errmsg(target.as_raw()));
+                return Err(format!("Backup could not be started: {}", message.to_string_lossy()).into());
+            }
+            loop {
+                match ffi::sqlite3_backup_step(backup, -1) {
+                    ffi::SQLITE_DONE => break,
+                    ffi::SQLITE_OK | ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => thread::sleep(Duration::from_millis(50)),
+                    code => {
+                        ffi::sqlite3_backup_finish(backup);
+                        let message = CStr::from_ptr(ffi::sqlite3_errstr(code));
+                        return Err(format!("Backup failed: {}", message.to_string_lossy()).into());
+                    }
+                }
+            }
+            match ffi::sqlite3_backup_finish(backup) {
+                ffi::SQLITE_OK => Ok(()),
+                code => Err(format!("Backup failed: {}", CStr::from_ptr(ffi::sqlite3_errstr(code)).to_string_lossy()).into()),
+            }
+        }
+    }
+
+    pub fn schema_version(&self) -> Result<i64, Box<dyn Error>> {
+        let mut statement = self.connection.prepare("PRAGMA user_version;")?;
+        statement.next()?;
+        Ok(statement.read::<i64, _>(0)?)
+    }
+
+    /// Applies every migration newer than the database in one transaction and returns the
+    /// resulting schema version. A database written by a newer release is refused untouched.
+    pub fn migrate(&self) -> Result<i64, Box<dyn Error>> {
+        let current = self.schema_version()?;
+        Self::supported(current)?;
+        if current == Migration::latest() {
+            debug!("Database schema is up to date at version {}", current);
+            return Ok(current);
+        }
+        // tables are rebuilt by some migrations, that only works with foreign keys off
+        // and the pragma is a no-op inside of a transaction
+        self.connection.execute("PRAGMA foreign_keys = OFF;")?;
+        let migrated = self.apply_migrations();
+        self.connection.execute(SqliteSettings::ForeignKeysEnabled.get_statement())?;
+        let (current, version) = migrated?;
+        if current == version {
+            debug!("Database schema has been migrated to version {} by another connection", version);
+        } else {
+            info!("Database schema has been migrated from version {} to {}", current, version);
+        }
+        Ok(version)
+    }
+
+    fn supported(version: i64) -> Result<(), Box<dyn Error>> {
+        let latest = Migration::latest();
+        if version > latest {
+            return Err(format!(
+                "Database schema version {} is newer than the supported version {}, refusing to touch it",
+                version, latest
+            )
+            .into());
         }
-        info!("Pre-flight database scripts have been executed!");
         Ok(())
     }
+
+    /// Returns the version the migrations started from and the one they ended at. The version
+    /// is read again once the write lock is held, another process starting at the same time
+    /// may have migrated the database in the meantime.
+    fn apply_migrations(&self) -> Result<(i64, i64), Box<dyn Error>> {
+        self.transaction(|connection| {
+            let current = connection.schema_version()?;
+            Self::supported(current)?;
+            let mut version = current;
+            for migration in Migration::iterator().filter(|m| m.version() > current) {
+                debug!("Applying migration: {}", migration);
+                connection.connection.execute(migration.get_statement())?;
+                version = migration.version();
+            }
+            let mut check = connection.prepare("PRAGMA foreign_key_check;")?;
+            if let sqlite::State::Row = check.next()? {
+                return Err(format!(
+                    "Migration to version {} left a dangling reference in {}",
+                    version,
+                    check.read::<String, _>(0)?
+                )
+                .into());
+            }
+            drop(check);
+            connection.connection.execute(format!("PRAGMA user_version = {};", version))?;
+            Ok((current, version))
+        })
+    }
+}
+
+/// Connections to `acme-sentry.db` under one base directory, shared by everything working
+/// with it. A connection is handed to one caller at a time and goes back to the pool once
+/// it's dropped, so the settings only run when a connection is opened.
+#[derive(Clone)]
+pub struct ConnectionPool {
+    base_dir: String,
+    idle: Arc<Mutex<Vec<DatabaseConnection>>>,
+}
+
+impl ConnectionPool {
+    /// Connections kept open while nobody uses them, more are opened when needed.
+    const MAX_IDLE: usize = 4;
+
+    pub fn new(base_dir: &str) -> Self {
+        ConnectionPool {
+            base_dir: base_dir.to_string(),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn get(&self) -> Result<PooledConnection, Box<dyn Error>> {
+        let idle = self.idle.lock().map_err(|e| e.to_string())?.pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => DatabaseConnection::open(self.base_dir.as_str())?,
+        };
+        Ok(PooledConnection {
+            connection: Some(connection),
+            idle: self.idle.clone(),
+        })
+    }
+}
+
+impl Debug for ConnectionPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionPool").field("base_dir", &self.base_dir).finish_non_exhaustive()
+    }
+}
+
+/// A connection borrowed from a [`ConnectionPool`].
+pub struct PooledConnection {
+    connection: Option<DatabaseConnection>,
+    idle: Arc<Mutex<Vec<DatabaseConnection>>>,
+}
+
+impl Deref for PooledConnection {
+    type Target = DatabaseConnection;
+
+    fn deref(&self) -> &DatabaseConnection {
+        self.connection.as_ref().expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        match (self.connection.take(), self.idle.lock()) {
+            (Some(connection), Ok(mut idle)) if idle.len() < ConnectionPool::MAX_IDLE => idle.push(connection),
+            _ => {}
+        }
+    }
 }
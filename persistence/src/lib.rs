@@ -1,2 +1,8 @@
+pub mod backup;
 pub mod database;
-pub mod data_model;
\ No newline at end of file
+pub mod data_model;
+mod migrations;
+pub mod repository;
+
+#[cfg(test)]
+mod test;
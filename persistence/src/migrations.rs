@@ -0,0 +1,230 @@
+use crate::database::SqlStatement;
+use common_utils::EnumIterator;
+use std::fmt::{Display, Formatter};
+use std::slice::Iter;
+
+/// Ordered schema changes, the version of the last applied one is kept in `PRAGMA user_version`.
+///
+/// Databases created before versioning report version 0, the statements of the first two
+/// migrations only create what's missing so they apply to those as well. Never change a
+/// released migration, add a new one instead.
+#[derive(Debug)]
+pub(crate) enum Migration {
+    /// `acme_users` and `acme_users_directory` as first released.
+    InitialSchema,
+    /// Certificates and the job scheduler tables.
+    CertificatesAndJobs,
+    /// Rebuilds `acme_users`, `key_type` was declared as `TEX(15)` and got numeric affinity.
+    UserKeyTypeAsText,
+    /// The account URL returned by the CA on registration and the `meta` object of the directory.
+    AccountUrlAndDirectoryMeta,
+    /// Orders placed with the CA, kept until the certificate is downloaded.
+    Orders,
+    /// Accounts per user and CA, directories and certificates are tied to the CA they came from.
+    AccountsPerCa,
+    /// Hash-chained history of every CA interaction and local state change.
+    AuditEvents,
+}
+
+impl Migration {
+    pub(crate) fn version(&self) -> i64 {
+        match self {
+            Migration::InitialSchema => 1,
+            Migration::CertificatesAndJobs => 2,
+            Migration::UserKeyTypeAsText => 3,
+            Migration::AccountUrlAndDirectoryMeta => 4,
+            Migration::Orders => 5,
+            Migration::AccountsPerCa => 6,
+            Migration::AuditEvents => 7,
+        }
+    }
+    /// Schema version this build creates and understands.
+    pub(crate) fn latest() -> i64 {
+        Migration::iterator().map(Migration::version).max().unwrap_or_default()
+    }
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} (v{})", self, self.version())
+    }
+}
+
+impl EnumIterator<Migration> for Migration {
+    fn iterator() -> Iter<'static, Migration> {
+        static MIGRATIONS: &[Migration] = &[
+            Migration::InitialSchema,
+            Migration::CertificatesAndJobs,
+            Migration::UserKeyTypeAsText,
+            Migration::AccountUrlAndDirectoryMeta,
+            Migration::Orders,
+            Migration::AccountsPerCa,
+            Migration::AuditEvents,
+        ];
+        MIGRATIONS.iter()
+    }
+}
+
+impl SqlStatement for Migration {
+    fn get_statement(&self) -> &'static str {
+        match self {
+            Migration::InitialSchema => {
+                r#"
+                CREATE TABLE IF NOT EXISTS acme_users(
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id TEXT(36) NOT NULL,
+                    key_type TEX(15) NOT NULL,
+                    key_path TEXT(256) NOT NULL,
+                    user_dump_path TEXT(256) NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS acme_users_directory(
+                    directory_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id INTEGER NOT NULL,
+                    new_nonce TEXT(512) NOT NULL,
+                    new_account TEXT(512),
+                    new_order TEXT(512) NOT NULL,
+                    new_authz TEXT(512) NOT NULL,
+                    revoke_cert TEXT(512) NOT NULL,
+                    key_change TEXT(512) NOT NULL,
+                    FOREIGN KEY (user_id) REFERENCES acme_users(id) ON DELETE RESTRICT
+                );
+            "#
+            }
+            Migration::CertificatesAndJobs => {
+                r#"
+                CREATE TABLE IF NOT EXISTS acme_certificates(
+                    certificate_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id INTEGER NOT NULL,
+                    name TEXT(256) NOT NULL,
+                    serial TEXT(64) NOT NULL,
+                    not_before INTEGER NOT NULL,
+                    not_after INTEGER NOT NULL,
+                    certificate_url TEXT(512) NOT NULL,
+                    chain_url TEXT(512) NOT NULL,
+                    chain_issuer TEXT(256),
+                    FOREIGN KEY (user_id) REFERENCES acme_users(id) ON DELETE RESTRICT
+                );
+                CREATE TABLE IF NOT EXISTS jobs(
+                    job_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    job_type TEXT(64) NOT NULL,
+                    payload TEXT NOT NULL,
+                    status TEXT(16) NOT NULL,
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    last_error TEXT,
+                    run_at INTEGER,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS recurring_jobs(
+                    recurring_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name TEXT(128) NOT NULL UNIQUE,
+                    job_type TEXT(64) NOT NULL,
+                    payload TEXT NOT NULL,
+                    schedule TEXT(128) NOT NULL,
+                    missed_runs TEXT(16) NOT NULL,
+                    last_run_at INTEGER,
+                    next_run_at INTEGER NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS job_dependencies(
+                    job_id INTEGER NOT NULL,
+                    depends_on INTEGER NOT NULL,
+                    PRIMARY KEY (job_id, depends_on),
+                    FOREIGN KEY (job_id) REFERENCES jobs(job_id) ON DELETE CASCADE,
+                    FOREIGN KEY (depends_on) REFERENCES jobs(job_id) ON DELETE CASCADE
+                );
+                CREATE TABLE IF NOT EXISTS job_outputs(
+                    job_id INTEGER PRIMARY KEY,
+                    output_type TEXT(64) NOT NULL,
+                    output TEXT NOT NULL,
+                    FOREIGN KEY (job_id) REFERENCES jobs(job_id) ON DELETE CASCADE
+                );
+            "#
+            }
+            Migration::UserKeyTypeAsText => {
+                r#"
+                CREATE TABLE acme_users_v3(
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id TEXT(36) NOT NULL,
+                    key_type TEXT(15) NOT NULL,
+                    key_path TEXT(256) NOT NULL,
+                    user_dump_path TEXT(256) NOT NULL
+                );
+                INSERT INTO acme_users_v3 (id, user_id, key_type, key_path, user_dump_path)
+                    SELECT id, user_id, CAST(key_type AS TEXT), key_path, user_dump_path FROM acme_users;
+                DROP TABLE acme_users;
+                ALTER TABLE acme_users_v3 RENAME TO acme_users;
+            "#
+            }
+            Migration::AccountUrlAndDirectoryMeta => {
+                r#"
+                ALTER TABLE acme_users ADD COLUMN account_url TEXT(512);
+                ALTER TABLE acme_users_directory ADD COLUMN meta TEXT;
+            "#
+            }
+            Migration::Orders => {
+                r#"
+                CREATE TABLE acme_orders(
+                    order_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id INTEGER NOT NULL,
+                    order_url TEXT(512) NOT NULL UNIQUE,
+                    status TEXT(16) NOT NULL,
+                    identifiers TEXT NOT NULL,
+                    finalize_url TEXT(512) NOT NULL,
+                    certificate_url TEXT(512),
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL,
+                    FOREIGN KEY (user_id) REFERENCES acme_users(id) ON DELETE RESTRICT
+                );
+            "#
+            }
+            Migration::AccountsPerCa => {
+                // accounts registered before are kept for the CA named `default`, which is
+                // what a configuration with a single `base-url` resolves to
+                r#"
+                CREATE TABLE acme_accounts(
+                    account_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id INTEGER NOT NULL,
+                    ca_name TEXT(64) NOT NULL,
+                    directory_url TEXT(512) NOT NULL,
+                    key_path TEXT(256) NOT NULL,
+                    account_url TEXT(512),
+                    status TEXT(16) NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL,
+                    UNIQUE (user_id, ca_name),
+                    FOREIGN KEY (user_id) REFERENCES acme_users(id) ON DELETE RESTRICT
+                );
+                INSERT INTO acme_accounts (user_id, ca_name, directory_url, key_path, account_url, status, created_at, updated_at)
+                    SELECT id, 'default', '', key_path, account_url, 'valid', 0, 0 FROM acme_users WHERE account_url IS NOT NULL;
+                ALTER TABLE acme_users_directory ADD COLUMN ca_name TEXT(64) NOT NULL DEFAULT 'default';
+                CREATE UNIQUE INDEX acme_users_directory_ca ON acme_users_directory(user_id, ca_name);
+                ALTER TABLE acme_certificates ADD COLUMN ca_name TEXT(64) NOT NULL DEFAULT 'default';
+            "#
+            }
+            Migration::AuditEvents => {
+                r#"
+                CREATE TABLE audit_events(
+                    event_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    occurred_at INTEGER NOT NULL,
+                    kind TEXT(64) NOT NULL,
+                    user_id TEXT(36),
+                    subject TEXT(512),
+                    details TEXT NOT NULL,
+                    previous_hash TEXT(64) NOT NULL,
+                    hash TEXT(64) NOT NULL UNIQUE
+                );
+                CREATE TRIGGER audit_events_no_update BEFORE UPDATE ON audit_events
+                BEGIN
+                    SELECT RAISE(ABORT, 'audit_events is append-only');
+                END;
+                CREATE TRIGGER audit_events_no_delete BEFORE DELETE ON audit_events
+                BEGIN
+                    SELECT RAISE(ABORT, 'audit_events is append-only');
+                END;
+            "#
+            }
+        }
+    }
+}
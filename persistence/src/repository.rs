@@ -0,0 +1,237 @@
+pub mod memory;
+pub mod sqlite;
+
+use crate::data_model::{
+    AccountStatus, AcmeAccount, AuditEvent, AuditKind, AcmeCertificate, AcmeDirectory, AcmeOrder, AcmeUser, JobRecord, JobStatus,
+    RecurringJobRecord,
+};
+use acme_client::comms::directory::AcmeDirectoryApi;
+use memory::MemoryRepository;
+use serde_json::Value;
+use sqlite::SqliteRepository;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keeps the stored schema in line with what this build expects.
+pub trait SchemaRepository: Debug + Send + Sync {
+    /// Brings the schema up to date and returns its version.
+    fn migrate(&self) -> Result<i64, Box<dyn Error>>;
+}
+
+/// Local users, `user_id` is the id from the configuration, `AcmeUser::id` the row id.
+pub trait UserRepository: Debug + Send + Sync {
+    fn find(&self, user_id: &str) -> Result<Option<AcmeUser>, Box<dyn Error>>;
+    fn create(&self, user_id: &str, key_type: &str, key_path: &str, user_dump_path: &str) -> Result<AcmeUser, Box<dyn Error>>;
+    fn list(&self) -> Result<Vec<AcmeUser>, Box<dyn Error>>;
+}
+
+/// The accounts of the local users, one per user and CA.
+pub trait AccountRepository: Debug + Send + Sync {
+    /// Creates the pending account of the user with the CA `ca_name`. If it exists already
+    /// only the directory URL is updated, the key and the registration are kept.
+    fn ensure(&self, user_id: i64, ca_name: &str, directory_url: &str, key_path: &str) -> Result<AcmeAccount, Box<dyn Error>>;
+    fn find(&self, user_id: i64, ca_name: &str) -> Result<Option<AcmeAccount>, Box<dyn Error>>;
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeAccount>, Box<dyn Error>>;
+    /// Records the account URL handed out by the CA, the account becomes valid.
+    fn register(&self, account_id: i64, account_url: &str) -> Result<AcmeAccount, Box<dyn Error>>;
+    fn set_status(&self, account_id: i64, status: AccountStatus) -> Result<AcmeAccount, Box<dyn Error>>;
+    /// True if the CA knows an account by the key of `user`, either from before accounts were
+    /// kept per CA or through any of its accounts. Such a key must never be replaced.
+    fn registered(&self, user: &AcmeUser) -> Result<bool, Box<dyn Error>> {
+        Ok(user.account_url.is_some() || self.for_user(user.id)?.iter().any(|a| a.account_url.is_some()))
+    }
+}
+
+/// The last directory fetched from each CA, one per user and CA.
+pub trait DirectoryRepository: Debug + Send + Sync {
+    fn find(&self, user_id: i64, ca_name: &str) -> Result<Option<AcmeDirectory>, Box<dyn Error>>;
+    /// Stores `directory` for the user and CA, replacing the one stored before.
+    fn save(&self, user_id: i64, ca_name: &str, directory: &AcmeDirectoryApi) -> Result<AcmeDirectory, Box<dyn Error>>;
+    /// Directories whose user no longer exists, left behind by databases written before
+    /// foreign keys were enforced.
+    fn orphaned(&self) -> Result<Vec<AcmeDirectory>, Box<dyn Error>>;
+    /// Removes the directory, returns false if there was none.
+    fn delete(&self, directory_id: i64) -> Result<bool, Box<dyn Error>>;
+}
+
+pub trait OrderRepository: Debug + Send + Sync {
+    fn create(
+        &self,
+        user_id: i64,
+        order_url: &str,
+        status: &str,
+        identifiers: &[String],
+        finalize_url: &str,
+    ) -> Result<AcmeOrder, Box<dyn Error>>;
+    fn find(&self, order_url: &str) -> Result<Option<AcmeOrder>, Box<dyn Error>>;
+    /// Records the status reported by the CA, `certificate_url` is kept if it's not passed.
+    fn update_status(&self, order_url: &str, status: &str, certificate_url: Option<&str>) -> Result<AcmeOrder, Box<dyn Error>>;
+    /// Moves the order from `from` to `to` atomically, fails if it isn't in `from` anymore,
+    /// e.g. because another job got to it first.
+    fn transition(&self, order_url: &str, from: &str, to: &str, certificate_url: Option<&str>) -> Result<AcmeOrder, Box<dyn Error>>;
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeOrder>, Box<dyn Error>>;
+}
+
+/// A downloaded certificate about to be recorded, see [`CertificateRepository::record`].
+#[derive(Debug)]
+pub struct NewCertificate<'a> {
+    pub user_id: i64,
+    pub ca_name: &'a str,
+    pub name: &'a str,
+    pub serial: &'a str,
+    pub not_before: i64,
+    pub not_after: i64,
+    pub certificate_url: &'a str,
+    pub chain_url: &'a str,
+    pub chain_issuer: Option<&'a str>,
+}
+
+pub trait CertificateRepository: Debug + Send + Sync {
+    fn record(&self, certificate: NewCertificate) -> Result<AcmeCertificate, Box<dyn Error>>;
+    /// The most recently recorded certificate called `name`.
+    fn latest(&self, name: &str) -> Result<Option<AcmeCertificate>, Box<dyn Error>>;
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeCertificate>, Box<dyn Error>>;
+}
+
+/// An audit event about to be appended, see [`AuditRepository::append`].
+#[derive(Debug)]
+pub struct NewAuditEvent<'a> {
+    pub kind: AuditKind,
+    pub user_id: Option<&'a str>,
+    pub subject: Option<&'a str>,
+    pub details: Value,
+}
+
+/// Narrows [`AuditRepository::list`] down, unset fields match every event.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub kind: Option<AuditKind>,
+    pub user_id: Option<String>,
+    /// Unix seconds, inclusive.
+    pub since: Option<i64>,
+    /// Unix seconds, exclusive.
+    pub until: Option<i64>,
+}
+
+/// The append-only audit log, entries are never changed or removed.
+pub trait AuditRepository: Debug + Send + Sync {
+    /// Appends the event to the end of the chain, atomically with reading the hash it chains to.
+    fn append(&self, event: NewAuditEvent) -> Result<AuditEvent, Box<dyn Error>>;
+    /// Events matching `filter`, oldest first.
+    fn list(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, Box<dyn Error>>;
+}
+
+/// Record of every job submitted to a persistent scheduler and of the recurring jobs.
+pub trait JobRepository: Debug + Send + Sync {
+    /// Persists a new job, it isn't due before `run_at` (unix seconds) if that's set.
+    fn insert_at(&self, job_type: &str, payload: &Value, run_at: Option<i64>) -> Result<i64, Box<dyn Error>> {
+        self.insert_with_dependencies(job_type, payload, run_at, &[])
+    }
+    /// Persists a new job together with the jobs it waits for, atomically so a recovered
+    /// job never runs without its dependencies.
+    fn insert_with_dependencies(
+        &self,
+        job_type: &str,
+        payload: &Value,
+        run_at: Option<i64>,
+        depends_on: &[i64],
+    ) -> Result<i64, Box<dyn Error>>;
+    /// Jobs that have to succeed before `job_id` may run.
+    fn dependencies(&self, job_id: i64) -> Result<Vec<i64>, Box<dyn Error>>;
+    /// Stores what a job hands to the jobs depending on it, `output_type` names the Rust type.
+    fn set_output(&self, job_id: i64, output_type: &str, output: &Value) -> Result<(), Box<dyn Error>>;
+    fn output(&self, job_id: i64) -> Result<Option<(String, Value)>, Box<dyn Error>>;
+    /// Marks the job as running and counts the attempt.
+    fn mark_running(&self, job_id: i64) -> Result<(), Box<dyn Error>>;
+    fn mark_queued(&self, job_id: i64) -> Result<(), Box<dyn Error>>;
+    fn mark_succeeded(&self, job_id: i64) -> Result<(), Box<dyn Error>>;
+    /// Records the failed attempt, the job is due again at `run_at` (unix seconds).
+    fn mark_retrying(&self, job_id: i64, error: &str, run_at: i64) -> Result<(), Box<dyn Error>>;
+    fn mark_failed(&self, job_id: i64, error: &str) -> Result<(), Box<dyn Error>>;
+    /// Cancels the job unless it finished in the meantime, returns whether it was cancelled.
+    fn mark_cancelled(&self, job_id: i64) -> Result<bool, Box<dyn Error>>;
+    /// Records why the job didn't run because of a job it depends on.
+    fn mark_skipped(&self, job_id: i64, reason: &str) -> Result<(), Box<dyn Error>>;
+    fn get(&self, job_id: i64) -> Result<Option<JobRecord>, Box<dyn Error>>;
+    /// Every job in `status`, or every job at all, oldest first.
+    fn list(&self, status: Option<JobStatus>) -> Result<Vec<JobRecord>, Box<dyn Error>>;
+    /// Jobs that were queued, running or waiting for a retry when the process went away, oldest first.
+    fn unfinished(&self) -> Result<Vec<JobRecord>, Box<dyn Error>>;
+    /// Jobs that ran out of attempts or failed permanently.
+    fn dead_letters(&self) -> Result<Vec<JobRecord>, Box<dyn Error>>;
+    /// Moves a dead-lettered job back to the queue with a fresh set of attempts.
+    fn replay(&self, job_id: i64) -> Result<JobRecord, Box<dyn Error>>;
+    /// Creates or updates the recurring job `name`. The next run is only moved to
+    /// `next_run_at` if the schedule changed, re-registering on every start keeps it.
+    fn upsert_recurring(
+        &self,
+        name: &str,
+        job_type: &str,
+        payload: &Value,
+        schedule: &str,
+        missed_runs: &str,
+        next_run_at: i64,
+    ) -> Result<RecurringJobRecord, Box<dyn Error>>;
+    /// Recurring jobs, the next one due first.
+    fn recurring(&self) -> Result<Vec<RecurringJobRecord>, Box<dyn Error>>;
+    /// Moves the next run of a recurring job, `last_run_at` is only set if it actually ran.
+    fn reschedule_recurring(&self, recurring_id: i64, last_run_at: Option<i64>, next_run_at: i64) -> Result<(), Box<dyn Error>>;
+}
+
+/// Every repository the jobs work with, all backed by the same store.
+#[derive(Clone, Debug)]
+pub struct Repositories {
+    pub schema: Arc<dyn SchemaRepository>,
+    pub users: Arc<dyn UserRepository>,
+    pub accounts: Arc<dyn AccountRepository>,
+    pub directories: Arc<dyn DirectoryRepository>,
+    pub orders: Arc<dyn OrderRepository>,
+    pub certificates: Arc<dyn CertificateRepository>,
+    pub jobs: Arc<dyn JobRepository>,
+    pub audit: Arc<dyn AuditRepository>,
+}
+
+impl Repositories {
+    /// Repositories on `acme-sentry.db` under `base_dir`.
+    pub fn sqlite(base_dir: &str) -> Self {
+        Self::backed_by(SqliteRepository::new(base_dir))
+    }
+    /// Repositories that live as long as the process, meant for tests and dry runs.
+    pub fn in_memory() -> Self {
+        Self::backed_by(MemoryRepository::new())
+    }
+    fn backed_by<R>(backend: R) -> Self
+    where
+        R: SchemaRepository
+            + UserRepository
+            + AccountRepository
+            + DirectoryRepository
+            + OrderRepository
+            + CertificateRepository
+            + JobRepository
+            + AuditRepository
+            + 'static,
+    {
+        let backend = Arc::new(backend);
+        Repositories {
+            schema: backend.clone(),
+            users: backend.clone(),
+            accounts: backend.clone(),
+            directories: backend.clone(),
+            orders: backend.clone(),
+            certificates: backend.clone(),
+            jobs: backend.clone(),
+            audit: backend,
+        }
+    }
+}
+
+/// Unix seconds, the unit of every timestamp in the store.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,522 @@
+use crate::data_model::{
+    AccountStatus, AcmeAccount, AcmeCertificate, AcmeDirectory, AcmeOrder, AcmeUser, AuditEvent, JobRecord, JobStatus,
+    RecurringJobRecord,
+};
+use crate::migrations::Migration;
+use crate::repository::{
+    now, AccountRepository, AuditFilter, AuditRepository, CertificateRepository, DirectoryRepository, JobRepository,
+    NewAuditEvent, NewCertificate, OrderRepository, SchemaRepository, UserRepository,
+};
+use acme_client::comms::directory::AcmeDirectoryApi;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// In-memory backend of every repository, behaves like the sqlite one including the
+/// references between rows, but nothing outlives the process.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryRepository {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    users: Vec<AcmeUser>,
+    accounts: Vec<AcmeAccount>,
+    directories: Vec<AcmeDirectory>,
+    orders: Vec<AcmeOrder>,
+    certificates: Vec<AcmeCertificate>,
+    jobs: BTreeMap<i64, JobRecord>,
+    dependencies: Vec<(i64, i64)>,
+    outputs: HashMap<i64, (String, Value)>,
+    recurring: Vec<RecurringJobRecord>,
+    audit: Vec<AuditEvent>,
+}
+
+impl MemoryState {
+    fn user_exists(&self, id: i64) -> Result<(), Box<dyn Error>> {
+        match self.users.iter().any(|u| u.id == id) {
+            true => Ok(()),
+            false => Err(format!("User {} does not exist", id).into()),
+        }
+    }
+    fn account(&mut self, account_id: i64) -> Result<&mut AcmeAccount, Box<dyn Error>> {
+        self.accounts
+            .iter_mut()
+            .find(|a| a.account_id == account_id)
+            .ok_or_else(|| format!("Account {} could not be found", account_id).into())
+    }
+    fn job(&mut self, job_id: i64) -> Option<&mut JobRecord> {
+        self.jobs.get_mut(&job_id)
+    }
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        MemoryRepository::default()
+    }
+    fn state(&self) -> Result<MutexGuard<'_, MemoryState>, Box<dyn Error>> {
+        self.state.lock().map_err(|e| e.to_string().into())
+    }
+    fn update_job(&self, job_id: i64, update: impl FnOnce(&mut JobRecord)) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state()?;
+        if let Some(record) = state.job(job_id) {
+            update(record);
+            record.updated_at = now();
+        }
+        Ok(())
+    }
+}
+
+impl SchemaRepository for MemoryRepository {
+    fn migrate(&self) -> Result<i64, Box<dyn Error>> {
+        Ok(Migration::latest())
+    }
+}
+
+impl UserRepository for MemoryRepository {
+    fn find(&self, user_id: &str) -> Result<Option<AcmeUser>, Box<dyn Error>> {
+        Ok(self.state()?.users.iter().find(|u| u.user_id == user_id).cloned())
+    }
+    fn create(&self, user_id: &str, key_type: &str, key_path: &str, user_dump_path: &str) -> Result<AcmeUser, Box<dyn Error>> {
+        let mut state = self.state()?;
+        let user = AcmeUser {
+            id: state.users.len() as i64 + 1,
+            user_id: user_id.to_string(),
+            key_type: key_type.to_string(),
+            key_path: key_path.to_string(),
+            user_dump_path: user_dump_path.to_string(),
+            account_url: None,
+        };
+        state.users.push(user.clone());
+        Ok(user)
+    }
+    fn list(&self) -> Result<Vec<AcmeUser>, Box<dyn Error>> {
+        Ok(self.state()?.users.clone())
+    }
+}
+
+impl AccountRepository for MemoryRepository {
+    fn ensure(&self, user_id: i64, ca_name: &str, directory_url: &str, key_path: &str) -> Result<AcmeAccount, Box<dyn Error>> {
+        let mut state = self.state()?;
+        state.user_exists(user_id)?;
+        if let Some(account) = state.accounts.iter_mut().find(|a| a.user_id == user_id && a.ca_name == ca_name) {
+            account.directory_url = directory_url.to_string();
+            account.updated_at = now();
+            return Ok(account.clone());
+        }
+        let account = AcmeAccount {
+            account_id: state.accounts.len() as i64 + 1,
+            user_id,
+            ca_name: ca_name.to_string(),
+            directory_url: directory_url.to_string(),
+            key_path: key_path.to_string(),
+            account_url: None,
+            status: AccountStatus::Pending,
+            created_at: now(),
+            updated_at: now(),
+        };
+        state.accounts.push(account.clone());
+        Ok(account)
+    }
+    fn find(&self, user_id: i64, ca_name: &str) -> Result<Option<AcmeAccount>, Box<dyn Error>> {
+        Ok(self.state()?.accounts.iter().find(|a| a.user_id == user_id && a.ca_name == ca_name).cloned())
+    }
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeAccount>, Box<dyn Error>> {
+        Ok(self.state()?.accounts.iter().filter(|a| a.user_id == user_id).cloned().collect())
+    }
+    fn register(&self, account_id: i64, account_url: &str) -> Result<AcmeAccount, Box<dyn Error>> {
+        let mut state = self.state()?;
+        let account = state.account(account_id)?;
+        account.account_url = Some(account_url.to_string());
+        account.status = AccountStatus::Valid;
+        account.updated_at = now();
+        Ok(account.clone())
+    }
+    fn set_status(&self, account_id: i64, status: AccountStatus) -> Result<AcmeAccount, Box<dyn Error>> {
+        let mut state = self.state()?;
+        let account = state.account(account_id)?;
+        account.status = status;
+        account.updated_at = now();
+        Ok(account.clone())
+    }
+}
+
+impl DirectoryRepository for MemoryRepository {
+    fn find(&self, user_id: i64, ca_name: &str) -> Result<Option<AcmeDirectory>, Box<dyn Error>> {
+        let user_id = user_id.to_string();
+        Ok(self
+            .state()?
+            .directories
+            .iter()
+            .find(|d| d.user_id == user_id && d.ca_name == ca_name)
+            .cloned())
+    }
+    fn save(&self, user_id: i64, ca_name: &str, directory: &AcmeDirectoryApi) -> Result<AcmeDirectory, Box<dyn Error>> {
+        let mut state = self.state()?;
+        state.user_exists(user_id)?;
+        let stored = state
+            .directories
+            .iter()
+            .position(|d| d.user_id == user_id.to_string() && d.ca_name == ca_name);
+        let directory_id = match stored {
+            Some(index) => state.directories.remove(index).directory_id,
+            None => state.directories.iter().map(|d| d.directory_id).max().unwrap_or_default() + 1,
+        };
+        let saved = AcmeDirectory {
+            directory_id,
+            user_id: user_id.to_string(),
+            ca_name: ca_name.to_string(),
+            key_change: directory.key_change.clone(),
+            new_authz: Some(directory.new_authz.clone().unwrap_or_default()),
+            new_nonce: directory.new_nonce.clone(),
+            new_account: directory.new_account.clone(),
+            new_order: directory.new_order.clone(),
+            revoke_cert: directory.revoke_cert.clone(),
+            meta: None,
+        };
+        state.directories.push(saved.clone());
+        Ok(saved)
+    }
+    fn orphaned(&self) -> Result<Vec<AcmeDirectory>, Box<dyn Error>> {
+        let state = self.state()?;
+        Ok(state
+            .directories
+            .iter()
+            .filter(|d| !state.users.iter().any(|u| u.id.to_string() == d.user_id))
+            .cloned()
+            .collect())
+    }
+    fn delete(&self, directory_id: i64) -> Result<bool, Box<dyn Error>> {
+        let mut state = self.state()?;
+        let before = state.directories.len();
+        state.directories.retain(|d| d.directory_id != directory_id);
+        Ok(state.directories.len() != before)
+    }
+}
+
+impl OrderRepository for MemoryRepository {
+    fn create(
+        &self,
+        user_id: i64,
+        order_url: &str,
+        status: &str,
+        identifiers: &[String],
+        finalize_url: &str,
+    ) -> Result<AcmeOrder, Box<dyn Error>> {
+        let mut state = self.state()?;
+        state.user_exists(user_id)?;
+        if state.orders.iter().any(|o| o.order_url == order_url) {
+            return Err(format!("Order {} is already stored", order_url).into());
+        }
+        let order = AcmeOrder {
+            order_id: state.orders.len() as i64 + 1,
+            user_id,
+            order_url: order_url.to_string(),
+            status: status.to_string(),
+            identifiers: identifiers.to_vec(),
+            finalize_url: finalize_url.to_string(),
+            certificate_url: None,
+            created_at: now(),
+            updated_at: now(),
+        };
+        state.orders.push(order.clone());
+        Ok(order)
+    }
+    fn find(&self, order_url: &str) -> Result<Option<AcmeOrder>, Box<dyn Error>> {
+        Ok(self.state()?.orders.iter().find(|o| o.order_url == order_url).cloned())
+    }
+    fn update_status(&self, order_url: &str, status: &str, certificate_url: Option<&str>) -> Result<AcmeOrder, Box<dyn Error>> {
+        let mut state = self.state()?;
+        let order = state
+            .orders
+            .iter_mut()
+            .find(|o| o.order_url == order_url)
+            .ok_or_else(|| format!("Order {} could not be found", order_url))?;
+        order.status = status.to_string();
+        if let Some(certificate_url) = certificate_url {
+            order.certificate_url = Some(certificate_url.to_string());
+        }
+        order.updated_at = now();
+        Ok(order.clone())
+    }
+    fn transition(&self, order_url: &str, from: &str, to: &str, certificate_url: Option<&str>) -> Result<AcmeOrder, Box<dyn Error>> {
+        let mut state = self.state()?;
+        let order = state
+            .orders
+            .iter_mut()
+            .find(|o| o.order_url == order_url)
+            .ok_or_else(|| format!("Order {} could not be found", order_url))?;
+        if order.status != from {
+            return Err(format!("Order {} is {}, not {}", order_url, order.status, from).into());
+        }
+        order.status = to.to_string();
+        if let Some(certificate_url) = certificate_url {
+            order.certificate_url = Some(certificate_url.to_string());
+        }
+        order.updated_at = now();
+        Ok(order.clone())
+    }
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeOrder>, Box<dyn Error>> {
+        Ok(self.state()?.orders.iter().filter(|o| o.user_id == user_id).cloned().collect())
+    }
+}
+
+impl CertificateRepository for MemoryRepository {
+    fn record(&self, certificate: NewCertificate) -> Result<AcmeCertificate, Box<dyn Error>> {
+        let mut state = self.state()?;
+        state.user_exists(certificate.user_id)?;
+        let recorded = AcmeCertificate {
+            certificate_id: state.certificates.len() as i64 + 1,
+            user_id: certificate.user_id,
+            ca_name: certificate.ca_name.to_string(),
+            name: certificate.name.to_string(),
+            serial: certificate.serial.to_string(),
+            not_before: certificate.not_before,
+            not_after: certificate.not_after,
+            certificate_url: certificate.certificate_url.to_string(),
+            chain_url: certificate.chain_url.to_string(),
+            chain_issuer: certificate.chain_issuer.map(str::to_string),
+        };
+        state.certificates.push(recorded.clone());
+        Ok(recorded)
+    }
+    fn latest(&self, name: &str) -> Result<Option<AcmeCertificate>, Box<dyn Error>> {
+        Ok(self.state()?.certificates.iter().rev().find(|c| c.name == name).cloned())
+    }
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeCertificate>, Box<dyn Error>> {
+        Ok(self.state()?.certificates.iter().filter(|c| c.user_id == user_id).cloned().collect())
+    }
+}
+
+impl JobRepository for MemoryRepository {
+    fn insert_with_dependencies(
+        &self,
+        job_type: &str,
+        payload: &Value,
+        run_at: Option<i64>,
+        depends_on: &[i64],
+    ) -> Result<i64, Box<dyn Error>> {
+        let mut state = self.state()?;
+        if let Some(missing) = depends_on.iter().find(|id| !state.jobs.contains_key(id)) {
+            return Err(format!("Job {} does not exist", missing).into());
+        }
+        let job_id = state.jobs.keys().next_back().copied().unwrap_or_default() + 1;
+        state.jobs.insert(
+            job_id,
+            JobRecord {
+                job_id,
+                job_type: job_type.to_string(),
+                payload: payload.to_string(),
+                status: JobStatus::Queued,
+                attempts: 0,
+                last_error: None,
+                run_at,
+                created_at: now(),
+                updated_at: now(),
+            },
+        );
+        for upstream in depends_on {
+            state.dependencies.push((job_id, *upstream));
+        }
+        Ok(job_id)
+    }
+    fn dependencies(&self, job_id: i64) -> Result<Vec<i64>, Box<dyn Error>> {
+        let mut dependencies: Vec<i64> = self
+            .state()?
+            .dependencies
+            .iter()
+            .filter(|(job, _)| *job == job_id)
+            .map(|(_, upstream)| *upstream)
+            .collect();
+        dependencies.sort();
+        Ok(dependencies)
+    }
+    fn set_output(&self, job_id: i64, output_type: &str, output: &Value) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state()?;
+        if !state.jobs.contains_key(&job_id) {
+            return Err(format!("Job {} does not exist", job_id).into());
+        }
+        state.outputs.insert(job_id, (output_type.to_string(), output.clone()));
+        Ok(())
+    }
+    fn output(&self, job_id: i64) -> Result<Option<(String, Value)>, Box<dyn Error>> {
+        Ok(self.state()?.outputs.get(&job_id).cloned())
+    }
+    fn mark_running(&self, job_id: i64) -> Result<(), Box<dyn Error>> {
+        self.update_job(job_id, |record| {
+            record.status = JobStatus::Running;
+            record.attempts += 1;
+        })
+    }
+    fn mark_queued(&self, job_id: i64) -> Result<(), Box<dyn Error>> {
+        self.update_job(job_id, |record| record.status = JobStatus::Queued)
+    }
+    fn mark_succeeded(&self, job_id: i64) -> Result<(), Box<dyn Error>> {
+        self.update_job(job_id, |record| {
+            record.status = JobStatus::Succeeded;
+            record.last_error = None;
+        })
+    }
+    fn mark_retrying(&self, job_id: i64, error: &str, run_at: i64) -> Result<(), Box<dyn Error>> {
+        self.update_job(job_id, |record| {
+            record.status = JobStatus::Retrying;
+            record.last_error = Some(error.to_string());
+            record.run_at = Some(run_at);
+        })
+    }
+    fn mark_failed(&self, job_id: i64, error: &str) -> Result<(), Box<dyn Error>> {
+        self.update_job(job_id, |record| {
+            record.status = JobStatus::Failed;
+            record.last_error = Some(error.to_string());
+        })
+    }
+    fn mark_cancelled(&self, job_id: i64) -> Result<bool, Box<dyn Error>> {
+        let mut state = self.state()?;
+        match state.job(job_id) {
+            Some(record) if record.status.is_unfinished() => {
+                record.status = JobStatus::Cancelled;
+                record.updated_at = now();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+    fn mark_skipped(&self, job_id: i64, reason: &str) -> Result<(), Box<dyn Error>> {
+        self.update_job(job_id, |record| {
+            record.status = JobStatus::Skipped;
+            record.last_error = Some(reason.to_string());
+        })
+    }
+    fn get(&self, job_id: i64) -> Result<Option<JobRecord>, Box<dyn Error>> {
+        Ok(self.state()?.jobs.get(&job_id).cloned())
+    }
+    fn unfinished(&self) -> Result<Vec<JobRecord>, Box<dyn Error>> {
+        Ok(self.state()?.jobs.values().filter(|r| r.status.is_unfinished()).cloned().collect())
+    }
+    fn list(&self, status: Option<JobStatus>) -> Result<Vec<JobRecord>, Box<dyn Error>> {
+        Ok(self
+            .state()?
+            .jobs
+            .values()
+            .filter(|r| status.is_none_or(|s| r.status == s))
+            .cloned()
+            .collect())
+    }
+    fn dead_letters(&self) -> Result<Vec<JobRecord>, Box<dyn Error>> {
+        Ok(self.state()?.jobs.values().filter(|r| r.status == JobStatus::Failed).cloned().collect())
+    }
+    fn replay(&self, job_id: i64) -> Result<JobRecord, Box<dyn Error>> {
+        let mut state = self.state()?;
+        match state.job(job_id) {
+            Some(record) if record.status == JobStatus::Failed => {
+                record.status = JobStatus::Queued;
+                record.attempts = 0;
+                record.last_error = None;
+                record.run_at = None;
+                record.updated_at = now();
+                Ok(record.clone())
+            }
+            _ => Err(format!("Job {} is not in the dead-letter state", job_id).into()),
+        }
+    }
+    fn upsert_recurring(
+        &self,
+        name: &str,
+        job_type: &str,
+        payload: &Value,
+        schedule: &str,
+        missed_runs: &str,
+        next_run_at: i64,
+    ) -> Result<RecurringJobRecord, Box<dyn Error>> {
+        let mut state = self.state()?;
+        let recurring_id = state.recurring.len() as i64 + 1;
+        let record = match state.recurring.iter_mut().find(|r| r.name == name) {
+            Some(record) => record,
+            None => {
+                state.recurring.push(RecurringJobRecord {
+                    recurring_id,
+                    name: name.to_string(),
+                    job_type: String::new(),
+                    payload: String::new(),
+                    schedule: schedule.to_string(),
+                    missed_runs: String::new(),
+                    last_run_at: None,
+                    next_run_at,
+                    created_at: now(),
+                    updated_at: now(),
+                });
+                state.recurring.last_mut().unwrap()
+            }
+        };
+        if record.schedule != schedule {
+            record.next_run_at = next_run_at;
+        }
+        record.job_type = job_type.to_string();
+        record.payload = payload.to_string();
+        record.schedule = schedule.to_string();
+        record.missed_runs = missed_runs.to_string();
+        record.updated_at = now();
+        Ok(record.clone())
+    }
+    fn recurring(&self) -> Result<Vec<RecurringJobRecord>, Box<dyn Error>> {
+        let mut records = self.state()?.recurring.clone();
+        records.sort_by_key(|r| r.next_run_at);
+        Ok(records)
+    }
+    fn reschedule_recurring(&self, recurring_id: i64, last_run_at: Option<i64>, next_run_at: i64) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state()?;
+        if let Some(record) = state.recurring.iter_mut().find(|r| r.recurring_id == recurring_id) {
+            if last_run_at.is_some() {
+                record.last_run_at = last_run_at;
+            }
+            record.next_run_at = next_run_at;
+            record.updated_at = now();
+        }
+        Ok(())
+    }
+}
+
+impl AuditRepository for MemoryRepository {
+    fn append(&self, event: NewAuditEvent) -> Result<AuditEvent, Box<dyn Error>> {
+        let mut state = self.state()?;
+        let previous_hash = match state.audit.last() {
+            Some(last) => last.hash.clone(),
+            None => AuditEvent::GENESIS_HASH.to_string(),
+        };
+        let details = event.details.to_string();
+        let occurred_at = now();
+        let appended = AuditEvent {
+            event_id: state.audit.len() as i64 + 1,
+            occurred_at,
+            kind: event.kind,
+            user_id: event.user_id.map(str::to_string),
+            subject: event.subject.map(str::to_string),
+            hash: AuditEvent::chain_hash(
+                previous_hash.as_str(),
+                occurred_at,
+                event.kind,
+                event.user_id,
+                event.subject,
+                details.as_str(),
+            ),
+            details,
+            previous_hash,
+        };
+        state.audit.push(appended.clone());
+        Ok(appended)
+    }
+    fn list(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, Box<dyn Error>> {
+        Ok(self
+            .state()?
+            .audit
+            .iter()
+            .filter(|e| filter.kind.is_none_or(|kind| e.kind == kind))
+            .filter(|e| filter.user_id.is_none() || e.user_id == filter.user_id)
+            .filter(|e| filter.since.is_none_or(|since| e.occurred_at >= since))
+            .filter(|e| filter.until.is_none_or(|until| e.occurred_at < until))
+            .cloned()
+            .collect())
+    }
+}
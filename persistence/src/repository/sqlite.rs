@@ -0,0 +1,682 @@
+use crate::data_model::{
+    AccountStatus, AcmeAccount, AcmeCertificate, AcmeDirectory, AcmeOrder, AcmeUser, AuditEvent, JobRecord, JobStatus,
+    RecurringJobRecord,
+};
+use crate::database::{ConnectionPool, PooledConnection};
+use crate::repository::{
+    now, AccountRepository, AuditFilter, AuditRepository, CertificateRepository, DirectoryRepository, JobRepository,
+    NewAuditEvent, NewCertificate, OrderRepository, SchemaRepository, UserRepository,
+};
+use acme_client::comms::directory::AcmeDirectoryApi;
+use serde_json::Value;
+use sqlite::{State, Statement};
+use std::error::Error;
+use tracing::debug;
+
+/// Sqlite backend of every repository.
+///
+/// Every operation borrows a connection from the pool, the calls block so async callers
+/// should move them off the runtime threads.
+#[derive(Clone, Debug)]
+pub struct SqliteRepository {
+    pool: ConnectionPool,
+}
+
+impl SqliteRepository {
+    pub fn new(base_dir: &str) -> Self {
+        SqliteRepository {
+            pool: ConnectionPool::new(base_dir),
+        }
+    }
+    fn connection(&self) -> Result<PooledConnection, Box<dyn Error>> {
+        self.pool.get()
+    }
+    fn update_job(&self, sql: &str, job_id: i64, status: JobStatus, error: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, job_id))?;
+        statement.bind((2, status.as_str()))?;
+        statement.bind((3, now()))?;
+        if let Some(error) = error {
+            statement.bind((4, error))?;
+        }
+        while let State::Row = statement.next()? {}
+        Ok(())
+    }
+    fn read_jobs(mut statement: Statement) -> Result<Vec<JobRecord>, Box<dyn Error>> {
+        let mut records = Vec::new();
+        while let State::Row = statement.next()? {
+            records.push(JobRecord::read_row(&statement)?);
+        }
+        Ok(records)
+    }
+}
+
+impl SchemaRepository for SqliteRepository {
+    fn migrate(&self) -> Result<i64, Box<dyn Error>> {
+        self.connection()?.migrate()
+    }
+}
+
+impl UserRepository for SqliteRepository {
+    fn find(&self, user_id: &str) -> Result<Option<AcmeUser>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_users WHERE user_id = ?1;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        match statement.next()? {
+            State::Row => Ok(Some(AcmeUser::read_row(&statement)?)),
+            State::Done => Ok(None),
+        }
+    }
+    fn create(&self, user_id: &str, key_type: &str, key_path: &str, user_dump_path: &str) -> Result<AcmeUser, Box<dyn Error>> {
+        let sql = r#"
+            INSERT INTO acme_users (user_id, key_type, key_path, user_dump_path)
+            VALUES (?1, ?2, ?3, ?4) RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        statement.bind((2, key_type))?;
+        statement.bind((3, key_path))?;
+        statement.bind((4, user_dump_path))?;
+        AcmeUser::scan_statement(statement)
+    }
+    fn list(&self) -> Result<Vec<AcmeUser>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_users ORDER BY id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        let mut users = Vec::new();
+        while let State::Row = statement.next()? {
+            users.push(AcmeUser::read_row(&statement)?);
+        }
+        Ok(users)
+    }
+}
+
+impl AccountRepository for SqliteRepository {
+    fn ensure(&self, user_id: i64, ca_name: &str, directory_url: &str, key_path: &str) -> Result<AcmeAccount, Box<dyn Error>> {
+        let sql = r#"
+            INSERT INTO acme_accounts (user_id, ca_name, directory_url, key_path, status, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+            ON CONFLICT (user_id, ca_name) DO UPDATE SET directory_url = excluded.directory_url, updated_at = excluded.updated_at
+            RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        statement.bind((2, ca_name))?;
+        statement.bind((3, directory_url))?;
+        statement.bind((4, key_path))?;
+        statement.bind((5, AccountStatus::Pending.as_str()))?;
+        statement.bind((6, now()))?;
+        AcmeAccount::scan_statement(statement)?.ok_or_else(|| "Account could not be picked back up!".into())
+    }
+    fn find(&self, user_id: i64, ca_name: &str) -> Result<Option<AcmeAccount>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_accounts WHERE user_id = ?1 AND ca_name = ?2;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        statement.bind((2, ca_name))?;
+        AcmeAccount::scan_statement(statement)
+    }
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeAccount>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_accounts WHERE user_id = ?1 ORDER BY account_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        let mut accounts = Vec::new();
+        while let State::Row = statement.next()? {
+            accounts.push(AcmeAccount::read_row(&statement)?);
+        }
+        Ok(accounts)
+    }
+    fn register(&self, account_id: i64, account_url: &str) -> Result<AcmeAccount, Box<dyn Error>> {
+        let sql = r#"
+            UPDATE acme_accounts SET account_url = ?2, status = ?3, updated_at = ?4 WHERE account_id = ?1 RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, account_id))?;
+        statement.bind((2, account_url))?;
+        statement.bind((3, AccountStatus::Valid.as_str()))?;
+        statement.bind((4, now()))?;
+        AcmeAccount::scan_statement(statement)?.ok_or_else(|| format!("Account {} could not be found", account_id).into())
+    }
+    fn set_status(&self, account_id: i64, status: AccountStatus) -> Result<AcmeAccount, Box<dyn Error>> {
+        let sql = r#"
+            UPDATE acme_accounts SET status = ?2, updated_at = ?3 WHERE account_id = ?1 RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, account_id))?;
+        statement.bind((2, status.as_str()))?;
+        statement.bind((3, now()))?;
+        AcmeAccount::scan_statement(statement)?.ok_or_else(|| format!("Account {} could not be found", account_id).into())
+    }
+}
+
+impl DirectoryRepository for SqliteRepository {
+    fn find(&self, user_id: i64, ca_name: &str) -> Result<Option<AcmeDirectory>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_users_directory WHERE user_id = ?1 AND ca_name = ?2;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        statement.bind((2, ca_name))?;
+        AcmeDirectory::scan_statement(statement)
+    }
+    fn save(&self, user_id: i64, ca_name: &str, directory: &AcmeDirectoryApi) -> Result<AcmeDirectory, Box<dyn Error>> {
+        let sql = match DirectoryRepository::find(self, user_id, ca_name)? {
+            None => {
+                r#"
+            INSERT INTO acme_users_directory(
+                user_id,
+                new_nonce,
+                new_account,
+                new_order,
+                new_authz,
+                revoke_cert,
+                key_change,
+                ca_name
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) RETURNING *;
+            "#
+            }
+            Some(_) => {
+                r#"
+            UPDATE acme_users_directory SET
+                new_nonce = ?2,
+                new_account = ?3,
+                new_order = ?4,
+                new_authz = ?5,
+                revoke_cert = ?6,
+                key_change = ?7
+            WHERE user_id = ?1 AND ca_name = ?8 RETURNING *;
+            "#
+            }
+        };
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        statement.bind((2, directory.new_nonce.as_str()))?;
+        statement.bind((3, directory.new_account.as_str()))?;
+        statement.bind((4, directory.new_order.as_str()))?;
+        statement.bind((5, directory.new_authz.as_deref().unwrap_or("")))?;
+        statement.bind((6, directory.revoke_cert.as_str()))?;
+        statement.bind((7, directory.key_change.as_str()))?;
+        statement.bind((8, ca_name))?;
+        AcmeDirectory::scan_statement(statement)?.ok_or_else(|| "Directory could not be picked back up!".into())
+    }
+    fn orphaned(&self) -> Result<Vec<AcmeDirectory>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_users_directory
+            WHERE user_id NOT IN (SELECT id FROM acme_users) ORDER BY directory_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        let mut directories = Vec::new();
+        while let State::Row = statement.next()? {
+            directories.push(AcmeDirectory::read_row(&statement)?);
+        }
+        Ok(directories)
+    }
+    fn delete(&self, directory_id: i64) -> Result<bool, Box<dyn Error>> {
+        let sql = r#"
+            DELETE FROM acme_users_directory WHERE directory_id = ?1 RETURNING directory_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, directory_id))?;
+        Ok(matches!(statement.next()?, State::Row))
+    }
+}
+
+impl OrderRepository for SqliteRepository {
+    fn create(
+        &self,
+        user_id: i64,
+        order_url: &str,
+        status: &str,
+        identifiers: &[String],
+        finalize_url: &str,
+    ) -> Result<AcmeOrder, Box<dyn Error>> {
+        let sql = r#"
+            INSERT INTO acme_orders (user_id, order_url, status, identifiers, finalize_url, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6) RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        statement.bind((2, order_url))?;
+        statement.bind((3, status))?;
+        statement.bind((4, serde_json::to_string(identifiers)?.as_str()))?;
+        statement.bind((5, finalize_url))?;
+        statement.bind((6, now()))?;
+        AcmeOrder::scan_statement(statement)?.ok_or_else(|| "Order could not be picked back up!".into())
+    }
+    fn find(&self, order_url: &str) -> Result<Option<AcmeOrder>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_orders WHERE order_url = ?1;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, order_url))?;
+        AcmeOrder::scan_statement(statement)
+    }
+    fn update_status(&self, order_url: &str, status: &str, certificate_url: Option<&str>) -> Result<AcmeOrder, Box<dyn Error>> {
+        let sql = r#"
+            UPDATE acme_orders SET status = ?2, certificate_url = COALESCE(?3, certificate_url), updated_at = ?4
+            WHERE order_url = ?1 RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, order_url))?;
+        statement.bind((2, status))?;
+        statement.bind((3, certificate_url))?;
+        statement.bind((4, now()))?;
+        AcmeOrder::scan_statement(statement)?.ok_or_else(|| format!("Order {} could not be found", order_url).into())
+    }
+    fn transition(&self, order_url: &str, from: &str, to: &str, certificate_url: Option<&str>) -> Result<AcmeOrder, Box<dyn Error>> {
+        let connection = self.connection()?;
+        connection.transaction(|connection| {
+            let mut statement = connection.prepare("SELECT status FROM acme_orders WHERE order_url = ?1;")?;
+            statement.bind((1, order_url))?;
+            let status = match statement.next()? {
+                State::Row => statement.read::<String, _>("status")?,
+                State::Done => return Err(format!("Order {} could not be found", order_url).into()),
+            };
+            if status != from {
+                return Err(format!("Order {} is {}, not {}", order_url, status, from).into());
+            }
+            let sql = r#"
+                UPDATE acme_orders SET status = ?2, certificate_url = COALESCE(?3, certificate_url), updated_at = ?4
+                WHERE order_url = ?1 RETURNING *;
+                "#;
+            let mut statement = connection.prepare(sql)?;
+            statement.bind((1, order_url))?;
+            statement.bind((2, to))?;
+            statement.bind((3, certificate_url))?;
+            statement.bind((4, now()))?;
+            AcmeOrder::scan_statement(statement)?.ok_or_else(|| format!("Order {} could not be found", order_url).into())
+        })
+    }
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeOrder>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_orders WHERE user_id = ?1 ORDER BY order_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        let mut orders = Vec::new();
+        while let State::Row = statement.next()? {
+            orders.push(AcmeOrder::read_row(&statement)?);
+        }
+        Ok(orders)
+    }
+}
+
+impl CertificateRepository for SqliteRepository {
+    fn record(&self, certificate: NewCertificate) -> Result<AcmeCertificate, Box<dyn Error>> {
+        let sql = r#"
+            INSERT INTO acme_certificates (
+                user_id,
+                name,
+                serial,
+                not_before,
+                not_after,
+                certificate_url,
+                chain_url,
+                chain_issuer,
+                ca_name
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, certificate.user_id))?;
+        statement.bind((2, certificate.name))?;
+        statement.bind((3, certificate.serial))?;
+        statement.bind((4, certificate.not_before))?;
+        statement.bind((5, certificate.not_after))?;
+        statement.bind((6, certificate.certificate_url))?;
+        statement.bind((7, certificate.chain_url))?;
+        statement.bind((8, certificate.chain_issuer))?;
+        statement.bind((9, certificate.ca_name))?;
+        AcmeCertificate::scan_statement(statement)?.ok_or_else(|| "Certificate could not be picked back up!".into())
+    }
+    fn latest(&self, name: &str) -> Result<Option<AcmeCertificate>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_certificates WHERE name = ?1 ORDER BY certificate_id DESC LIMIT 1;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, name))?;
+        AcmeCertificate::scan_statement(statement)
+    }
+    fn for_user(&self, user_id: i64) -> Result<Vec<AcmeCertificate>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM acme_certificates WHERE user_id = ?1 ORDER BY certificate_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, user_id))?;
+        let mut certificates = Vec::new();
+        while let State::Row = statement.next()? {
+            certificates.push(AcmeCertificate::read_row(&statement)?);
+        }
+        Ok(certificates)
+    }
+}
+
+impl JobRepository for SqliteRepository {
+    fn insert_with_dependencies(
+        &self,
+        job_type: &str,
+        payload: &Value,
+        run_at: Option<i64>,
+        depends_on: &[i64],
+    ) -> Result<i64, Box<dyn Error>> {
+        let sql = r#"
+            INSERT INTO jobs (job_type, payload, status, attempts, run_at, created_at, updated_at)
+            VALUES (?1, ?2, ?3, 0, ?5, ?4, ?4) RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let job_id = connection.transaction(|connection| {
+            let mut statement = connection.prepare(sql)?;
+            statement.bind((1, job_type))?;
+            statement.bind((2, payload.to_string().as_str()))?;
+            statement.bind((3, JobStatus::Queued.as_str()))?;
+            statement.bind((4, now()))?;
+            statement.bind((5, run_at))?;
+            let record = JobRecord::scan_statement(statement)?.ok_or("Job could not be picked back up!")?;
+            for upstream in depends_on {
+                let mut statement = connection.prepare("INSERT INTO job_dependencies (job_id, depends_on) VALUES (?1, ?2);")?;
+                statement.bind((1, record.job_id))?;
+                statement.bind((2, *upstream))?;
+                while let State::Row = statement.next()? {}
+            }
+            Ok(record.job_id)
+        })?;
+        debug!("Persisted job {} as {}", job_type, job_id);
+        Ok(job_id)
+    }
+    fn dependencies(&self, job_id: i64) -> Result<Vec<i64>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT depends_on FROM job_dependencies WHERE job_id = ?1 ORDER BY depends_on;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, job_id))?;
+        let mut dependencies = Vec::new();
+        while let State::Row = statement.next()? {
+            dependencies.push(statement.read::<i64, _>("depends_on")?);
+        }
+        Ok(dependencies)
+    }
+    fn set_output(&self, job_id: i64, output_type: &str, output: &Value) -> Result<(), Box<dyn Error>> {
+        let sql = r#"
+            INSERT INTO job_outputs (job_id, output_type, output) VALUES (?1, ?2, ?3)
+            ON CONFLICT (job_id) DO UPDATE SET output_type = excluded.output_type, output = excluded.output;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, job_id))?;
+        statement.bind((2, output_type))?;
+        statement.bind((3, output.to_string().as_str()))?;
+        while let State::Row = statement.next()? {}
+        Ok(())
+    }
+    fn output(&self, job_id: i64) -> Result<Option<(String, Value)>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT output_type, output FROM job_outputs WHERE job_id = ?1;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, job_id))?;
+        if let State::Row = statement.next()? {
+            let output_type = statement.read::<String, _>("output_type")?;
+            let output = serde_json::from_str(statement.read::<String, _>("output")?.as_str())?;
+            return Ok(Some((output_type, output)));
+        }
+        Ok(None)
+    }
+    fn mark_running(&self, job_id: i64) -> Result<(), Box<dyn Error>> {
+        let sql = r#"
+            UPDATE jobs SET status = ?2, attempts = attempts + 1, updated_at = ?3 WHERE job_id = ?1;
+            "#;
+        self.update_job(sql, job_id, JobStatus::Running, None)
+    }
+    fn mark_queued(&self, job_id: i64) -> Result<(), Box<dyn Error>> {
+        let sql = r#"
+            UPDATE jobs SET status = ?2, updated_at = ?3 WHERE job_id = ?1;
+            "#;
+        self.update_job(sql, job_id, JobStatus::Queued, None)
+    }
+    fn mark_succeeded(&self, job_id: i64) -> Result<(), Box<dyn Error>> {
+        let sql = r#"
+            UPDATE jobs SET status = ?2, last_error = NULL, updated_at = ?3 WHERE job_id = ?1;
+            "#;
+        self.update_job(sql, job_id, JobStatus::Succeeded, None)
+    }
+    fn mark_retrying(&self, job_id: i64, error: &str, run_at: i64) -> Result<(), Box<dyn Error>> {
+        let sql = r#"
+            UPDATE jobs SET status = ?2, last_error = ?4, run_at = ?5, updated_at = ?3 WHERE job_id = ?1;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, job_id))?;
+        statement.bind((2, JobStatus::Retrying.as_str()))?;
+        statement.bind((3, now()))?;
+        statement.bind((4, error))?;
+        statement.bind((5, run_at))?;
+        while let State::Row = statement.next()? {}
+        Ok(())
+    }
+    fn mark_failed(&self, job_id: i64, error: &str) -> Result<(), Box<dyn Error>> {
+        let sql = r#"
+            UPDATE jobs SET status = ?2, last_error = ?4, updated_at = ?3 WHERE job_id = ?1;
+            "#;
+        self.update_job(sql, job_id, JobStatus::Failed, Some(error))
+    }
+    fn mark_cancelled(&self, job_id: i64) -> Result<bool, Box<dyn Error>> {
+        // checked and written in one statement, a job finishing in between stays finished
+        let sql = r#"
+            UPDATE jobs SET status = ?2, updated_at = ?3
+            WHERE job_id = ?1 AND status IN (?4, ?5, ?6) RETURNING job_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, job_id))?;
+        statement.bind((2, JobStatus::Cancelled.as_str()))?;
+        statement.bind((3, now()))?;
+        statement.bind((4, JobStatus::Queued.as_str()))?;
+        statement.bind((5, JobStatus::Running.as_str()))?;
+        statement.bind((6, JobStatus::Retrying.as_str()))?;
+        let mut cancelled = false;
+        while let State::Row = statement.next()? {
+            cancelled = true;
+        }
+        Ok(cancelled)
+    }
+    fn mark_skipped(&self, job_id: i64, reason: &str) -> Result<(), Box<dyn Error>> {
+        let sql = r#"
+            UPDATE jobs SET status = ?2, last_error = ?4, updated_at = ?3 WHERE job_id = ?1;
+            "#;
+        self.update_job(sql, job_id, JobStatus::Skipped, Some(reason))
+    }
+    fn get(&self, job_id: i64) -> Result<Option<JobRecord>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM jobs WHERE job_id = ?1;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, job_id))?;
+        JobRecord::scan_statement(statement)
+    }
+    fn unfinished(&self) -> Result<Vec<JobRecord>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM jobs WHERE status IN (?1, ?2, ?3) ORDER BY job_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, JobStatus::Queued.as_str()))?;
+        statement.bind((2, JobStatus::Running.as_str()))?;
+        statement.bind((3, JobStatus::Retrying.as_str()))?;
+        Self::read_jobs(statement)
+    }
+    fn list(&self, status: Option<JobStatus>) -> Result<Vec<JobRecord>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM jobs WHERE (?1 IS NULL OR status = ?1) ORDER BY job_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, status.map(|s| s.as_str())))?;
+        Self::read_jobs(statement)
+    }
+    fn dead_letters(&self) -> Result<Vec<JobRecord>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM jobs WHERE status = ?1 ORDER BY job_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, JobStatus::Failed.as_str()))?;
+        Self::read_jobs(statement)
+    }
+    fn replay(&self, job_id: i64) -> Result<JobRecord, Box<dyn Error>> {
+        let sql = r#"
+            UPDATE jobs SET status = ?2, attempts = 0, last_error = NULL, run_at = NULL, updated_at = ?3
+            WHERE job_id = ?1 AND status = ?4 RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, job_id))?;
+        statement.bind((2, JobStatus::Queued.as_str()))?;
+        statement.bind((3, now()))?;
+        statement.bind((4, JobStatus::Failed.as_str()))?;
+        JobRecord::scan_statement(statement)?
+            .ok_or_else(|| format!("Job {} is not in the dead-letter state", job_id).into())
+    }
+    fn upsert_recurring(
+        &self,
+        name: &str,
+        job_type: &str,
+        payload: &Value,
+        schedule: &str,
+        missed_runs: &str,
+        next_run_at: i64,
+    ) -> Result<RecurringJobRecord, Box<dyn Error>> {
+        let sql = r#"
+            INSERT INTO recurring_jobs (name, job_type, payload, schedule, missed_runs, next_run_at, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)
+            ON CONFLICT (name) DO UPDATE SET
+                job_type = excluded.job_type,
+                payload = excluded.payload,
+                missed_runs = excluded.missed_runs,
+                next_run_at = CASE WHEN schedule = excluded.schedule THEN next_run_at ELSE excluded.next_run_at END,
+                schedule = excluded.schedule,
+                updated_at = excluded.updated_at
+            RETURNING *;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, name))?;
+        statement.bind((2, job_type))?;
+        statement.bind((3, payload.to_string().as_str()))?;
+        statement.bind((4, schedule))?;
+        statement.bind((5, missed_runs))?;
+        statement.bind((6, next_run_at))?;
+        statement.bind((7, now()))?;
+        RecurringJobRecord::scan_statement(statement)?.ok_or_else(|| "Recurring job could not be picked back up!".into())
+    }
+    fn recurring(&self) -> Result<Vec<RecurringJobRecord>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM recurring_jobs ORDER BY next_run_at;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        let mut records = Vec::new();
+        while let State::Row = statement.next()? {
+            records.push(RecurringJobRecord::read_row(&statement)?);
+        }
+        Ok(records)
+    }
+    fn reschedule_recurring(&self, recurring_id: i64, last_run_at: Option<i64>, next_run_at: i64) -> Result<(), Box<dyn Error>> {
+        let sql = r#"
+            UPDATE recurring_jobs SET last_run_at = COALESCE(?2, last_run_at), next_run_at = ?3, updated_at = ?4
+            WHERE recurring_id = ?1;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, recurring_id))?;
+        statement.bind((2, last_run_at))?;
+        statement.bind((3, next_run_at))?;
+        statement.bind((4, now()))?;
+        while let State::Row = statement.next()? {}
+        Ok(())
+    }
+}
+
+impl AuditRepository for SqliteRepository {
+    fn append(&self, event: NewAuditEvent) -> Result<AuditEvent, Box<dyn Error>> {
+        let sql = r#"
+            INSERT INTO audit_events (occurred_at, kind, user_id, subject, details, previous_hash, hash)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING *;
+            "#;
+        let details = event.details.to_string();
+        let connection = self.connection()?;
+        connection.transaction(|connection| {
+            let mut statement = connection.prepare("SELECT hash FROM audit_events ORDER BY event_id DESC LIMIT 1;")?;
+            let previous_hash = match statement.next()? {
+                State::Row => statement.read::<String, _>("hash")?,
+                State::Done => AuditEvent::GENESIS_HASH.to_string(),
+            };
+            let occurred_at = now();
+            let hash = AuditEvent::chain_hash(
+                previous_hash.as_str(),
+                occurred_at,
+                event.kind,
+                event.user_id,
+                event.subject,
+                details.as_str(),
+            );
+            let mut statement = connection.prepare(sql)?;
+            statement.bind((1, occurred_at))?;
+            statement.bind((2, event.kind.as_str()))?;
+            statement.bind((3, event.user_id))?;
+            statement.bind((4, event.subject))?;
+            statement.bind((5, details.as_str()))?;
+            statement.bind((6, previous_hash.as_str()))?;
+            statement.bind((7, hash.as_str()))?;
+            AuditEvent::scan_statement(statement)?.ok_or_else(|| "Audit event could not be picked back up!".into())
+        })
+    }
+    fn list(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, Box<dyn Error>> {
+        let sql = r#"
+            SELECT * FROM audit_events
+            WHERE (?1 IS NULL OR kind = ?1)
+                AND (?2 IS NULL OR user_id = ?2)
+                AND (?3 IS NULL OR occurred_at >= ?3)
+                AND (?4 IS NULL OR occurred_at < ?4)
+            ORDER BY event_id;
+            "#;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        statement.bind((1, filter.kind.map(|k| k.as_str())))?;
+        statement.bind((2, filter.user_id.as_deref()))?;
+        statement.bind((3, filter.since))?;
+        statement.bind((4, filter.until))?;
+        let mut events = Vec::new();
+        while let State::Row = statement.next()? {
+            events.push(AuditEvent::read_row(&statement)?);
+        }
+        Ok(events)
+    }
+}
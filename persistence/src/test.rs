@@ -0,0 +1,4 @@
+mod backup;
+mod database;
+mod migrations;
+mod repository;
@@ -0,0 +1,127 @@
+use crate::backup::BackupArchive;
+use crate::repository::Repositories;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tempfile::{tempdir, TempDir};
+
+const PASSPHRASE: &[u8] = b"correct horse battery staple";
+
+/// A base directory with a user, its id file, an account and a certificate in the output directory.
+fn populated() -> TempDir {
+    let dir = tempdir().unwrap();
+    let base = dir.path().canonicalize().unwrap();
+    let keys = base.join("out/a1b2c3/login-keys/ec-p256");
+    fs::create_dir_all(&keys).unwrap();
+    fs::write(keys.join("a1b2c3.pem"), "key").unwrap();
+    #[cfg(unix)]
+    fs::set_permissions(keys.join("a1b2c3.pem"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::create_dir_all(base.join("out/live/example")).unwrap();
+    fs::write(base.join("out/live/example/cert.pem"), "certificate").unwrap();
+    fs::write(base.join("user-id"), "a1b2c3").unwrap();
+
+    let repositories = Repositories::sqlite(base.to_str().unwrap());
+    repositories.schema.migrate().unwrap();
+    let user = repositories
+        .users
+        .create(
+            "a1b2c3",
+            "ec-p256",
+            keys.to_str().unwrap(),
+            base.join("out/a1b2c3").to_str().unwrap(),
+        )
+        .unwrap();
+    repositories
+        .accounts
+        .ensure(user.id, "default", "https://ca/dir", keys.to_str().unwrap())
+        .unwrap();
+    dir
+}
+
+fn out_dir(dir: &TempDir) -> String {
+    dir.path().canonicalize().unwrap().join("out").to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_backup_is_restored_under_a_new_base_dir() {
+    let source = populated();
+    let user_id = source.path().canonicalize().unwrap().join("user-id");
+    let archive =
+        BackupArchive::create(source.path().to_str().unwrap(), &[out_dir(&source).as_str(), user_id.to_str().unwrap()]).unwrap();
+    let paths: Vec<_> = archive.manifest.files.iter().map(|f| f.path.as_str()).collect();
+    assert_eq!(paths, vec!["out/a1b2c3/login-keys/ec-p256/a1b2c3.pem", "out/live/example/cert.pem", "user-id"]);
+    let encrypted = archive.encrypt(PASSPHRASE).unwrap();
+    assert!(!encrypted.windows(11).any(|w| w == b"certificate"));
+
+    let target = tempdir().unwrap();
+    let summary = BackupArchive::decrypt(&encrypted, PASSPHRASE)
+        .unwrap()
+        .restore(target.path().to_str().unwrap())
+        .unwrap();
+    assert_eq!(summary.files, 3);
+    assert_eq!(summary.rewritten_paths, 3);
+
+    let base = target.path().canonicalize().unwrap();
+    let key = base.join("out/a1b2c3/login-keys/ec-p256/a1b2c3.pem");
+    assert_eq!(fs::read_to_string(&key).unwrap(), "key");
+    #[cfg(unix)]
+    assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(fs::read_to_string(base.join("out/live/example/cert.pem")).unwrap(), "certificate");
+    assert_eq!(fs::read_to_string(base.join("user-id")).unwrap(), "a1b2c3");
+
+    let repositories = Repositories::sqlite(base.to_str().unwrap());
+    let user = repositories.users.find("a1b2c3").unwrap().unwrap();
+    assert_eq!(Path::new(user.key_path.as_str()), key.parent().unwrap());
+    assert_eq!(Path::new(user.user_dump_path.as_str()), base.join("out/a1b2c3"));
+    let account = repositories.accounts.find(user.id, "default").unwrap().unwrap();
+    assert_eq!(account.key_path, user.key_path);
+}
+
+#[test]
+fn test_damaged_archive_or_wrong_passphrase_is_refused() {
+    let source = populated();
+    let encrypted = BackupArchive::create(source.path().to_str().unwrap(), &[]).unwrap().encrypt(PASSPHRASE).unwrap();
+    let error = BackupArchive::decrypt(&encrypted, b"wrong").unwrap_err();
+    assert_eq!(error.to_string(), "Backup is corrupt or the passphrase is wrong");
+    let mut damaged = encrypted.clone();
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 1;
+    assert!(BackupArchive::decrypt(&damaged, PASSPHRASE).is_err());
+    assert!(BackupArchive::decrypt(b"not a backup", PASSPHRASE).is_err());
+    assert!(BackupArchive::decrypt(&encrypted, PASSPHRASE).is_ok());
+}
+
+#[test]
+fn test_restore_refuses_newer_schemas_and_existing_state() {
+    let source = populated();
+    let mut archive = BackupArchive::create(source.path().to_str().unwrap(), &[]).unwrap();
+    // restoring onto itself would overwrite the database and the key
+    assert!(archive.restore(source.path().to_str().unwrap()).unwrap_err().to_string().contains("exists already"));
+
+    archive.manifest.schema_version += 1;
+    let target = tempdir().unwrap();
+    assert!(archive.restore(target.path().to_str().unwrap()).unwrap_err().to_string().contains("newer"));
+    assert!(!target.path().join("acme-sentry.db").exists());
+}
+
+#[test]
+fn test_failed_restore_removes_what_it_wrote() {
+    let source = populated();
+    let archive = BackupArchive::create(source.path().to_str().unwrap(), &[out_dir(&source).as_str()]).unwrap();
+    let target = tempdir().unwrap();
+    // the key is written first, the directory of the certificate can't be created
+    fs::create_dir(target.path().join("out")).unwrap();
+    fs::write(target.path().join("out/live"), "in the way").unwrap();
+    assert!(archive.restore(target.path().to_str().unwrap()).is_err());
+    assert!(!target.path().join("out/a1b2c3/login-keys/ec-p256/a1b2c3.pem").exists());
+    assert!(!target.path().join("acme-sentry.db").exists());
+}
+
+#[test]
+fn test_files_outside_the_base_dir_are_refused() {
+    let source = populated();
+    let elsewhere = tempdir().unwrap();
+    let error = BackupArchive::create(source.path().to_str().unwrap(), &[elsewhere.path().to_str().unwrap()]).unwrap_err();
+    assert!(error.to_string().contains("outside"));
+}
@@ -0,0 +1,94 @@
+use crate::database::{ConnectionPool, DatabaseConnection};
+use crate::repository::Repositories;
+use serde_json::json;
+use std::thread;
+use tempfile::tempdir;
+
+fn pragma(connection: &DatabaseConnection, pragma: &str) -> String {
+    let mut statement = connection.prepare(format!("PRAGMA {};", pragma).as_str()).unwrap();
+    statement.next().unwrap();
+    statement.read::<String, _>(0).unwrap()
+}
+
+#[test]
+fn test_connections_use_wal_and_wait_when_busy() {
+    let dir = tempdir().unwrap();
+    let connection = DatabaseConnection::open(dir.path().to_str().unwrap()).unwrap();
+    assert_eq!(pragma(&connection, "journal_mode"), "wal");
+    assert_eq!(pragma(&connection, "busy_timeout"), "5000");
+    assert_eq!(pragma(&connection, "foreign_keys"), "1");
+}
+
+#[test]
+fn test_pooled_connections_are_reused() {
+    let dir = tempdir().unwrap();
+    let pool = ConnectionPool::new(dir.path().to_str().unwrap());
+    let first = pool.get().unwrap();
+    first.prepare("CREATE TEMP TABLE marker(id INTEGER);").unwrap().next().unwrap();
+    drop(first);
+    // temp tables only exist on the connection that created them
+    let again = pool.get().unwrap();
+    assert!(again.prepare("SELECT * FROM temp.marker;").is_ok());
+    let other = pool.get().unwrap();
+    assert!(other.prepare("SELECT * FROM temp.marker;").is_err());
+}
+
+#[test]
+fn test_failed_transactions_are_rolled_back() {
+    let dir = tempdir().unwrap();
+    let connection = DatabaseConnection::open(dir.path().to_str().unwrap()).unwrap();
+    connection.migrate().unwrap();
+    let failed = connection.transaction(|connection| {
+        connection
+            .prepare("INSERT INTO jobs (job_type, payload, status, created_at, updated_at) VALUES ('a', '{}', 'queued', 0, 0);")?
+            .next()?;
+        Err::<(), _>("failing on purpose".into())
+    });
+    assert!(failed.is_err());
+    let mut statement = connection.prepare("SELECT COUNT(*) FROM jobs;").unwrap();
+    statement.next().unwrap();
+    assert_eq!(statement.read::<i64, _>(0).unwrap(), 0);
+}
+
+#[test]
+fn test_failed_commits_are_rolled_back() {
+    let dir = tempdir().unwrap();
+    let connection = DatabaseConnection::open(dir.path().to_str().unwrap()).unwrap();
+    connection.migrate().unwrap();
+    // a deferred foreign key only fails on COMMIT, which leaves the transaction open
+    let failed = connection.transaction(|connection| {
+        connection.prepare("PRAGMA defer_foreign_keys = ON;")?.next()?;
+        connection
+            .prepare("INSERT INTO job_outputs (job_id, output_type, output) VALUES (42, 'print', '{}');")?
+            .next()?;
+        Ok(())
+    });
+    assert!(failed.is_err());
+    connection.transaction(|_| Ok(())).unwrap();
+    let mut statement = connection.prepare("SELECT COUNT(*) FROM job_outputs;").unwrap();
+    statement.next().unwrap();
+    assert_eq!(statement.read::<i64, _>(0).unwrap(), 0);
+}
+
+#[test]
+fn test_concurrent_writers_do_not_fail_busy() {
+    let dir = tempdir().unwrap();
+    let repositories = Repositories::sqlite(dir.path().to_str().unwrap());
+    repositories.schema.migrate().unwrap();
+    let writers: Vec<_> = (0..8)
+        .map(|writer| {
+            let jobs = repositories.jobs.clone();
+            thread::spawn(move || {
+                for n in 0..25 {
+                    let upstream = jobs.insert_at("print", &json!({"writer": writer, "n": n}), None).unwrap();
+                    jobs.insert_with_dependencies("print", &json!({}), None, &[upstream]).unwrap();
+                    jobs.mark_running(upstream).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(repositories.jobs.unfinished().unwrap().len(), 8 * 25 * 2);
+}
@@ -0,0 +1,26 @@
+-- acme-sentry.db as written by the first release, schema version 1
+CREATE TABLE acme_users(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id TEXT(36) NOT NULL,
+    key_type TEX(15) NOT NULL,
+    key_path TEXT(256) NOT NULL,
+    user_dump_path TEXT(256) NOT NULL
+);
+CREATE TABLE acme_users_directory(
+    directory_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    new_nonce TEXT(512) NOT NULL,
+    new_account TEXT(512),
+    new_order TEXT(512) NOT NULL,
+    new_authz TEXT(512) NOT NULL,
+    revoke_cert TEXT(512) NOT NULL,
+    key_change TEXT(512) NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES acme_users(id) ON DELETE RESTRICT
+);
+INSERT INTO acme_users (user_id, key_type, key_path, user_dump_path)
+    VALUES ('a1b2c3', 'ec-p256', '/data/a1b2c3/login-keys/ec-p256', '/data/a1b2c3/user.json');
+INSERT INTO acme_users (user_id, key_type, key_path, user_dump_path)
+    VALUES ('d4e5f6', '256', '/data/d4e5f6/login-keys/256', '/data/d4e5f6/user.json');
+INSERT INTO acme_users_directory (user_id, new_nonce, new_account, new_order, new_authz, revoke_cert, key_change)
+    VALUES (1, 'https://ca/new-nonce', 'https://ca/new-acct', 'https://ca/new-order', '', 'https://ca/revoke-cert', 'https://ca/key-change');
+PRAGMA user_version = 1;
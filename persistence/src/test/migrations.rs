@@ -0,0 +1,194 @@
+use crate::data_model::{AccountStatus, AcmeAccount, AcmeDirectory, AcmeUser};
+use crate::database::{DatabaseConnection, SqlStatement};
+use crate::migrations::Migration;
+use common_utils::EnumIterator;
+use tempfile::{tempdir, TempDir};
+
+const V1_FIXTURE: &str = include_str!("fixtures/v1.sql");
+const LATEST: i64 = 7;
+
+fn database(sql: &str) -> TempDir {
+    let dir = tempdir().unwrap();
+    let connection = sqlite::open(dir.path().join("acme-sentry.db")).unwrap();
+    connection.execute(sql).unwrap();
+    dir
+}
+
+fn open(dir: &TempDir) -> DatabaseConnection {
+    DatabaseConnection::open(dir.path().to_str().unwrap()).unwrap()
+}
+
+fn user(connection: &DatabaseConnection, user_id: &str) -> AcmeUser {
+    let mut statement = connection.prepare("SELECT * FROM acme_users WHERE user_id = ?1;").unwrap();
+    statement.bind((1, user_id)).unwrap();
+    AcmeUser::scan_statement(statement).unwrap()
+}
+
+fn column_type(connection: &DatabaseConnection, table: &str, column: &str) -> Option<String> {
+    let mut statement = connection
+        .prepare("SELECT type FROM pragma_table_info(?1) WHERE name = ?2;")
+        .unwrap();
+    statement.bind((1, table)).unwrap();
+    statement.bind((2, column)).unwrap();
+    match statement.next().unwrap() {
+        sqlite::State::Row => Some(statement.read::<String, _>(0).unwrap()),
+        sqlite::State::Done => None,
+    }
+}
+
+#[test]
+fn test_v1_fixture_is_upgraded() {
+    let dir = database(V1_FIXTURE);
+    let connection = open(&dir);
+    assert_eq!(connection.schema_version().unwrap(), 1);
+    assert_eq!(column_type(&connection, "acme_users", "key_type").unwrap(), "TEX(15)");
+
+    assert_eq!(connection.migrate().unwrap(), LATEST);
+    assert_eq!(connection.schema_version().unwrap(), LATEST);
+    assert_eq!(column_type(&connection, "acme_users", "key_type").unwrap(), "TEXT(15)");
+    assert_eq!(column_type(&connection, "acme_users", "account_url").unwrap(), "TEXT(512)");
+    assert_eq!(column_type(&connection, "acme_users_directory", "meta").unwrap(), "TEXT");
+    assert!(column_type(&connection, "jobs", "job_id").is_some());
+    assert!(column_type(&connection, "acme_orders", "order_url").is_some());
+
+    let user_a = user(&connection, "a1b2c3");
+    assert_eq!(user_a.id, 1);
+    assert_eq!(user_a.key_type, "ec-p256");
+    assert!(user_a.account_url.is_none());
+    // stored as an integer while the column had numeric affinity
+    assert_eq!(user(&connection, "d4e5f6").key_type, "256");
+
+    let statement = connection.prepare("SELECT * FROM acme_users_directory WHERE user_id = 1;").unwrap();
+    let directory = AcmeDirectory::scan_statement(statement).unwrap().unwrap();
+    assert_eq!(directory.new_account, "https://ca/new-acct");
+    assert!(directory.meta.is_none());
+    assert_eq!(directory.ca_name, "default");
+}
+
+#[test]
+fn test_registered_accounts_are_kept_for_the_default_ca() {
+    let dir = database(V1_FIXTURE);
+    {
+        let connection = sqlite::open(dir.path().join("acme-sentry.db")).unwrap();
+        for migration in Migration::iterator().filter(|m| (2..=5).contains(&m.version())) {
+            connection.execute(migration.get_statement()).unwrap();
+        }
+        connection
+            .execute("UPDATE acme_users SET account_url = 'https://ca/acct/1' WHERE id = 1; PRAGMA user_version = 5;")
+            .unwrap();
+    }
+    let connection = open(&dir);
+    assert_eq!(connection.migrate().unwrap(), LATEST);
+
+    let statement = connection.prepare("SELECT * FROM acme_accounts ORDER BY account_id;").unwrap();
+    let account = AcmeAccount::scan_statement(statement).unwrap().unwrap();
+    assert_eq!((account.user_id, account.ca_name.as_str()), (1, "default"));
+    assert_eq!(account.account_url.as_deref(), Some("https://ca/acct/1"));
+    assert_eq!(account.status, AccountStatus::Valid);
+    let mut statement = connection.prepare("SELECT count(*) FROM acme_accounts;").unwrap();
+    statement.next().unwrap();
+    assert_eq!(statement.read::<i64, _>(0).unwrap(), 1);
+}
+
+#[test]
+fn test_foreign_keys_survive_the_rebuild() {
+    let dir = database(V1_FIXTURE);
+    let connection = open(&dir);
+    connection.migrate().unwrap();
+    let insert = r#"
+        INSERT INTO acme_users_directory (user_id, new_nonce, new_order, new_authz, revoke_cert, key_change)
+        VALUES (?1, 'n', 'o', 'a', 'r', 'k');
+        "#;
+    let mut statement = connection.prepare(insert).unwrap();
+    statement.bind((1, 99)).unwrap();
+    assert!(statement.next().is_err());
+    let mut statement = connection.prepare("DELETE FROM acme_users WHERE id = 1;").unwrap();
+    assert!(statement.next().is_err());
+    // ids keep counting from where the old table stopped
+    let mut statement = connection
+        .prepare("INSERT INTO acme_users (user_id, key_type, key_path, user_dump_path) VALUES ('g', 'rsa', 'k', 'd') RETURNING id;")
+        .unwrap();
+    statement.next().unwrap();
+    assert_eq!(statement.read::<i64, _>(0).unwrap(), 3);
+}
+
+#[test]
+fn test_migrations_run_once() {
+    let dir = tempdir().unwrap();
+    let connection = DatabaseConnection::open(dir.path().to_str().unwrap()).unwrap();
+    assert_eq!(connection.schema_version().unwrap(), 0);
+    assert_eq!(connection.migrate().unwrap(), LATEST);
+    assert_eq!(connection.migrate().unwrap(), LATEST);
+    connection.internal_structure_check().unwrap();
+    assert_eq!(column_type(&connection, "acme_users", "key_type").unwrap(), "TEXT(15)");
+}
+
+#[test]
+fn test_concurrent_starts_migrate_once() {
+    // e.g. the daemon and a subcommand started together, each on its own connection
+    let dir = database(V1_FIXTURE);
+    let start = std::sync::Arc::new(std::sync::Barrier::new(4));
+    let migrations: Vec<_> = (0..4)
+        .map(|_| {
+            let (connection, start) = (open(&dir), start.clone());
+            std::thread::spawn(move || {
+                start.wait();
+                connection.migrate().map_err(|e| e.to_string())
+            })
+        })
+        .collect();
+    for migration in migrations {
+        assert_eq!(migration.join().unwrap(), Ok(LATEST));
+    }
+    assert_eq!(user(&open(&dir), "a1b2c3").key_type, "ec-p256");
+}
+
+#[test]
+fn test_unversioned_database_is_upgraded() {
+    // written before versioning, the tables of the first two migrations already exist
+    let dir = database(V1_FIXTURE.replace("PRAGMA user_version = 1;", "").as_str());
+    let connection = open(&dir);
+    assert_eq!(connection.schema_version().unwrap(), 0);
+    assert_eq!(connection.migrate().unwrap(), LATEST);
+    assert_eq!(user(&connection, "a1b2c3").key_type, "ec-p256");
+}
+
+#[test]
+fn test_newer_database_is_refused() {
+    let dir = database("CREATE TABLE acme_users(id INTEGER PRIMARY KEY); PRAGMA user_version = 99;");
+    let connection = open(&dir);
+    let error = connection.migrate().unwrap_err();
+    assert!(error.to_string().contains("newer"));
+    assert_eq!(connection.schema_version().unwrap(), 99);
+    assert_eq!(column_type(&connection, "acme_users", "key_type"), None);
+}
+
+#[test]
+fn test_failed_migration_rolls_back() {
+    // the rebuild of acme_users in version 3 can't create its temporary table
+    let dir = database(format!("{}\nCREATE TABLE acme_users_v3(id INTEGER);", V1_FIXTURE).as_str());
+    let connection = open(&dir);
+    assert!(connection.migrate().is_err());
+    assert_eq!(connection.schema_version().unwrap(), 1);
+    assert!(column_type(&connection, "acme_certificates", "certificate_id").is_none());
+    assert_eq!(column_type(&connection, "acme_users", "key_type").unwrap(), "TEX(15)");
+    // foreign keys are back on
+    let mut statement = connection.prepare("DELETE FROM acme_users WHERE id = 1;").unwrap();
+    assert!(statement.next().is_err());
+}
+
+#[test]
+fn test_audit_events_are_append_only() {
+    let dir = tempdir().unwrap();
+    let connection = open(&dir);
+    connection.migrate().unwrap();
+    connection
+        .prepare("INSERT INTO audit_events (occurred_at, kind, details, previous_hash, hash) VALUES (1, 'config-changed', '{}', 'p', 'h');")
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut statement = connection.prepare("UPDATE audit_events SET details = '{\"a\":1}';").unwrap();
+    assert!(statement.next().unwrap_err().to_string().contains("append-only"));
+    let mut statement = connection.prepare("DELETE FROM audit_events;").unwrap();
+    assert!(statement.next().is_err());
+}
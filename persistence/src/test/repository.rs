@@ -0,0 +1,275 @@
+use crate::database::DatabaseConnection;
+use crate::data_model::{AccountStatus, AuditEvent, AuditKind, JobStatus};
+use crate::repository::{AuditFilter, NewAuditEvent, NewCertificate, Repositories};
+use acme_client::comms::directory::AcmeDirectoryApi;
+use serde_json::json;
+use tempfile::{tempdir, TempDir};
+
+fn sqlite() -> (Repositories, TempDir) {
+    let dir = tempdir().unwrap();
+    let repositories = Repositories::sqlite(dir.path().to_str().unwrap());
+    repositories.schema.migrate().unwrap();
+    (repositories, dir)
+}
+
+fn directory(new_nonce: &str) -> AcmeDirectoryApi {
+    AcmeDirectoryApi {
+        directory_id: 0,
+        user_id: String::new(),
+        key_change: "https://ca/key-change".to_string(),
+        new_authz: None,
+        new_nonce: new_nonce.to_string(),
+        new_account: "https://ca/new-acct".to_string(),
+        new_order: "https://ca/new-order".to_string(),
+        revoke_cert: "https://ca/revoke-cert".to_string(),
+    }
+}
+
+fn users_and_accounts(repositories: &Repositories) {
+    assert!(repositories.users.find("a1b2c3").unwrap().is_none());
+    let created = repositories.users.create("a1b2c3", "ec-p256", "/keys", "/dump").unwrap();
+    let found = repositories.users.find("a1b2c3").unwrap().unwrap();
+    assert_eq!(found.id, created.id);
+    assert_eq!(found.key_type, "ec-p256");
+    repositories.users.create("d4e5f6", "rsa-2048", "/keys", "/dump").unwrap();
+    let listed: Vec<_> = repositories.users.list().unwrap().into_iter().map(|u| u.user_id).collect();
+    assert_eq!(listed, vec!["a1b2c3", "d4e5f6"]);
+
+    let accounts = &repositories.accounts;
+    assert!(accounts.find(created.id, "production").unwrap().is_none());
+    let production = accounts.ensure(created.id, "production", "https://ca/dir", "/keys").unwrap();
+    assert_eq!(production.status, AccountStatus::Pending);
+    assert!(production.account_url.is_none());
+    let staging = accounts.ensure(created.id, "staging", "https://staging.ca/dir", "/keys").unwrap();
+    assert_ne!(production.account_id, staging.account_id);
+    assert!(accounts.ensure(99, "production", "https://ca/dir", "/keys").is_err());
+
+    assert!(!accounts.registered(&found).unwrap());
+    let registered = accounts.register(production.account_id, "https://ca/acct/1").unwrap();
+    assert!(accounts.registered(&found).unwrap());
+    assert_eq!(registered.status, AccountStatus::Valid);
+    // a moved directory keeps the registration
+    let moved = accounts.ensure(created.id, "production", "https://ca/v2/dir", "/other").unwrap();
+    assert_eq!(moved.account_id, production.account_id);
+    assert_eq!(moved.directory_url, "https://ca/v2/dir");
+    assert_eq!(moved.key_path, "/keys");
+    assert_eq!(moved.account_url.as_deref(), Some("https://ca/acct/1"));
+
+    accounts.set_status(staging.account_id, AccountStatus::Deactivated).unwrap();
+    let statuses: Vec<_> = accounts.for_user(created.id).unwrap().iter().map(|a| (a.ca_name.clone(), a.status)).collect();
+    assert_eq!(
+        statuses,
+        vec![("production".to_string(), AccountStatus::Valid), ("staging".to_string(), AccountStatus::Deactivated)]
+    );
+    assert!(accounts.register(99, "https://ca/acct/2").is_err());
+}
+
+fn directories(repositories: &Repositories) {
+    let user = repositories.users.create("a1b2c3", "ec-p256", "/keys", "/dump").unwrap();
+    let directories = &repositories.directories;
+    assert!(directories.find(user.id, "production").unwrap().is_none());
+    let first = directories.save(user.id, "production", &directory("https://ca/nonce")).unwrap();
+    let second = directories.save(user.id, "production", &directory("https://ca/new-nonce")).unwrap();
+    assert_eq!(first.directory_id, second.directory_id);
+    let staging = directories.save(user.id, "staging", &directory("https://staging.ca/nonce")).unwrap();
+    assert_ne!(staging.directory_id, first.directory_id);
+    let stored = directories.find(user.id, "production").unwrap().unwrap();
+    assert_eq!(stored.new_nonce, "https://ca/new-nonce");
+    assert_eq!(stored.new_authz.as_deref(), Some(""));
+    assert_eq!(directories.find(user.id, "staging").unwrap().unwrap().new_nonce, "https://staging.ca/nonce");
+    assert!(directories.save(user.id + 1, "production", &directory("https://ca/nonce")).is_err());
+
+    assert!(directories.orphaned().unwrap().is_empty());
+    assert!(directories.delete(staging.directory_id).unwrap());
+    assert!(!directories.delete(staging.directory_id).unwrap());
+    assert!(directories.find(user.id, "staging").unwrap().is_none());
+}
+
+fn orders_and_certificates(repositories: &Repositories) {
+    let user = repositories.users.create("a1b2c3", "ec-p256", "/keys", "/dump").unwrap();
+    let identifiers = vec!["example.org".to_string(), "www.example.org".to_string()];
+    repositories
+        .orders
+        .create(user.id, "https://ca/order/1", "pending", &identifiers, "https://ca/finalize/1")
+        .unwrap();
+    let valid = repositories
+        .orders
+        .update_status("https://ca/order/1", "valid", Some("https://ca/cert/1"))
+        .unwrap();
+    assert_eq!(valid.identifiers, identifiers);
+    let found = repositories.orders.find("https://ca/order/1").unwrap().unwrap();
+    assert_eq!(found.status, "valid");
+    assert_eq!(found.certificate_url.as_deref(), Some("https://ca/cert/1"));
+    assert_eq!(repositories.orders.for_user(user.id).unwrap().len(), 1);
+    assert!(repositories.orders.update_status("https://ca/order/2", "valid", None).is_err());
+
+    repositories
+        .orders
+        .create(user.id, "https://ca/order/2", "pending", &identifiers, "https://ca/finalize/2")
+        .unwrap();
+    let ready = repositories.orders.transition("https://ca/order/2", "pending", "ready", None).unwrap();
+    assert_eq!(ready.status, "ready");
+    assert!(repositories.orders.transition("https://ca/order/2", "pending", "ready", None).is_err());
+    assert_eq!(repositories.orders.find("https://ca/order/2").unwrap().unwrap().status, "ready");
+
+    for serial in ["01", "02"] {
+        repositories
+            .certificates
+            .record(NewCertificate {
+                user_id: user.id,
+                ca_name: "production",
+                name: "example",
+                serial,
+                not_before: 1,
+                not_after: 2,
+                certificate_url: "https://ca/cert/1",
+                chain_url: "https://ca/cert/1/1",
+                chain_issuer: None,
+            })
+            .unwrap();
+    }
+    let latest = repositories.certificates.latest("example").unwrap().unwrap();
+    assert_eq!((latest.serial.as_str(), latest.ca_name.as_str()), ("02", "production"));
+    assert!(repositories.certificates.latest("other").unwrap().is_none());
+    assert_eq!(repositories.certificates.for_user(user.id).unwrap().len(), 2);
+}
+
+fn jobs(repositories: &Repositories) {
+    let jobs = &repositories.jobs;
+    let first = jobs.insert_at("print", &json!({}), None).unwrap();
+    let second = jobs.insert_with_dependencies("print", &json!({"n": 2}), Some(42), &[first]).unwrap();
+    assert!(jobs.insert_with_dependencies("print", &json!({}), None, &[99]).is_err());
+    assert_eq!(jobs.dependencies(second).unwrap(), vec![first]);
+
+    jobs.set_output(first, "greeting", &json!("hello")).unwrap();
+    assert_eq!(jobs.output(first).unwrap().unwrap(), ("greeting".to_string(), json!("hello")));
+    assert!(jobs.output(second).unwrap().is_none());
+
+    jobs.mark_running(first).unwrap();
+    jobs.mark_succeeded(first).unwrap();
+    jobs.mark_running(second).unwrap();
+    jobs.mark_retrying(second, "boom", 100).unwrap();
+    let retrying = jobs.get(second).unwrap().unwrap();
+    assert_eq!(retrying.status, JobStatus::Retrying);
+    assert_eq!(retrying.run_at, Some(100));
+    assert_eq!(jobs.unfinished().unwrap().iter().map(|r| r.job_id).collect::<Vec<_>>(), vec![second]);
+    assert_eq!(jobs.list(Some(JobStatus::Succeeded)).unwrap().iter().map(|r| r.job_id).collect::<Vec<_>>(), vec![first]);
+    assert_eq!(jobs.list(None).unwrap().len(), 2);
+
+    assert!(jobs.replay(second).is_err());
+    jobs.mark_failed(second, "boom").unwrap();
+    assert_eq!(jobs.dead_letters().unwrap().len(), 1);
+    let replayed = jobs.replay(second).unwrap();
+    assert_eq!(replayed.status, JobStatus::Queued);
+    assert_eq!(replayed.attempts, 0);
+    assert!(replayed.last_error.is_none());
+    assert!(jobs.get(99).unwrap().is_none());
+
+    // only unfinished jobs can be cancelled, a finished one keeps its status
+    assert!(!jobs.mark_cancelled(first).unwrap());
+    assert_eq!(jobs.get(first).unwrap().unwrap().status, JobStatus::Succeeded);
+    assert!(jobs.mark_cancelled(second).unwrap());
+    assert_eq!(jobs.get(second).unwrap().unwrap().status, JobStatus::Cancelled);
+    assert!(!jobs.mark_cancelled(second).unwrap());
+    assert!(!jobs.mark_cancelled(99).unwrap());
+}
+
+fn recurring_jobs(repositories: &Repositories) {
+    let jobs = &repositories.jobs;
+    let record = jobs.upsert_recurring("renew", "print", &json!({}), "1h", "skip", 10).unwrap();
+    jobs.reschedule_recurring(record.recurring_id, Some(10), 20).unwrap();
+    jobs.upsert_recurring("renew", "print", &json!({}), "1h", "catch-up", 30).unwrap();
+    let kept = jobs.recurring().unwrap().remove(0);
+    assert_eq!((kept.next_run_at, kept.last_run_at), (20, Some(10)));
+    assert_eq!(kept.missed_runs, "catch-up");
+    jobs.upsert_recurring("renew", "print", &json!({}), "2h", "skip", 30).unwrap();
+    assert_eq!(jobs.recurring().unwrap().remove(0).next_run_at, 30);
+}
+
+fn audit_log(repositories: &Repositories) {
+    let audit = &repositories.audit;
+    let events = [
+        (AuditKind::UserCreated, Some("a1b2c3"), None),
+        (AuditKind::AccountCreated, Some("a1b2c3"), Some("production")),
+        (AuditKind::ConfigChanged, None, None),
+        (AuditKind::AccountCreated, Some("d4e5f6"), Some("staging")),
+    ];
+    for (kind, user_id, subject) in events {
+        audit
+            .append(NewAuditEvent {
+                kind,
+                user_id,
+                subject,
+                details: json!({"ca": subject}),
+            })
+            .unwrap();
+    }
+    let all = audit.list(&AuditFilter::default()).unwrap();
+    assert_eq!(all.len(), 4);
+    assert_eq!(all[0].previous_hash, AuditEvent::GENESIS_HASH);
+    assert_eq!(all[1].previous_hash, all[0].hash);
+    assert_eq!(AuditEvent::verify_chain(&all).unwrap(), 4);
+
+    let accounts = AuditFilter {
+        kind: Some(AuditKind::AccountCreated),
+        ..AuditFilter::default()
+    };
+    assert_eq!(audit.list(&accounts).unwrap().len(), 2);
+    let user = AuditFilter {
+        user_id: Some("a1b2c3".to_string()),
+        ..AuditFilter::default()
+    };
+    assert_eq!(audit.list(&user).unwrap().len(), 2);
+    let future = AuditFilter {
+        since: Some(all[3].occurred_at + 1),
+        ..AuditFilter::default()
+    };
+    assert!(audit.list(&future).unwrap().is_empty());
+
+    let mut altered = all.clone();
+    altered[1].details = json!({"ca": "elsewhere"}).to_string();
+    assert_eq!(AuditEvent::verify_chain(&altered).unwrap_err().to_string(), "Audit event 2 has been altered");
+    let mut dropped = all.clone();
+    dropped.remove(2);
+    assert!(AuditEvent::verify_chain(&dropped).is_err());
+}
+
+#[test]
+fn test_directories_of_deleted_users_are_orphaned() {
+    let (repositories, dir) = sqlite();
+    let user = repositories.users.create("a1b2c3", "ec-p256", "/keys", "/dump").unwrap();
+    let kept = repositories.users.create("d4e5f6", "ec-p256", "/keys", "/dump").unwrap();
+    let orphan = repositories.directories.save(user.id, "production", &directory("https://ca/nonce")).unwrap();
+    repositories.directories.save(kept.id, "production", &directory("https://ca/nonce")).unwrap();
+    // databases written before foreign keys were enforced could lose users under their rows
+    let connection = DatabaseConnection::open(dir.path().to_str().unwrap()).unwrap();
+    connection.prepare("PRAGMA foreign_keys = OFF;").unwrap().next().unwrap();
+    let mut statement = connection.prepare("DELETE FROM acme_users WHERE id = ?1;").unwrap();
+    statement.bind((1, user.id)).unwrap();
+    statement.next().unwrap();
+
+    let orphaned = repositories.directories.orphaned().unwrap();
+    assert_eq!(orphaned.iter().map(|d| d.directory_id).collect::<Vec<_>>(), vec![orphan.directory_id]);
+    assert!(repositories.directories.delete(orphan.directory_id).unwrap());
+    assert!(repositories.directories.orphaned().unwrap().is_empty());
+}
+
+macro_rules! on_both_backends {
+    ($($check:ident),*) => {
+        $(
+            mod $check {
+                #[test]
+                fn sqlite() {
+                    let (repositories, _dir) = super::sqlite();
+                    super::$check(&repositories);
+                }
+                #[test]
+                fn in_memory() {
+                    super::$check(&crate::repository::Repositories::in_memory());
+                }
+            }
+        )*
+    };
+}
+
+on_both_backends!(users_and_accounts, directories, orders_and_certificates, jobs, recurring_jobs, audit_log);
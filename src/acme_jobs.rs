@@ -1,3 +1,32 @@
+pub mod account_management;
+pub mod certificate_download;
+pub mod certificate_order;
+pub mod certificate_reconciliation;
+pub mod certificate_revocation;
 pub mod directory_query;
 pub mod db_initialization;
-pub mod initialize_keys_for_user;
\ No newline at end of file
+pub mod initialize_keys_for_user;
+
+use crate::job_execution::job_registry::JobRegistry;
+use account_management::AccountJob;
+use certificate_download::CertificateDownloadJob;
+use certificate_order::CertificateOrderJob;
+use certificate_reconciliation::CertificateReconciliationJob;
+use certificate_revocation::CertificateRevocationJob;
+use db_initialization::DbInitializationJob;
+use directory_query::DirectoryUpdateJob;
+use initialize_keys_for_user::InitializeLocalUserJob;
+
+/// Registry of every job that can be rebuilt from the `jobs` table after a restart.
+pub fn job_registry() -> JobRegistry {
+    let mut registry = JobRegistry::new();
+    registry.register_deserializable::<DbInitializationJob>(DbInitializationJob::JOB_TYPE);
+    registry.register_deserializable::<InitializeLocalUserJob>(InitializeLocalUserJob::JOB_TYPE);
+    registry.register_deserializable::<DirectoryUpdateJob>(DirectoryUpdateJob::JOB_TYPE);
+    registry.register_deserializable::<AccountJob>(AccountJob::JOB_TYPE);
+    registry.register_deserializable::<CertificateRevocationJob>(CertificateRevocationJob::JOB_TYPE);
+    registry.register_deserializable::<CertificateReconciliationJob>(CertificateReconciliationJob::JOB_TYPE);
+    registry.register_deserializable::<CertificateOrderJob>(CertificateOrderJob::JOB_TYPE);
+    registry.register_deserializable::<CertificateDownloadJob>(CertificateDownloadJob::JOB_TYPE);
+    registry
+}
@@ -0,0 +1,309 @@
+use crate::job_execution::job_base::{Job, JobContext, JobOutput, Priority};
+use crate::job_execution::retry::Permanent;
+use acme_client::comms::account::{
+    AccountObject, change_key, contacts, deactivate_account, fetch_account, register_account, update_account,
+};
+use acme_client::comms::session::AcmeSession;
+use acme_client::crypto::SupportedKey;
+use acme_client::keys::PrivateKey;
+use anyhow::{Context, anyhow};
+use async_trait::async_trait;
+use common_utils::APPLICATION_CONFIG;
+use common_utils::fs::{FileOptions, FileSystem};
+use persistence::data_model::{AccountStatus, AcmeAccount, AcmeDirectory, AcmeUser, AuditKind};
+use persistence::repository::{NewAuditEvent, Repositories};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, instrument};
+
+/// The account of a user with one CA, together with what it takes to sign requests for it.
+pub struct LoadedAccount {
+    pub user: AcmeUser,
+    pub account: AcmeAccount,
+    pub directory: AcmeDirectory,
+    pub key: PrivateKey,
+}
+
+impl LoadedAccount {
+    /// Needs the directory of the CA, i.e. a directory refresh has to have run before.
+    pub fn load(repositories: &Repositories, user_id: &str, ca: &str) -> anyhow::Result<LoadedAccount> {
+        let config = APPLICATION_CONFIG.get().unwrap();
+        let user = repositories
+            .users
+            .find(user_id)
+            .map_err(|e| anyhow!(e.to_string()))?
+            .ok_or_else(|| anyhow!("User {} could not be found", user_id))?;
+        let account = repositories
+            .accounts
+            .find(user.id, ca)
+            .map_err(|e| anyhow!(e.to_string()))?
+            .ok_or_else(|| anyhow!("User {} has no account with CA {}", user_id, ca))?;
+        let directory = repositories
+            .directories
+            .find(user.id, ca)
+            .map_err(|e| anyhow!(e.to_string()))?
+            .ok_or_else(|| anyhow!("No acme directory of CA {} stored for user {}", ca, user_id))?;
+        let system = FileSystem::new(config.output_dir.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+        let pem = system
+            .read_from_file(account.key_path.as_str(), format!("{}.pem", user.user_id).as_str())
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let key_type = SupportedKey::from_str(user.key_type.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+        let key = PrivateKey::load_private_bytes(&pem, key_type).map_err(|e| anyhow!(e.to_string()))?;
+        Ok(LoadedAccount {
+            user,
+            account,
+            directory,
+            key,
+        })
+    }
+
+    /// Session signed by the registered account, refused for accounts that aren't.
+    pub fn session(self) -> anyhow::Result<AcmeSession> {
+        let account_url = self
+            .account
+            .account_url
+            .ok_or_else(|| anyhow!("Account of user {} with CA {} isn't registered yet", self.user.user_id, self.account.ca_name))
+            .context(Permanent)?;
+        let config = APPLICATION_CONFIG.get().unwrap();
+        AcmeSession::new(self.key, account_url, self.directory.new_nonce, config.accept_invalid_certs).map_err(|e| anyhow!(e))
+    }
+
+    fn key_file(&self) -> std::path::PathBuf {
+        Path::new(self.account.key_path.as_str()).join(format!("{}.pem", self.user.user_id))
+    }
+}
+
+/// What an [`AccountJob`] does with the account.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum AccountAction {
+    /// Registers the account key with `email` as contact, agreeing to the terms of service.
+    Register { email: String },
+    /// Replaces the contact of the account.
+    Update { email: String },
+    /// Deactivates the account, there is no way back.
+    Deactivate,
+    /// Moves the account over to a new key of the same type.
+    Rollover,
+}
+
+/// The account as the CA returned it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountOutput {
+    pub account_id: i64,
+    pub ca: String,
+    pub account_url: Option<String>,
+    pub status: String,
+    pub contact: Vec<String>,
+}
+impl JobOutput for AccountOutput {
+    const OUTPUT_TYPE: &'static str = "acme-account";
+}
+
+/// Registers or manages the account of a user with one CA, the directory of the CA has to
+/// be refreshed before.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AccountJob {
+    user_id: String,
+    ca: String,
+    action: AccountAction,
+}
+impl AccountJob {
+    pub const JOB_TYPE: &str = "account-job";
+    pub fn new(user_id: String, ca: String, action: AccountAction) -> Self {
+        AccountJob { user_id, ca, action }
+    }
+
+    async fn run(&self, context: &JobContext, loaded: LoadedAccount) -> anyhow::Result<AccountOutput> {
+        let account_id = loaded.account.account_id;
+        let mut account_url = loaded.account.account_url.clone();
+        let (kind, account) = match &self.action {
+            AccountAction::Register { email } => {
+                let new_account = loaded.directory.new_account.clone();
+                let accept_invalid_certs = APPLICATION_CONFIG.get().unwrap().accept_invalid_certs;
+                let session =
+                    AcmeSession::unregistered(loaded.key, loaded.directory.new_nonce, accept_invalid_certs).map_err(|e| anyhow!(e))?;
+                let permit = context.handle.ca_permit().await;
+                let (registered_url, account) = register_account(&session, new_account.as_str(), &contacts(std::slice::from_ref(email)))
+                    .await
+                    .map_err(|e| anyhow!(e))?;
+                drop(permit);
+                info!("Account of user {} with CA {} registered as {}", self.user_id, self.ca, registered_url);
+                let url = registered_url.clone();
+                self.store(context, move |repositories| repositories.accounts.register(account_id, url.as_str()).map(|_| ()))
+                    .await?;
+                account_url = Some(registered_url);
+                (AuditKind::AccountRegistered, account)
+            }
+            AccountAction::Update { email } => {
+                let session = loaded.session()?;
+                let _permit = context.handle.ca_permit().await;
+                let account = update_account(&session, &contacts(std::slice::from_ref(email))).await.map_err(|e| anyhow!(e))?;
+                (AuditKind::AccountUpdated, account)
+            }
+            AccountAction::Deactivate => {
+                let session = loaded.session()?;
+                let permit = context.handle.ca_permit().await;
+                let account = deactivate_account(&session).await.map_err(|e| anyhow!(e))?;
+                drop(permit);
+                self.store(context, move |repositories| {
+                    repositories.accounts.set_status(account_id, AccountStatus::Deactivated).map(|_| ())
+                })
+                .await?;
+                (AuditKind::AccountDeactivated, account)
+            }
+            AccountAction::Rollover => (AuditKind::KeyRollover, self.rollover(context, loaded).await?),
+        };
+        let (job, details) = (self.clone(), json!({"account_id": account_id, "status": account.status, "contact": account.contact}));
+        self.store(context, move |repositories| {
+            repositories
+                .audit
+                .append(NewAuditEvent {
+                    kind,
+                    user_id: Some(job.user_id.as_str()),
+                    subject: Some(job.ca.as_str()),
+                    details,
+                })
+                .map(|_| ())
+        })
+        .await?;
+        Ok(AccountOutput {
+            account_id,
+            ca: self.ca.clone(),
+            account_url,
+            status: account.status,
+            contact: account.contact,
+        })
+    }
+
+    /// The new key is written next to the old one first, it only replaces it once the CA
+    /// took it. A key shared with the registered accounts of other CAs is left alone, they
+    /// would be locked out.
+    async fn rollover(&self, context: &JobContext, loaded: LoadedAccount) -> anyhow::Result<AccountObject> {
+        let key_file = loaded.key_file();
+        let (user_id, account_id, key_path) = (loaded.user.id, loaded.account.account_id, loaded.account.key_path.clone());
+        let sharing: Vec<String> = context
+            .with_repositories(move |repositories| {
+                Ok(repositories
+                    .accounts
+                    .for_user(user_id)
+                    .map_err(|e| anyhow!(e.to_string()))?
+                    .into_iter()
+                    .filter(|a| a.account_id != account_id && a.key_path == key_path && a.account_url.is_some())
+                    .map(|a| a.ca_name)
+                    .collect())
+            })
+            .await?;
+        if !sharing.is_empty() {
+            return Err(anyhow!("Key of CA {} is shared with CA {}, refusing to roll it over", self.ca, sharing.join(", ")))
+                .context(Permanent);
+        }
+        let config = APPLICATION_CONFIG.get().unwrap();
+        let system = FileSystem::new(config.output_dir.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+        let new_key = PrivateKey::from_supported_type(loaded.key.kt.clone()).map_err(|e| anyhow!(e.to_string()))?;
+        let staged = key_file.with_extension("pem.new");
+        system
+            .write_to_file_with(
+                loaded.account.key_path.as_str(),
+                format!("{}.pem.new", loaded.user.user_id).as_str(),
+                new_key.get_pem_bytes().map_err(|e| anyhow!(e.to_string()))?.as_slice(),
+                &FileOptions::private(),
+            )
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let (key_change, new_nonce) = (loaded.directory.key_change.clone(), loaded.directory.new_nonce.clone());
+        let session = loaded.session()?;
+        let account_url = session.account_url().unwrap_or_default().to_string();
+        let _permit = context.handle.ca_permit().await;
+        if let Err(e) = change_key(&session, key_change.as_str(), &new_key).await {
+            let _ = fs::remove_file(&staged);
+            return Err(anyhow!(e));
+        }
+        fs::rename(&staged, &key_file)
+            .with_context(|| format!("The CA took the new key, move {} to {}", staged.display(), key_file.display()))
+            .context(Permanent)?;
+        info!("Account of user {} with CA {} rolled over to a new key", self.user_id, self.ca);
+        let session = AcmeSession::new(new_key, account_url, new_nonce, config.accept_invalid_certs).map_err(|e| anyhow!(e))?;
+        fetch_account(&session).await.map_err(|e| anyhow!(e))
+    }
+
+    async fn store<F>(&self, context: &JobContext, work: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&Repositories) -> Result<(), Box<dyn std::error::Error>> + Send + 'static,
+    {
+        context
+            .with_repositories(move |repositories| work(repositories).map_err(|e| anyhow!(e.to_string())))
+            .await
+    }
+}
+#[async_trait]
+impl Job for AccountJob {
+    fn job_type(&self) -> &'static str {
+        Self::JOB_TYPE
+    }
+    fn payload(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+    fn concurrency_key(&self) -> Option<String> {
+        Some(format!("{}:{}", self.user_id, self.ca))
+    }
+    fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+    /// A rollover interrupted between the CA accepting the new key and the key being written
+    /// locally locks the account out, so it goes ahead of everything queued before a shutdown.
+    fn priority(&self) -> Priority {
+        match self.action {
+            AccountAction::Rollover | AccountAction::Deactivate => Priority::Critical,
+            _ => Priority::High,
+        }
+    }
+    #[instrument(level = "trace", name = "account_job", fields(job_name = %self.job_type()), skip_all)]
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+        let job = self.clone();
+        let loaded = context
+            .with_repositories(move |repositories| LoadedAccount::load(repositories, job.user_id.as_str(), job.ca.as_str()))
+            .await?;
+        let output = self.run(&context, loaded).await?;
+        info!("Account {} of user {} with CA {} is {}", output.account_id, self.user_id, self.ca, output.status);
+        context.set_output(&output).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccountAction, AccountJob};
+    use crate::job_execution::job_base::{Job, Priority};
+    use serde_json::json;
+
+    #[test]
+    fn test_actions_are_persisted_with_their_arguments() {
+        let job = AccountJob::new(
+            "a1b2c3".to_string(),
+            "staging".to_string(),
+            AccountAction::Update {
+                email: "admin@example.org".to_string(),
+            },
+        );
+        let payload = serde_json::to_value(&job).unwrap();
+        assert_eq!(
+            payload,
+            json!({"user_id": "a1b2c3", "ca": "staging", "action": {"action": "update", "email": "admin@example.org"}})
+        );
+        let rollover: AccountJob =
+            serde_json::from_value(json!({"user_id": "a1b2c3", "ca": "default", "action": {"action": "rollover"}})).unwrap();
+        assert_eq!(rollover.action, AccountAction::Rollover);
+    }
+    #[test]
+    fn test_rollovers_go_ahead_of_other_account_changes() {
+        let job = |action| AccountJob::new("a1b2c3".to_string(), "default".to_string(), action);
+        assert_eq!(job(AccountAction::Rollover).priority(), Priority::Critical);
+        assert_eq!(job(AccountAction::Deactivate).priority(), Priority::Critical);
+        let update = job(AccountAction::Update {
+            email: "admin@example.org".to_string(),
+        });
+        assert_eq!(update.priority(), Priority::High);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::acme_jobs::account_management::LoadedAccount;
+use crate::acme_jobs::certificate_order::OrderOutput;
+use crate::certificate_output::CertificateOutput;
+use crate::job_execution::job_base::{DependencyFailure, Job, JobContext, JobOutput, Priority};
+use crate::job_execution::retry::{Permanent, RetryPolicy};
+use acme_client::comms::certificate::{DownloadedChain, PreferredChain, download_certificate};
+use acme_client::keys::PrivateKey;
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use common_utils::APPLICATION_CONFIG;
+use persistence::data_model::{AcmeCertificate, AcmeUser, AuditKind};
+use persistence::repository::{NewAuditEvent, NewCertificate};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+
+/// The certificate that went live.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadOutput {
+    pub name: String,
+    pub serial: String,
+    pub not_after: i64,
+    /// Archive version of the files under `live/<name>`.
+    pub version: u32,
+    pub chain_issuer: Option<String>,
+}
+impl JobOutput for DownloadOutput {
+    const OUTPUT_TYPE: &'static str = "certificate-download";
+}
+
+/// Downloads the certificate of the [`CertificateOrderJob`](super::certificate_order::CertificateOrderJob)
+/// it depends on, picking the chain matching `preferred_chain` from the default and the
+/// `rel="alternate"` chains, and records the choice in `acme_certificates`.
+///
+/// `ca` names the CA the certificate was issued by, the download is signed with the
+/// account key of that CA. The certificate key the order left behind is moved into the
+/// output layout.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CertificateDownloadJob {
+    user_id: String,
+    ca: String,
+    name: String,
+}
+impl CertificateDownloadJob {
+    pub const JOB_TYPE: &str = "certificate-download-job";
+    pub fn new(user_id: String, ca: String, name: String) -> Self {
+        CertificateDownloadJob { user_id, ca, name }
+    }
+    fn write_output(&self, downloaded: &DownloadedChain, key_path: &str) -> anyhow::Result<u32> {
+        let config = APPLICATION_CONFIG.get().unwrap();
+        let pem = std::fs::read(key_path)?;
+        let key_type = downloaded
+            .chain
+            .leaf()
+            .key_type()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .ok_or_else(|| anyhow!("Certificate {} has an unsupported key type", self.name))?;
+        let key = PrivateKey::load_private_bytes(&pem, key_type).map_err(|e| anyhow!(e.to_string()))?;
+        if !downloaded.chain.leaf().matches_key(&key).map_err(|e| anyhow!(e.to_string()))? {
+            return Err(anyhow!("Certificate {} does not match the key at {}", self.name, key_path)).context(Permanent);
+        }
+        let formats = config
+            .certificates
+            .iter()
+            .find(|definition| definition.name == self.name)
+            .and_then(|definition| definition.output_formats.as_ref())
+            .unwrap_or(&config.output_formats);
+        let output = CertificateOutput::new(config.output_dir.as_str(), formats, config.pkcs12_password.clone())
+            .and_then(|o| o.with_owner(config.file_owner.as_deref(), config.file_group.as_deref()))
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let version = output
+            .write(self.name.as_str(), &downloaded.chain, &key)
+            .map_err(|e| anyhow!(e.to_string()))?;
+        info!("Live files for {} available in: {}", self.name, output.live_dir(self.name.as_str()).map_err(|e| anyhow!(e.to_string()))?.display());
+        Ok(version)
+    }
+    async fn record_certificate(
+        &self,
+        context: &JobContext,
+        user: &AcmeUser,
+        certificate_url: String,
+        downloaded: &DownloadedChain,
+    ) -> anyhow::Result<AcmeCertificate> {
+        let leaf = downloaded.chain.leaf();
+        let serial = leaf.serial().map_err(|e| anyhow!(e.to_string()))?;
+        let not_before = leaf.not_before().map_err(|e| anyhow!(e.to_string()))?;
+        let not_after = leaf.not_after().map_err(|e| anyhow!(e.to_string()))?;
+        let (job, user_id, chain_url, issuer) = (self.clone(), user.id, downloaded.url.clone(), downloaded.issuer());
+        context
+            .with_repositories(move |repositories| {
+                let certificate = repositories
+                    .certificates
+                    .record(NewCertificate {
+                        user_id,
+                        ca_name: job.ca.as_str(),
+                        name: job.name.as_str(),
+                        serial: serial.as_str(),
+                        not_before,
+                        not_after,
+                        certificate_url: certificate_url.as_str(),
+                        chain_url: chain_url.as_str(),
+                        chain_issuer: issuer.as_deref(),
+                    })
+                    .map_err(|e| anyhow!(e.to_string()))?;
+                repositories
+                    .audit
+                    .append(NewAuditEvent {
+                        kind: AuditKind::CertificateIssued,
+                        user_id: Some(job.user_id.as_str()),
+                        subject: Some(certificate_url.as_str()),
+                        details: json!({
+                            "ca": job.ca,
+                            "name": certificate.name,
+                            "serial": certificate.serial,
+                            "not_after": certificate.not_after,
+                            "chain_url": certificate.chain_url,
+                        }),
+                    })
+                    .map_err(|e| anyhow!(e.to_string()))?;
+                Ok(certificate)
+            })
+            .await
+    }
+}
+#[async_trait]
+impl Job for CertificateDownloadJob {
+    fn job_type(&self) -> &'static str {
+        Self::JOB_TYPE
+    }
+    fn payload(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::exponential(3, Duration::from_secs(30)).with_max_delay(Duration::from_secs(5 * 60))
+    }
+    fn concurrency_key(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+    fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(120))
+    }
+    /// Without its order there is nothing to download, the pair ends up in the dead-letter
+    /// queue together.
+    fn on_dependency_failure(&self) -> DependencyFailure {
+        DependencyFailure::Fail
+    }
+    /// Goes ahead of orders of the same certificate queued meanwhile, those find it current.
+    fn priority(&self) -> Priority {
+        Priority::High
+    }
+    #[instrument(level = "trace", name = "certificate_download_job", fields(job_name = %self.job_type()), skip_all)]
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+        let config = APPLICATION_CONFIG.get().unwrap();
+        let preferred = config.preferred_chain.as_deref().map(PreferredChain::parse);
+        let order = context.upstream::<OrderOutput>().await.context(Permanent)?;
+        let (user_id, ca) = (self.user_id.clone(), self.ca.clone());
+        let loaded = context
+            .with_repositories(move |repositories| LoadedAccount::load(repositories, user_id.as_str(), ca.as_str()))
+            .await?;
+        let user = loaded.user.clone();
+        let session = loaded.session()?;
+        info!("Downloading certificate {} from {}", self.name, order.certificate_url);
+        let permit = context.handle.ca_permit().await;
+        let downloaded = download_certificate(&session, order.certificate_url.as_str(), preferred.as_ref())
+            .await
+            .map_err(|e| anyhow!(e))?;
+        drop(permit);
+        if let Some(preferred) = preferred.as_ref().filter(|preferred| !preferred.matches(&downloaded.chain)) {
+            let hint = match preferred {
+                PreferredChain::RootFingerprint(_) => ", a fingerprint only matches certificates the CA sends in the chain",
+                PreferredChain::IssuerCommonName(_) => "",
+            };
+            warn!(
+                "No chain of {} matched the preferred chain {}, keeping the default chain{}",
+                self.name,
+                config.preferred_chain.as_deref().unwrap_or_default(),
+                hint
+            );
+        }
+        let version = self.write_output(&downloaded, order.key_path.as_str())?;
+        info!("Certificate {} written to the output directory as version {}", self.name, version);
+        let certificate = self
+            .record_certificate(&context, &user, order.certificate_url.clone(), &downloaded)
+            .await?;
+        info!(
+            "Certificate {} stored with id: {} - chain: {} (issuer: {})",
+            certificate.name,
+            certificate.certificate_id,
+            certificate.chain_url,
+            certificate.chain_issuer.as_deref().unwrap_or("unknown")
+        );
+        // the archive has its own copy now
+        if let Err(e) = std::fs::remove_file(order.key_path.as_str()) {
+            info!("Key {} of the order could not be removed: {}", order.key_path, e);
+        }
+        context
+            .set_output(&DownloadOutput {
+                name: certificate.name,
+                serial: certificate.serial,
+                not_after: certificate.not_after,
+                version,
+                chain_issuer: certificate.chain_issuer,
+            })
+            .await
+    }
+}
@@ -0,0 +1,416 @@
+use crate::acme_jobs::account_management::LoadedAccount;
+use crate::acme_jobs::certificate_download::CertificateDownloadJob;
+use crate::acme_jobs::certificate_reconciliation::{issued, plan, Reconciliation};
+use crate::job_execution::job_base::{Job, JobContext, JobOutput};
+use crate::job_execution::job_store::now;
+use crate::job_execution::retry::{Permanent, RetryPolicy};
+use acme_client::comms::order::{
+    fetch_authorization, fetch_order, finalize_order, key_authorization, new_order, respond_to_challenge, AuthorizationObject,
+    ChallengeObject, OrderObject,
+};
+use acme_client::comms::session::AcmeSession;
+use acme_client::crypto::SupportedKey;
+use acme_client::csr::{CsrBuilder, CsrIdentifier};
+use acme_client::keys::PrivateKey;
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use common_utils::fs::{FileOptions, FileSystem};
+use common_utils::{CertificateDefinition, ChallengeMethod, APPLICATION_CONFIG};
+use persistence::data_model::AuditKind;
+use persistence::repository::NewAuditEvent;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, instrument};
+
+/// Where the key of a certificate waits until the certificate is downloaded, under the output dir.
+const ORDERS_DIR: &str = "orders";
+const CHALLENGE_DIR: &str = ".well-known/acme-challenge";
+/// Pause between two looks at an authorization or order the CA is still working on.
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+const MAX_POLLS: u32 = 100;
+
+/// The valid order, handed to the [`CertificateDownloadJob`] submitted for it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderOutput {
+    pub name: String,
+    pub ca: String,
+    pub order_url: String,
+    pub certificate_url: String,
+    /// PEM of the certificate key, until the download moves it into the output layout.
+    pub key_path: String,
+    pub download_job: i64,
+}
+impl JobOutput for OrderOutput {
+    const OUTPUT_TYPE: &'static str = "certificate-order";
+}
+
+/// Orders the declared certificate `name`, solves its challenges, finalizes the order and
+/// submits the [`CertificateDownloadJob`] that puts the certificate live.
+///
+/// Unless `force` is set the certificate is reconciled again first and nothing is ordered if
+/// it's current, a job submitted to run at `renew_at` finds it renewed in the meantime.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CertificateOrderJob {
+    user_id: String,
+    name: String,
+    force: bool,
+    renew_at: Option<i64>,
+}
+impl CertificateOrderJob {
+    pub const JOB_TYPE: &str = "certificate-order-job";
+    pub fn new(user_id: String, name: String, force: bool, renew_at: Option<i64>) -> Self {
+        CertificateOrderJob {
+            user_id,
+            name,
+            force,
+            renew_at,
+        }
+    }
+    fn definition(&self) -> anyhow::Result<&'static CertificateDefinition> {
+        APPLICATION_CONFIG
+            .get()
+            .unwrap()
+            .certificates
+            .iter()
+            .find(|definition| definition.name == self.name)
+            .ok_or_else(|| anyhow!("Certificate {} is not declared in the configuration", self.name))
+            .context(Permanent)
+    }
+    /// The key the certificate is requested for, a retry of the job picks up the key of the
+    /// attempt before.
+    fn certificate_key(&self, context: &JobContext, definition: &CertificateDefinition) -> anyhow::Result<(PrivateKey, PathBuf)> {
+        let config = APPLICATION_CONFIG.get().unwrap();
+        let key_type = SupportedKey::from_str(definition.key_type.as_str())
+            .map_err(|e| anyhow!(e.to_string()))
+            .context(Permanent)?;
+        let system = FileSystem::new(config.output_dir.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+        let dir = format!("{}/{}", ORDERS_DIR, self.name);
+        let path = system.ensure_sub_dir(dir.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+        let file_name = format!("{}.pem", context.job_id);
+        if system.file_exists(path.clone(), file_name.as_str()) {
+            let pem = system.read_from_file(dir.as_str(), file_name.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+            let key = PrivateKey::load_private_bytes(&pem, key_type).map_err(|e| anyhow!(e.to_string()))?;
+            return Ok((key, path.join(file_name)));
+        }
+        let key = PrivateKey::from_supported_type(key_type).map_err(|e| anyhow!(e.to_string()))?;
+        let pem = key.get_pem_bytes().map_err(|e| anyhow!(e.to_string()))?;
+        let path = system
+            .write_to_file_with(dir.as_str(), file_name.as_str(), pem.as_slice(), &FileOptions::private())
+            .map_err(|e| anyhow!(e.to_string()))?;
+        Ok((key, path))
+    }
+    /// Places the order and records it, the CA may hand out a pending order for the same
+    /// identifiers instead of a new one.
+    async fn place(
+        &self,
+        context: &JobContext,
+        session: &AcmeSession,
+        new_order_url: &str,
+        definition: &CertificateDefinition,
+        user_id: i64,
+    ) -> anyhow::Result<(String, OrderObject)> {
+        let identifiers: Vec<CsrIdentifier> = definition.identifiers.iter().map(|i| CsrIdentifier::parse(i)).collect();
+        let permit = context.handle.ca_permit().await;
+        let (order_url, order) = new_order(session, new_order_url, &identifiers, definition.profile.as_deref())
+            .await
+            .map_err(|e| anyhow!(e))?;
+        drop(permit);
+        info!("Certificate {} ordered as {} ({})", self.name, order_url, order.status);
+        let (job, url, status, ca) = (self.clone(), order_url.clone(), order.status.clone(), definition.ca.clone());
+        let (identifiers, finalize_url) = (definition.identifiers.clone(), order.finalize.clone());
+        context
+            .with_repositories(move |repositories| {
+                let orders = repositories.orders.as_ref();
+                let recorded = match orders.find(url.as_str()).map_err(|e| anyhow!(e.to_string()))? {
+                    Some(_) => orders.update_status(url.as_str(), status.as_str(), None),
+                    None => orders.create(user_id, url.as_str(), status.as_str(), &identifiers, finalize_url.as_str()),
+                }
+                .map_err(|e| anyhow!(e.to_string()))?;
+                repositories
+                    .audit
+                    .append(NewAuditEvent {
+                        kind: AuditKind::OrderCreated,
+                        user_id: Some(job.user_id.as_str()),
+                        subject: Some(url.as_str()),
+                        details: json!({"ca": ca, "name": job.name, "identifiers": recorded.identifiers, "status": recorded.status}),
+                    })
+                    .map_err(|e| anyhow!(e.to_string()))?;
+                Ok(())
+            })
+            .await?;
+        Ok((order_url, order))
+    }
+    /// Solves the pending authorizations of the order one after another.
+    async fn authorize(
+        &self,
+        context: &JobContext,
+        session: &AcmeSession,
+        definition: &CertificateDefinition,
+        order: &OrderObject,
+    ) -> anyhow::Result<()> {
+        for authorization_url in &order.authorizations {
+            let permit = context.handle.ca_permit().await;
+            let authorization = fetch_authorization(session, authorization_url.as_str())
+                .await
+                .map_err(|e| anyhow!(e))?;
+            drop(permit);
+            if authorization.status == "valid" {
+                continue;
+            }
+            let challenge_file = self.solve(session, definition, &authorization)?;
+            let result = self.validate(context, session, definition, authorization_url.as_str(), &authorization).await;
+            if let Err(e) = std::fs::remove_file(&challenge_file) {
+                info!("Challenge file {} could not be removed: {}", challenge_file.display(), e);
+            }
+            result?;
+        }
+        Ok(())
+    }
+    /// Puts the key authorization of the authorization's http-01 challenge into the webroot,
+    /// returns the file written.
+    fn solve(
+        &self,
+        session: &AcmeSession,
+        definition: &CertificateDefinition,
+        authorization: &AuthorizationObject,
+    ) -> anyhow::Result<PathBuf> {
+        if definition.challenge != ChallengeMethod::Http01 {
+            return Err(anyhow!("There is no solver for {} challenges yet", definition.challenge.as_str())).context(Permanent);
+        }
+        let challenge = self.challenge(definition, authorization)?;
+        let token = challenge
+            .token
+            .as_deref()
+            .ok_or_else(|| anyhow!("Challenge {} comes without a token", challenge.url))
+            .context(Permanent)?;
+        let webroot = definition
+            .challenge_settings
+            .get("webroot")
+            .ok_or_else(|| anyhow!("Certificate {} has no webroot for http-01", self.name))
+            .context(Permanent)?;
+        let key_authorization = key_authorization(token, session.key()).map_err(|e| anyhow!(e.to_string()))?;
+        let system = FileSystem::new(webroot).map_err(|e| anyhow!(e.to_string()))?;
+        system.ensure_sub_dir(CHALLENGE_DIR).map_err(|e| anyhow!(e.to_string()))?;
+        system
+            .write_to_file(CHALLENGE_DIR, token, key_authorization.as_bytes())
+            .map_err(|e| anyhow!(e.to_string()))
+            .context(Permanent)
+    }
+    fn challenge<'a>(
+        &self,
+        definition: &CertificateDefinition,
+        authorization: &'a AuthorizationObject,
+    ) -> anyhow::Result<&'a ChallengeObject> {
+        authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == definition.challenge.as_str())
+            .ok_or_else(|| {
+                anyhow!(
+                    "The CA offers no {} challenge for {}",
+                    definition.challenge.as_str(),
+                    authorization.identifier.value
+                )
+            })
+            .context(Permanent)
+    }
+    /// Asks the CA to validate the solved challenge and waits until the authorization is valid.
+    async fn validate(
+        &self,
+        context: &JobContext,
+        session: &AcmeSession,
+        definition: &CertificateDefinition,
+        authorization_url: &str,
+        authorization: &AuthorizationObject,
+    ) -> anyhow::Result<()> {
+        let challenge = self.challenge(definition, authorization)?;
+        let permit = context.handle.ca_permit().await;
+        let attempted = respond_to_challenge(session, challenge.url.as_str()).await.map_err(|e| anyhow!(e))?;
+        drop(permit);
+        let details = json!({
+            "name": self.name,
+            "identifier": authorization.identifier.value,
+            "type": attempted.kind,
+            "status": attempted.status,
+        });
+        self.audit(context, AuditKind::ChallengeAttempted, challenge.url.clone(), details).await?;
+        for _ in 0..MAX_POLLS {
+            pause(context).await?;
+            let permit = context.handle.ca_permit().await;
+            let authorization = fetch_authorization(session, authorization_url).await.map_err(|e| anyhow!(e))?;
+            drop(permit);
+            match authorization.status.as_str() {
+                "valid" => {
+                    info!("Identifier {} of certificate {} validated", authorization.identifier.value, self.name);
+                    return Ok(());
+                }
+                "pending" => continue,
+                status => {
+                    let problem = self
+                        .challenge(definition, &authorization)
+                        .ok()
+                        .and_then(|challenge| challenge.error.clone())
+                        .unwrap_or(Value::Null);
+                    return Err(anyhow!(
+                        "Authorization of {} is {}: {}",
+                        authorization.identifier.value,
+                        status,
+                        problem
+                    ))
+                    .context(Permanent);
+                }
+            }
+        }
+        Err(anyhow!("Authorization {} is still pending", authorization_url))
+    }
+    /// Sends the CSR once the order is ready and waits for the certificate URL.
+    async fn finalize(
+        &self,
+        context: &JobContext,
+        session: &AcmeSession,
+        definition: &CertificateDefinition,
+        order_url: &str,
+        key: &PrivateKey,
+    ) -> anyhow::Result<String> {
+        let mut order = self.poll_order(context, session, order_url, |status| status != "pending").await?;
+        if order.status == "ready" {
+            let csr = definition
+                .identifiers
+                .iter()
+                .fold(CsrBuilder::new(), |builder, identifier| builder.identifier(CsrIdentifier::parse(identifier)))
+                .build(key)
+                .map_err(|e| anyhow!(e.to_string()))?;
+            let permit = context.handle.ca_permit().await;
+            order = finalize_order(session, order.finalize.as_str(), &csr).await.map_err(|e| anyhow!(e))?;
+            drop(permit);
+            self.record_status(context, order_url, &order).await?;
+            if order.status == "processing" {
+                order = self.poll_order(context, session, order_url, |status| status != "processing").await?;
+            }
+        }
+        match (order.status.as_str(), order.certificate) {
+            ("valid", Some(certificate_url)) => Ok(certificate_url),
+            (status, _) => Err(anyhow!(
+                "Order {} is {}: {}",
+                order_url,
+                status,
+                order.error.unwrap_or(Value::Null)
+            ))
+            .context(Permanent),
+        }
+    }
+    async fn poll_order<F>(&self, context: &JobContext, session: &AcmeSession, order_url: &str, done: F) -> anyhow::Result<OrderObject>
+    where
+        F: Fn(&str) -> bool,
+    {
+        for _ in 0..MAX_POLLS {
+            let permit = context.handle.ca_permit().await;
+            let order = fetch_order(session, order_url).await.map_err(|e| anyhow!(e))?;
+            drop(permit);
+            if done(order.status.as_str()) {
+                self.record_status(context, order_url, &order).await?;
+                return Ok(order);
+            }
+            pause(context).await?;
+        }
+        Err(anyhow!("Order {} didn't move on in time", order_url))
+    }
+    async fn record_status(&self, context: &JobContext, order_url: &str, order: &OrderObject) -> anyhow::Result<()> {
+        let (url, status, certificate_url) = (order_url.to_string(), order.status.clone(), order.certificate.clone());
+        let recorded = context
+            .with_repositories(move |repositories| {
+                repositories
+                    .orders
+                    .update_status(url.as_str(), status.as_str(), certificate_url.as_deref())
+                    .map_err(|e| anyhow!(e.to_string()))
+            })
+            .await?;
+        let details = json!({"name": self.name, "status": recorded.status, "certificate_url": recorded.certificate_url});
+        self.audit(context, AuditKind::OrderUpdated, order_url.to_string(), details).await
+    }
+    async fn audit(&self, context: &JobContext, kind: AuditKind, subject: String, details: Value) -> anyhow::Result<()> {
+        let user_id = self.user_id.clone();
+        context
+            .with_repositories(move |repositories| {
+                repositories
+                    .audit
+                    .append(NewAuditEvent {
+                        kind,
+                        user_id: Some(user_id.as_str()),
+                        subject: Some(subject.as_str()),
+                        details,
+                    })
+                    .map_err(|e| anyhow!(e.to_string()))?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+/// Waits before the next look at the CA, gives up if the job is cancelled meanwhile.
+async fn pause(context: &JobContext) -> anyhow::Result<()> {
+    tokio::select! {
+        _ = tokio::time::sleep(POLL_INTERVAL) => Ok(()),
+        _ = context.cancel.cancelled() => Err(anyhow!("Job {} was cancelled", context.job_id)).context(Permanent),
+    }
+}
+
+#[async_trait]
+impl Job for CertificateOrderJob {
+    fn job_type(&self) -> &'static str {
+        Self::JOB_TYPE
+    }
+    fn payload(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::exponential(3, Duration::from_secs(60)).with_max_delay(Duration::from_secs(15 * 60))
+    }
+    fn concurrency_key(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+    fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(15 * 60))
+    }
+    #[instrument(level = "trace", name = "certificate_order_job", fields(job_name = %self.job_type()), skip_all)]
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+        let definition = self.definition()?;
+        if !self.force {
+            let user_id = self.user_id.clone();
+            let issued = context.with_repositories(move |repositories| issued(repositories, user_id.as_str())).await?;
+            if let Some(Reconciliation::Current { renew_at, .. }) = plan(std::slice::from_ref(definition), &issued, now()).first() {
+                info!("Certificate {} is current until {}, nothing to order", self.name, renew_at);
+                return Ok(());
+            }
+        }
+        let (user_id, ca) = (self.user_id.clone(), definition.ca.clone());
+        let loaded = context
+            .with_repositories(move |repositories| LoadedAccount::load(repositories, user_id.as_str(), ca.as_str()))
+            .await?;
+        let (user, new_order_url) = (loaded.user.id, loaded.directory.new_order.clone());
+        let session = loaded.session()?;
+        let (key, key_path) = self.certificate_key(&context, definition)?;
+        let (order_url, order) = self.place(&context, &session, new_order_url.as_str(), definition, user).await?;
+        self.authorize(&context, &session, definition, &order).await?;
+        let certificate_url = self.finalize(&context, &session, definition, order_url.as_str(), &key).await?;
+        info!("Order {} of certificate {} is valid", order_url, self.name);
+        let download = CertificateDownloadJob::new(self.user_id.clone(), definition.ca.clone(), self.name.clone());
+        let download_job = context
+            .handle
+            .submit_depending_on(&[context.job_id], download)
+            .await
+            .map_err(|e| anyhow!(e))?;
+        context
+            .set_output(&OrderOutput {
+                name: self.name.clone(),
+                ca: definition.ca.clone(),
+                order_url,
+                certificate_url,
+                key_path: key_path.display().to_string(),
+                download_job: download_job.0,
+            })
+            .await
+    }
+}
@@ -0,0 +1,265 @@
+use crate::acme_jobs::certificate_order::CertificateOrderJob;
+use crate::job_execution::job_base::{Job, JobContext, JobOutput};
+use crate::job_execution::job_store::now;
+use acme_client::certificate::CertificateChain;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use common_utils::fs::FileSystem;
+use common_utils::{APPLICATION_CONFIG, CertificateDefinition};
+use persistence::data_model::AcmeCertificate;
+use persistence::repository::Repositories;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::{info, instrument, warn};
+
+/// What reconciling one declared or recorded certificate comes down to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Reconciliation {
+    /// Declared, but never issued.
+    Issue { name: String, reason: String },
+    /// Issued, but due for renewal or no longer matching its declaration.
+    Renew { name: String, reason: String },
+    /// Issued and nothing to do until `renew_at`.
+    Current { name: String, renew_at: i64 },
+    /// Issued, but no longer declared. Its files are left in place.
+    Removed { name: String, serial: String },
+}
+
+/// The certificate as it was issued last, `identifiers` are those of the live files, if
+/// there still are any.
+pub struct IssuedCertificate {
+    pub record: AcmeCertificate,
+    pub identifiers: Option<Vec<String>>,
+}
+
+/// Compares the declared certificates with the issued ones, in the order they are declared
+/// followed by the removed ones.
+pub fn plan(definitions: &[CertificateDefinition], issued: &HashMap<String, IssuedCertificate>, now: i64) -> Vec<Reconciliation> {
+    let mut plan = Vec::new();
+    for definition in definitions {
+        let name = definition.name.clone();
+        let Some(IssuedCertificate { record, identifiers }) = issued.get(&definition.name) else {
+            plan.push(Reconciliation::Issue {
+                name,
+                reason: "not issued yet".to_string(),
+            });
+            continue;
+        };
+        let declared: BTreeSet<&str> = definition.identifiers.iter().map(String::as_str).collect();
+        let reason = match identifiers {
+            _ if record.ca_name != definition.ca => {
+                Some(format!("issued by CA {}, declared for CA {}", record.ca_name, definition.ca))
+            }
+            None => Some("live files are missing".to_string()),
+            Some(live) if live.iter().map(String::as_str).collect::<BTreeSet<_>>() != declared => {
+                Some(format!("identifiers changed from {}", live.join(", ")))
+            }
+            _ => None,
+        };
+        let renew_at = match definition.renew_before {
+            Some(renew_before) => record.not_after - renew_before as i64,
+            None => record.not_after - (record.not_after - record.not_before) / 3,
+        };
+        plan.push(match reason {
+            Some(reason) => Reconciliation::Renew { name, reason },
+            None if now >= renew_at => Reconciliation::Renew {
+                name,
+                reason: format!("due since {}, expires at {}", renew_at, record.not_after),
+            },
+            None => Reconciliation::Current { name, renew_at },
+        });
+    }
+    let declared: BTreeSet<&str> = definitions.iter().map(|d| d.name.as_str()).collect();
+    let removed: BTreeMap<&String, &IssuedCertificate> =
+        issued.iter().filter(|(name, _)| !declared.contains(name.as_str())).collect();
+    for (name, issued) in removed {
+        plan.push(Reconciliation::Removed {
+            name: name.clone(),
+            serial: issued.record.serial.clone(),
+        });
+    }
+    plan
+}
+
+/// The latest certificate of each name issued to the user, with the identifiers of its live
+/// files.
+pub fn issued(repositories: &Repositories, user_id: &str) -> anyhow::Result<HashMap<String, IssuedCertificate>> {
+    let config = APPLICATION_CONFIG.get().unwrap();
+    let user = repositories
+        .users
+        .find(user_id)
+        .map_err(|e| anyhow!(e.to_string()))?
+        .ok_or_else(|| anyhow!("User {} could not be found", user_id))?;
+    let system = FileSystem::new(config.output_dir.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+    let mut issued = HashMap::new();
+    // ordered by id, later records of a name replace the earlier ones
+    for record in repositories.certificates.for_user(user.id).map_err(|e| anyhow!(e.to_string()))? {
+        issued.insert(record.name.clone(), record);
+    }
+    Ok(issued
+        .into_iter()
+        .map(|(name, record)| {
+            let identifiers = system
+                .read_from_file(format!("live/{}", name).as_str(), "cert.pem")
+                .ok()
+                .and_then(|pem| CertificateChain::from_pem(&pem).ok())
+                .map(|chain| chain.leaf().subject_alt_names().iter().map(|i| i.value()).collect());
+            (name, IssuedCertificate { record, identifiers })
+        })
+        .collect())
+}
+
+/// The plan the declared certificates were reconciled with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReconciliationOutput {
+    pub certificates: Vec<Reconciliation>,
+}
+impl JobOutput for ReconciliationOutput {
+    const OUTPUT_TYPE: &'static str = "certificate-reconciliation";
+}
+
+/// Reconciles the certificates declared in the configuration with those issued to the user.
+///
+/// Certificates due for issuance or renewal are ordered right away, current ones get an
+/// order submitted to run at their renewal time.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CertificateReconciliationJob {
+    user_id: String,
+}
+impl CertificateReconciliationJob {
+    pub const JOB_TYPE: &str = "certificate-reconciliation-job";
+    pub fn new(user_id: String) -> Self {
+        CertificateReconciliationJob { user_id }
+    }
+}
+#[async_trait]
+impl Job for CertificateReconciliationJob {
+    fn job_type(&self) -> &'static str {
+        Self::JOB_TYPE
+    }
+    fn payload(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+    fn concurrency_key(&self) -> Option<String> {
+        Some(Self::JOB_TYPE.to_string())
+    }
+    fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+    #[instrument(level = "trace", name = "certificate_reconciliation_job", fields(job_name = %self.job_type()), skip_all)]
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+        let config = APPLICATION_CONFIG.get().unwrap();
+        let user_id = self.user_id.clone();
+        let issued = context.with_repositories(move |repositories| issued(repositories, user_id.as_str())).await?;
+        let certificates = plan(&config.certificates, &issued, now());
+        for reconciliation in &certificates {
+            match reconciliation {
+                Reconciliation::Issue { name, reason } | Reconciliation::Renew { name, reason } => {
+                    let order = CertificateOrderJob::new(self.user_id.clone(), name.clone(), false, None);
+                    let job_id = context.handle.submit(order).await.map_err(|e| anyhow!(e))?;
+                    info!("Certificate {} has to be ordered ({}), submitted as job {}", name, reason, job_id);
+                }
+                Reconciliation::Current { name, renew_at } => {
+                    let order = CertificateOrderJob::new(self.user_id.clone(), name.clone(), false, Some(*renew_at));
+                    let at = UNIX_EPOCH + Duration::from_secs((*renew_at).max(0) as u64);
+                    let job_id = context.handle.submit_at(at, order).await.map_err(|e| anyhow!(e))?;
+                    info!("Certificate {} is current until {}, renewal scheduled as job {}", name, renew_at, job_id);
+                }
+                Reconciliation::Removed { name, serial } => warn!(
+                    "Certificate {} with serial {} is no longer declared, its files are left in place",
+                    name, serial
+                ),
+            }
+        }
+        context.set_output(&ReconciliationOutput { certificates }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IssuedCertificate, Reconciliation, plan};
+    use common_utils::{CertificateDefinition, CertificateHooks, ChallengeMethod};
+    use persistence::data_model::AcmeCertificate;
+    use std::collections::{BTreeMap, HashMap};
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn definition(name: &str, identifiers: &[&str], renew_before: Option<u64>) -> CertificateDefinition {
+        CertificateDefinition {
+            name: name.to_string(),
+            identifiers: identifiers.iter().map(|i| i.to_string()).collect(),
+            key_type: "ec-p256".to_string(),
+            challenge: ChallengeMethod::Http01,
+            challenge_settings: BTreeMap::new(),
+            ca: "default".to_string(),
+            profile: None,
+            renew_before,
+            output_formats: None,
+            hooks: CertificateHooks::default(),
+        }
+    }
+
+    fn issued(name: &str, ca: &str, identifiers: Option<&[&str]>) -> (String, IssuedCertificate) {
+        let record = AcmeCertificate {
+            certificate_id: 1,
+            user_id: 1,
+            ca_name: ca.to_string(),
+            name: name.to_string(),
+            serial: format!("{}-serial", name),
+            not_before: 0,
+            not_after: 90 * DAY,
+            certificate_url: format!("https://ca/cert/{}", name),
+            chain_url: format!("https://ca/cert/{}", name),
+            chain_issuer: None,
+        };
+        let identifiers = identifiers.map(|i| i.iter().map(|i| i.to_string()).collect());
+        (name.to_string(), IssuedCertificate { record, identifiers })
+    }
+
+    #[test]
+    fn test_declared_certificates_are_reconciled_with_the_issued_ones() {
+        let definitions = vec![
+            definition("new", &["new.example.org"], None),
+            definition("current", &["example.org", "www.example.org"], None),
+            definition("due", &["due.example.org"], Some(45 * DAY as u64)),
+            definition("changed", &["changed.example.org", "192.0.2.1"], None),
+            definition("moved", &["moved.example.org"], None),
+            definition("lost", &["lost.example.org"], None),
+        ];
+        let issued: HashMap<_, _> = [
+            issued("current", "default", Some(&["www.example.org", "example.org"])),
+            issued("due", "default", Some(&["due.example.org"])),
+            issued("changed", "default", Some(&["changed.example.org"])),
+            issued("moved", "staging", Some(&["moved.example.org"])),
+            issued("lost", "default", None),
+            issued("old", "default", Some(&["old.example.org"])),
+        ]
+        .into_iter()
+        .collect();
+        let actions: Vec<_> = plan(&definitions, &issued, 59 * DAY)
+            .into_iter()
+            .map(|reconciliation| match reconciliation {
+                Reconciliation::Issue { name, .. } => format!("issue {}", name),
+                Reconciliation::Renew { name, .. } => format!("renew {}", name),
+                Reconciliation::Current { name, renew_at } => format!("current {} {}", name, renew_at / DAY),
+                Reconciliation::Removed { name, serial } => format!("removed {} {}", name, serial),
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                "issue new",
+                "current current 60",
+                "renew due",
+                "renew changed",
+                "renew moved",
+                "renew lost",
+                "removed old old-serial",
+            ]
+        );
+        assert!(matches!(&plan(&definitions, &issued, 60 * DAY)[1], Reconciliation::Renew { .. }));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::acme_jobs::account_management::LoadedAccount;
+use crate::job_execution::job_base::{Job, JobContext, JobOutput, Priority};
+use crate::job_execution::retry::Permanent;
+use acme_client::certificate::CertificateChain;
+use acme_client::comms::certificate::revoke_certificate;
+use anyhow::{Context, anyhow};
+use async_trait::async_trait;
+use common_utils::APPLICATION_CONFIG;
+use common_utils::fs::FileSystem;
+use persistence::data_model::{AcmeCertificate, AuditKind};
+use persistence::repository::{NewAuditEvent, Repositories};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::time::Duration;
+use tracing::{info, instrument};
+
+/// The certificate that has been revoked.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevocationOutput {
+    pub name: String,
+    pub ca: String,
+    pub serial: String,
+    pub reason: Option<u32>,
+}
+impl JobOutput for RevocationOutput {
+    const OUTPUT_TYPE: &'static str = "certificate-revocation";
+}
+
+/// Revokes the live certificate `name` with the CA that issued it, `reason` is a CRL reason
+/// code (RFC 5280 section 5.3.1).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CertificateRevocationJob {
+    user_id: String,
+    name: String,
+    reason: Option<u32>,
+}
+impl CertificateRevocationJob {
+    pub const JOB_TYPE: &str = "certificate-revocation-job";
+    pub fn new(user_id: String, name: String, reason: Option<u32>) -> Self {
+        CertificateRevocationJob { user_id, name, reason }
+    }
+    /// The live certificate together with its record, they have to agree on the serial.
+    fn load(&self, repositories: &Repositories) -> anyhow::Result<(CertificateChain, AcmeCertificate, LoadedAccount)> {
+        let config = APPLICATION_CONFIG.get().unwrap();
+        let record = repositories
+            .certificates
+            .latest(self.name.as_str())
+            .map_err(|e| anyhow!(e.to_string()))?
+            .ok_or_else(|| anyhow!("No certificate {} has been issued", self.name))
+            .context(Permanent)?;
+        let loaded = LoadedAccount::load(repositories, self.user_id.as_str(), record.ca_name.as_str())?;
+        if record.user_id != loaded.user.id {
+            return Err(anyhow!("Certificate {} was not issued to user {}", self.name, self.user_id)).context(Permanent);
+        }
+        let system = FileSystem::new(config.output_dir.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+        let pem = system
+            .read_from_file(format!("live/{}", self.name).as_str(), "cert.pem")
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let chain = CertificateChain::from_pem(&pem).map_err(|e| anyhow!(e.to_string()))?;
+        let serial = chain.leaf().serial().map_err(|e| anyhow!(e.to_string()))?;
+        if serial != record.serial {
+            return Err(anyhow!(
+                "Live certificate {} has serial {}, the one issued last {}",
+                self.name,
+                serial,
+                record.serial
+            ))
+            .context(Permanent);
+        }
+        Ok((chain, record, loaded))
+    }
+}
+#[async_trait]
+impl Job for CertificateRevocationJob {
+    fn job_type(&self) -> &'static str {
+        Self::JOB_TYPE
+    }
+    fn payload(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+    fn concurrency_key(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+    fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+    fn priority(&self) -> Priority {
+        Priority::Critical
+    }
+    #[instrument(level = "trace", name = "certificate_revocation_job", fields(job_name = %self.job_type()), skip_all)]
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+        let job = self.clone();
+        let (chain, record, loaded) = context.with_repositories(move |repositories| job.load(repositories)).await?;
+        let revoke_url = loaded.directory.revoke_cert.clone();
+        let session = loaded.session()?;
+        let permit = context.handle.ca_permit().await;
+        revoke_certificate(&session, revoke_url.as_str(), chain.leaf(), self.reason)
+            .await
+            .map_err(|e| anyhow!(e))?;
+        drop(permit);
+        info!("Certificate {} with serial {} revoked by CA {}", self.name, record.serial, record.ca_name);
+        let output = RevocationOutput {
+            name: self.name.clone(),
+            ca: record.ca_name.clone(),
+            serial: record.serial.clone(),
+            reason: self.reason,
+        };
+        let (job, details) = (self.clone(), json!({"ca": output.ca, "name": output.name, "serial": output.serial, "reason": output.reason}));
+        context
+            .with_repositories(move |repositories| {
+                repositories
+                    .audit
+                    .append(NewAuditEvent {
+                        kind: AuditKind::CertificateRevoked,
+                        user_id: Some(job.user_id.as_str()),
+                        subject: Some(record.certificate_url.as_str()),
+                        details,
+                    })
+                    .map_err(|e| anyhow!(e.to_string()))?;
+                Ok(())
+            })
+            .await?;
+        context.set_output(&output).await
+    }
+}
@@ -1,13 +1,14 @@
+use anyhow::anyhow;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tracing::instrument;
-use persistence::database::DatabaseConnection;
-use crate::job_execution::job_base::{Job, SchedulerHandle};
+use crate::job_execution::job_base::{Job, JobContext, Priority};
 
 #[derive(Serialize, Deserialize)]
 pub struct DbInitializationJob {}
 impl DbInitializationJob {
+    pub const JOB_TYPE: &str = "db-initialization-job";
     pub fn new() -> Self {
         DbInitializationJob {}
     }
@@ -15,15 +16,17 @@ impl DbInitializationJob {
 #[async_trait]
 impl Job for DbInitializationJob {
     fn job_type(&self) -> &'static str {
-        "db-initialization-job"
+        Self::JOB_TYPE
     }
     fn payload(&self) -> Value {
         serde_json::to_value(self).unwrap()
     }
+    fn priority(&self) -> Priority {
+        Priority::High
+    }
     #[instrument(level = "trace", name = "db_initialization_job", fields(job_name = %self.job_type()), skip_all)]
-    async fn execute(&self, _: SchedulerHandle) -> anyhow::Result<()> {
-        let db = DatabaseConnection::get_connection().unwrap();
-        db.internal_structure_check().unwrap();
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+        context.repositories.schema.migrate().map_err(|e| anyhow!(e.to_string()))?;
         Ok(())
     }
 }
\ No newline at end of file
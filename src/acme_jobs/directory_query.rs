@@ -1,40 +1,59 @@
-use crate::acme_jobs::initialize_keys_for_user::InitializeLocalUserJob;
-use crate::job_execution::job_base::{Job, SchedulerHandle};
+use crate::job_execution::job_base::{Job, JobContext, JobOutput, Priority};
+use crate::job_execution::retry::RetryPolicy;
 use acme_client::comms::directory::AcmeDirectoryApi;
+use anyhow::anyhow;
 use async_trait::async_trait;
-use common_utils::CompareFields;
-use persistence::data_model::AcmeDirectory;
-use persistence::database::DatabaseConnection;
+use common_utils::{APPLICATION_CONFIG, CertificateAuthority, CompareFields, DEFAULT_CA_NAME};
+use persistence::data_model::{AcmeDirectory, AuditKind};
+use persistence::repository::{NewAuditEvent, Repositories};
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_value, Value};
+use serde_json::{from_value, json, Value};
 use std::error::Error;
+use std::time::Duration;
 use tracing::{info, instrument};
 
-#[derive(Serialize, Deserialize)]
+/// Handed to the jobs depending on a directory refresh, e.g. account registration.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DirectoryOutput {
+    pub directory_id: i64,
+    pub account_id: i64,
+    pub new_nonce: String,
+    pub new_account: String,
+    pub new_order: String,
+}
+impl JobOutput for DirectoryOutput {
+    const OUTPUT_TYPE: &'static str = "acme-directory";
+}
+
+/// Refreshes the directory of one CA for the user, the account with that CA is created
+/// as pending on the first refresh.
+///
+/// `base_url` is the full directory URL, the name is kept for the jobs persisted before.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DirectoryUpdateJob {
     pub base_url: String,
     pub user_id: String,
+    #[serde(default = "default_ca")]
+    pub ca: String,
+}
+fn default_ca() -> String {
+    DEFAULT_CA_NAME.to_string()
 }
 impl DirectoryUpdateJob {
-    pub fn new(base_url: String, user_id: String) -> Result<Self, Box<dyn Error>> {
-        let url = Self::validate_url(Some(base_url.clone()))?;
+    pub const JOB_TYPE: &str = "directory-update-job";
+    pub fn new(ca: &CertificateAuthority, user_id: String) -> Result<Self, Box<dyn Error>> {
+        let url = Url::parse(ca.directory_url.as_str())?;
         Ok(DirectoryUpdateJob {
             user_id,
             base_url: url.to_string(),
+            ca: ca.name.clone(),
         })
     }
-    pub fn validate_url(base_url: Option<String>) -> Result<Url, Box<dyn Error>> {
-        if let Some(base_url) = base_url {
-            let x = base_url.to_owned() + "/dir";
-            let url = Url::parse(x.as_str())?;
-            return Ok(url);
-        }
-        Err("Acme CA base url could not be parsed!".into())
-    }
     async fn call_directory(&self) -> anyhow::Result<Value> {
+        let config = APPLICATION_CONFIG.get().unwrap();
         let client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_certs(config.accept_invalid_certs)
             .build()?;
         info!("Calling the requested ACME directory");
         let response = client.get(self.base_url.as_str()).send().await?;
@@ -44,99 +63,178 @@ impl DirectoryUpdateJob {
         let value = serde_json::from_slice::<Value>(slice)?;
         Ok(value)
     }
-    fn get_existing(
-        &self,
-        user_id: i64,
-        connection: &DatabaseConnection,
-    ) -> anyhow::Result<Option<AcmeDirectory>> {
-        let sql = r#"
-            SELECT * FROM acme_users_directory WHERE user_id = $1;
-            "#;
-        let mut statement = connection.prepare(sql).unwrap();
-        statement.bind((1, user_id))?;
-        Ok(AcmeDirectory::scan_statement(statement).unwrap())
-    }
+    /// Stores `acme_directory` for the user and CA unless the stored one is already up to date,
+    /// returns it together with the id of the account with the CA.
     fn refresh_if_diff(
         &self,
+        repositories: &Repositories,
         acme_directory: AcmeDirectoryApi,
-    ) -> anyhow::Result<Option<AcmeDirectory>> {
-        let connection = DatabaseConnection::get_connection().unwrap();
-        let user = InitializeLocalUserJob::get_user(self.user_id.as_str(), &connection).unwrap();
-        if user.is_none() {
-            return Err(anyhow::anyhow!(
-                "Could not complete directory job since queried user could not be found!"
-            ))?;
+    ) -> anyhow::Result<(AcmeDirectory, i64)> {
+        let user = repositories
+            .users
+            .find(self.user_id.as_str())
+            .map_err(|e| anyhow!(e.to_string()))?
+            .ok_or_else(|| anyhow!("Could not complete directory job since queried user could not be found!"))?;
+        let known = repositories
+            .accounts
+            .find(user.id, self.ca.as_str())
+            .map_err(|e| anyhow!(e.to_string()))?
+            .is_some();
+        let account = repositories
+            .accounts
+            .ensure(user.id, self.ca.as_str(), self.base_url.as_str(), user.key_path.as_str())
+            .map_err(|e| anyhow!(e.to_string()))?;
+        if !known {
+            self.audit(repositories, AuditKind::AccountCreated, json!({"account_id": account.account_id}))?;
         }
-        let user = user.unwrap();
-        let existing_dir = self.get_existing(user.id, &connection)?;
-        let sql;
-        if existing_dir.is_none() {
-            sql = r#"
-            INSERT INTO acme_users_directory(
-                user_id,
-                new_nonce,
-                new_account,
-                new_order,
-                new_authz,
-                revoke_cert,
-                key_change
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING *;
-            "#;
-        } else {
-            let existing = existing_dir.unwrap();
-            info!("Existing acme directory found for user_id: {} - directory id: {}", user.id, existing.directory_id);
+        let existing_dir = repositories
+            .directories
+            .find(user.id, self.ca.as_str())
+            .map_err(|e| anyhow!(e.to_string()))?;
+        if let Some(existing) = existing_dir {
+            info!("Existing acme directory of CA {} found for user_id: {} - directory id: {}", self.ca, user.id, existing.directory_id);
             if existing.is_equal_to(&acme_directory) {
                 info!(
                     "Existing acme directory was found to be up to date - skipping refresh..."
                 );
-                return Ok(Some(existing));
+                return Ok((existing, account.account_id));
             }
             info!("Acme directory found not to be equal to request - refreshing...");
-            sql = self.get_update();
         }
-        let mut statement = connection.prepare(sql).unwrap();
-        statement.bind((1, user.id))?;
-        statement.bind((2, acme_directory.new_nonce.as_str()))?;
-        statement.bind((3, acme_directory.new_account.as_str()))?;
-        statement.bind((4, acme_directory.new_order.as_str()))?;
-        statement.bind((
-            5,
-            acme_directory.new_authz.unwrap_or("".to_string()).as_str(),
-        ))?;
-        statement.bind((6, acme_directory.revoke_cert.as_str()))?;
-        statement.bind((7, acme_directory.key_change.as_str()))?;
-        Ok(AcmeDirectory::scan_statement(statement).unwrap())
-    }
-    fn get_update(&self) -> &'static str {
-        r#"
-        UPDATE acme_users_directory SET
-            new_nonce = ?2,
-            new_account = ?3,
-            new_order = ?4,
-            new_authz = ?5,
-            revoke_cert = ?6,
-            key_change = ?7
-        WHERE user_id = ?1 RETURNING *;
-        "#
+        let saved = repositories
+            .directories
+            .save(user.id, self.ca.as_str(), &acme_directory)
+            .map_err(|e| anyhow!(e.to_string()))?;
+        self.audit(repositories, AuditKind::DirectoryRefreshed, json!({"directory_id": saved.directory_id}))?;
+        Ok((saved, account.account_id))
+    }
+    fn audit(&self, repositories: &Repositories, kind: AuditKind, mut details: Value) -> anyhow::Result<()> {
+        details["directory_url"] = json!(self.base_url);
+        repositories
+            .audit
+            .append(NewAuditEvent {
+                kind,
+                user_id: Some(self.user_id.as_str()),
+                subject: Some(self.ca.as_str()),
+                details,
+            })
+            .map_err(|e| anyhow!(e.to_string()))?;
+        Ok(())
     }
 }
 
 #[async_trait]
 impl Job for DirectoryUpdateJob {
     fn job_type(&self) -> &'static str {
-        "directory-update-job"
+        Self::JOB_TYPE
     }
     fn payload(&self) -> Value {
         serde_json::to_value(self).unwrap()
     }
+    fn concurrency_key(&self) -> Option<String> {
+        Some(format!("{}:{}", self.user_id, self.ca))
+    }
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::exponential(5, Duration::from_secs(30)).with_max_delay(Duration::from_secs(15 * 60))
+    }
+    fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+    fn priority(&self) -> Priority {
+        Priority::Low
+    }
     #[instrument(level = "trace", name = "directory_update_job", fields(job_name = %self.job_type()), skip_all)]
-    async fn execute(&self, _scheduler: SchedulerHandle) -> anyhow::Result<()> {
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+        let permit = context.handle.ca_permit().await;
         let value = self.call_directory().await?;
+        drop(permit);
         let dir: AcmeDirectoryApi = from_value(value.clone())?;
-        let t = self.refresh_if_diff(dir)?;
-        if let Some(dir) = t {
-            info!("Directory refresh returned directory with id: {}", dir.directory_id);
-        }
+        let job = self.clone();
+        let (dir, account_id) = context
+            .with_repositories(move |repositories| job.refresh_if_diff(repositories, dir))
+            .await?;
+        info!("Directory refresh of CA {} returned directory with id: {}", self.ca, dir.directory_id);
+        context.set_output(&DirectoryOutput {
+            directory_id: dir.directory_id,
+            account_id,
+            new_nonce: dir.new_nonce,
+            new_account: dir.new_account,
+            new_order: dir.new_order,
+        })
+        .await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DirectoryUpdateJob;
+    use acme_client::comms::directory::AcmeDirectoryApi;
+    use common_utils::CertificateAuthority;
+    use persistence::data_model::{AccountStatus, AuditKind};
+    use persistence::repository::{AuditFilter, Repositories};
+
+    fn job(name: &str, directory_url: &str) -> DirectoryUpdateJob {
+        let ca = CertificateAuthority {
+            name: name.to_string(),
+            directory_url: directory_url.to_string(),
+        };
+        DirectoryUpdateJob::new(&ca, "a1b2c3".to_string()).unwrap()
+    }
+
+    fn directory(new_nonce: &str) -> AcmeDirectoryApi {
+        AcmeDirectoryApi {
+            directory_id: 0,
+            user_id: String::new(),
+            key_change: "https://ca/key-change".to_string(),
+            new_authz: None,
+            new_nonce: new_nonce.to_string(),
+            new_account: "https://ca/new-acct".to_string(),
+            new_order: "https://ca/new-order".to_string(),
+            revoke_cert: "https://ca/revoke-cert".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_directory_is_only_refreshed_on_change() {
+        let repositories = Repositories::in_memory();
+        let job = job("production", "https://ca/dir");
+        assert!(job.refresh_if_diff(&repositories, directory("https://ca/nonce")).is_err());
+
+        let user = repositories.users.create("a1b2c3", "ec-p256", "/keys", "/dump").unwrap();
+        let (stored, _) = job.refresh_if_diff(&repositories, directory("https://ca/nonce")).unwrap();
+        let (unchanged, _) = job.refresh_if_diff(&repositories, directory("https://ca/nonce")).unwrap();
+        assert_eq!(unchanged.directory_id, stored.directory_id);
+        let (refreshed, _) = job.refresh_if_diff(&repositories, directory("https://ca/new-nonce")).unwrap();
+        assert_eq!(refreshed.directory_id, stored.directory_id);
+        let found = repositories.directories.find(user.id, "production").unwrap().unwrap();
+        assert_eq!(found.new_nonce, "https://ca/new-nonce");
+        let kinds: Vec<_> = repositories.audit.list(&AuditFilter::default()).unwrap().iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![AuditKind::AccountCreated, AuditKind::DirectoryRefreshed, AuditKind::DirectoryRefreshed]);
+    }
+
+    #[test]
+    fn test_each_ca_gets_its_own_directory_and_account() {
+        let repositories = Repositories::in_memory();
+        let user = repositories.users.create("a1b2c3", "ec-p256", "/keys", "/dump").unwrap();
+        let production = job("production", "https://ca/dir");
+        let staging = job("staging", "https://staging.ca/dir");
+        let (first, production_account) = production.refresh_if_diff(&repositories, directory("https://ca/nonce")).unwrap();
+        let (second, staging_account) = staging.refresh_if_diff(&repositories, directory("https://staging.ca/nonce")).unwrap();
+        assert_ne!(first.directory_id, second.directory_id);
+        assert_ne!(production_account, staging_account);
+
+        let accounts = repositories.accounts.for_user(user.id).unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert!(accounts.iter().all(|a| a.status == AccountStatus::Pending && a.key_path == "/keys"));
+        assert_eq!(accounts[1].directory_url, "https://staging.ca/dir");
+        assert_eq!(repositories.directories.find(user.id, "production").unwrap().unwrap().new_nonce, "https://ca/nonce");
+    }
+
+    #[test]
+    fn test_jobs_persisted_before_named_cas_refresh_the_default_ca() {
+        let job: DirectoryUpdateJob =
+            serde_json::from_value(serde_json::json!({"base_url": "https://localhost/dir", "user_id": "u"})).unwrap();
+        assert_eq!(job.ca, "default");
+    }
+}
@@ -1,25 +1,28 @@
-use crate::job_execution::job_base::{Job, SchedulerHandle};
+use crate::job_execution::job_base::{Job, JobContext, Priority};
+use crate::job_execution::retry::Permanent;
 use acme_client::crypto::SupportedKey;
 use acme_client::keys::PrivateKey;
 use async_trait::async_trait;
 use common_utils::fs;
-use fs::FileSystem;
-use persistence::data_model::AcmeUser;
-use persistence::database::DatabaseConnection;
+use fs::{FileOptions, FileSystem};
+use persistence::data_model::{AcmeUser, AuditKind};
+use persistence::repository::{NewAuditEvent, Repositories};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::error::Error;
-use std::path::PathBuf;
-use anyhow::anyhow;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, Context};
 use tracing::{info, instrument};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct InitializeLocalUserJob {
     path: String,
     key_type: String,
     user_id: String,
 }
 impl InitializeLocalUserJob {
+    pub const JOB_TYPE: &str = "initialize-local-user-job";
     pub fn new(path: String, key_type: String, user_id: String) -> Self {
         InitializeLocalUserJob { path, key_type, user_id }
     }
@@ -42,59 +45,140 @@ impl InitializeLocalUserJob {
         } else {
             info!("Key file for user {}, not found! Generating required keys!", user.user_id);
             key = self.create_from_incoming_type(supported_key)?;
-            let _ = system.write_to_file(result.as_path().to_str().unwrap(), s.as_str(), key.get_pem_bytes()?.as_slice())?;
+            let _ = system.write_to_file_with(result.as_path().to_str().unwrap(), s.as_str(), key.get_pem_bytes()?.as_slice(), &FileOptions::private())?;
         }
         Ok(key)
     }
-    pub fn get_user(user_id: &str, conn: &DatabaseConnection) -> anyhow::Result<Option<AcmeUser>, Box<dyn Error>> {
-        let sql = r#"
-            SELECT * FROM acme_users WHERE user_id = ?1
-            "#;
-        let mut statement = conn.prepare(sql)?;
-        statement.bind((1, user_id))?;
-        Ok(Some(AcmeUser::scan_statement(statement)?))
-    }
-    fn new_user(&self, key_path: PathBuf, dump_path: PathBuf, conn: &DatabaseConnection) -> Result<Option<AcmeUser>, Box<dyn Error>> {
-        let sql = r#"
-            INSERT INTO acme_users (user_id, key_type, key_path, user_dump_path)
-            VALUES (?1, ?2, ?3, ?4) RETURNING *;
-            "#;
-        let mut statement = conn.prepare(sql)?;
-        statement.bind((1, self.user_id.as_str()))?;
-        statement.bind((2, self.key_type.as_str()))?;
-        statement.bind((3, key_path.as_path().to_str().unwrap()))?;
-        statement.bind((4, dump_path.as_path().to_str().unwrap()))?;
-        Ok(Some(AcmeUser::scan_statement(statement)?))
+    /// Picks the user up, creating the entry and its directories on the first run.
+    fn get_or_create_user(&self, repositories: &Repositories) -> anyhow::Result<AcmeUser> {
+        let users = repositories.users.as_ref();
+        if let Some(user) = users.find(self.user_id.as_str()).map_err(|e| anyhow!(e.to_string()))? {
+            return Ok(user);
+        }
+        info!("User not found, creating user entry in database..");
+        let system = FileSystem::new(self.path.as_str()).map_err(|e| anyhow!(e.to_string()))?;
+        let path = system
+            .ensure_sub_dir(format!("{}/login-keys/{}", self.user_id, self.key_type).as_str())
+            .map_err(|e| anyhow!("Refusing key directory for user {}: {}", self.user_id, e))?;
+        let dump_path = system
+            .ensure_sub_dir(self.user_id.as_str())
+            .map_err(|e| anyhow!("Refusing user directory for user {}: {}", self.user_id, e))?;
+        let user = users
+            .create(
+                self.user_id.as_str(),
+                self.key_type.as_str(),
+                path.as_path().to_str().unwrap(),
+                dump_path.as_path().to_str().unwrap(),
+            )
+            .map_err(|e| anyhow!("User could not be picked back up: {}", e))?;
+        info!("User created: User [ id: {}, user_id: {} ] with key type: {}", user.id, user.user_id, user.key_type);
+        repositories
+            .audit
+            .append(NewAuditEvent {
+                kind: AuditKind::UserCreated,
+                user_id: Some(user.user_id.as_str()),
+                subject: None,
+                details: json!({"key_type": user.key_type, "key_path": user.key_path}),
+            })
+            .map_err(|e| anyhow!(e.to_string()))?;
+        Ok(user)
     }
 }
 #[async_trait]
 impl Job for InitializeLocalUserJob {
     fn job_type(&self) -> &'static str {
-        "initialize-local-user-job"
+        Self::JOB_TYPE
     }
     fn payload(&self) -> Value {
         serde_json::to_value(self).unwrap()
     }
+    fn concurrency_key(&self) -> Option<String> {
+        Some(self.user_id.clone())
+    }
+    fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+    fn priority(&self) -> Priority {
+        Priority::High
+    }
     #[instrument(level = "trace", name = "initialize_local_user_job", fields(job_name = %self.job_type()), skip_all)]
-    async fn execute(&self, handle: SchedulerHandle) -> anyhow::Result<()>{
-        let connection = DatabaseConnection::get_connection().unwrap();
-        let mut user = Self::get_user(self.user_id.as_str(), &connection);
-        if user.is_err() {
-            info!("User not found, creating user entry in database..");
-            let system = FileSystem::new(self.path.as_str()).unwrap();
-            let path = system.ensure_sub_dir(format!("{}/login-keys/{}", self.user_id, self.key_type).as_str()).unwrap();
-            let dump_path = system.ensure_sub_dir(format!("{}", self.user_id).as_str()).unwrap();
-            let result = self.new_user(path, dump_path, &connection).unwrap();
-            if let Some(acme_user) = result {
-                info!("User created: User [ id: {}, user_id: {} ] with key type: {}", acme_user.id, acme_user.user_id, acme_user.key_type);
-                user = Ok(Some(acme_user));
-            } else {
-                return Err(anyhow::anyhow!("User could not be picked back up!"));
-            }
-        }
-        let user = user.unwrap().unwrap();
-        info!("User found in database: User [ id: \"{}\", user_id: \"{}\" ]", user.id, user.user_id);
-        let key = self.check_for_required_files(user).unwrap();
-        Ok(())
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()>{
+        // key generation blocks as well, RSA keys in particular take a while
+        let job = self.clone();
+        context
+            .with_repositories(move |repositories| {
+                let user = job.get_or_create_user(repositories)?;
+                info!("User found in database: User [ id: \"{}\", user_id: \"{}\" ]", user.id, user.user_id);
+                // a new key would orphan the account the CA knows by the lost one
+                let registered = repositories.accounts.registered(&user).map_err(|e| anyhow!(e.to_string()))?;
+                let key_file = Path::new(user.key_path.as_str()).join(format!("{}.pem", user.user_id));
+                if registered && !key_file.exists() {
+                    return Err(anyhow!(
+                        "Key {} of user {} is missing but an account is registered with it, restore it or run db check",
+                        key_file.display(),
+                        user.user_id
+                    ))
+                    .context(Permanent);
+                }
+                job.check_for_required_files(user).map_err(|e| anyhow!(e.to_string()))?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InitializeLocalUserJob;
+    use crate::job_execution::job_base::Scheduler;
+    use persistence::data_model::{AuditKind, JobStatus};
+    use persistence::repository::{AuditFilter, Repositories};
+
+    #[tokio::test]
+    async fn test_user_and_key_are_created_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_string();
+        let repositories = Repositories::in_memory();
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let job = || InitializeLocalUserJob::new(path.clone(), "ec-p256".to_string(), "a1b2c3".to_string());
+
+        let first = handle.submit(job()).await.unwrap();
+        assert_eq!(handle.completion(first).await.unwrap(), JobStatus::Succeeded);
+        let user = repositories.users.find("a1b2c3").unwrap().unwrap();
+        let key_file = dir.path().join("a1b2c3/login-keys/ec-p256/a1b2c3.pem");
+        assert_eq!(std::path::Path::new(user.key_path.as_str()), key_file.parent().unwrap());
+        let key = std::fs::read(&key_file).unwrap();
+
+        let second = handle.submit(job()).await.unwrap();
+        assert_eq!(handle.completion(second).await.unwrap(), JobStatus::Succeeded);
+        assert_eq!(repositories.users.find("a1b2c3").unwrap().unwrap().id, user.id);
+        assert_eq!(std::fs::read(&key_file).unwrap(), key);
+        let created = repositories.audit.list(&AuditFilter::default()).unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].kind, AuditKind::UserCreated);
+        handle.shutdown().await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_lost_key_of_a_registered_account_is_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_string();
+        let repositories = Repositories::in_memory();
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let job = || InitializeLocalUserJob::new(path.clone(), "ec-p256".to_string(), "a1b2c3".to_string());
+        let first = handle.submit(job()).await.unwrap();
+        assert_eq!(handle.completion(first).await.unwrap(), JobStatus::Succeeded);
+        let user = repositories.users.find("a1b2c3").unwrap().unwrap();
+        let account = repositories.accounts.ensure(user.id, "default", "https://ca/dir", user.key_path.as_str()).unwrap();
+        repositories.accounts.register(account.account_id, "https://ca/acct/1").unwrap();
+        let key_file = dir.path().join("a1b2c3/login-keys/ec-p256/a1b2c3.pem");
+        std::fs::remove_file(&key_file).unwrap();
+
+        let second = handle.submit(job()).await.unwrap();
+        assert_eq!(handle.completion(second).await.unwrap(), JobStatus::Failed);
+        assert!(!key_file.exists());
+        handle.shutdown().await;
+    }
+}
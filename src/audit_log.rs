@@ -0,0 +1,129 @@
+use crate::cli::AuditFilterArgs;
+use chrono::DateTime;
+use common_utils::ApplicationConfig;
+use persistence::data_model::{AuditEvent, AuditKind};
+use persistence::repository::{AuditFilter, AuditRepository, NewAuditEvent};
+use serde_json::json;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+use tracing::info;
+
+/// Appends a `config-changed` event when the effective configuration differs from the one
+/// recorded last. Only a fingerprint is stored, the configuration may hold secrets.
+pub fn record_config_change(config: &ApplicationConfig, audit: &Arc<dyn AuditRepository>) -> Result<bool, Box<dyn Error>> {
+    let fingerprint = config_fingerprint(config);
+    let filter = AuditFilter {
+        kind: Some(AuditKind::ConfigChanged),
+        ..AuditFilter::default()
+    };
+    let recorded = audit.list(&filter)?.pop().map(|event| event.subject.unwrap_or_default());
+    if recorded.as_deref() == Some(fingerprint.as_str()) {
+        return Ok(false);
+    }
+    let certificate_authorities: Vec<_> = config.certificate_authorities.iter().map(|ca| ca.name.as_str()).collect();
+    audit.append(NewAuditEvent {
+        kind: AuditKind::ConfigChanged,
+        user_id: Some(config.user_id.as_str()),
+        subject: Some(fingerprint.as_str()),
+        details: json!({"previous": recorded, "certificate_authorities": certificate_authorities}),
+    })?;
+    info!("Configuration changed since the last start, recorded in the audit log");
+    Ok(true)
+}
+
+fn config_fingerprint(config: &ApplicationConfig) -> String {
+    openssl::sha::sha256(format!("{:?}", config).as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The audit filter of `audit list` and `audit export`.
+pub fn audit_filter(args: &AuditFilterArgs) -> Result<AuditFilter, Box<dyn Error>> {
+    Ok(AuditFilter {
+        kind: args.kind.as_deref().map(AuditKind::from_str).transpose()?,
+        user_id: args.user.clone(),
+        since: args.since.as_deref().map(parse_timestamp).transpose()?,
+        until: args.until.as_deref().map(parse_timestamp).transpose()?,
+    })
+}
+
+/// Writes `events` to `path` as JSON Lines, one event with its hashes per line.
+pub fn export_events(events: &[AuditEvent], path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for event in events {
+        writeln!(writer, "{}", event.to_json())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Unix seconds of an RFC 3339 timestamp like `2026-01-31T00:00:00Z`.
+fn parse_timestamp(value: &str) -> Result<i64, Box<dyn Error>> {
+    Ok(DateTime::parse_from_rfc3339(value)
+        .map_err(|e| format!("Invalid timestamp {}: {}", value, e))?
+        .timestamp())
+}
+
+pub(crate) fn format_timestamp(timestamp: i64) -> String {
+    match DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => time.to_rfc3339(),
+        None => timestamp.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_timestamp, record_config_change};
+    use common_utils::ApplicationConfig;
+    use persistence::data_model::AuditKind;
+    use persistence::repository::{AuditFilter, Repositories};
+
+    fn config(workers: usize) -> ApplicationConfig {
+        ApplicationConfig {
+            application_mode: false,
+            certificate_authorities: vec![],
+            base_dir: "/opt/acme-sentry".to_string(),
+            output_dir: "/opt/acme-sentry/out".to_string(),
+            user_id: "a1b2c3".to_string(),
+            user_email: "admin@example.org".to_string(),
+            key_type: "ec-p256".to_string(),
+            logging_level: None,
+            preferred_chain: None,
+            accept_invalid_certs: false,
+            output_formats: vec![],
+            pkcs12_password: Some("secret".to_string()),
+            file_owner: None,
+            file_group: None,
+            fix_key_permissions: false,
+            directory_refresh: None,
+            missed_runs: "catch-up".to_string(),
+            workers,
+            max_ca_requests: 2,
+            shutdown_timeout: 30,
+            certificates: vec![],
+            reconciliation: "1h".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_config_changes_are_recorded_once() {
+        let repositories = Repositories::in_memory();
+        assert!(record_config_change(&config(4), &repositories.audit).unwrap());
+        assert!(!record_config_change(&config(4), &repositories.audit).unwrap());
+        assert!(record_config_change(&config(8), &repositories.audit).unwrap());
+        let events = repositories.audit.list(&AuditFilter::default()).unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.kind == AuditKind::ConfigChanged && !e.details.contains("secret")));
+        assert!(events[1].details.contains(events[0].subject.as_deref().unwrap()));
+    }
+
+    #[test]
+    fn test_timestamps_are_rfc3339() {
+        assert_eq!(parse_timestamp("1970-01-01T00:01:00Z").unwrap(), 60);
+        assert_eq!(parse_timestamp("1970-01-01T01:01:00+01:00").unwrap(), 60);
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+}
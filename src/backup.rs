@@ -0,0 +1,93 @@
+use crate::config::{ENV_PREFIX, USER_ID_FILE, env_value};
+use common_utils::{ApplicationConfig, InternalIdTooling};
+use persistence::backup::{BackupArchive, BackupManifest, RestoreSummary};
+use persistence::data_model::AuditKind;
+use persistence::repository::{NewAuditEvent, Repositories};
+use serde_json::json;
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use tracing::info;
+
+const PASSPHRASE_VARIABLE: &str = "BACKUP_PASSPHRASE";
+
+/// Checks that the backup at `path` decrypts and is intact, returns what it contains.
+pub fn verify(path: &str, passphrase_file: Option<&str>) -> Result<BackupManifest, Box<dyn Error>> {
+    Ok(BackupArchive::decrypt(&fs::read(path)?, &passphrase(passphrase_file)?)?.manifest)
+}
+
+/// Restores the backup at `path` into the base directory, which must not hold a database yet.
+pub fn restore(config: &ApplicationConfig, path: &str, passphrase_file: Option<&str>) -> Result<RestoreSummary, Box<dyn Error>> {
+    let archive = BackupArchive::decrypt(&fs::read(path)?, &passphrase(passphrase_file)?)?;
+    let summary = archive.restore(config.base_dir.as_str())?;
+    let repositories = Repositories::sqlite(config.base_dir.as_str());
+    repositories.audit.append(NewAuditEvent {
+        kind: AuditKind::BackupRestored,
+        user_id: None,
+        subject: Some(path),
+        details: json!({
+            "created_at": archive.manifest.created_at,
+            "from_base_dir": archive.manifest.base_dir,
+            "files": summary.files,
+            "rewritten_paths": summary.rewritten_paths,
+        }),
+    })?;
+    Ok(summary)
+}
+
+/// Writes an encrypted backup of the database and the key material to `path`, which must not
+/// exist yet. The archive is written next to it first, a failed write leaves nothing behind.
+pub fn create(config: &ApplicationConfig, path: &str, passphrase_file: Option<&str>) -> Result<BackupManifest, Box<dyn Error>> {
+    if Path::new(path).exists() {
+        return Err(format!("{} exists already", path).into());
+    }
+    let passphrase = passphrase(passphrase_file)?;
+    let repositories = Repositories::sqlite(config.base_dir.as_str());
+    repositories.schema.migrate()?;
+    let user_id_file = Path::new(config.base_dir.as_str()).join(USER_ID_FILE);
+    let user_id_file = user_id_file.to_str().ok_or("Base dir isn't valid unicode")?;
+    let archive = BackupArchive::create(config.base_dir.as_str(), &[config.output_dir.as_str(), user_id_file])?;
+    let encrypted = archive.encrypt(&passphrase)?;
+    let tmp_path = format!("{}.{}.tmp", path, InternalIdTooling::new_compact_id());
+    let written = write_archive(tmp_path.as_str(), &encrypted).and_then(|_| Ok(fs::rename(&tmp_path, path)?));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    repositories.audit.append(NewAuditEvent {
+        kind: AuditKind::BackupCreated,
+        user_id: None,
+        subject: Some(path),
+        details: json!({"files": archive.manifest.files.len(), "schema_version": archive.manifest.schema_version}),
+    })?;
+    info!("Backup written to {}", path);
+    Ok(archive.manifest)
+}
+
+fn write_archive(path: &str, archive: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    file.write_all(archive)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn passphrase(passphrase_file: Option<&str>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let passphrase = match passphrase_file {
+        Some(file) => fs::read_to_string(file)?,
+        None => env_value(&|name| env::var(name).ok(), PASSPHRASE_VARIABLE)?.ok_or_else(|| {
+            format!(
+                "Pass --passphrase-file or set {}{} or {}{}_FILE",
+                ENV_PREFIX, PASSPHRASE_VARIABLE, ENV_PREFIX, PASSPHRASE_VARIABLE
+            )
+        })?,
+    };
+    Ok(passphrase.trim_end_matches(['\r', '\n']).as_bytes().to_vec())
+}
@@ -0,0 +1,291 @@
+use acme_client::certificate::CertificateChain;
+use acme_client::keys::PrivateKey;
+use common_utils::fs::{FileOptions, FileSystem, lookup_gid, lookup_uid};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const ARCHIVE_DIR: &str = "archive";
+const LIVE_DIR: &str = "live";
+
+/// Extra files written next to the PEM set every certificate gets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputFormat {
+    /// `privkey` + `fullchain` in one file, the way HAProxy wants it.
+    Haproxy,
+    /// Password protected PKCS#12 with key, leaf and intermediates.
+    Pkcs12,
+    /// DER encoded leaf certificate.
+    Der,
+}
+
+impl OutputFormat {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> Result<OutputFormat, Box<dyn Error>> {
+        match value.to_lowercase().as_str() {
+            "haproxy" => Ok(OutputFormat::Haproxy),
+            "pkcs12" | "p12" => Ok(OutputFormat::Pkcs12),
+            "der" => Ok(OutputFormat::Der),
+            _ => Err(format!("Unknown output format {}", value).into()),
+        }
+    }
+    fn file_names(&self) -> (&'static str, &'static str) {
+        match self {
+            OutputFormat::Haproxy => ("combined", "pem"),
+            OutputFormat::Pkcs12 => ("cert", "p12"),
+            OutputFormat::Der => ("cert", "der"),
+        }
+    }
+}
+
+/// Certbot style layout under the output dir:
+///
+/// ```text
+/// archive/<name>/cert1.pem chain1.pem fullchain1.pem privkey1.pem
+/// live/<name> -> .<name>.1
+/// live/.<name>.1/cert.pem -> ../../archive/<name>/cert1.pem
+/// ```
+///
+/// Every issuance adds a new version to `archive` and a directory of links to it to `live`.
+/// `live/<name>` is repointed at that directory in one rename, so readers see either the old
+/// or the new set of files but never a mix of both.
+pub struct CertificateOutput {
+    system: FileSystem,
+    formats: Vec<OutputFormat>,
+    pkcs12_password: Option<String>,
+    owner: Option<u32>,
+    group: Option<u32>,
+}
+
+impl CertificateOutput {
+    pub fn new(output_dir: &str, formats: &[String], pkcs12_password: Option<String>) -> Result<Self, Box<dyn Error>> {
+        let formats = formats
+            .iter()
+            .map(|f| OutputFormat::from_str(f.as_str()))
+            .collect::<Result<Vec<OutputFormat>, Box<dyn Error>>>()?;
+        if formats.contains(&OutputFormat::Pkcs12) && pkcs12_password.is_none() {
+            return Err("PKCS#12 output requires a password".into());
+        }
+        Ok(CertificateOutput {
+            system: FileSystem::new(output_dir)?,
+            formats,
+            pkcs12_password,
+            owner: None,
+            group: None,
+        })
+    }
+
+    /// Owner and group (names or ids) the written files are handed over to.
+    pub fn with_owner(mut self, owner: Option<&str>, group: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        self.owner = owner.map(lookup_uid).transpose()?;
+        self.group = group.map(lookup_gid).transpose()?;
+        Ok(self)
+    }
+
+    /// Writes a new archive version and repoints `live/<name>`, returns the version number.
+    pub fn write(&self, name: &str, chain: &CertificateChain, key: &PrivateKey) -> Result<u32, Box<dyn Error>> {
+        FileSystem::validate_file_name(name)?;
+        let archive = format!("{}/{}", ARCHIVE_DIR, name);
+        self.system.ensure_sub_dir(archive.as_str())?;
+        let version = self.next_version(archive.as_str())?;
+        let links = format!("{}/{}", LIVE_DIR, Self::links_dir(name, version));
+        self.system.ensure_sub_dir(links.as_str())?;
+
+        let privkey = key.get_pem_bytes()?;
+        let fullchain = chain.fullchain_pem()?;
+        let public = FileOptions::public().with_owner(self.owner, self.group);
+        let private = FileOptions::private().with_owner(self.owner, self.group);
+        let mut files: Vec<(&str, &str, Vec<u8>, &FileOptions)> = vec![
+            ("cert", "pem", chain.cert_pem()?, &public),
+            ("chain", "pem", chain.chain_pem()?, &public),
+            ("fullchain", "pem", fullchain.clone(), &public),
+            ("privkey", "pem", privkey.clone(), &private),
+        ];
+        for format in &self.formats {
+            let (stem, extension) = format.file_names();
+            let (data, options) = match format {
+                OutputFormat::Haproxy => ([privkey.as_slice(), fullchain.as_slice()].concat(), &private),
+                OutputFormat::Pkcs12 => (
+                    chain.to_pkcs12(key, name, self.pkcs12_password.as_deref().unwrap_or_default())?,
+                    &private,
+                ),
+                OutputFormat::Der => (chain.leaf().to_der()?, &public),
+            };
+            files.push((stem, extension, data, options));
+        }
+        for (stem, extension, data, options) in files {
+            let archived = format!("{}{}.{}", stem, version, extension);
+            self.system
+                .write_to_file_with(archive.as_str(), archived.as_str(), data.as_slice(), options)?;
+            let target = Path::new("..").join("..").join(archive.as_str()).join(archived.as_str());
+            self.system.symlink(links.as_str(), format!("{}.{}", stem, extension).as_str(), &target)?;
+        }
+        self.repoint_live(name, version)?;
+        info!("Certificate {} written as version {}", name, version);
+        Ok(version)
+    }
+
+    /// `live/<name>`, the link itself rather than the version it currently points at.
+    pub fn live_dir(&self, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+        FileSystem::validate_file_name(name)?;
+        Ok(self.system.sub_dir(LIVE_DIR)?.join(name))
+    }
+
+    fn links_dir(name: &str, version: u32) -> String {
+        format!(".{}.{}", name, version)
+    }
+
+    /// Swaps `live/<name>` over to the links of `version`. The links of the version before
+    /// are kept for readers that are still resolving paths through them, older ones go.
+    fn repoint_live(&self, name: &str, version: u32) -> Result<(), Box<dyn Error>> {
+        let live = self.system.sub_dir(LIVE_DIR)?;
+        let current = live.join(name);
+        // certificates written before live/<name> became a link have a directory of links there
+        if current.symlink_metadata().is_ok_and(|metadata| metadata.is_dir()) {
+            fs::rename(&current, live.join(Self::links_dir(name, 0)))?;
+        }
+        self.system.symlink(LIVE_DIR, name, Path::new(Self::links_dir(name, version).as_str()))?;
+        let prefix = format!(".{}.", name);
+        for dir in self.system.list_dirs(LIVE_DIR)? {
+            let outdated = dir
+                .strip_prefix(prefix.as_str())
+                .and_then(|old| old.parse::<u32>().ok())
+                .is_some_and(|old| old + 1 < version);
+            if outdated {
+                fs::remove_dir_all(live.join(dir))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_version(&self, archive: &str) -> Result<u32, Box<dyn Error>> {
+        let latest = self
+            .system
+            .list_files(archive)?
+            .iter()
+            .filter_map(|f| f.strip_prefix("cert")?.strip_suffix(".pem")?.parse::<u32>().ok())
+            .max()
+            .unwrap_or(0);
+        Ok(latest + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::certificate_output::CertificateOutput;
+    use acme_client::certificate::CertificateChain;
+    use acme_client::crypto::SupportedKey;
+    use acme_client::keys::PrivateKey;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use std::fs;
+
+    fn self_signed(key: &PrivateKey) -> CertificateChain {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "example.org").unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key.k).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(90).unwrap()).unwrap();
+        builder.sign(&key.k, MessageDigest::sha256()).unwrap();
+        CertificateChain::from_pem(&builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_live_links_follow_latest_archive() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let output = CertificateOutput::new(tmp_dir.path().to_str().unwrap(), &[], None).unwrap();
+        let first_key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        let second_key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        assert_eq!(output.write("web", &self_signed(&first_key), &first_key).unwrap(), 1);
+        assert_eq!(output.write("web", &self_signed(&second_key), &second_key).unwrap(), 2);
+
+        let live = output.live_dir("web").unwrap();
+        assert_eq!(fs::read_link(&live).unwrap().to_str().unwrap(), ".web.2");
+        let link = fs::read_link(live.join("privkey.pem")).unwrap();
+        assert_eq!(link.to_str().unwrap(), "../../archive/web/privkey2.pem");
+        assert_eq!(fs::read(live.join("privkey.pem")).unwrap(), second_key.get_pem_bytes().unwrap());
+        for file in ["cert.pem", "chain.pem", "fullchain.pem"] {
+            assert!(live.join(file).exists());
+        }
+        assert!(tmp_dir.path().join("archive/web/cert1.pem").exists());
+
+        output.write("web", &self_signed(&first_key), &first_key).unwrap();
+        let live_dirs = |dir: &str| tmp_dir.path().join("live").join(dir).exists();
+        assert_eq!((live_dirs(".web.1"), live_dirs(".web.2"), live_dirs(".web.3")), (false, true, true));
+        assert_eq!(fs::read(live.join("privkey.pem")).unwrap(), first_key.get_pem_bytes().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_live_dirs_of_earlier_layouts_are_replaced() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let output = CertificateOutput::new(tmp_dir.path().to_str().unwrap(), &[], None).unwrap();
+        let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        output.write("web", &self_signed(&key), &key).unwrap();
+        let live = tmp_dir.path().join("live");
+        fs::remove_file(live.join("web")).unwrap();
+        fs::rename(live.join(".web.1"), live.join("web")).unwrap();
+
+        assert_eq!(output.write("web", &self_signed(&key), &key).unwrap(), 2);
+        assert_eq!(fs::read_link(live.join("web")).unwrap().to_str().unwrap(), ".web.2");
+        assert!(!live.join(".web.0").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_material_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let formats = ["haproxy".to_string()];
+        let output = CertificateOutput::new(tmp_dir.path().to_str().unwrap(), &formats, None).unwrap();
+        let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        output.write("web", &self_signed(&key), &key).unwrap();
+        let mode = |file: &str| fs::metadata(output.live_dir("web").unwrap().join(file)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("privkey.pem"), 0o600);
+        assert_eq!(mode("combined.pem"), 0o600);
+        assert_eq!(mode("fullchain.pem"), 0o644);
+    }
+
+    #[test]
+    fn test_extra_formats() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let formats = ["haproxy".to_string(), "pkcs12".to_string(), "der".to_string()];
+        let output = CertificateOutput::new(tmp_dir.path().to_str().unwrap(), &formats, Some("secret".to_string())).unwrap();
+        let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        let chain = self_signed(&key);
+        output.write("web", &chain, &key).unwrap();
+
+        let live = output.live_dir("web").unwrap();
+        let combined = fs::read(live.join("combined.pem")).unwrap();
+        assert!(combined.starts_with(&key.get_pem_bytes().unwrap()));
+        assert!(combined.ends_with(&chain.fullchain_pem().unwrap()));
+        assert_eq!(fs::read(live.join("cert.der")).unwrap(), chain.leaf().to_der().unwrap());
+        let p12 = openssl::pkcs12::Pkcs12::from_der(&fs::read(live.join("cert.p12")).unwrap()).unwrap();
+        assert!(p12.parse2("secret").is_ok());
+    }
+
+    #[test]
+    fn test_rejects_names_outside_output_dir() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let output = CertificateOutput::new(tmp_dir.path().to_str().unwrap(), &[], None).unwrap();
+        let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        for name in ["../escape", "nested/name", "..", ""] {
+            assert!(output.write(name, &self_signed(&key), &key).is_err(), "{} accepted", name);
+        }
+        assert!(!tmp_dir.path().parent().unwrap().join("escape").exists());
+    }
+
+    #[test]
+    fn test_invalid_format_configuration() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().to_str().unwrap();
+        assert!(CertificateOutput::new(path, &["jks".to_string()], None).is_err());
+        assert!(CertificateOutput::new(path, &["pkcs12".to_string()], None).is_err());
+    }
+}
@@ -0,0 +1,854 @@
+use crate::acme_jobs::account_management::{AccountAction, AccountJob};
+use crate::acme_jobs::certificate_order::CertificateOrderJob;
+use crate::acme_jobs::certificate_revocation::CertificateRevocationJob;
+use crate::acme_jobs::job_registry;
+use crate::backup;
+use crate::audit_log::{audit_filter, export_events, format_timestamp};
+use crate::config::EffectiveSetting;
+use crate::config_check;
+use crate::doctor::Doctor;
+use crate::job_execution::job_base::{Job, JobId, SchedulerHandle};
+use clap::Subcommand;
+use common_utils::{ApplicationConfig, CertificateAuthority, CertificateDefinition};
+use persistence::data_model::{AcmeUser, AuditEvent, JobRecord, JobStatus};
+use persistence::repository::{AuditFilter, Repositories};
+use serde_json::{Value, json};
+use std::error::Error;
+use std::path::Path;
+
+/// How command results are printed, `json` is meant for scripts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    Text,
+    Json,
+}
+
+pub fn output_parse(value: &str) -> Result<OutputMode, String> {
+    match value {
+        "text" => Ok(OutputMode::Text),
+        "json" => Ok(OutputMode::Json),
+        other => Err(format!("Unknown output {}, expected text or json", other)),
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    #[command(subcommand, about = "Register and manage the ACME account of the user")]
+    Account(AccountCommand),
+    #[command(subcommand, about = "Issue, revoke and inspect certificates")]
+    Cert(CertCommand),
+    #[command(subcommand, about = "Inspect and refresh the directories of the CAs")]
+    Directory(DirectoryCommand),
+    #[command(subcommand, about = "Inspect, retry and cancel persisted jobs")]
+    Jobs(JobsCommand),
+    #[command(subcommand, about = "Maintain the database")]
+    Db(DbCommand),
+    #[command(subcommand, about = "List, verify and export the audit log")]
+    Audit(AuditCommand),
+    #[command(subcommand, about = "Check the configuration")]
+    Config(ConfigCommand),
+    #[command(about = "Run in application mode until interrupted, like --application-mode")]
+    Daemon,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    #[command(about = "Check the YAML configuration and report every problem with its line, fails if there are any")]
+    Check,
+    #[command(about = "Print the effective configuration and where each setting comes from, secrets redacted")]
+    Show,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum AccountCommand {
+    #[command(about = "Register the account key with the CA, agreeing to its terms of service")]
+    Register {
+        #[arg(long, help = "Name of the CA, may be left out when only one is configured")]
+        ca: Option<String>,
+    },
+    #[command(about = "Show the accounts of the user")]
+    Show {
+        #[arg(long, help = "Only show the account with this CA")]
+        ca: Option<String>,
+    },
+    #[command(about = "Replace the contact of the account")]
+    Update {
+        #[arg(long, help = "Name of the CA, may be left out when only one is configured")]
+        ca: Option<String>,
+        #[arg(long, help = "New contact email, defaults to the configured one")]
+        email: Option<String>,
+    },
+    #[command(about = "Deactivate the account for good")]
+    Deactivate {
+        #[arg(long, help = "Name of the CA, may be left out when only one is configured")]
+        ca: Option<String>,
+        #[arg(long, default_value_t = false, help = "Confirm that the account can't be used afterwards")]
+        yes: bool,
+    },
+    #[command(about = "Move the account over to a new key")]
+    Rollover {
+        #[arg(long, help = "Name of the CA, may be left out when only one is configured")]
+        ca: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CertCommand {
+    #[command(about = "Order a certificate declared in the configuration and put it live")]
+    Issue { name: String },
+    #[command(about = "Order an issued certificate again, whether it's due or not")]
+    Renew { name: String },
+    #[command(about = "Revoke the live certificate with the CA that issued it")]
+    Revoke {
+        name: String,
+        #[arg(long, help = "CRL reason code, e.g. 1 for a compromised key")]
+        reason: Option<u32>,
+    },
+    #[command(about = "List the certificates issued to the user")]
+    List,
+    #[command(about = "Show the certificate issued last under a name")]
+    Show { name: String },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DirectoryCommand {
+    #[command(about = "Show the stored directories")]
+    Show {
+        #[arg(long, help = "Only show the directory of this CA")]
+        ca: Option<String>,
+    },
+    #[command(about = "Fetch the directories from the CAs")]
+    Refresh {
+        #[arg(long, help = "Only refresh the directory of this CA")]
+        ca: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum JobsCommand {
+    #[command(about = "List the persisted jobs")]
+    List {
+        #[arg(long, help = "Only list jobs in this status, e.g. failed, dead lists the dead-letter queue")]
+        status: Option<String>,
+    },
+    #[command(about = "Run a dead-lettered job again and wait for it")]
+    Retry { job_id: i64 },
+    #[command(about = "Cancel a queued, running or retrying job, a running daemon picks the cancellation up")]
+    Cancel { job_id: i64 },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DbCommand {
+    #[command(about = "Bring the schema up to date")]
+    Migrate,
+    #[command(about = "Check the database against the files on disk")]
+    Check {
+        #[arg(long, default_value_t = false, help = "Repair what can be repaired safely")]
+        repair: bool,
+    },
+    #[command(about = "Write an encrypted backup of the database and the key material")]
+    Backup {
+        file: String,
+        #[arg(long, help = "File holding the backup passphrase, defaults to ACME_SENTRY_BACKUP_PASSPHRASE")]
+        passphrase_file: Option<String>,
+    },
+    #[command(about = "Restore an encrypted backup into the base directory, which must not hold a database yet")]
+    Restore {
+        file: String,
+        #[arg(long, help = "File holding the backup passphrase, defaults to ACME_SENTRY_BACKUP_PASSPHRASE")]
+        passphrase_file: Option<String>,
+    },
+    #[command(about = "Check that an encrypted backup is intact")]
+    VerifyBackup {
+        file: String,
+        #[arg(long, help = "File holding the backup passphrase, defaults to ACME_SENTRY_BACKUP_PASSPHRASE")]
+        passphrase_file: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum AuditCommand {
+    #[command(about = "List the audit events")]
+    List {
+        #[command(flatten)]
+        filter: AuditFilterArgs,
+    },
+    #[command(about = "Verify the hash chain of the audit log")]
+    Verify,
+    #[command(about = "Export the audit events as JSON Lines")]
+    Export {
+        file: String,
+        #[command(flatten)]
+        filter: AuditFilterArgs,
+    },
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct AuditFilterArgs {
+    #[arg(long, help = "Only events of this kind, e.g. certificate-issued")]
+    pub kind: Option<String>,
+    #[arg(long, help = "Only events of this user id")]
+    pub user: Option<String>,
+    #[arg(long, help = "Only events at or after this RFC 3339 timestamp")]
+    pub since: Option<String>,
+    #[arg(long, help = "Only events before this RFC 3339 timestamp")]
+    pub until: Option<String>,
+}
+
+/// What a command prints, once for people and once for scripts. A report can carry a failure,
+/// it's printed all the same before the command exits with it.
+pub struct Report {
+    text: Vec<String>,
+    json: Value,
+    failure: Option<String>,
+}
+
+impl Report {
+    fn new(text: Vec<String>, json: Value) -> Self {
+        Report { text, json, failure: None }
+    }
+    pub fn print(&self, mode: OutputMode) -> Result<(), Box<dyn Error>> {
+        match mode {
+            OutputMode::Text => self.text.iter().for_each(|line| println!("{}", line)),
+            OutputMode::Json => println!("{}", self.json),
+        }
+        match &self.failure {
+            Some(failure) => Err(failure.clone().into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `config check`, runs before the configuration is loaded as loading fails on the first
+/// problem.
+pub fn check_config(file: Option<&str>) -> Result<Report, Box<dyn Error>> {
+    let file = file.ok_or("config check needs --yaml-config or ACME_SENTRY_YAML_CONFIG")?;
+    let diagnostics = config_check::check_file(file, true);
+    let mut report = Report::new(
+        diagnostics.iter().map(|diagnostic| format!("{}:{}", file, diagnostic)).collect(),
+        json!({"file": file, "diagnostics": diagnostics}),
+    );
+    match diagnostics.len() {
+        0 => report.text.push(format!("{} is valid", file)),
+        problems => report.failure = Some(format!("{} problem(s) in {}", problems, file)),
+    }
+    Ok(report)
+}
+
+/// The effective configuration, one setting per line with the layer it was taken from.
+pub fn show_config(settings: &[EffectiveSetting]) -> Report {
+    Report::new(
+        settings
+            .iter()
+            .map(|setting| format!("{} = {} ({})", setting.key, setting.value, setting.source.as_str()))
+            .collect(),
+        json!({"settings": settings}),
+    )
+}
+
+/// Runs the backup commands, before anything else opens the database. Returns `None` for the
+/// other commands.
+pub fn run_backup(command: &Command, config: &ApplicationConfig) -> Result<Option<Report>, Box<dyn Error>> {
+    let report = match command {
+        Command::Db(DbCommand::Backup { file, passphrase_file }) => {
+            let manifest = backup::create(config, file.as_str(), passphrase_file.as_deref())?;
+            Report::new(
+                vec![format!("Backup of the database and {} file(s) written to {}", manifest.files.len(), file)],
+                json!({"file": file, "files": manifest.files.len(), "schema_version": manifest.schema_version}),
+            )
+        }
+        Command::Db(DbCommand::Restore { file, passphrase_file }) => {
+            let summary = backup::restore(config, file.as_str(), passphrase_file.as_deref())?;
+            Report::new(
+                vec![format!(
+                    "Backup restored into {}: {} file(s), {} stored path(s) rewritten, schema version {}",
+                    config.base_dir, summary.files, summary.rewritten_paths, summary.schema_version
+                )],
+                json!({
+                    "base_dir": config.base_dir,
+                    "files": summary.files,
+                    "rewritten_paths": summary.rewritten_paths,
+                    "schema_version": summary.schema_version,
+                }),
+            )
+        }
+        Command::Db(DbCommand::VerifyBackup { file, passphrase_file }) => {
+            let manifest = backup::verify(file.as_str(), passphrase_file.as_deref())?;
+            Report::new(
+                vec![format!(
+                    "Backup {} is intact: schema version {}, {} file(s), taken from {}",
+                    file,
+                    manifest.schema_version,
+                    manifest.files.len(),
+                    manifest.base_dir
+                )],
+                json!({"file": file, "manifest": manifest}),
+            )
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(report))
+}
+
+/// Runs the commands that only need the database and the files on disk, returns `None` for
+/// the ones that have to go through the scheduler, see [`run_scheduled`].
+pub fn run_offline(command: &Command, config: &ApplicationConfig, repositories: &Repositories) -> Result<Option<Report>, Box<dyn Error>> {
+    let report = match command {
+        Command::Account(AccountCommand::Show { ca }) => show_accounts(config, repositories, ca.as_deref())?,
+        Command::Cert(CertCommand::List) => list_certificates(config, repositories)?,
+        Command::Cert(CertCommand::Show { name }) => show_certificate(config, repositories, name.as_str())?,
+        Command::Directory(DirectoryCommand::Show { ca }) => show_directories(config, repositories, ca.as_deref())?,
+        Command::Jobs(JobsCommand::List { status }) => jobs_report(match status.as_deref() {
+            Some("dead") => repositories.jobs.dead_letters()?,
+            status => repositories.jobs.list(status.map(JobStatus::from_str).transpose()?)?,
+        }),
+        Command::Db(DbCommand::Migrate) => {
+            let version = repositories.schema.migrate()?;
+            Report::new(vec![format!("Database schema is at version {}", version)], json!({"schema_version": version}))
+        }
+        Command::Db(DbCommand::Check { repair }) => check_database(config, repositories, *repair)?,
+        Command::Audit(audit) => audit_log(audit, repositories)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(report))
+}
+
+/// The CAs whose directory has to be refreshed before a scheduled command runs.
+pub fn certificate_authorities<'a>(command: &Command, config: &'a ApplicationConfig) -> Result<Vec<&'a CertificateAuthority>, Box<dyn Error>> {
+    match command {
+        Command::Account(
+            AccountCommand::Register { ca } | AccountCommand::Update { ca, .. } | AccountCommand::Deactivate { ca, .. } | AccountCommand::Rollover { ca },
+        ) => Ok(vec![select_ca(config, ca.as_deref())?]),
+        Command::Directory(DirectoryCommand::Refresh { ca: Some(ca) }) => Ok(vec![select_ca(config, Some(ca.as_str()))?]),
+        Command::Cert(CertCommand::Issue { name } | CertCommand::Renew { name }) => {
+            let definition = declared(config, name.as_str())?;
+            Ok(vec![select_ca(config, Some(definition.ca.as_str()))?])
+        }
+        Command::Jobs(_) => Ok(Vec::new()),
+        _ => Ok(config.certificate_authorities.iter().collect()),
+    }
+}
+
+/// Runs a command as a job on `handle`, once the directories in `directory_updates` are
+/// refreshed, and waits for it.
+pub async fn run_scheduled(
+    command: &Command,
+    config: &ApplicationConfig,
+    repositories: &Repositories,
+    handle: &SchedulerHandle,
+    directory_updates: &[(&str, JobId)],
+) -> Result<Report, Box<dyn Error>> {
+    let refreshed: Vec<JobId> = directory_updates.iter().map(|(_, job_id)| *job_id).collect();
+    let user_id = config.user_id.clone();
+    let account_job = |ca: &Option<String>, action: AccountAction| -> Result<AccountJob, Box<dyn Error>> {
+        Ok(AccountJob::new(user_id.clone(), select_ca(config, ca.as_deref())?.name.clone(), action))
+    };
+    let job: Box<dyn Job> = match command {
+        Command::Account(AccountCommand::Register { ca }) => Box::new(account_job(
+            ca,
+            AccountAction::Register {
+                email: config.user_email.clone(),
+            },
+        )?),
+        Command::Account(AccountCommand::Update { ca, email }) => Box::new(account_job(
+            ca,
+            AccountAction::Update {
+                email: email.clone().unwrap_or(config.user_email.clone()),
+            },
+        )?),
+        Command::Account(AccountCommand::Deactivate { ca, yes }) => {
+            if !yes {
+                return Err("Deactivating an account can't be undone, confirm with --yes".into());
+            }
+            Box::new(account_job(ca, AccountAction::Deactivate)?)
+        }
+        Command::Account(AccountCommand::Rollover { ca }) => Box::new(account_job(ca, AccountAction::Rollover)?),
+        Command::Cert(CertCommand::Revoke { name, reason }) => {
+            Box::new(CertificateRevocationJob::new(user_id.clone(), name.clone(), *reason))
+        }
+        Command::Cert(CertCommand::Issue { name } | CertCommand::Renew { name }) => {
+            declared(config, name.as_str())?;
+            if matches!(command, Command::Cert(CertCommand::Renew { .. })) && repositories.certificates.latest(name.as_str())?.is_none() {
+                return Err(format!("Certificate {} has not been issued yet, see cert issue", name).into());
+            }
+            let order = handle
+                .submit_depending_on(&refreshed, CertificateOrderJob::new(user_id.clone(), name.clone(), true, None))
+                .await?;
+            let output = completed(handle, repositories, order).await?;
+            let download = output["download_job"]
+                .as_i64()
+                .ok_or_else(|| format!("Order job {} didn't submit a download", order))?;
+            let mut output = completed(handle, repositories, JobId(download)).await?;
+            let text = vec![format!(
+                "Certificate {} with serial {} is live as version {}, expires at {}",
+                name,
+                output["serial"].as_str().unwrap_or_default(),
+                output["version"],
+                output["not_after"].as_i64().map(format_timestamp).unwrap_or_default()
+            )];
+            output["order_job"] = json!(order.0);
+            output["download_job"] = json!(download);
+            return Ok(Report::new(text, output));
+        }
+        Command::Jobs(JobsCommand::Retry { job_id }) => {
+            handle.replay(JobId(*job_id), &job_registry()).await?;
+            completed(handle, repositories, JobId(*job_id)).await?;
+            return Ok(jobs_report(repositories.jobs.get(*job_id)?.into_iter().collect()));
+        }
+        Command::Jobs(JobsCommand::Cancel { job_id }) => {
+            if !handle.cancel(JobId(*job_id)).await? {
+                let record = repositories.jobs.get(*job_id)?.ok_or_else(|| format!("Job {} does not exist", job_id))?;
+                return Err(format!("Job {} is {}, only unfinished jobs can be cancelled", job_id, record.status.as_str()).into());
+            }
+            return Ok(jobs_report(repositories.jobs.get(*job_id)?.into_iter().collect()));
+        }
+        Command::Directory(DirectoryCommand::Refresh { .. }) => {
+            let mut text = Vec::new();
+            let mut refreshes = Vec::new();
+            for (ca, job_id) in directory_updates {
+                let output = completed(handle, repositories, *job_id).await?;
+                text.push(format!("{}\tdirectory: {}\taccount: {}", ca, output["directory_id"], output["account_id"]));
+                refreshes.push(json!({"ca": ca, "directory": output}));
+            }
+            return Ok(Report::new(text, Value::Array(refreshes)));
+        }
+        other => return Err(format!("{:?} doesn't run as a job", other).into()),
+    };
+    let job_id = handle.submit_boxed_depending_on(&refreshed, job).await?;
+    let output = completed(handle, repositories, job_id).await?;
+    let text = match command {
+        Command::Cert(_) => vec![format!("Certificate {} with serial {} has been revoked", output["name"], output["serial"])],
+        _ => vec![format!(
+            "{}\t{}\t{}\tcontact: {}",
+            output["ca"].as_str().unwrap_or_default(),
+            output["status"].as_str().unwrap_or_default(),
+            output["account_url"].as_str().unwrap_or("-"),
+            output["contact"]
+        )],
+    };
+    Ok(Report::new(text, output))
+}
+
+/// Waits for the job, returns its output or why it didn't succeed.
+async fn completed(handle: &SchedulerHandle, repositories: &Repositories, job_id: JobId) -> Result<Value, Box<dyn Error>> {
+    let status = handle.completion(job_id).await?;
+    if status != JobStatus::Succeeded {
+        let error = repositories.jobs.get(job_id.0)?.and_then(|record| record.last_error);
+        return Err(format!("Job {} is {}: {}", job_id, status.as_str(), error.unwrap_or_default()).into());
+    }
+    Ok(repositories.jobs.output(job_id.0)?.map(|(_, output)| output).unwrap_or(Value::Null))
+}
+
+/// The definition of the certificate `name`, only declared certificates can be ordered.
+fn declared<'a>(config: &'a ApplicationConfig, name: &str) -> Result<&'a CertificateDefinition, Box<dyn Error>> {
+    config
+        .certificates
+        .iter()
+        .find(|definition| definition.name == name)
+        .ok_or_else(|| format!("Certificate {} is not declared in the configuration", name).into())
+}
+
+fn select_ca<'a>(config: &'a ApplicationConfig, name: Option<&str>) -> Result<&'a CertificateAuthority, Box<dyn Error>> {
+    match (name, config.certificate_authorities.as_slice()) {
+        (Some(name), cas) => cas
+            .iter()
+            .find(|ca| ca.name == name)
+            .ok_or_else(|| format!("No CA named {} is configured", name).into()),
+        (None, [only]) => Ok(only),
+        (None, _) => Err("Several CAs are configured, pick one with --ca".into()),
+    }
+}
+
+fn find_user(config: &ApplicationConfig, repositories: &Repositories) -> Result<AcmeUser, Box<dyn Error>> {
+    repositories
+        .users
+        .find(config.user_id.as_str())?
+        .ok_or_else(|| format!("User {} has not been initialized yet", config.user_id).into())
+}
+
+fn show_accounts(config: &ApplicationConfig, repositories: &Repositories, ca: Option<&str>) -> Result<Report, Box<dyn Error>> {
+    let user = find_user(config, repositories)?;
+    let accounts: Vec<_> = repositories
+        .accounts
+        .for_user(user.id)?
+        .into_iter()
+        .filter(|account| ca.is_none_or(|ca| account.ca_name == ca))
+        .collect();
+    let text = accounts
+        .iter()
+        .map(|a| {
+            format!(
+                "{}\t{}\t{}\tdirectory: {}\tkey: {}",
+                a.ca_name,
+                a.status.as_str(),
+                a.account_url.as_deref().unwrap_or("-"),
+                a.directory_url,
+                a.key_path
+            )
+        })
+        .collect();
+    let json = accounts
+        .iter()
+        .map(|a| {
+            json!({
+                "account_id": a.account_id,
+                "ca": a.ca_name,
+                "status": a.status.as_str(),
+                "account_url": a.account_url,
+                "directory_url": a.directory_url,
+                "key_path": a.key_path,
+                "created_at": a.created_at,
+                "updated_at": a.updated_at,
+            })
+        })
+        .collect();
+    Ok(Report::new(text, Value::Array(json)))
+}
+
+fn list_certificates(config: &ApplicationConfig, repositories: &Repositories) -> Result<Report, Box<dyn Error>> {
+    let user = find_user(config, repositories)?;
+    let certificates = repositories.certificates.for_user(user.id)?;
+    let text = certificates
+        .iter()
+        .map(|c| format!("{}\t{}\tserial: {}\texpires: {}", c.name, c.ca_name, c.serial, format_timestamp(c.not_after)))
+        .collect();
+    let json = certificates.iter().map(|c| certificate_json(c, config)).collect();
+    Ok(Report::new(text, Value::Array(json)))
+}
+
+fn show_certificate(config: &ApplicationConfig, repositories: &Repositories, name: &str) -> Result<Report, Box<dyn Error>> {
+    let user = find_user(config, repositories)?;
+    let certificate = repositories
+        .certificates
+        .latest(name)?
+        .filter(|c| c.user_id == user.id)
+        .ok_or_else(|| format!("No certificate {} has been issued to user {}", name, user.user_id))?;
+    let json = certificate_json(&certificate, config);
+    let text = vec![
+        format!("name: {}", certificate.name),
+        format!("ca: {}", certificate.ca_name),
+        format!("serial: {}", certificate.serial),
+        format!("valid: {} - {}", format_timestamp(certificate.not_before), format_timestamp(certificate.not_after)),
+        format!("issuer: {}", certificate.chain_issuer.as_deref().unwrap_or("unknown")),
+        format!("live: {}", json["live_dir"].as_str().unwrap_or_default()),
+    ];
+    Ok(Report::new(text, json))
+}
+
+fn certificate_json(certificate: &persistence::data_model::AcmeCertificate, config: &ApplicationConfig) -> Value {
+    json!({
+        "certificate_id": certificate.certificate_id,
+        "name": certificate.name,
+        "ca": certificate.ca_name,
+        "serial": certificate.serial,
+        "not_before": certificate.not_before,
+        "not_after": certificate.not_after,
+        "certificate_url": certificate.certificate_url,
+        "chain_url": certificate.chain_url,
+        "chain_issuer": certificate.chain_issuer,
+        "live_dir": Path::new(config.output_dir.as_str()).join("live").join(certificate.name.as_str()),
+    })
+}
+
+fn show_directories(config: &ApplicationConfig, repositories: &Repositories, ca: Option<&str>) -> Result<Report, Box<dyn Error>> {
+    let user = find_user(config, repositories)?;
+    let (mut text, mut json) = (Vec::new(), Vec::new());
+    for certificate_authority in &config.certificate_authorities {
+        if ca.is_some_and(|ca| ca != certificate_authority.name) {
+            continue;
+        }
+        let Some(directory) = repositories.directories.find(user.id, certificate_authority.name.as_str())? else {
+            text.push(format!("{}\tnot fetched yet", certificate_authority.name));
+            continue;
+        };
+        text.push(format!(
+            "{}\t{}\tnewAccount: {}\tnewOrder: {}",
+            directory.ca_name, certificate_authority.directory_url, directory.new_account, directory.new_order
+        ));
+        json.push(json!({
+            "ca": directory.ca_name,
+            "directory_url": certificate_authority.directory_url,
+            "new_nonce": directory.new_nonce,
+            "new_account": directory.new_account,
+            "new_order": directory.new_order,
+            "revoke_cert": directory.revoke_cert,
+            "key_change": directory.key_change,
+            "meta": directory.meta.as_deref().and_then(|meta| serde_json::from_str::<Value>(meta).ok()),
+        }));
+    }
+    Ok(Report::new(text, Value::Array(json)))
+}
+
+fn jobs_report(records: Vec<JobRecord>) -> Report {
+    let text = records
+        .iter()
+        .map(|r| {
+            format!(
+                "{}\t{}\t{}\tattempts: {}\terror: {}",
+                r.job_id,
+                r.job_type,
+                r.status.as_str(),
+                r.attempts,
+                r.last_error.as_deref().unwrap_or_default().lines().next().unwrap_or_default()
+            )
+        })
+        .collect();
+    Report::new(text, Value::Array(records.iter().map(job_json).collect()))
+}
+
+fn audit_log(command: &AuditCommand, repositories: &Repositories) -> Result<Report, Box<dyn Error>> {
+    match command {
+        AuditCommand::List { filter } => {
+            let events = repositories.audit.list(&audit_filter(filter)?)?;
+            let text = events
+                .iter()
+                .map(|event| {
+                    format!(
+                        "{}\t{}\t{}\tuser: {}\tsubject: {}\t{}",
+                        event.event_id,
+                        format_timestamp(event.occurred_at),
+                        event.kind.as_str(),
+                        event.user_id.as_deref().unwrap_or_default(),
+                        event.subject.as_deref().unwrap_or_default(),
+                        event.details
+                    )
+                })
+                .collect();
+            Ok(Report::new(text, Value::Array(events.iter().map(AuditEvent::to_json).collect())))
+        }
+        AuditCommand::Verify => {
+            let verified = AuditEvent::verify_chain(&repositories.audit.list(&AuditFilter::default())?)?;
+            Ok(Report::new(
+                vec![format!("Audit log is intact, {} event(s) verified", verified)],
+                json!({"verified": verified}),
+            ))
+        }
+        AuditCommand::Export { file, filter } => {
+            let events = repositories.audit.list(&audit_filter(filter)?)?;
+            export_events(&events, file.as_str())?;
+            Ok(Report::new(
+                vec![format!("{} audit event(s) exported to {}", events.len(), file)],
+                json!({"file": file, "events": events.len()}),
+            ))
+        }
+    }
+}
+
+fn job_json(record: &JobRecord) -> Value {
+    json!({
+        "job_id": record.job_id,
+        "job_type": record.job_type,
+        "status": record.status.as_str(),
+        "attempts": record.attempts,
+        "last_error": record.last_error,
+        "payload": serde_json::from_str::<Value>(record.payload.as_str()).unwrap_or(Value::Null),
+        "run_at": record.run_at,
+        "created_at": record.created_at,
+        "updated_at": record.updated_at,
+    })
+}
+
+fn check_database(config: &ApplicationConfig, repositories: &Repositories, repair: bool) -> Result<Report, Box<dyn Error>> {
+    let version = repositories.schema.migrate()?;
+    let doctor = Doctor::new(repositories, config.output_dir.as_str(), config.base_dir.as_str())?;
+    let mut text = vec![format!("Database schema is at version {}", version)];
+    let (mut findings, mut unresolved) = (Vec::new(), 0);
+    for finding in doctor.check()? {
+        text.push(finding.to_string());
+        let repaired = if repair {
+            doctor
+                .repair(&finding)
+                .inspect_err(|e| text.push(format!("  not repaired: {}", e)))
+                .ok()
+        } else {
+            None
+        };
+        match &repaired {
+            Some(repaired) => text.push(format!("  repaired: {}", repaired)),
+            None => unresolved += 1,
+        }
+        findings.push(json!({"finding": finding.to_string(), "repaired": repaired}));
+    }
+    let mut report = Report::new(text, json!({"schema_version": version, "findings": findings}));
+    match unresolved {
+        0 => report.text.push("Database and files on disk are consistent".to_string()),
+        _ if repair => report.failure = Some(format!("{} problem(s) need attention", unresolved)),
+        _ => report.failure = Some(format!("{} problem(s) need attention, see db check --repair", unresolved)),
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, JobsCommand, certificate_authorities, run_offline, run_scheduled, select_ca};
+    use crate::acme_jobs::db_initialization::DbInitializationJob;
+    use crate::job_execution::job_base::Scheduler;
+    use crate::audit_log::record_config_change;
+    use crate::statics::Args;
+    use clap::Parser;
+    use common_utils::{ApplicationConfig, CertificateAuthority};
+    use persistence::data_model::{AuditEvent, AuditKind, JobStatus};
+    use persistence::repository::Repositories;
+    use serde_json::json;
+
+    fn config(cas: &[&str]) -> ApplicationConfig {
+        ApplicationConfig {
+            application_mode: false,
+            certificate_authorities: cas
+                .iter()
+                .map(|name| CertificateAuthority {
+                    name: name.to_string(),
+                    directory_url: format!("https://{}.ca/dir", name),
+                })
+                .collect(),
+            base_dir: "/opt/acme-sentry".to_string(),
+            output_dir: "/opt/acme-sentry/out".to_string(),
+            user_id: "a1b2c3".to_string(),
+            user_email: "admin@example.org".to_string(),
+            key_type: "ec-p256".to_string(),
+            logging_level: None,
+            preferred_chain: None,
+            accept_invalid_certs: false,
+            output_formats: vec![],
+            pkcs12_password: None,
+            file_owner: None,
+            file_group: None,
+            fix_key_permissions: false,
+            directory_refresh: None,
+            missed_runs: "catch-up".to_string(),
+            workers: 4,
+            max_ca_requests: 2,
+            shutdown_timeout: 30,
+            certificates: vec![],
+            reconciliation: "1h".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_subcommands_are_parsed() {
+        let args = Args::parse_from(["acme-sentry", "--output", "json", "account", "update", "--ca", "staging", "--email", "ops@example.org"]);
+        assert_eq!(args.output, super::OutputMode::Json);
+        assert!(matches!(
+            args.command,
+            Some(Command::Account(super::AccountCommand::Update { ca: Some(ref ca), email: Some(ref email) }))
+                if ca == "staging" && email == "ops@example.org"
+        ));
+        let args = Args::parse_from(["acme-sentry", "jobs", "list", "--status", "failed", "--output", "json"]);
+        assert_eq!(args.output, super::OutputMode::Json);
+        assert!(Args::try_parse_from(["acme-sentry", "cert", "revoke"]).is_err());
+        let args = Args::parse_from(["acme-sentry", "db", "restore", "/backups/sentry.bak", "--passphrase-file", "/run/secrets/backup"]);
+        assert!(matches!(
+            args.command,
+            Some(Command::Db(super::DbCommand::Restore { ref file, passphrase_file: Some(_) })) if file == "/backups/sentry.bak"
+        ));
+        assert!(matches!(
+            Args::parse_from(["acme-sentry", "db", "check", "--repair"]).command,
+            Some(Command::Db(super::DbCommand::Check { repair: true }))
+        ));
+        assert!(Args::try_parse_from(["acme-sentry", "--doctor"]).is_err());
+        assert!(Args::parse_from(["acme-sentry"]).command.is_none());
+    }
+
+    #[test]
+    fn test_ca_has_to_be_named_when_several_are_configured() {
+        assert_eq!(select_ca(&config(&["default"]), None).unwrap().name, "default");
+        assert!(select_ca(&config(&["production", "staging"]), None).is_err());
+        assert_eq!(select_ca(&config(&["production", "staging"]), Some("staging")).unwrap().name, "staging");
+        assert!(select_ca(&config(&["production"]), Some("staging")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jobs_are_listed_retried_and_cancelled() {
+        let repositories = Repositories::in_memory();
+        let config = config(&["default"]);
+        let failed = repositories.jobs.insert_at("print", &json!({"n": 1}), None).unwrap();
+        let queued = repositories.jobs.insert_at("print", &json!({"n": 2}), None).unwrap();
+        repositories.jobs.mark_failed(failed, "boom").unwrap();
+
+        let jobs = |command: JobsCommand| run_offline(&Command::Jobs(command), &config, &repositories).unwrap().unwrap();
+        let list = |status: Option<&str>| jobs(JobsCommand::List { status: status.map(Into::into) });
+        assert_eq!(list(None).json.as_array().unwrap().len(), 2);
+        let report = list(Some("failed"));
+        assert_eq!(report.json[0]["job_id"], failed);
+        assert_eq!(report.json[0]["payload"], json!({"n": 1}));
+        assert!(report.text[0].contains("boom"));
+        assert_eq!(list(Some("dead")).json, report.json);
+
+        // a replayed job runs on the scheduler of the command, nothing else would pick it up
+        let dead = repositories.jobs.insert_at(DbInitializationJob::JOB_TYPE, &json!({}), None).unwrap();
+        repositories.jobs.mark_failed(dead, "database is locked").unwrap();
+        let retry = Command::Jobs(JobsCommand::Retry { job_id: dead });
+        assert!(run_offline(&retry, &config, &repositories).unwrap().is_none());
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let report = run_scheduled(&retry, &config, &repositories, &handle, &[]).await.unwrap();
+        assert_eq!(report.json[0]["status"], "succeeded");
+        assert_eq!(report.json[0]["attempts"], 1);
+        // a job type the binary doesn't know can't run, it stays dead
+        assert!(run_scheduled(&Command::Jobs(JobsCommand::Retry { job_id: failed }), &config, &repositories, &handle, &[]).await.is_err());
+        assert_eq!(repositories.jobs.get(failed).unwrap().unwrap().status, JobStatus::Failed);
+
+        // the job belongs to another scheduler, e.g. the daemon's, it's cancelled through the job repository
+        let cancel = Command::Jobs(JobsCommand::Cancel { job_id: queued });
+        assert!(run_offline(&cancel, &config, &repositories).unwrap().is_none());
+        assert!(certificate_authorities(&cancel, &config).unwrap().is_empty());
+        let report = run_scheduled(&cancel, &config, &repositories, &handle, &[]).await.unwrap();
+        assert_eq!(report.json[0]["status"], "cancelled");
+        assert_eq!(repositories.jobs.get(queued).unwrap().unwrap().status, JobStatus::Cancelled);
+        assert!(run_scheduled(&cancel, &config, &repositories, &handle, &[]).await.is_err());
+        handle.shutdown().await;
+    }
+
+    #[test]
+    fn test_offline_commands_report_without_the_scheduler() {
+        let repositories = Repositories::in_memory();
+        let config = config(&["default"]);
+        let user = repositories.users.create("a1b2c3", "ec-p256", "/keys", "/dump").unwrap();
+        let account = repositories.accounts.ensure(user.id, "default", "https://default.ca/dir", "/keys").unwrap();
+        repositories.accounts.register(account.account_id, "https://default.ca/acct/1").unwrap();
+
+        let args = Args::parse_from(["acme-sentry", "account", "show"]);
+        let report = run_offline(args.command.as_ref().unwrap(), &config, &repositories).unwrap().unwrap();
+        assert_eq!(report.json[0]["account_url"], "https://default.ca/acct/1");
+        assert_eq!(report.json[0]["status"], "valid");
+        let args = Args::parse_from(["acme-sentry", "account", "register"]);
+        assert!(run_offline(args.command.as_ref().unwrap(), &config, &repositories).unwrap().is_none());
+        let args = Args::parse_from(["acme-sentry", "cert", "issue", "web"]);
+        assert!(run_offline(args.command.as_ref().unwrap(), &config, &repositories).unwrap().is_none());
+        assert!(certificate_authorities(args.command.as_ref().unwrap(), &config).is_err());
+    }
+
+    #[test]
+    fn test_filtered_events_are_exported_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let export = dir.path().join("audit.jsonl");
+        let repositories = Repositories::in_memory();
+        record_config_change(&config(&["default"]), &repositories.audit).unwrap();
+        record_config_change(&config(&["default", "staging"]), &repositories.audit).unwrap();
+        let run = |args: &[&str]| {
+            let args = Args::parse_from(args);
+            run_offline(args.command.as_ref().unwrap(), &config(&["default"]), &repositories).unwrap().unwrap()
+        };
+        assert_eq!(run(&["acme-sentry", "audit", "verify"]).json["verified"], 2);
+        let report = run(&["acme-sentry", "audit", "export", export.to_str().unwrap(), "--kind", "config-changed"]);
+        assert_eq!(report.json["events"], 2);
+        assert_eq!(run(&["acme-sentry", "audit", "list", "--kind", "order-created"]).json, json!([]));
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&export)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["previous_hash"], lines[0]["hash"]);
+        let expected = AuditEvent::chain_hash(
+            lines[0]["previous_hash"].as_str().unwrap(),
+            lines[0]["occurred_at"].as_i64().unwrap(),
+            AuditKind::ConfigChanged,
+            lines[0]["user_id"].as_str(),
+            lines[0]["subject"].as_str(),
+            lines[0]["details"].as_str().unwrap(),
+        );
+        assert_eq!(lines[0]["hash"], expected);
+    }
+}
@@ -0,0 +1,629 @@
+use crate::certificate_output::OutputFormat;
+use crate::cli::Command;
+use crate::config_check;
+use crate::job_execution::recurring::{MissedRunPolicy, Schedule};
+use crate::statics::{
+    Args, YamlConfig, ca_parse, certificate_authorities, certificate_definitions, log_level_parse,
+};
+use acme_client::crypto::SupportedKey;
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use common_utils::fs::{FileOptions, FileSystem};
+use common_utils::{ApplicationConfig, CertificateDefinition, InternalIdTooling};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Every environment variable is the name of its setting behind this prefix, e.g.
+/// `ACME_SENTRY_EMAIL`. `ACME_SENTRY_<NAME>_FILE` reads the value from a file instead.
+pub const ENV_PREFIX: &str = "ACME_SENTRY_";
+const REDACTED: &str = "<redacted>";
+/// Holds the id generated for a daemon without `user.id`, in `fs.base-dir`.
+pub const USER_ID_FILE: &str = "user-id";
+/// Keys whose values never leave the process, see [`EffectiveSetting`].
+const SECRETS: [&str; 1] = ["fs.pkcs12-password"];
+/// Names of free-form values that are redacted, the challenge settings and the environment
+/// variables hooks are started with, e.g. the API token of a DNS provider.
+const SECRET_NAMES: [&str; 4] = ["token", "secret", "password", "key"];
+
+/// The layer the value of a setting comes from, later layers take precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Default,
+    Yaml,
+    Env,
+    Cli,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Default => "default",
+            Source::Yaml => "yaml",
+            Source::Env => "env",
+            Source::Cli => "cli",
+        }
+    }
+}
+
+/// A setting of the effective configuration, keyed like the YAML file. Secrets are redacted.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EffectiveSetting {
+    pub key: &'static str,
+    pub value: Value,
+    pub source: Source,
+}
+
+/// Looks up a variable of the environment, [`std::env::var`] outside of tests.
+pub type Environment<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// `ACME_SENTRY_<name>`, or the contents of the file `ACME_SENTRY_<name>_FILE` names without
+/// the trailing newline. Setting both is refused, one of them would be ignored silently.
+pub fn env_value(env: Environment, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let variable = format!("{}{}", ENV_PREFIX, name);
+    let file_variable = format!("{}_FILE", variable);
+    match (env(variable.as_str()), env(file_variable.as_str())) {
+        (Some(_), Some(_)) => Err(format!("Set either {} or {}, not both", variable, file_variable).into()),
+        (Some(value), None) => Ok(Some(value)),
+        (None, Some(file)) => {
+            let value = fs::read_to_string(file.as_str())
+                .map_err(|e| format!("{} names {}, which can't be read: {}", file_variable, file, e))?;
+            Ok(Some(value.trim_end_matches(['\r', '\n']).to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Resolves the settings one by one, the first layer that sets one wins.
+struct Layers<'a> {
+    matches: &'a ArgMatches,
+    env: Environment<'a>,
+    settings: Vec<EffectiveSetting>,
+}
+
+impl Layers<'_> {
+    /// `arg` is the id of the flag, `cli` its parsed value which clap fills with the default
+    /// when the flag isn't given. `variable` is the name of the environment variable without
+    /// [`ENV_PREFIX`], `yaml` is `None` when the file doesn't set the key.
+    fn resolve<T: Serialize>(
+        &mut self,
+        key: &'static str,
+        arg: &str,
+        variable: &str,
+        cli: T,
+        yaml: Option<T>,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<T, Box<dyn Error>> {
+        let (value, source) = if self.matches.value_source(arg) == Some(ValueSource::CommandLine) {
+            (cli, Source::Cli)
+        } else if let Some(value) = env_value(self.env, variable)? {
+            (parse(value.as_str()).map_err(|e| format!("{}{}: {}", ENV_PREFIX, variable, e))?, Source::Env)
+        } else if let Some(value) = yaml {
+            (value, Source::Yaml)
+        } else {
+            (cli, Source::Default)
+        };
+        self.record(key, serde_json::to_value(&value)?, source);
+        Ok(value)
+    }
+
+    fn record(&mut self, key: &'static str, value: Value, source: Source) {
+        let value = match value {
+            Value::Null => Value::Null,
+            _ if SECRETS.contains(&key) => json!(REDACTED),
+            value => value,
+        };
+        self.settings.push(EffectiveSetting { key, value, source });
+    }
+}
+
+fn secret_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SECRET_NAMES.iter().any(|secret| name.contains(secret))
+}
+
+/// A hook command with the values of secret `NAME=value` assignments in front of it redacted.
+fn redact_hook(command: &str) -> String {
+    let mut words = Vec::new();
+    let mut assignments = true;
+    for word in command.split(' ') {
+        let name = word
+            .split_once('=')
+            .map(|(name, _)| name)
+            .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        assignments &= word.is_empty() || name.is_some();
+        match name {
+            Some(name) if assignments && secret_name(name) => words.push(format!("{}={}", name, REDACTED)),
+            _ => words.push(word.to_string()),
+        }
+    }
+    words.join(" ")
+}
+
+/// A declared certificate as `config show` prints it, secret challenge settings and hook
+/// variables redacted.
+fn certificate_json(certificate: &CertificateDefinition) -> Value {
+    let settings: serde_json::Map<String, Value> = certificate
+        .challenge_settings
+        .iter()
+        .map(|(name, value)| (name.clone(), json!(if secret_name(name) { REDACTED } else { value.as_str() })))
+        .collect();
+    let hooks = &certificate.hooks;
+    json!({
+        "name": certificate.name,
+        "identifiers": certificate.identifiers,
+        "ca": certificate.ca,
+        "challenge": certificate.challenge.as_str(),
+        "challenge-settings": settings,
+        "hooks": {
+            "pre": hooks.pre.as_deref().map(redact_hook),
+            "post": hooks.post.as_deref().map(redact_hook),
+            "deploy": hooks.deploy.as_deref().map(redact_hook),
+        },
+    })
+}
+
+fn text(value: &str) -> Result<String, String> {
+    Ok(value.to_string())
+}
+
+fn optional(value: &str) -> Result<Option<String>, String> {
+    Ok(Some(value.to_string()))
+}
+
+/// Lists in the environment are comma separated.
+fn list(value: &str) -> Result<Vec<String>, String> {
+    Ok(value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::to_string).collect())
+}
+
+fn flag(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        other => Err(format!("Expected true or false, got {}", other)),
+    }
+}
+
+fn number<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| format!("Expected a number, got {}", value))
+}
+
+/// The YAML file of `--yaml-config` or `ACME_SENTRY_YAML_CONFIG`, the flag wins.
+pub fn yaml_file(args: &Args, matches: &ArgMatches, env: Environment) -> Result<Option<String>, Box<dyn Error>> {
+    match matches.value_source("yaml_config") {
+        Some(ValueSource::CommandLine) => Ok(args.yaml_config.clone()),
+        _ => Ok(env_value(env, "YAML_CONFIG")?.or(args.yaml_config.clone())),
+    }
+}
+
+/// The YAML file of [`yaml_file`], checked before it's used. Its directories are left to
+/// `config check` and the daemon's start, see [`check`].
+fn load_yaml(args: &Args, matches: &ArgMatches, env: Environment) -> Result<Option<YamlConfig>, Box<dyn Error>> {
+    let Some(file) = yaml_file(args, matches, env)? else {
+        if args.yaml {
+            return Err("--yaml needs --yaml-config or ACME_SENTRY_YAML_CONFIG".into());
+        }
+        return Ok(None);
+    };
+    let diagnostics = config_check::check_file(file.as_str(), false);
+    if !diagnostics.is_empty() {
+        let problems: Vec<String> = diagnostics.iter().map(|diagnostic| format!("{}:{}", file, diagnostic)).collect();
+        return Err(format!("Configuration {} is invalid:\n{}", file, problems.join("\n")).into());
+    }
+    Ok(Some(serde_yaml::from_str(fs::read_to_string(file.as_str())?.as_str())?))
+}
+
+/// The effective configuration out of, in increasing precedence, the defaults of [`Args`],
+/// the YAML file, `ACME_SENTRY_*` variables and the flags given on the command line. The
+/// settings are returned as well, with the layer each one comes from.
+pub fn resolve(
+    args: &Args,
+    matches: &ArgMatches,
+    env: Environment,
+) -> Result<(ApplicationConfig, Vec<EffectiveSetting>), Box<dyn Error>> {
+    let yaml = load_yaml(args, matches, env)?.map(|yaml| yaml.acme_sentry_configuration);
+    let file = yaml.unwrap_or_default();
+    let mut layers = Layers {
+        matches,
+        env,
+        settings: Vec::new(),
+    };
+
+    // the daemon subcommand is application mode whatever the other layers say
+    let application_mode = if matches!(args.command, Some(Command::Daemon)) {
+        layers.record("application-mode", json!(true), Source::Cli);
+        true
+    } else {
+        layers.resolve("application-mode", "application_mode", "APPLICATION_MODE", args.application_mode, None, flag)?
+    };
+    let base_url = layers.resolve("base-url", "acme_base_url", "BASE_URL", args.acme_base_url.clone(), file.base_url.clone().map(Some), optional)?;
+    let yaml_cas: Vec<String> = file
+        .certificate_authorities
+        .iter()
+        .map(|ca| format!("{}={}", ca.name, ca.directory_url))
+        .collect();
+    let cli_cas = args
+        .certificate_authorities
+        .iter()
+        .map(|ca| format!("{}={}", ca.name, ca.directory_url))
+        .collect();
+    let named = layers.resolve(
+        "certificate-authorities",
+        "certificate_authorities",
+        "CA",
+        cli_cas,
+        Some(yaml_cas).filter(|cas| !cas.is_empty()),
+        list,
+    )?;
+    let named = named.iter().map(|ca| ca_parse(ca)).collect::<Result<Vec<_>, String>>()?;
+    let certificate_authorities = certificate_authorities(base_url, named)?;
+    let preferred_chain = layers.resolve(
+        "preferred-chain",
+        "preferred_chain",
+        "PREFERRED_CHAIN",
+        args.preferred_chain.clone(),
+        file.preferred_chain.clone().map(Some),
+        optional,
+    )?;
+    let accept_invalid_certs = layers.resolve(
+        "accept-invalid-certs",
+        "accept_invalid_certs",
+        "ACCEPT_INVALID_CERTS",
+        args.accept_invalid_certs,
+        file.accept_invalid_certs,
+        flag,
+    )?;
+
+    let base_dir = layers.resolve("fs.base-dir", "base_dir", "BASE_DIR", args.base_dir.clone(), file.fs.base_dir.clone(), text)?;
+    let output_dir = layers.resolve("fs.output-dir", "output_dir", "OUTPUT_DIR", args.output_dir.clone(), file.fs.output_dir.clone(), text)?;
+    let output_formats = layers.resolve(
+        "fs.output-formats",
+        "output_formats",
+        "OUTPUT_FORMATS",
+        args.output_formats.clone(),
+        Some(file.fs.output_formats.clone()).filter(|formats| !formats.is_empty()),
+        list,
+    )?;
+    let pkcs12_password = layers.resolve(
+        "fs.pkcs12-password",
+        "pkcs12_password",
+        "PKCS12_PASSWORD",
+        args.pkcs12_password.clone(),
+        file.fs.pkcs12_password.clone().map(Some),
+        optional,
+    )?;
+    let file_owner = layers.resolve("fs.owner", "file_owner", "FILE_OWNER", args.file_owner.clone(), file.fs.owner.clone().map(Some), optional)?;
+    let file_group = layers.resolve("fs.group", "file_group", "FILE_GROUP", args.file_group.clone(), file.fs.group.clone().map(Some), optional)?;
+    let fix_key_permissions = layers.resolve(
+        "fs.fix-key-permissions",
+        "fix_key_permissions",
+        "FIX_KEY_PERMISSIONS",
+        args.fix_key_permissions,
+        file.fs.fix_key_permissions,
+        flag,
+    )?;
+
+    let user_id = layers.resolve("user.id", "with_user_id", "USER_ID", args.with_user_id.clone(), file.user.id.clone().map(Some), optional)?;
+    // without one the id is generated on the first start of the daemon and kept, the
+    // subcommands read it back
+    let stored_user_id = match user_id {
+        Some(_) => None,
+        None => stored_user_id(base_dir.as_str())?,
+    };
+    let generate_user_id = user_id.is_none() && stored_user_id.is_none();
+    let user_id = user_id.or(stored_user_id).unwrap_or_else(InternalIdTooling::new_compact_id);
+    if let Some(setting) = layers.settings.iter_mut().find(|setting| setting.key == "user.id") {
+        setting.value = json!(user_id);
+    }
+    let user_email = layers
+        .resolve("user.email", "with_email", "EMAIL", args.with_email.clone(), file.user.email.clone().map(Some), optional)?
+        .ok_or("No email configured, set user.email, ACME_SENTRY_EMAIL or --with-email")?;
+    if !config_check::valid_email(user_email.as_str()) {
+        return Err(format!("Email {:?} isn't an email address", user_email).into());
+    }
+    let key_type = layers.resolve(
+        "user.login-key-type",
+        "requested_login_key_type",
+        "LOGIN_KEY_TYPE",
+        args.requested_login_key_type.clone(),
+        file.user.key_type.clone(),
+        text,
+    )?;
+    let logging_level = layers.resolve(
+        "logging.logging-level",
+        "logging_level",
+        "LOGGING_LEVEL",
+        args.logging_level.to_string().to_lowercase(),
+        file.logging.logging_level.map(|level| level.to_string().to_lowercase()),
+        |value| Ok(value.to_lowercase()),
+    )?;
+
+    let scheduler = &file.scheduler;
+    let directory_refresh = layers.resolve(
+        "scheduler.directory-refresh",
+        "directory_refresh",
+        "DIRECTORY_REFRESH",
+        args.directory_refresh.clone(),
+        scheduler.directory_refresh.clone().map(Some),
+        optional,
+    )?;
+    let missed_runs =
+        layers.resolve("scheduler.missed-runs", "missed_runs", "MISSED_RUNS", args.missed_runs.clone(), scheduler.missed_runs.clone(), text)?;
+    let workers = layers.resolve("scheduler.workers", "workers", "WORKERS", args.workers, scheduler.workers, number)?;
+    let max_ca_requests = layers.resolve(
+        "scheduler.max-ca-requests",
+        "max_ca_requests",
+        "MAX_CA_REQUESTS",
+        args.max_ca_requests,
+        scheduler.max_ca_requests,
+        number,
+    )?;
+    let shutdown_timeout = layers.resolve(
+        "scheduler.shutdown-timeout",
+        "shutdown_timeout",
+        "SHUTDOWN_TIMEOUT",
+        args.shutdown_timeout,
+        scheduler.shutdown_timeout,
+        number,
+    )?;
+    let reconciliation = layers.resolve(
+        "scheduler.reconciliation",
+        "reconciliation",
+        "RECONCILIATION",
+        args.reconciliation.clone(),
+        scheduler.reconciliation.clone(),
+        text,
+    )?;
+
+    let certificates = certificate_definitions(file.certificates, &certificate_authorities)?;
+    let source = if certificates.is_empty() { Source::Default } else { Source::Yaml };
+    layers.record("certificates", Value::Array(certificates.iter().map(certificate_json).collect()), source);
+
+    let config = ApplicationConfig {
+        application_mode,
+        certificate_authorities,
+        output_dir: PathBuf::from(base_dir.as_str())
+            .join(output_dir.as_str())
+            .to_str()
+            .ok_or("Output dir isn't valid unicode")?
+            .to_string(),
+        base_dir,
+        user_id,
+        user_email,
+        key_type,
+        logging_level: Some(log_level_parse(logging_level.as_str())?),
+        preferred_chain,
+        accept_invalid_certs,
+        output_formats,
+        pkcs12_password,
+        file_owner,
+        file_group,
+        fix_key_permissions,
+        directory_refresh,
+        missed_runs,
+        workers,
+        max_ca_requests,
+        shutdown_timeout,
+        certificates,
+        reconciliation,
+    };
+    check(&config)?;
+    if generate_user_id && application_mode {
+        FileSystem::new(config.base_dir.as_str())?
+            .write_to_file_with("", USER_ID_FILE, config.user_id.as_bytes(), &FileOptions::private())
+            .map_err(|e| format!("The generated user id can't be stored in fs.base-dir: {}", e))?;
+    }
+    Ok((config, layers.settings))
+}
+
+/// The user id an earlier start of the daemon generated, if any.
+fn stored_user_id(base_dir: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let path = Path::new(base_dir).join(USER_ID_FILE);
+    match fs::read_to_string(&path) {
+        Ok(id) if id.trim().is_empty() => Err(format!("{} is empty", path.display()).into()),
+        Ok(id) => Ok(Some(id.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("{} can't be read: {}", path.display(), e).into()),
+    }
+}
+
+/// Checks the settings the YAML file can't be checked for on its own, either because they
+/// come from the environment or because they depend on another layer. The directories are
+/// only probed for the daemon, a subcommand shouldn't leave files behind in them.
+fn check(config: &ApplicationConfig) -> Result<(), Box<dyn Error>> {
+    if config.application_mode {
+        config_check::writable_dir(Path::new(config.base_dir.as_str())).map_err(|e| format!("fs.base-dir: {}", e))?;
+        config_check::writable_dir(Path::new(config.output_dir.as_str())).map_err(|e| format!("fs.output-dir: {}", e))?;
+    }
+    config_check::inside_base_dir(Path::new(config.base_dir.as_str()), Path::new(config.output_dir.as_str()))
+        .map_err(|e| format!("fs.output-dir: {}", e))?;
+    SupportedKey::from_str(config.key_type.as_str()).map_err(|e| format!("user.login-key-type: {}", e))?;
+    let mut pkcs12 = false;
+    for format in config.output_formats.iter().chain(config.certificates.iter().flat_map(|c| c.output_formats.iter().flatten())) {
+        pkcs12 |= OutputFormat::from_str(format).map_err(|e| format!("fs.output-formats: {}", e))? == OutputFormat::Pkcs12;
+    }
+    if pkcs12 && config.pkcs12_password.is_none() {
+        return Err("PKCS#12 output requires fs.pkcs12-password, ACME_SENTRY_PKCS12_PASSWORD or --pkcs12-password".into());
+    }
+    if let Some(Err(e)) = config.directory_refresh.as_deref().map(Schedule::parse) {
+        return Err(format!("scheduler.directory-refresh: {}", e).into());
+    }
+    Schedule::parse(config.reconciliation.as_str()).map_err(|e| format!("scheduler.reconciliation: {}", e))?;
+    MissedRunPolicy::from_str(config.missed_runs.as_str()).map_err(|e| format!("scheduler.missed-runs: {}", e))?;
+    if config.workers == 0 {
+        return Err("scheduler.workers: At least one worker is needed".into());
+    }
+    if config.max_ca_requests == 0 {
+        return Err("scheduler.max-ca-requests: At least one request to the CA has to be allowed".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EffectiveSetting, Source, certificate_json, resolve, yaml_file};
+    use crate::statics::Args;
+    use clap::{CommandFactory, FromArgMatches};
+    use common_utils::{ApplicationConfig, CertificateDefinition, CertificateHooks, ChallengeMethod};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use tempfile::TempDir;
+
+    fn resolve_with(
+        flags: &[&str],
+        env: &[(&str, &str)],
+    ) -> Result<(ApplicationConfig, Vec<EffectiveSetting>), Box<dyn Error>> {
+        let matches = Args::command().try_get_matches_from([&["acme-sentry"], flags].concat()).unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        let env: HashMap<String, String> = env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        resolve(&args, &matches, &|name| env.get(name).cloned())
+    }
+
+    fn setting<'a>(settings: &'a [EffectiveSetting], key: &str) -> &'a EffectiveSetting {
+        settings.iter().find(|setting| setting.key == key).unwrap()
+    }
+
+    #[test]
+    fn test_flags_win_over_the_environment_over_the_file_over_the_defaults() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("acme-sentry.yaml");
+        let yaml = format!(
+            "acme-sentry:\n  base-url: https://yaml.example.org\n  fs:\n    base-dir: {}\n  user:\n    email: yaml@example.org\n  scheduler:\n    workers: 3\n    max-ca-requests: 3\n",
+            dir.path().display()
+        );
+        std::fs::write(&file, yaml).unwrap();
+        let file = file.to_str().unwrap();
+        let env = [
+            ("ACME_SENTRY_YAML_CONFIG", file),
+            ("ACME_SENTRY_EMAIL", "env@example.org"),
+            ("ACME_SENTRY_WORKERS", "5"),
+        ];
+        let (config, settings) = resolve_with(&["--workers", "7"], &env).unwrap();
+        assert_eq!(config.workers, 7);
+        assert_eq!(config.user_email, "env@example.org");
+        assert_eq!(config.max_ca_requests, 3);
+        assert_eq!(config.certificate_authorities[0].directory_url, "https://yaml.example.org/dir");
+        assert_eq!(config.shutdown_timeout, 30);
+        let sources: Vec<_> = ["scheduler.workers", "user.email", "scheduler.max-ca-requests", "scheduler.shutdown-timeout"]
+            .iter()
+            .map(|key| setting(&settings, key).source)
+            .collect();
+        assert_eq!(sources, vec![Source::Cli, Source::Env, Source::Yaml, Source::Default]);
+    }
+
+    #[test]
+    fn test_secrets_are_read_from_files_and_redacted() {
+        let dir = TempDir::new().unwrap();
+        let secret = dir.path().join("pkcs12-password");
+        std::fs::write(&secret, "s3cret\n").unwrap();
+        let secret = secret.to_str().unwrap();
+        let flags = ["--acme-base-url", "https://ca.example.org", "--with-email", "admin@example.org", "--output-format", "pkcs12"];
+        let (config, settings) = resolve_with(&flags, &[("ACME_SENTRY_PKCS12_PASSWORD_FILE", secret)]).unwrap();
+        assert_eq!(config.pkcs12_password.as_deref(), Some("s3cret"));
+        assert_eq!(setting(&settings, "fs.pkcs12-password").value, json!("<redacted>"));
+        assert!(!serde_json::to_string(&settings).unwrap().contains("s3cret"));
+
+        let both = [("ACME_SENTRY_PKCS12_PASSWORD_FILE", secret), ("ACME_SENTRY_PKCS12_PASSWORD", "other")];
+        assert!(resolve_with(&flags, &both).unwrap_err().to_string().contains("not both"));
+        assert!(resolve_with(&flags, &[]).unwrap_err().to_string().contains("PKCS#12"));
+    }
+
+    #[test]
+    fn test_secret_challenge_settings_and_hook_variables_are_redacted() {
+        let certificate = CertificateDefinition {
+            name: "www".to_string(),
+            identifiers: vec!["example.org".to_string()],
+            key_type: "ec-p256".to_string(),
+            challenge: ChallengeMethod::Dns01,
+            challenge_settings: [("provider", "cloudflare"), ("api-token", "t0k3n"), ("TSIG_Key", "k3y"), ("client-secret", "s3cr3t")]
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ca: "default".to_string(),
+            profile: None,
+            renew_before: None,
+            output_formats: None,
+            hooks: CertificateHooks {
+                pre: Some("DEPLOY_PASSWORD=hunter2 REGION=eu ./pre.sh TOKEN=argument".to_string()),
+                post: None,
+                deploy: Some("systemctl reload nginx".to_string()),
+            },
+        };
+        let json = certificate_json(&certificate);
+        assert_eq!(
+            json["challenge-settings"],
+            json!({"provider": "cloudflare", "api-token": "<redacted>", "TSIG_Key": "<redacted>", "client-secret": "<redacted>"})
+        );
+        assert_eq!(json["hooks"]["pre"], "DEPLOY_PASSWORD=<redacted> REGION=eu ./pre.sh TOKEN=argument");
+        assert_eq!(json["hooks"]["post"], json!(null));
+        assert_eq!(json["hooks"]["deploy"], "systemctl reload nginx");
+        assert!(!json.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn test_ca_certificates_are_verified_unless_configured_otherwise() {
+        let flags = ["--acme-base-url", "https://ca.example.org", "--with-email", "admin@example.org"];
+        let (config, settings) = resolve_with(&flags, &[]).unwrap();
+        assert!(!config.accept_invalid_certs);
+        assert_eq!(setting(&settings, "accept-invalid-certs").source, Source::Default);
+        let (config, _) = resolve_with(&flags, &[("ACME_SENTRY_ACCEPT_INVALID_CERTS", "true")]).unwrap();
+        assert!(config.accept_invalid_certs);
+    }
+
+    #[test]
+    fn test_missing_and_invalid_settings_are_errors() {
+        let error = resolve_with(&["--acme-base-url", "https://ca.example.org"], &[]).unwrap_err();
+        assert!(error.to_string().contains("ACME_SENTRY_EMAIL"), "{}", error);
+        let flags = ["--acme-base-url", "https://ca.example.org", "--with-email", "admin@example.org"];
+        let error = resolve_with(&flags, &[("ACME_SENTRY_WORKERS", "many")]).unwrap_err();
+        assert!(error.to_string().starts_with("ACME_SENTRY_WORKERS"), "{}", error);
+        assert!(resolve_with(&flags, &[("ACME_SENTRY_MISSED_RUNS", "sometimes")]).is_err());
+        assert!(resolve_with(&["--yaml", "--with-email", "admin@example.org"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_a_generated_user_id_is_kept_for_the_next_runs() {
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().to_str().unwrap();
+        let flags = ["--acme-base-url", "https://ca.example.org", "--with-email", "admin@example.org", "--base-dir", base_dir];
+        let (listed, _) = resolve_with(&[&flags[..], &["jobs", "list"]].concat(), &[]).unwrap();
+        assert!(!dir.path().join("user-id").exists());
+
+        let (daemon, settings) = resolve_with(&[&flags[..], &["daemon"]].concat(), &[]).unwrap();
+        assert_ne!(daemon.user_id, listed.user_id);
+        assert_eq!(setting(&settings, "user.id").value, json!(daemon.user_id));
+        assert_eq!(resolve_with(&[&flags[..], &["daemon"]].concat(), &[]).unwrap().0.user_id, daemon.user_id);
+        assert_eq!(resolve_with(&[&flags[..], &["jobs", "list"]].concat(), &[]).unwrap().0.user_id, daemon.user_id);
+        let configured = resolve_with(&[&flags[..], &["--with-user-id", "a1b2c3", "daemon"]].concat(), &[]).unwrap();
+        assert_eq!(configured.0.user_id, "a1b2c3");
+    }
+
+    #[test]
+    fn test_output_dir_outside_the_base_dir_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().join("base");
+        let flags = ["--acme-base-url", "https://ca.example.org", "--with-email", "admin@example.org", "--base-dir", base_dir.to_str().unwrap()];
+        let error = resolve_with(&flags, &[("ACME_SENTRY_OUTPUT_DIR", "../out")]).unwrap_err();
+        assert!(error.to_string().starts_with("fs.output-dir"), "{}", error);
+        assert!(resolve_with(&flags, &[("ACME_SENTRY_OUTPUT_DIR", "certs/out")]).is_ok());
+    }
+
+    #[test]
+    fn test_config_check_finds_the_file_like_every_other_command() {
+        let file = |flags: &[&str], env: &[(&str, &str)]| {
+            let matches = Args::command().try_get_matches_from([&["acme-sentry"], flags, &["config", "check"]].concat()).unwrap();
+            let args = Args::from_arg_matches(&matches).unwrap();
+            let env: HashMap<String, String> = env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+            yaml_file(&args, &matches, &|name| env.get(name).cloned()).unwrap()
+        };
+        assert_eq!(file(&[], &[]), None);
+        assert_eq!(file(&[], &[("ACME_SENTRY_YAML_CONFIG", "env.yaml")]), Some("env.yaml".to_string()));
+        let flags = ["--yaml-config", "cli.yaml"];
+        assert_eq!(file(&flags, &[("ACME_SENTRY_YAML_CONFIG", "env.yaml")]), Some("cli.yaml".to_string()));
+    }
+}
@@ -0,0 +1,496 @@
+use crate::certificate_output::OutputFormat;
+use crate::job_execution::recurring::{MissedRunPolicy, Schedule};
+use crate::statics::{YamlConfig, certificate_problems};
+use acme_client::crypto::SupportedKey;
+use common_utils::fs::{lookup_gid, lookup_uid, resolve_path};
+use common_utils::{CertificateAuthority, DEFAULT_CA_NAME};
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use yaml_rust2::parser::{MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+use yaml_rust2::Event;
+
+const LOGGING_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+/// A problem with the configuration file, located at the key it is about.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    /// Dotted path of the key, e.g. `acme-sentry.certificates[0].key-type`, empty for
+    /// problems with the file as a whole.
+    pub path: String,
+    /// 1-based, unset for problems the file has no place for.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}: ", line, column)?,
+            (Some(line), None) => write!(f, "{}: ", line)?,
+            _ => {}
+        }
+        match self.path.as_str() {
+            "" => write!(f, "{}", self.message),
+            path => write!(f, "{}: {}", path, self.message),
+        }
+    }
+}
+
+/// Checks the configuration file at `file`, every problem found is reported. An empty list
+/// means the file can be loaded. `probe` checks the directories as well, by creating and
+/// removing a file in them, see [`writable_dir`].
+pub fn check_file(file: &str, probe: bool) -> Vec<Diagnostic> {
+    match fs::read_to_string(file) {
+        Ok(text) => check(text.as_str(), probe),
+        Err(e) => vec![Diagnostic {
+            path: String::new(),
+            line: None,
+            column: None,
+            message: format!("{} can't be read: {}", file, e),
+        }],
+    }
+}
+
+/// Checks a configuration. Syntax errors and keys of the wrong shape stop the check at the
+/// first one, everything after that is checked as a whole.
+pub fn check(text: &str, probe: bool) -> Vec<Diagnostic> {
+    let locations = match Locations::parse(text) {
+        Ok(locations) => locations,
+        Err(e) => {
+            return vec![Diagnostic {
+                path: String::new(),
+                line: Some(e.marker().line()),
+                column: Some(e.marker().col() + 1),
+                message: e.info().to_string(),
+            }];
+        }
+    };
+    // misspelled keys would be ignored and their setting silently fall back to the default
+    let mut unknown = Vec::new();
+    let deserializer = serde_yaml::Deserializer::from_str(text);
+    let config: YamlConfig = match serde_ignored::deserialize(deserializer, |path| unknown.push(ignored_path(&path))) {
+        Ok(config) => config,
+        Err(e) => {
+            // the location is part of the diagnostic, not of the message
+            let message = e.to_string();
+            let message = message.split(" at line ").next().unwrap_or_default().to_string();
+            return vec![Diagnostic {
+                path: String::new(),
+                line: e.location().map(|l| l.line()),
+                column: e.location().map(|l| l.column()),
+                message,
+            }];
+        }
+    };
+    unknown
+        .into_iter()
+        .map(|path| {
+            let key = path.rsplit('.').next().unwrap_or_default().to_string();
+            (path, format!("Unknown key {}", key))
+        })
+        .chain(problems(&config, &locations.scalars, probe))
+        .map(|(path, message)| {
+            let (line, column) = locations.locate(path.as_str());
+            Diagnostic { path, line, column, message }
+        })
+        .collect()
+}
+
+/// Problems of a parsed configuration by the path of the key they are about. `scalars` are
+/// the values as they were written, for keys that are read leniently. The directories are
+/// only probed with `probe`.
+fn problems(config: &YamlConfig, scalars: &HashMap<String, String>, probe: bool) -> Vec<(String, String)> {
+    let root = "acme-sentry";
+    let configuration = &config.acme_sentry_configuration;
+    let mut problems = Vec::new();
+    let mut report = |path: String, message: String| problems.push((path, message));
+
+    let mut certificate_authorities = Vec::new();
+    if let Some(base_url) = &configuration.base_url {
+        if let Err(e) = http_url(base_url) {
+            report(format!("{}.base-url", root), e);
+        }
+        certificate_authorities.push(CertificateAuthority {
+            name: DEFAULT_CA_NAME.to_string(),
+            directory_url: format!("{}/dir", base_url),
+        });
+    }
+    for (index, ca) in configuration.certificate_authorities.iter().enumerate() {
+        let path = format!("{}.certificate-authorities[{}]", root, index);
+        if ca.name.is_empty() {
+            report(format!("{}.name", path), "CA name is empty".to_string());
+        } else if certificate_authorities.iter().any(|known: &CertificateAuthority| known.name == ca.name) {
+            report(format!("{}.name", path), format!("CA {} is configured more than once", ca.name));
+        }
+        if let Err(e) = http_url(ca.directory_url.as_str()) {
+            report(format!("{}.directory-url", path), e);
+        }
+        certificate_authorities.push(CertificateAuthority {
+            name: ca.name.clone(),
+            directory_url: ca.directory_url.clone(),
+        });
+    }
+
+    if let Some(email) = configuration.user.email.as_deref().filter(|email| !valid_email(email)) {
+        report(format!("{}.user.email", root), format!("{:?} isn't an email address", email));
+    }
+    if let Some(Err(e)) = configuration.user.key_type.as_deref().map(SupportedKey::from_str) {
+        report(format!("{}.user.login-key-type", root), e.to_string());
+    }
+    let level_path = format!("{}.logging.logging-level", root);
+    if let Some(level) = scalars.get(&level_path).filter(|level| !LOGGING_LEVELS.contains(&level.to_lowercase().as_str())) {
+        report(level_path, format!("Unknown logging level {}, expected one of {}", level, LOGGING_LEVELS.join(", ")));
+    }
+
+    let fs_config = &configuration.fs;
+    // the directories may as well be set in the environment or on the command line, the
+    // defaults are only checked once the configuration is resolved
+    if let Some(Err(e)) = fs_config.base_dir.as_deref().filter(|_| probe).map(|base_dir| writable_dir(Path::new(base_dir))) {
+        report(format!("{}.fs.base-dir", root), e);
+    }
+    if let Some(output_dir) = fs_config.output_dir.as_ref().filter(|_| probe) {
+        let base_dir = fs_config.base_dir.as_deref().unwrap_or(".");
+        if let Err(e) = writable_dir(&Path::new(base_dir).join(output_dir)) {
+            report(format!("{}.fs.output-dir", root), e);
+        }
+    }
+    let output_dir = fs_config.base_dir.as_deref().map(Path::new).zip(fs_config.output_dir.as_deref());
+    if let Some(Err(e)) = output_dir.map(|(base_dir, output_dir)| inside_base_dir(base_dir, &base_dir.join(output_dir))) {
+        report(format!("{}.fs.output-dir", root), e);
+    }
+    for format in &fs_config.output_formats {
+        if let Err(e) = OutputFormat::from_str(format) {
+            report(format!("{}.fs.output-formats", root), e.to_string());
+        }
+    }
+    if let Some(Err(e)) = fs_config.owner.as_deref().map(lookup_uid) {
+        report(format!("{}.fs.owner", root), e.to_string());
+    }
+    if let Some(Err(e)) = fs_config.group.as_deref().map(lookup_gid) {
+        report(format!("{}.fs.group", root), e.to_string());
+    }
+
+    let scheduler = &configuration.scheduler;
+    if let Some(Err(e)) = scheduler.directory_refresh.as_deref().map(Schedule::parse) {
+        report(format!("{}.scheduler.directory-refresh", root), e.to_string());
+    }
+    if let Some(Err(e)) = scheduler.reconciliation.as_deref().map(Schedule::parse) {
+        report(format!("{}.scheduler.reconciliation", root), e.to_string());
+    }
+    if let Some(Err(e)) = scheduler.missed_runs.as_deref().map(MissedRunPolicy::from_str) {
+        report(format!("{}.scheduler.missed-runs", root), e.to_string());
+    }
+    if scheduler.workers == Some(0) {
+        report(format!("{}.scheduler.workers", root), "At least one worker is needed".to_string());
+    }
+    if scheduler.max_ca_requests == Some(0) {
+        report(format!("{}.scheduler.max-ca-requests", root), "At least one request to the CA has to be allowed".to_string());
+    }
+
+    let mut names = HashSet::new();
+    for (index, certificate) in configuration.certificates.iter().enumerate() {
+        let path = format!("{}.certificates[{}]", root, index);
+        if !names.insert(certificate.name.as_str()) {
+            report(format!("{}.name", path), format!("Certificate {} is declared more than once", certificate.name));
+        }
+        // without a CA in the file they come from the environment or the command line, the
+        // CAs of the certificates are checked once they are resolved
+        let problems = certificate_problems(certificate, &certificate_authorities);
+        for (key, problem) in problems.into_iter().filter(|(key, _)| *key != "ca" || !certificate_authorities.is_empty()) {
+            report(format!("{}.{}", path, key), problem);
+        }
+    }
+    problems
+}
+
+fn http_url(value: &str) -> Result<(), String> {
+    let url = Url::parse(value).map_err(|e| format!("{:?} isn't a valid url: {}", value, e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(format!("{:?} has to be an http or https url, not {}", value, scheme)),
+    }
+}
+
+/// Good enough to catch typos, the CA has the final say on the address.
+pub fn valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+/// The output directory holds the keys the database refers to, a backup of the base directory
+/// only restores them if it's inside of it.
+pub fn inside_base_dir(base_dir: &Path, output_dir: &Path) -> Result<(), String> {
+    let resolve = |path: &Path| resolve_path(path).map_err(|e| format!("{} can't be resolved: {}", path.display(), e));
+    if resolve(output_dir)?.starts_with(resolve(base_dir)?) {
+        Ok(())
+    } else {
+        Err(format!("{} is outside of the base directory {}, it has to be inside of it", output_dir.display(), base_dir.display()))
+    }
+}
+
+/// A directory files can be created in, or one that can be created. The nearest existing
+/// directory is probed with a file that is removed right away.
+pub fn writable_dir(path: &Path) -> Result<(), String> {
+    let mut existing = path;
+    while !existing.exists() {
+        existing = match existing.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+    }
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()));
+    }
+    let probe = existing.join(format!(".acme-sentry-check-{}", std::process::id()));
+    fs::File::create(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| format!("{} is not writable: {}", existing.display(), e))
+}
+
+/// The path of a key serde ignored, in the notation of [`Diagnostic::path`].
+fn ignored_path(path: &serde_ignored::Path) -> String {
+    match path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent, index } => format!("{}[{}]", ignored_path(parent), index),
+        serde_ignored::Path::Map { parent, key } => join(ignored_path(parent).as_str(), key.as_str()),
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => ignored_path(parent),
+    }
+}
+
+fn join(parent: &str, key: &str) -> String {
+    match parent {
+        "" => key.to_string(),
+        parent => format!("{}.{}", parent, key),
+    }
+}
+
+enum Node {
+    Mapping { path: String, key: Option<String> },
+    Sequence { path: String, next: usize },
+}
+
+/// Where the keys and sequence items of a YAML document start, by their dotted path.
+#[derive(Default)]
+struct Locations {
+    /// 1-based line and column.
+    marks: HashMap<String, (usize, usize)>,
+    /// Scalar values as they were written.
+    scalars: HashMap<String, String>,
+    stack: Vec<Node>,
+}
+
+impl Locations {
+    fn parse(text: &str) -> Result<Locations, yaml_rust2::ScanError> {
+        let mut locations = Locations::default();
+        Parser::new_from_str(text).load(&mut locations, false)?;
+        Ok(locations)
+    }
+
+    /// Line and column of `path`, or of the closest enclosing key that is in the file.
+    fn locate(&self, path: &str) -> (Option<usize>, Option<usize>) {
+        let mut path = path;
+        loop {
+            if let Some((line, column)) = self.marks.get(path) {
+                return (Some(*line), Some(*column));
+            }
+            match path.rfind(['.', '[']) {
+                Some(end) => path = &path[..end],
+                None => return (None, None),
+            }
+        }
+    }
+
+    /// Path of the value starting at `mark`, `None` if the value is a mapping key.
+    fn value_path(&mut self, mark: Marker) -> Option<String> {
+        match self.stack.last_mut() {
+            None => Some(String::new()),
+            Some(Node::Mapping { path, key }) => key.take().map(|key| join(path, key.as_str())),
+            Some(Node::Sequence { path, next }) => {
+                let item = format!("{}[{}]", path, next);
+                *next += 1;
+                self.marks.insert(item.clone(), (mark.line(), mark.col() + 1));
+                Some(item)
+            }
+        }
+    }
+}
+
+impl MarkedEventReceiver for Locations {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => {
+                if let Some(Node::Mapping { path, key: key @ None }) = self.stack.last_mut() {
+                    self.marks.insert(join(path, value.as_str()), (mark.line(), mark.col() + 1));
+                    *key = Some(value);
+                } else if let Some(path) = self.value_path(mark) {
+                    self.scalars.insert(path, value);
+                }
+            }
+            Event::Alias(_) => {
+                self.value_path(mark);
+            }
+            Event::MappingStart(..) | Event::SequenceStart(..) => {
+                // mappings as keys aren't used by the configuration, they end up at the root
+                let path = self.value_path(mark).unwrap_or_default();
+                self.stack.push(match event {
+                    Event::MappingStart(..) => Node::Mapping { path, key: None },
+                    _ => Node::Sequence { path, next: 0 },
+                });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, valid_email};
+    use tempfile::TempDir;
+
+    fn config(base_dir: &str) -> String {
+        format!(
+            r#"acme-sentry:
+  certificate-authorities:
+    - name: letsencrypt
+      directory-url: https://acme-v02.api.letsencrypt.org/directory
+  fs:
+    base-dir: {}
+    output-dir: out
+  user:
+    email: admin@example.org
+    login-key-type: ec-p256
+  logging:
+    logging-level: info
+  certificates:
+    - name: www
+      identifiers: [example.org, www.example.org]
+      challenge:
+        method: http-01
+        webroot: /var/www/html
+"#,
+            base_dir
+        )
+    }
+
+    #[test]
+    fn test_valid_configuration_has_no_diagnostics() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(check(config(dir.path().to_str().unwrap()).as_str(), true), vec![]);
+    }
+
+    #[test]
+    fn test_every_problem_is_reported_at_its_line() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let yaml = config(dir.path().to_str().unwrap())
+            .replace("https://acme-v02.api.letsencrypt.org/directory", "acme-v02.api.letsencrypt.org")
+            .replace("output-dir: out", &format!("output-dir: {}", file.join("out").display()))
+            .replace("admin@example.org", "admin")
+            .replace("login-key-type: ec-p256", "login-key-type: ec-p999")
+            .replace("logging-level: info", "logging-level: loud")
+            .replace("[example.org, www.example.org]", "[example.org, \"*.example.org\"]")
+            + "    - name: www\n      identifiers: [192.0.2.1]\n      key-type: dsa\n      challenge:\n        method: dns-01\n        server: ns1\n";
+        let diagnostics: Vec<_> = check(yaml.as_str(), true).into_iter().map(|d| (d.line.unwrap(), d.path)).collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                (4, "acme-sentry.certificate-authorities[0].directory-url".to_string()),
+                (9, "acme-sentry.user.email".to_string()),
+                (10, "acme-sentry.user.login-key-type".to_string()),
+                (12, "acme-sentry.logging.logging-level".to_string()),
+                (7, "acme-sentry.fs.output-dir".to_string()),
+                (15, "acme-sentry.certificates[0].identifiers".to_string()),
+                (19, "acme-sentry.certificates[1].name".to_string()),
+                (22, "acme-sentry.certificates[1].challenge".to_string()),
+                (22, "acme-sentry.certificates[1].challenge".to_string()),
+                (20, "acme-sentry.certificates[1].identifiers".to_string()),
+                (21, "acme-sentry.certificates[1].key-type".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_configuration_stops_at_the_first_problem() {
+        let dir = TempDir::new().unwrap();
+        let yaml = config(dir.path().to_str().unwrap()).replace("  logging:\n", "  scheduler:\n    workers: many\n  logging:\n");
+        let diagnostics = check(yaml.as_str(), true);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, Some(12));
+        assert!(diagnostics[0].message.contains("invalid type"), "{}", diagnostics[0].message);
+        let diagnostics = check("acme-sentry:\n  fs: [unclosed\n", true);
+        assert_eq!((diagnostics.len(), diagnostics[0].line), (1, Some(3)));
+    }
+
+    #[test]
+    fn test_unknown_keys_are_reported_at_their_line() {
+        let dir = TempDir::new().unwrap();
+        let yaml = config(dir.path().to_str().unwrap())
+            .replace("    base-dir:", "    bsae-dir:")
+            .replace("      identifiers:", "      renewal:\n        befor-expiry: 30d\n      identifiers:");
+        let diagnostics: Vec<_> = check(yaml.as_str(), false).into_iter().map(|d| (d.line.unwrap(), d.path, d.message)).collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                (6, "acme-sentry.fs.bsae-dir".to_string(), "Unknown key bsae-dir".to_string()),
+                (16, "acme-sentry.certificates[0].renewal.befor-expiry".to_string(), "Unknown key befor-expiry".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directories_are_only_probed_on_request() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let yaml = config(file.to_str().unwrap());
+        assert_eq!(check(yaml.as_str(), false), vec![]);
+        let probed: Vec<_> = check(yaml.as_str(), true).into_iter().map(|d| d.path).collect();
+        assert_eq!(probed, vec!["acme-sentry.fs.base-dir".to_string(), "acme-sentry.fs.output-dir".to_string()]);
+    }
+
+    #[test]
+    fn test_output_dir_has_to_be_inside_the_base_dir() {
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().join("base");
+        let yaml = config(base_dir.to_str().unwrap());
+        for output_dir in ["../out", dir.path().join("out").to_str().unwrap()] {
+            let diagnostics = check(yaml.replace("output-dir: out", &format!("output-dir: {}", output_dir)).as_str(), false);
+            assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+            assert_eq!((diagnostics[0].line, diagnostics[0].path.as_str()), (Some(7), "acme-sentry.fs.output-dir"));
+            assert!(diagnostics[0].message.contains("outside of the base directory"), "{}", diagnostics[0].message);
+        }
+        let inside = yaml.replace("output-dir: out", &format!("output-dir: {}", base_dir.join("certs/../out").display()));
+        assert_eq!(check(inside.as_str(), false), vec![]);
+    }
+
+    #[test]
+    fn test_email_addresses_are_checked_for_typos() {
+        assert!(valid_email("admin@example.org"));
+        assert!(!valid_email("admin"));
+        assert!(!valid_email("@example.org"));
+        assert!(!valid_email("admin@localhost"));
+        assert!(!valid_email("admin@example.org."));
+        assert!(!valid_email("ad min@example.org"));
+    }
+}
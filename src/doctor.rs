@@ -0,0 +1,370 @@
+use acme_client::certificate::CertificateChain;
+use acme_client::crypto::SupportedKey;
+use acme_client::keys::PrivateKey;
+use common_utils::ApplicationConfig;
+use common_utils::fs::{FileOptions, FileSystem};
+use persistence::data_model::AcmeUser;
+use persistence::repository::Repositories;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+const LIVE_DIR: &str = "live";
+const LOGIN_KEYS_DIR: &str = "login-keys";
+/// Under the base dir, orphan files are moved here instead of being deleted.
+const QUARANTINE_DIR: &str = "orphaned";
+
+/// Something the database and the files on disk disagree about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    /// The key of a user or one of its accounts is gone. A `registered` key can't be replaced,
+    /// the CA only knows the account by the lost one.
+    MissingKey {
+        user_id: String,
+        key_type: String,
+        path: PathBuf,
+        registered: bool,
+    },
+    /// A login key under the output dir no user or account refers to.
+    OrphanFile { path: PathBuf },
+    /// `live/<name>/privkey.pem` is not the key of `live/<name>/cert.pem`.
+    KeyMismatch { name: String },
+    /// A directory row whose user has been deleted.
+    OrphanDirectory { directory_id: i64, user_id: String, ca_name: String },
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Finding::MissingKey { user_id, path, registered: true, .. } => {
+                write!(f, "Key {} of user {} is missing, an account is registered with it", path.display(), user_id)
+            }
+            Finding::MissingKey { user_id, path, .. } => write!(f, "Key {} of user {} is missing", path.display(), user_id),
+            Finding::OrphanFile { path } => write!(f, "Key {} does not belong to any user", path.display()),
+            Finding::KeyMismatch { name } => write!(f, "Private key of certificate {} does not match the certificate", name),
+            Finding::OrphanDirectory { directory_id, user_id, ca_name } => {
+                write!(f, "Directory {} of CA {} belongs to deleted user {}", directory_id, ca_name, user_id)
+            }
+        }
+    }
+}
+
+/// Reconciles the database with the key and certificate files under the output dir.
+pub struct Doctor<'a> {
+    repositories: &'a Repositories,
+    output: FileSystem,
+    quarantine: PathBuf,
+}
+
+impl<'a> Doctor<'a> {
+    pub fn new(repositories: &'a Repositories, output_dir: &str, base_dir: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Doctor {
+            repositories,
+            output: FileSystem::new(output_dir)?,
+            quarantine: Path::new(base_dir).join(QUARANTINE_DIR),
+        })
+    }
+
+    pub fn check(&self) -> Result<Vec<Finding>, Box<dyn Error>> {
+        let mut findings = Vec::new();
+        let mut expected = BTreeSet::new();
+        for user in self.repositories.users.list()? {
+            for (path, registered) in self.key_files(&user)? {
+                if path.exists() {
+                    expected.insert(path.canonicalize()?);
+                } else {
+                    findings.push(Finding::MissingKey {
+                        user_id: user.user_id.clone(),
+                        key_type: user.key_type.clone(),
+                        path,
+                        registered,
+                    });
+                }
+            }
+        }
+        for path in self.login_keys()? {
+            if !expected.contains(&path) {
+                findings.push(Finding::OrphanFile { path });
+            }
+        }
+        for name in self.live_certificates()? {
+            if !self.key_matches(name.as_str()).unwrap_or(false) {
+                findings.push(Finding::KeyMismatch { name });
+            }
+        }
+        for directory in self.repositories.directories.orphaned()? {
+            findings.push(Finding::OrphanDirectory {
+                directory_id: directory.directory_id,
+                user_id: directory.user_id,
+                ca_name: directory.ca_name,
+            });
+        }
+        Ok(findings)
+    }
+
+    /// Repairs `finding` and describes what was done. Registered keys and mismatching
+    /// certificates are refused, they need a restored key or a new certificate.
+    pub fn repair(&self, finding: &Finding) -> Result<String, Box<dyn Error>> {
+        match finding {
+            Finding::MissingKey { registered: true, .. } => {
+                Err("Refusing to replace the key of a registered account, restore it from a backup".into())
+            }
+            Finding::MissingKey { key_type, path, .. } => {
+                let key = PrivateKey::from_supported_type(SupportedKey::from_str(key_type.as_str())?)?;
+                let dir = path.parent().ok_or("Key path has no directory")?;
+                let name = path.file_name().ok_or("Key path has no file name")?;
+                self.output.ensure_sub_dir(dir.to_str().unwrap_or_default())?;
+                self.output.write_to_file_with(
+                    dir.to_str().unwrap_or_default(),
+                    name.to_str().unwrap_or_default(),
+                    key.get_pem_bytes()?.as_slice(),
+                    &FileOptions::private(),
+                )?;
+                Ok(format!("Generated a new {} key", key_type))
+            }
+            Finding::OrphanFile { path } => {
+                let relative = path.strip_prefix(self.output.base_dir())?;
+                let target = self.quarantine.join(relative);
+                fs::create_dir_all(target.parent().ok_or("Quarantine path has no directory")?)?;
+                fs::rename(path, &target)?;
+                Ok(format!("Moved to {}", target.display()))
+            }
+            Finding::KeyMismatch { .. } => Err("Reissue the certificate to replace it".into()),
+            Finding::OrphanDirectory { directory_id, .. } => {
+                self.repositories.directories.delete(*directory_id)?;
+                Ok("Deleted the directory".to_string())
+            }
+        }
+    }
+
+    /// The key files of `user` and its accounts, with whether an account is registered with them.
+    fn key_files(&self, user: &AcmeUser) -> Result<Vec<(PathBuf, bool)>, Box<dyn Error>> {
+        let file_name = format!("{}.pem", user.user_id);
+        let mut files = vec![(Path::new(user.key_path.as_str()).join(&file_name), user.account_url.is_some())];
+        for account in self.repositories.accounts.for_user(user.id)? {
+            let path = Path::new(account.key_path.as_str()).join(&file_name);
+            match files.iter_mut().find(|(known, _)| *known == path) {
+                Some((_, registered)) => *registered |= account.account_url.is_some(),
+                None => files.push((path, account.account_url.is_some())),
+            }
+        }
+        Ok(files)
+    }
+
+    /// Every `<user>/login-keys/<key type>/*.pem` under the output dir.
+    fn login_keys(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut keys = Vec::new();
+        for user_dir in self.output.list_dirs("")? {
+            let login_keys = format!("{}/{}", user_dir, LOGIN_KEYS_DIR);
+            for key_type in self.output.list_dirs(login_keys.as_str())? {
+                let dir = format!("{}/{}", login_keys, key_type);
+                for file in self.output.list_files(dir.as_str())? {
+                    if file.ends_with(".pem") {
+                        keys.push(self.output.sub_dir(dir.as_str())?.join(file));
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Names of the certificates in `live`, the links to their current version and the
+    /// directories certificates written before those links existed have.
+    fn live_certificates(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut names = self.output.list_links(LIVE_DIR)?;
+        names.extend(self.output.list_dirs(LIVE_DIR)?.into_iter().filter(|dir| !dir.starts_with('.')));
+        names.sort();
+        Ok(names)
+    }
+
+    fn key_matches(&self, name: &str) -> Result<bool, Box<dyn Error>> {
+        let live = format!("{}/{}", LIVE_DIR, name);
+        let chain = CertificateChain::from_pem(&self.output.read_from_file(live.as_str(), "cert.pem")?)?;
+        let key_type = chain.leaf().key_type()?.ok_or("Unsupported certificate key type")?;
+        let key = PrivateKey::load_private_bytes(&self.output.read_from_file(live.as_str(), "privkey.pem")?, key_type)?;
+        chain.leaf().matches_key(&key)
+    }
+}
+
+/// Report-only check on startup, problems are logged but never repaired.
+pub fn startup_check(config: &ApplicationConfig, repositories: &Repositories) {
+    let findings = Doctor::new(repositories, config.output_dir.as_str(), config.base_dir.as_str()).and_then(|d| d.check());
+    match findings {
+        Ok(findings) if findings.is_empty() => {}
+        Ok(findings) => {
+            for finding in &findings {
+                warn!("{}", finding);
+            }
+            warn!("{} problem(s) found, run db check --repair to repair what can be repaired", findings.len());
+        }
+        Err(e) => warn!("Database and files on disk could not be checked: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Doctor, Finding};
+    use crate::certificate_output::CertificateOutput;
+    use acme_client::certificate::CertificateChain;
+    use acme_client::crypto::SupportedKey;
+    use acme_client::keys::PrivateKey;
+    use acme_client::comms::directory::AcmeDirectoryApi;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use persistence::data_model::AcmeUser;
+    use persistence::repository::Repositories;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn self_signed(key: &PrivateKey) -> CertificateChain {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "example.org").unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key.k).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(90).unwrap()).unwrap();
+        builder.sign(&key.k, MessageDigest::sha256()).unwrap();
+        CertificateChain::from_pem(&builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    /// A user with its key on disk, laid out the way `InitializeLocalUserJob` does it.
+    fn user(repositories: &Repositories, dir: &TempDir, user_id: &str) -> AcmeUser {
+        let out = dir.path().canonicalize().unwrap().join("out");
+        let keys = out.join(format!("{}/login-keys/ec-p256", user_id));
+        fs::create_dir_all(&keys).unwrap();
+        let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        fs::write(keys.join(format!("{}.pem", user_id)), key.get_pem_bytes().unwrap()).unwrap();
+        let dump = out.join(user_id);
+        repositories
+            .users
+            .create(user_id, "ec-p256", keys.to_str().unwrap(), dump.to_str().unwrap())
+            .unwrap()
+    }
+
+    fn doctor<'a>(repositories: &'a Repositories, dir: &TempDir) -> Doctor<'a> {
+        let base = dir.path().to_str().unwrap();
+        Doctor::new(repositories, format!("{}/out", base).as_str(), base).unwrap()
+    }
+
+    #[test]
+    fn test_consistent_state_has_no_findings() {
+        let dir = tempfile::tempdir().unwrap();
+        let repositories = Repositories::in_memory();
+        let user = user(&repositories, &dir, "a1b2c3");
+        repositories.accounts.ensure(user.id, "default", "https://ca/dir", user.key_path.as_str()).unwrap();
+        let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        let output = CertificateOutput::new(dir.path().join("out").to_str().unwrap(), &[], None).unwrap();
+        output.write("web", &self_signed(&key), &key).unwrap();
+        assert!(doctor(&repositories, &dir).check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_registered_keys_are_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let repositories = Repositories::in_memory();
+        let registered = user(&repositories, &dir, "a1b2c3");
+        let account = repositories.accounts.ensure(registered.id, "default", "https://ca/dir", registered.key_path.as_str()).unwrap();
+        repositories.accounts.register(account.account_id, "https://ca/acct/1").unwrap();
+        let pending = user(&repositories, &dir, "d4e5f6");
+        let registered_key = Path::new(registered.key_path.as_str()).join("a1b2c3.pem");
+        let pending_key = Path::new(pending.key_path.as_str()).join("d4e5f6.pem");
+        fs::remove_file(&registered_key).unwrap();
+        fs::remove_file(&pending_key).unwrap();
+
+        let doctor = doctor(&repositories, &dir);
+        let findings = doctor.check().unwrap();
+        assert_eq!(
+            findings,
+            vec![
+                Finding::MissingKey {
+                    user_id: "a1b2c3".to_string(),
+                    key_type: "ec-p256".to_string(),
+                    path: registered_key.clone(),
+                    registered: true,
+                },
+                Finding::MissingKey {
+                    user_id: "d4e5f6".to_string(),
+                    key_type: "ec-p256".to_string(),
+                    path: pending_key.clone(),
+                    registered: false,
+                },
+            ]
+        );
+        assert!(doctor.repair(&findings[0]).is_err());
+        assert!(!registered_key.exists());
+        doctor.repair(&findings[1]).unwrap();
+        let key = fs::read(&pending_key).unwrap();
+        assert!(PrivateKey::load_private_bytes(&key, SupportedKey::EcP256).is_ok());
+        assert_eq!(doctor.check().unwrap(), vec![findings[0].clone()]);
+    }
+
+    #[test]
+    fn test_orphans_are_quarantined_and_mismatches_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let repositories = Repositories::in_memory();
+        let user = user(&repositories, &dir, "a1b2c3");
+        let stray = Path::new(user.key_path.as_str()).join("old.pem");
+        fs::write(&stray, "key").unwrap();
+        let output = CertificateOutput::new(dir.path().join("out").to_str().unwrap(), &[], None).unwrap();
+        let key = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        let other = PrivateKey::from_supported_type(SupportedKey::EcP256).unwrap();
+        output.write("web", &self_signed(&key), &other).unwrap();
+
+        let doctor = doctor(&repositories, &dir);
+        let findings = doctor.check().unwrap();
+        assert_eq!(
+            findings,
+            vec![Finding::OrphanFile { path: stray.clone() }, Finding::KeyMismatch { name: "web".to_string() }]
+        );
+        doctor.repair(&findings[0]).unwrap();
+        assert!(!stray.exists());
+        let quarantined = dir.path().join("orphaned/a1b2c3/login-keys/ec-p256/old.pem");
+        assert_eq!(fs::read_to_string(quarantined).unwrap(), "key");
+        assert!(doctor.repair(&findings[1]).is_err());
+        assert_eq!(doctor.check().unwrap(), vec![findings[1].clone()]);
+    }
+
+    #[test]
+    fn test_directories_of_deleted_users_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap();
+        let repositories = Repositories::sqlite(base);
+        repositories.schema.migrate().unwrap();
+        let user = user(&repositories, &dir, "a1b2c3");
+        let directory = AcmeDirectoryApi {
+            directory_id: 0,
+            user_id: String::new(),
+            key_change: "https://ca/key-change".to_string(),
+            new_authz: None,
+            new_nonce: "https://ca/nonce".to_string(),
+            new_account: "https://ca/new-acct".to_string(),
+            new_order: "https://ca/new-order".to_string(),
+            revoke_cert: "https://ca/revoke-cert".to_string(),
+        };
+        let saved = repositories.directories.save(user.id, "default", &directory).unwrap();
+        let connection = persistence::database::DatabaseConnection::open(base).unwrap();
+        connection.prepare("PRAGMA foreign_keys = OFF;").unwrap().next().unwrap();
+        connection.prepare("DELETE FROM acme_users;").unwrap().next().unwrap();
+
+        let doctor = doctor(&repositories, &dir);
+        let findings = doctor.check().unwrap();
+        assert_eq!(
+            findings.last(),
+            Some(&Finding::OrphanDirectory {
+                directory_id: saved.directory_id,
+                user_id: user.id.to_string(),
+                ca_name: "default".to_string(),
+            })
+        );
+        doctor.repair(findings.last().unwrap()).unwrap();
+        assert!(repositories.directories.orphaned().unwrap().is_empty());
+    }
+}
@@ -1,2 +1,5 @@
 pub mod job_base;
-pub mod job_registry;
\ No newline at end of file
+pub mod job_registry;
+pub mod job_store;
+pub mod recurring;
+pub mod retry;
\ No newline at end of file
@@ -1,38 +1,617 @@
+use crate::job_execution::job_registry::JobRegistry;
+use crate::job_execution::job_store::{now, JobStore};
+use crate::job_execution::recurring::{MissedRunPolicy, Schedule};
+use crate::job_execution::retry::{is_permanent, RetryPolicy};
+use anyhow::anyhow;
 use async_trait::async_trait;
+use persistence::data_model::{JobRecord, JobStatus};
+use persistence::repository::{JobRepository, Repositories};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::Value;
-use tokio::sync::{mpsc, oneshot, watch};
-use tracing::{error, info, instrument, trace, warn, Instrument, Span};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinSet};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, warn, Instrument};
+
+/// How often a persistent scheduler looks for its jobs being cancelled by another process,
+/// e.g. `jobs cancel` while the daemon is running.
+pub const CANCELLATION_CHECK: Duration = Duration::from_secs(2);
+
+/// Identifies a submitted job, for persisted jobs it's the row id in the `jobs` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(pub i64);
+
+impl Display for JobId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Published on every status change of a job, see [`SchedulerHandle::subscribe`].
+#[derive(Debug, Clone)]
+pub struct JobEvent {
+    pub job_id: JobId,
+    pub job_type: &'static str,
+    pub status: JobStatus,
+    /// Why the job failed, will be retried or was skipped.
+    pub error: Option<String>,
+}
+
+/// Everything a job gets to see while it's executing.
+#[derive(Clone, Debug)]
+pub struct JobContext {
+    pub job_id: JobId,
+    /// The jobs that had to succeed before this one could run.
+    pub depends_on: Vec<JobId>,
+    pub handle: SchedulerHandle,
+    /// Cancelled by [`SchedulerHandle::cancel`] or when a shutdown deadline passes, long
+    /// running jobs should check it between steps. The scheduler stops polling the job
+    /// either way, so anything not cancellation safe has to be guarded by the job itself.
+    pub cancel: CancellationToken,
+    /// Where the job reads and writes users, directories, certificates and the like.
+    pub repositories: Repositories,
+}
+impl JobContext {
+    /// Runs `work` on the repositories off the runtime threads, see [`blocking`].
+    pub async fn with_repositories<T, F>(&self, work: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Repositories) -> anyhow::Result<T> + Send + 'static,
+    {
+        let repositories = self.repositories.clone();
+        blocking(move || work(&repositories)).await
+    }
+    /// Hands `output` to the jobs depending on this one, see [`upstream`](Self::upstream).
+    pub async fn set_output<T: JobOutput>(&self, output: &T) -> anyhow::Result<()> {
+        let (handle, job_id, output) = (self.handle.clone(), self.job_id, serde_json::to_value(output)?);
+        blocking(move || handle.set_output(job_id, T::OUTPUT_TYPE, output)).await
+    }
+    /// The output of type `T` handed over by one of the jobs this one depends on.
+    pub async fn upstream<T: JobOutput>(&self) -> anyhow::Result<T> {
+        for upstream in self.depends_on.iter().copied() {
+            let handle = self.handle.clone();
+            match blocking(move || handle.output(upstream)).await? {
+                Some((output_type, output)) if output_type == T::OUTPUT_TYPE => return Ok(serde_json::from_value(output)?),
+                _ => continue,
+            }
+        }
+        Err(anyhow!("None of the jobs {:?} handed over a {}", self.depends_on, T::OUTPUT_TYPE))
+    }
+}
+
+/// Runs blocking work, the repositories in particular, on tokio's blocking pool so it doesn't
+/// hold up the runtime threads the scheduler and the other jobs are polled on.
+pub async fn blocking<T, F>(work: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    let span = tracing::Span::current();
+    task::spawn_blocking(move || span.in_scope(work)).await?
+}
+
+/// Typed result a job hands to the jobs depending on it, e.g. the account URL after registration.
+pub trait JobOutput: Serialize + DeserializeOwned {
+    /// Tells outputs apart when a job depends on several others.
+    const OUTPUT_TYPE: &'static str;
+}
+
+/// What happens to a job when one of the jobs it depends on doesn't succeed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DependencyFailure {
+    /// The job is marked skipped and never runs.
+    Skip,
+    /// The job ends up in the dead-letter queue, from where it can be replayed.
+    Fail,
+}
 
 #[async_trait]
 pub trait Job: Send + 'static {
     fn job_type(&self) -> &'static str;
     fn payload(&self) -> Value;
-    async fn execute(&self, handle: SchedulerHandle) -> anyhow::Result<()>;
+    async fn execute(&self, context: JobContext) -> anyhow::Result<()>;
+    /// Jobs aren't retried unless they opt in.
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::none()
+    }
+    /// Errors marked [`Permanent`](crate::job_execution::retry::Permanent) are never retried.
+    fn is_retryable(&self, error: &anyhow::Error) -> bool {
+        !is_permanent(error)
+    }
+    /// Jobs sharing a key (e.g. a user id or certificate name) run one after another,
+    /// jobs without one run in parallel on any free worker.
+    fn concurrency_key(&self) -> Option<String> {
+        None
+    }
+    /// An attempt running longer than this fails with a (retryable) timeout error.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+    fn on_dependency_failure(&self) -> DependencyFailure {
+        DependencyFailure::Skip
+    }
+    fn priority(&self) -> Priority {
+        Priority::Normal
+    }
+    /// Submitting a job while one with the same key is queued, running or waiting for a retry
+    /// hands out the id of that job instead. Made of `job_type()` and `payload()` by default,
+    /// jobs that have to run once per submission return `None`.
+    fn idempotency_key(&self) -> Option<String> {
+        Some(format!("{}:{}", self.job_type(), self.payload()))
+    }
+}
+
+/// Order in which pending jobs are started, jobs of the same priority start in submission order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Background work like the periodic directory refresh.
+    Low,
+    Normal,
+    High,
+    /// Revocations and anything that has to be done before a shutdown.
+    Critical,
+}
+/// A job on its way to the scheduler, `record_id` is its row in the `jobs` table if persisted.
+struct QueuedJob {
+    id: JobId,
+    record_id: Option<i64>,
+    /// Attempts made so far.
+    attempts: u32,
+    cancel: CancellationToken,
+    depends_on: Vec<JobId>,
+    priority: Priority,
+    job: Box<dyn Job>,
+}
+
+/// Jobs that are queued, running or waiting for a retry or for their dependencies.
+#[derive(Debug, Default)]
+struct LiveJobs {
+    tokens: HashMap<JobId, CancellationToken>,
+    /// See [`Job::idempotency_key`].
+    keys: HashMap<String, JobId>,
 }
 enum SchedulerMessage {
-    Job(Box<dyn Job>),
-    Shutdown(oneshot::Sender<()>),
+    Job(QueuedJob),
+    /// Stops accepting jobs, the queue is drained until the optional deadline passes.
+    Shutdown(oneshot::Sender<()>, Option<Duration>),
+    /// Nudges the scheduler to look at its waiting jobs, e.g. after one of them was cancelled.
+    Wake,
 }
 #[derive(Clone, Debug)]
 pub struct SchedulerHandle {
     sender: mpsc::Sender<SchedulerMessage>,
     shutdown_rx: watch::Receiver<bool>,
+    store: Option<JobStore>,
+    ca_permits: Arc<Semaphore>,
+    live: Arc<Mutex<LiveJobs>>,
+    /// Parent of all job tokens, cancelled once a shutdown deadline passes.
+    shutdown_token: CancellationToken,
+    next_id: Arc<AtomicI64>,
+    /// Outputs of in-memory jobs, persisted ones go to the `job_outputs` table.
+    outputs: Arc<Mutex<HashMap<JobId, (String, Value)>>>,
+    /// Statuses of in-memory jobs, persisted ones are read from the `jobs` table.
+    statuses: Arc<Mutex<HashMap<JobId, JobStatus>>>,
+    events: broadcast::Sender<JobEvent>,
+    repositories: Repositories,
+    /// Held while a job is persisted, so two submissions with the same idempotency key can't
+    /// both miss the live one while the `live` lock is released for the insert.
+    submitting: Arc<tokio::sync::Mutex<()>>,
 }
 impl SchedulerHandle {
-    pub async fn submit<J: Job>(&self, job: J) -> Result<(), &'static str> {
-        self.sender
-            .send(SchedulerMessage::Job(Box::new(job)))
+    /// Waits for one of the global CA request slots, the permit has to be held until the
+    /// response from the CA has been read.
+    pub async fn ca_permit(&self) -> OwnedSemaphorePermit {
+        self.ca_permits
+            .clone()
+            .acquire_owned()
             .await
-            .map_err(|_| "Scheduler is shut down")
+            .expect("CA request semaphore is never closed")
+    }
+    pub async fn submit<J: Job>(&self, job: J) -> Result<JobId, &'static str> {
+        self.submit_boxed(Box::new(job)).await
+    }
+    pub async fn submit_boxed(&self, job: Box<dyn Job>) -> Result<JobId, &'static str> {
+        self.submit_with(job, &[]).await
+    }
+    /// Runs the job once every job in `depends_on` succeeded, if one of them doesn't the job
+    /// is skipped or failed according to its [`Job::on_dependency_failure`].
+    pub async fn submit_depending_on<J: Job>(&self, depends_on: &[JobId], job: J) -> Result<JobId, &'static str> {
+        self.submit_with(Box::new(job), depends_on).await
+    }
+    pub async fn submit_boxed_depending_on(&self, depends_on: &[JobId], job: Box<dyn Job>) -> Result<JobId, &'static str> {
+        self.submit_with(job, depends_on).await
+    }
+    async fn submit_with(&self, job: Box<dyn Job>, depends_on: &[JobId]) -> Result<JobId, &'static str> {
+        let (id, queued) = self.enqueue(job, None, depends_on).await?;
+        if let Some(queued) = queued {
+            self.send(queued).await?;
+        }
+        Ok(id)
+    }
+    /// Runs the job once `delay` has passed, the delay survives restarts of a persistent scheduler.
+    pub async fn submit_after<J: Job>(&self, delay: Duration, job: J) -> Result<JobId, &'static str> {
+        let run_at = now() + delay.as_millis().div_ceil(1000) as i64;
+        let (id, queued) = self.enqueue(Box::new(job), Some(run_at), &[]).await?;
+        if let Some(queued) = queued {
+            self.send_after(queued, delay);
+        }
+        Ok(id)
+    }
+    /// Runs the job at `at`, or right away if that's in the past.
+    pub async fn submit_at<J: Job>(&self, at: SystemTime, job: J) -> Result<JobId, &'static str> {
+        let delay = at.duration_since(SystemTime::now()).unwrap_or_default();
+        self.submit_after(delay, job).await
+    }
+    /// Cancels a queued, running or retrying job. A persisted job this scheduler doesn't run,
+    /// e.g. one of a daemon sharing the job repository, is marked cancelled there and the
+    /// scheduler running it cancels it once it notices, see [`CANCELLATION_CHECK`].
+    ///
+    /// Returns false if the job isn't known or already finished.
+    pub async fn cancel(&self, job_id: JobId) -> anyhow::Result<bool> {
+        let token = self.live.lock().unwrap().tokens.get(&job_id).cloned();
+        if let Some(token) = token {
+            info!("Cancelling job {}", job_id);
+            token.cancel();
+            // a job waiting for its dependencies isn't looked at otherwise
+            if self.sender.try_send(SchedulerMessage::Wake).is_err() {
+                debug!("Scheduler is busy or gone, it'll notice the cancellation on its own");
+            }
+            return Ok(true);
+        }
+        let Some(store) = self.store.clone() else {
+            return Ok(false);
+        };
+        blocking(move || {
+            let cancelled = store.mark_cancelled(job_id.0).map_err(|e| anyhow!(e.to_string()))?;
+            if cancelled {
+                info!("Marked job {} as cancelled for the scheduler running it", job_id);
+            }
+            Ok(cancelled)
+        })
+        .await
+    }
+    /// Cancels the live jobs some other process marked as cancelled in the job repository.
+    async fn notice_cancellations(&self) {
+        let Some(store) = self.store.clone() else {
+            return;
+        };
+        let live = self.live.lock().unwrap().tokens.keys().copied().collect::<Vec<JobId>>();
+        if live.is_empty() {
+            return;
+        }
+        let cancelled = blocking(move || {
+            let mut cancelled = Vec::new();
+            for job_id in live {
+                let record = store.get(job_id.0).map_err(|e| anyhow!(e.to_string()))?;
+                if record.is_some_and(|record| record.status == JobStatus::Cancelled) {
+                    cancelled.push(job_id);
+                }
+            }
+            Ok(cancelled)
+        })
+        .await;
+        match cancelled {
+            Ok(cancelled) => {
+                let live = self.live.lock().unwrap();
+                for token in cancelled.iter().filter_map(|job_id| live.tokens.get(job_id)) {
+                    token.cancel();
+                }
+            }
+            Err(e) => error!("Failed to look up cancelled jobs: {}", e),
+        }
+    }
+    /// Current status of a job, `None` if it's not known to this scheduler.
+    pub async fn status(&self, job_id: JobId) -> anyhow::Result<Option<JobStatus>> {
+        match self.store.clone() {
+            Some(store) => blocking(move || {
+                Ok(store
+                    .get(job_id.0)
+                    .map_err(|e| anyhow!(e.to_string()))?
+                    .map(|record| record.status))
+            })
+            .await,
+            None => Ok(self.statuses.lock().unwrap().get(&job_id).copied()),
+        }
+    }
+    /// Lifecycle events of every job submitted from now on. A receiver that falls behind
+    /// by more than the channel capacity loses the oldest events, see [`broadcast`].
+    pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+        self.events.subscribe()
+    }
+    /// Waits until the job succeeded, failed, was cancelled or skipped and returns that status.
+    ///
+    /// Fails if the job isn't known or the scheduler stops while the job is still unfinished.
+    pub async fn completion(&self, job_id: JobId) -> anyhow::Result<JobStatus> {
+        // subscribe first, so the final event can't slip in between
+        let mut events = self.subscribe();
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        // the status is only looked up again if events were missed
+        let mut look_up = true;
+        loop {
+            if look_up {
+                match self.status(job_id).await? {
+                    Some(status) if !status.is_unfinished() => return Ok(status),
+                    Some(_) => look_up = false,
+                    None => return Err(anyhow!("Job {} is not known to the scheduler", job_id)),
+                }
+            }
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = shutdown_rx.wait_for(|stopped| *stopped) => {
+                    return Err(anyhow!("Scheduler stopped before job {} finished", job_id));
+                }
+            };
+            match event {
+                Ok(event) if event.job_id == job_id && !event.status.is_unfinished() => return Ok(event.status),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("Missed {} job events while waiting for job {}", missed, job_id);
+                    look_up = true;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(anyhow!("Scheduler stopped before job {} finished", job_id));
+                }
+            }
+        }
+    }
+    /// Registers (or updates) the recurring job `name`, it's submitted by the
+    /// [`RecurringRunner`](crate::job_execution::recurring::RecurringRunner) whenever it's due.
+    pub async fn register_recurring<J: Job>(
+        &self,
+        name: &str,
+        schedule: &Schedule,
+        missed_runs: MissedRunPolicy,
+        job: &J,
+    ) -> anyhow::Result<()> {
+        let store = self
+            .store
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Recurring jobs require a persistent scheduler"))?;
+        let next_run_at = schedule.next_after(now())?;
+        let (name, job_type, payload, spec) = (name.to_string(), job.job_type(), job.payload(), schedule.spec());
+        let record = blocking(move || {
+            store
+                .upsert_recurring(
+                    name.as_str(),
+                    job_type,
+                    &payload,
+                    spec.as_str(),
+                    missed_runs.as_str(),
+                    next_run_at,
+                )
+                .map_err(|e| anyhow::anyhow!(e.to_string()))
+        })
+        .await?;
+        info!(
+            "Recurring job {} ({}) registered with schedule {}, next run at {}",
+            record.name, record.job_type, record.schedule, record.next_run_at
+        );
+        Ok(())
+    }
+    /// Persists the job if the scheduler has a store and hands out its id and token. Returns
+    /// the id of the already live job without a [`QueuedJob`] if the job is coalesced into it,
+    /// jobs with dependencies never are.
+    async fn enqueue(
+        &self,
+        job: Box<dyn Job>,
+        run_at: Option<i64>,
+        depends_on: &[JobId],
+    ) -> Result<(JobId, Option<QueuedJob>), &'static str> {
+        let key = if depends_on.is_empty() { job.idempotency_key() } else { None };
+        let _submitting = self.submitting.lock().await;
+        let existing = key.and_then(|key| self.live.lock().unwrap().keys.get(&key).copied());
+        if let Some(existing) = existing {
+            info!("Job {} is already queued as {}, not submitting it again", job.job_type(), existing);
+            return Ok((existing, None));
+        }
+        let record_id = match self.store.clone() {
+            Some(store) => {
+                let (job_type, payload) = (job.job_type(), job.payload());
+                let upstream = depends_on.iter().map(|id| id.0).collect::<Vec<i64>>();
+                let record_id = blocking(move || {
+                    store
+                        .insert_with_dependencies(job_type, &payload, run_at, &upstream)
+                        .map_err(|e| anyhow!(e.to_string()))
+                })
+                .await
+                .map_err(|e| {
+                    error!("Failed to persist job {}: {}", job_type, e);
+                    "Job could not be persisted"
+                })?;
+                Some(record_id)
+            }
+            None => None,
+        };
+        let id = JobId(record_id.unwrap_or_else(|| self.next_id.fetch_add(1, Ordering::SeqCst)));
+        let queued = self.track(&mut self.live.lock().unwrap(), id, record_id, 0, depends_on.to_vec(), job);
+        Ok((id, Some(queued)))
+    }
+    fn track(
+        &self,
+        live: &mut LiveJobs,
+        id: JobId,
+        record_id: Option<i64>,
+        attempts: u32,
+        depends_on: Vec<JobId>,
+        job: Box<dyn Job>,
+    ) -> QueuedJob {
+        let cancel = self.shutdown_token.child_token();
+        live.tokens.insert(id, cancel.clone());
+        if let Some(key) = job.idempotency_key().filter(|_| depends_on.is_empty()) {
+            live.keys.insert(key, id);
+        }
+        self.publish(id, job.job_type(), JobStatus::Queued, None);
+        QueuedJob {
+            id,
+            record_id,
+            attempts,
+            cancel,
+            depends_on,
+            priority: job.priority(),
+            job,
+        }
+    }
+    /// Records the new status of an in-memory job and tells the subscribers about it.
+    fn publish(&self, job_id: JobId, job_type: &'static str, status: JobStatus, error: Option<String>) {
+        if self.store.is_none() {
+            self.statuses.lock().unwrap().insert(job_id, status);
+        }
+        // nobody listening is fine
+        let _ = self.events.send(JobEvent {
+            job_id,
+            job_type,
+            status,
+            error,
+        });
+    }
+    fn is_live(&self, id: JobId) -> bool {
+        self.live.lock().unwrap().tokens.contains_key(&id)
+    }
+    fn set_output(&self, id: JobId, output_type: &str, output: Value) -> anyhow::Result<()> {
+        match &self.store {
+            Some(store) => store
+                .set_output(id.0, output_type, &output)
+                .map_err(|e| anyhow!(e.to_string())),
+            None => {
+                self.outputs.lock().unwrap().insert(id, (output_type.to_string(), output));
+                Ok(())
+            }
+        }
+    }
+    fn output(&self, id: JobId) -> anyhow::Result<Option<(String, Value)>> {
+        match &self.store {
+            Some(store) => store.output(id.0).map_err(|e| anyhow!(e.to_string())),
+            None => Ok(self.outputs.lock().unwrap().get(&id).cloned()),
+        }
+    }
+    fn untrack(&self, id: JobId) {
+        let mut live = self.live.lock().unwrap();
+        if live.tokens.remove(&id).is_some() {
+            live.keys.retain(|_, live_id| *live_id != id);
+        }
+    }
+    async fn send(&self, queued: QueuedJob) -> Result<(), &'static str> {
+        let id = queued.id;
+        let sent = self.sender.send(SchedulerMessage::Job(queued)).await;
+        if sent.is_err() {
+            self.untrack(id);
+            return Err("Scheduler is shut down");
+        }
+        Ok(())
+    }
+    /// Sends the job once `delay` has passed. If the scheduler is gone by then the job
+    /// stays in the `jobs` table and is picked up by the next [`recover`](Self::recover).
+    fn send_after(&self, queued: QueuedJob, delay: Duration) {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let job_type = queued.job.job_type();
+            if handle.send(queued).await.is_err() {
+                debug!("Scheduler shut down before {} was due again", job_type);
+            }
+        });
+    }
+    /// Re-enqueues the jobs that were still queued, running or waiting for a retry when the process stopped,
+    /// jobs that aren't due yet are held back until their `run_at`.
+    ///
+    /// Jobs whose type isn't known to `registry` or whose payload no longer parses are
+    /// marked failed instead. Returns the number of re-enqueued jobs.
+    pub async fn recover(&self, registry: &JobRegistry) -> anyhow::Result<usize> {
+        let Some(store) = self.store.clone() else {
+            return Ok(0);
+        };
+        let records = {
+            let store = store.clone();
+            blocking(move || store.unfinished().map_err(|e| anyhow::anyhow!(e.to_string()))).await?
+        };
+        let mut recovered = 0;
+        for record in records {
+            info!("Recovering job {} ({}) after restart", record.job_id, record.job_type);
+            if self.resume(&store, record, registry).await? {
+                recovered += 1;
+            }
+        }
+        Ok(recovered)
+    }
+    /// Moves a dead-lettered job back to the queue with a fresh set of attempts and runs it
+    /// on this scheduler, whichever scheduler it failed on.
+    pub async fn replay(&self, job_id: JobId, registry: &JobRegistry) -> anyhow::Result<()> {
+        let Some(store) = self.store.clone() else {
+            return Err(anyhow!("Job {} is not known to the scheduler", job_id));
+        };
+        let record = {
+            let store = store.clone();
+            blocking(move || store.replay(job_id.0).map_err(|e| anyhow!(e.to_string()))).await?
+        };
+        info!("Replaying job {} ({})", record.job_id, record.job_type);
+        if !self.resume(&store, record, registry).await? {
+            return Err(anyhow!("Job {} can't be replayed, see its error", job_id));
+        }
+        Ok(())
+    }
+    /// Enqueues a persisted job again, returns false if it can't be reconstructed, it's marked
+    /// as failed then.
+    async fn resume(&self, store: &JobStore, record: JobRecord, registry: &JobRegistry) -> anyhow::Result<bool> {
+        let job = serde_json::from_str::<Value>(record.payload.as_str())
+            .map_err(anyhow::Error::from)
+            .and_then(|payload| registry.reconstruct(record.job_type.as_str(), payload));
+        match job {
+            Ok(job) => {
+                let (upstream_store, job_id) = (store.clone(), record.job_id);
+                let depends_on = blocking(move || upstream_store.dependencies(job_id).map_err(|e| anyhow!(e.to_string())))
+                    .await?
+                    .into_iter()
+                    .map(JobId)
+                    .collect();
+                let queued = self.track(
+                    &mut self.live.lock().unwrap(),
+                    JobId(record.job_id),
+                    Some(record.job_id),
+                    record.attempts as u32,
+                    depends_on,
+                    job,
+                );
+                let wait = record.run_at.map(|run_at| run_at - now()).unwrap_or_default();
+                if wait > 0 {
+                    self.send_after(queued, Duration::from_secs(wait as u64));
+                } else {
+                    let store = Some((store.clone(), record.job_id));
+                    Scheduler::record(&store, |store, id| store.mark_queued(id)).await?;
+                    self.send(queued).await.map_err(|e| anyhow::anyhow!(e))?;
+                }
+                Ok(true)
+            }
+            Err(e) => {
+                warn!("Job {} ({}) can't be recovered: {}", record.job_id, record.job_type, e);
+                let (store, message) = (Some((store.clone(), record.job_id)), e.to_string());
+                Scheduler::record(&store, move |store, id| store.mark_failed(id, message.as_str())).await?;
+                Ok(false)
+            }
+        }
     }
+    /// Waits for every queued job to finish, however long that takes.
     pub async fn shutdown(self) {
-        let rx = self.internal_shutdown().await;
+        let rx = self.internal_shutdown(None).await;
         rx.await.expect("Scheduler didn't confirm shutdown");
     }
-    pub async fn internal_shutdown(&self) -> oneshot::Receiver<()> {
+    /// Drains the queue for at most `deadline`. Jobs still running by then are cancelled and,
+    /// like the jobs that didn't get to run, stay queued in the `jobs` table for the next start.
+    pub async fn shutdown_with_deadline(self, deadline: Duration) {
+        let rx = self.internal_shutdown(Some(deadline)).await;
+        rx.await.expect("Scheduler didn't confirm shutdown");
+    }
+    pub async fn internal_shutdown(&self, deadline: Option<Duration>) -> oneshot::Receiver<()> {
         let (tx, rx) = oneshot::channel();
         self.sender
-            .send(SchedulerMessage::Shutdown(tx))
+            .send(SchedulerMessage::Shutdown(tx, deadline))
             .await
             .expect("Scheduler is already gone");
         info!("Scheduler shutdown requested");
@@ -46,68 +625,467 @@ impl SchedulerHandle {
     }
 }
 
+/// Size of the worker pool and of the global limit on in-flight CA requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SchedulerLimits {
+    pub workers: usize,
+    pub ca_requests: usize,
+}
+impl Default for SchedulerLimits {
+    fn default() -> Self {
+        SchedulerLimits {
+            workers: 4,
+            ca_requests: 2,
+        }
+    }
+}
+
+/// How an attempt ended, failures are handed to the retry policy.
+enum Outcome {
+    Finished(anyhow::Result<()>),
+    Cancelled,
+    /// Cancelled by a shutdown deadline, the job is left queued for the next start.
+    Interrupted,
+}
+
+/// Final state of a job as far as the jobs depending on it are concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Settled {
+    Succeeded,
+    /// Failed, cancelled or skipped.
+    Failed,
+}
+
+/// Jobs held back until the jobs they depend on have settled.
+#[derive(Default)]
+struct Dependencies {
+    waiting: HashMap<JobId, QueuedJob>,
+}
+
 pub struct Scheduler {
     receiver: mpsc::Receiver<SchedulerMessage>,
     shutdown_tx: watch::Sender<bool>,
+    store: Option<JobStore>,
+    workers: usize,
 }
 impl Scheduler {
+    /// A scheduler that keeps jobs in memory only, with the default limits. The binary sizes
+    /// its schedulers from the configuration, see [`with_limits`](Self::with_limits).
+    #[cfg(test)]
     pub fn new(buffer: usize) -> (Self, SchedulerHandle) {
+        Self::with_limits(buffer, None, SchedulerLimits::default())
+    }
+    /// A scheduler that records every submitted job in the job repository, see [`SchedulerHandle::recover`].
+    #[cfg(test)]
+    pub fn new_persistent(buffer: usize, repositories: Repositories) -> (Self, SchedulerHandle) {
+        Self::with_limits(buffer, Some(repositories), SchedulerLimits::default())
+    }
+    /// Jobs are recorded in and work with `repositories`, without them they're kept in
+    /// memory and get in-memory repositories.
+    pub fn with_limits(buffer: usize, repositories: Option<Repositories>, limits: SchedulerLimits) -> (Self, SchedulerHandle) {
+        let store = repositories.as_ref().map(|r| r.jobs.clone());
+        let repositories = repositories.unwrap_or_else(Repositories::in_memory);
         let (sender, receiver) = mpsc::channel(buffer);
         let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
         let scheduler = Scheduler {
             receiver,
             shutdown_tx,
+            store: store.clone(),
+            workers: limits.workers.max(1),
         };
         let handle = SchedulerHandle {
             sender,
             shutdown_rx,
+            store,
+            ca_permits: Arc::new(Semaphore::new(limits.ca_requests.max(1))),
+            live: Arc::new(Mutex::new(LiveJobs::default())),
+            shutdown_token: CancellationToken::new(),
+            next_id: Arc::new(AtomicI64::new(1)),
+            outputs: Arc::new(Mutex::new(HashMap::new())),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+            events: broadcast::channel(256).0,
+            repositories,
+            submitting: Arc::new(tokio::sync::Mutex::new(())),
         };
         (scheduler, handle)
     }
+    /// Runs a single attempt, racing it against its timeout and cancellation token.
+    async fn attempt(
+        run: impl Future<Output = anyhow::Result<()>>,
+        timeout: Option<Duration>,
+        cancel: CancellationToken,
+        handle: &SchedulerHandle,
+    ) -> Outcome {
+        let result = tokio::select! {
+            result = async {
+                match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, run)
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("Job timed out after {:?}", timeout))),
+                    None => run.await,
+                }
+            } => result,
+            _ = cancel.cancelled() => return Self::cancelled(handle),
+        };
+        // a job noticing the token may return an error of its own
+        if result.is_err() && cancel.is_cancelled() {
+            return Self::cancelled(handle);
+        }
+        Outcome::Finished(result)
+    }
+    fn cancelled(handle: &SchedulerHandle) -> Outcome {
+        if handle.shutdown_token.is_cancelled() {
+            Outcome::Interrupted
+        } else {
+            Outcome::Cancelled
+        }
+    }
+    /// Runs the job and records the outcome. Failed jobs are re-enqueued according to their
+    /// [`RetryPolicy`], once that's exhausted they end up in the dead-letter state.
+    ///
+    /// Returns how the job settled, `None` while it's waiting for a retry or was interrupted.
+    async fn execute(
+        store: Option<JobStore>,
+        mut queued: QueuedJob,
+        handle: SchedulerHandle,
+        span: tracing::Span,
+    ) -> (JobId, Option<Settled>) {
+        let store = store.zip(queued.record_id);
+        let job_id = queued.id;
+        let job_type = queued.job.job_type();
+        // jobs cancelled while they were waiting never start, also when that happened elsewhere
+        if Self::cancelled_elsewhere(&store).await {
+            queued.cancel.cancel();
+        }
+        let outcome = if queued.cancel.is_cancelled() {
+            Self::cancelled(&handle)
+        } else {
+            if let Err(e) = Self::record(&store, |store, id| store.mark_running(id)).await {
+                error!("Failed to mark job {} as running: {}", queued.id, e);
+            }
+            handle.publish(job_id, job_type, JobStatus::Running, None);
+            queued.attempts += 1;
+            let context = JobContext {
+                job_id: queued.id,
+                depends_on: queued.depends_on.clone(),
+                handle: handle.clone(),
+                cancel: queued.cancel.clone(),
+                repositories: handle.repositories.clone(),
+            };
+            let run = queued.job.execute(context).instrument(span);
+            Self::attempt(run, queued.job.timeout(), queued.cancel.clone(), &handle).await
+        };
+        let (update, status, error) = match outcome {
+            Outcome::Finished(Ok(())) => (
+                Self::record(&store, |store, id| store.mark_succeeded(id)).await,
+                JobStatus::Succeeded,
+                None,
+            ),
+            Outcome::Cancelled => {
+                info!("Job {} ({}) has been cancelled", queued.id, job_type);
+                (Self::record(&store, |store, id| store.mark_cancelled(id).map(|_| ())).await, JobStatus::Cancelled, None)
+            }
+            Outcome::Interrupted => {
+                warn!("Job {} ({}) was interrupted by the shutdown deadline", queued.id, job_type);
+                handle.untrack(queued.id);
+                (Self::record(&store, |store, id| store.mark_queued(id)).await, JobStatus::Queued, None)
+            }
+            Outcome::Finished(Err(e)) => {
+                let error = format!("{:?}", e);
+                let policy = queued.job.retry_policy();
+                if queued.job.is_retryable(&e) && policy.should_retry(queued.attempts) {
+                    let delay = policy.delay_for(queued.attempts);
+                    warn!(
+                        "Job {} failed (attempt {}/{}), retrying in {:?}: {:?}",
+                        job_type, queued.attempts, policy.max_attempts, delay, e
+                    );
+                    let (message, run_at) = (error.clone(), now() + delay.as_secs() as i64);
+                    let update = Self::record(&store, move |store, id| store.mark_retrying(id, message.as_str(), run_at)).await;
+                    handle.send_after(queued, delay);
+                    (update, JobStatus::Retrying, Some(error))
+                } else {
+                    error!(
+                        "Job {} failed after {} attempt(s), moving it to the dead-letter queue: {:?}",
+                        job_type, queued.attempts, e
+                    );
+                    let message = error.clone();
+                    let update = Self::record(&store, move |store, id| store.mark_failed(id, message.as_str())).await;
+                    (update, JobStatus::Failed, Some(error))
+                }
+            }
+        };
+        if let Err(e) = update {
+            error!("Failed to record result of job {}: {}", job_type, e);
+        }
+        handle.publish(job_id, job_type, status, error);
+        let settled = match status {
+            JobStatus::Succeeded => Some(Settled::Succeeded),
+            status if status.is_unfinished() => None,
+            _ => Some(Settled::Failed),
+        };
+        (job_id, settled)
+    }
+    /// Whether another process marked the persisted job as cancelled.
+    async fn cancelled_elsewhere(store: &Option<(JobStore, i64)>) -> bool {
+        let Some((store, id)) = store.clone() else {
+            return false;
+        };
+        let record = blocking(move || store.get(id).map_err(|e| anyhow!(e.to_string()))).await;
+        match record {
+            Ok(record) => record.is_some_and(|record| record.status == JobStatus::Cancelled),
+            Err(e) => {
+                error!("Failed to look up job {}: {}", id, e);
+                false
+            }
+        }
+    }
+    /// Records a status change of a persisted job off the runtime threads, in-memory jobs
+    /// have nothing to record.
+    async fn record<F>(store: &Option<(JobStore, i64)>, update: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&dyn JobRepository, i64) -> Result<(), Box<dyn Error>> + Send + 'static,
+    {
+        let Some((store, id)) = store.clone() else {
+            return Ok(());
+        };
+        blocking(move || update(store.as_ref(), id).map_err(|e| anyhow!(e.to_string()))).await
+    }
+    /// State of a job some other job depends on, `None` while it hasn't settled yet.
+    async fn upstream_state(handle: &SchedulerHandle, upstream: JobId) -> Option<Settled> {
+        if handle.is_live(upstream) {
+            return None;
+        }
+        match handle.status(upstream).await {
+            Ok(Some(JobStatus::Succeeded)) => Some(Settled::Succeeded),
+            // not recovered yet
+            Ok(Some(status)) if status.is_unfinished() => None,
+            Ok(Some(_)) => Some(Settled::Failed),
+            Ok(None) => {
+                warn!("Job {} is not known to this scheduler", upstream);
+                Some(Settled::Failed)
+            }
+            Err(e) => {
+                error!("Failed to look up job {}: {}", upstream, e);
+                Some(Settled::Failed)
+            }
+        }
+    }
+    /// Queues the job if everything it depends on succeeded, holds it back while some of
+    /// that is still outstanding and rejects it once any of it failed.
+    async fn admit(
+        &self,
+        queued: QueuedJob,
+        pending: &mut VecDeque<QueuedJob>,
+        dependencies: &mut Dependencies,
+        handle: &SchedulerHandle,
+    ) {
+        let mut outstanding = false;
+        for upstream in queued.depends_on.iter().copied() {
+            match Self::upstream_state(handle, upstream).await {
+                Some(Settled::Succeeded) => {}
+                Some(Settled::Failed) => {
+                    let job_id = queued.id;
+                    self.reject(queued, upstream, handle).await;
+                    self.settle(job_id, Settled::Failed, pending, dependencies, handle).await;
+                    return;
+                }
+                None => outstanding = true,
+            }
+        }
+        // cancelled jobs go through the queue so they're recorded like any other
+        if outstanding && !queued.cancel.is_cancelled() {
+            debug!("Job {} ({}) waits for {:?}", queued.id, queued.job.job_type(), queued.depends_on);
+            dependencies.waiting.insert(queued.id, queued);
+        } else {
+            Self::queue(pending, queued);
+        }
+    }
+    /// Adds the job behind the pending jobs of the same or a higher priority.
+    fn queue(pending: &mut VecDeque<QueuedJob>, queued: QueuedJob) {
+        let index = pending
+            .iter()
+            .position(|pending| pending.priority < queued.priority)
+            .unwrap_or(pending.len());
+        pending.insert(index, queued);
+    }
+    /// Skips or fails a job whose `upstream` didn't succeed, see [`Job::on_dependency_failure`].
+    async fn reject(&self, queued: QueuedJob, upstream: JobId, handle: &SchedulerHandle) {
+        let reason = format!("Job {} it depends on did not succeed", upstream);
+        let store = self.store.clone().zip(queued.record_id);
+        let message = reason.clone();
+        let (update, status) = match queued.job.on_dependency_failure() {
+            DependencyFailure::Skip => {
+                info!("Skipping job {} ({}): {}", queued.id, queued.job.job_type(), reason);
+                let update = Self::record(&store, move |store, id| store.mark_skipped(id, message.as_str())).await;
+                (update, JobStatus::Skipped)
+            }
+            DependencyFailure::Fail => {
+                error!(
+                    "Job {} ({}) is moved to the dead-letter queue: {}",
+                    queued.id,
+                    queued.job.job_type(),
+                    reason
+                );
+                let update = Self::record(&store, move |store, id| store.mark_failed(id, message.as_str())).await;
+                (update, JobStatus::Failed)
+            }
+        };
+        if let Err(e) = update {
+            error!("Failed to record result of job {}: {}", queued.id, e);
+        }
+        handle.publish(queued.id, queued.job.job_type(), status, Some(reason));
+        handle.untrack(queued.id);
+    }
+    /// Records that `job_id` settled and releases or rejects the jobs waiting for it,
+    /// a rejected job settles as failed in turn.
+    async fn settle(
+        &self,
+        job_id: JobId,
+        settled: Settled,
+        pending: &mut VecDeque<QueuedJob>,
+        dependencies: &mut Dependencies,
+        handle: &SchedulerHandle,
+    ) {
+        let mut settling = vec![(job_id, settled)];
+        while let Some((upstream, settled)) = settling.pop() {
+            handle.untrack(upstream);
+            let dependents = dependencies
+                .waiting
+                .iter()
+                .filter(|(_, queued)| queued.depends_on.contains(&upstream))
+                .map(|(id, _)| *id)
+                .collect::<Vec<JobId>>();
+            for dependent in dependents {
+                let Some(queued) = dependencies.waiting.remove(&dependent) else {
+                    continue;
+                };
+                if settled == Settled::Failed {
+                    self.reject(queued, upstream, handle).await;
+                    settling.push((dependent, Settled::Failed));
+                    continue;
+                }
+                let mut released = true;
+                for id in queued.depends_on.iter().copied() {
+                    released &= Self::upstream_state(handle, id).await == Some(Settled::Succeeded);
+                }
+                if released {
+                    Self::queue(pending, queued);
+                } else {
+                    dependencies.waiting.insert(dependent, queued);
+                }
+            }
+        }
+    }
+    /// Starts pending jobs in queue order while workers are free. A job whose concurrency key
+    /// is held by a running job stays queued, later jobs with other keys may overtake it.
+    fn dispatch(
+        &self,
+        pending: &mut VecDeque<QueuedJob>,
+        running: &mut JoinSet<(JobId, Option<Settled>)>,
+        running_jobs: &mut HashMap<task::Id, (JobId, Option<String>)>,
+        handle: &SchedulerHandle,
+        draining: bool,
+    ) {
+        let mut index = 0;
+        while running.len() < self.workers && index < pending.len() {
+            let key = pending[index].job.concurrency_key();
+            if key.is_some() && running_jobs.values().any(|(_, running)| *running == key) {
+                index += 1;
+                continue;
+            }
+            let Some(queued) = pending.remove(index) else {
+                break;
+            };
+            let job_id = queued.id;
+            let job_name = queued.job.job_type();
+            let span = if draining {
+                tracing::info_span!("worker-cleanup", job_name = job_name)
+            } else {
+                tracing::info_span!("worker", job_name = job_name)
+            };
+            span.follows_from(tracing::Span::current());
+            let task = running.spawn(Self::execute(self.store.clone(), queued, handle.clone(), span));
+            running_jobs.insert(task.id(), (job_id, key));
+        }
+    }
+    /// Jobs that didn't get to run before the scheduler stopped, they keep their queued row.
+    fn abandon(jobs: impl Iterator<Item = QueuedJob>, handle: &SchedulerHandle) {
+        for queued in jobs {
+            handle.untrack(queued.id);
+            match queued.record_id {
+                Some(_) => info!("Job {} ({}) stays queued for the next start", queued.id, queued.job.job_type()),
+                None => warn!("Job {} ({}) is dropped, it wasn't persisted", queued.id, queued.job.job_type()),
+            }
+        }
+    }
     #[instrument(level = "trace", name = "scheduler", skip_all)]
     pub async fn run(mut self, handle: SchedulerHandle) {
-        info!("Scheduler started");
-        let mut clean_lever = false;
-        while let Some(message) = self.receiver.recv().await {
-            match message {
-                SchedulerMessage::Job(job) => {
-                    if !clean_lever {
-                        let job_name = job.job_type();
-                        let span = tracing::info_span!("worker", job_name = job_name);
-                        span.follows_from(tracing::Span::current());
-                        let job_result = job.execute(handle.clone()).instrument(span).await;
-                        if job_result.is_err() {
-                            let e = job_result.unwrap_err();
-                            error!("Failed to execute job: {:?}", e);
-                            warn!("A job in the queue has errored out queue will be alive until shutdown hook is called");
-                            clean_lever = true;
-                        }
-                    } else {
-                        warn!("Scheduler ignoring job: {}", job.job_type());
+        info!("Scheduler started with {} worker(s)", self.workers);
+        let mut pending = VecDeque::new();
+        let mut dependencies = Dependencies::default();
+        let mut running = JoinSet::new();
+        let mut running_jobs = HashMap::new();
+        let mut shutdown_ack = None;
+        let mut deadline: Option<Instant> = None;
+        let mut receiving = true;
+        let mut cancellation_check = tokio::time::interval(CANCELLATION_CHECK);
+        cancellation_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            // waiting jobs cancelled in the meantime are recorded without waiting any longer
+            let cancelled = dependencies
+                .waiting
+                .iter()
+                .filter(|(_, queued)| queued.cancel.is_cancelled())
+                .map(|(id, _)| *id)
+                .collect::<Vec<JobId>>();
+            pending.extend(cancelled.iter().filter_map(|id| dependencies.waiting.remove(id)));
+            if handle.shutdown_token.is_cancelled() {
+                Self::abandon(pending.drain(..), &handle);
+            } else {
+                self.dispatch(&mut pending, &mut running, &mut running_jobs, &handle, shutdown_ack.is_some());
+            }
+            if !receiving && pending.is_empty() && running.is_empty() {
+                break;
+            }
+            let deadline_passed = deadline.filter(|_| !handle.shutdown_token.is_cancelled());
+            tokio::select! {
+                message = self.receiver.recv(), if receiving => match message {
+                    Some(SchedulerMessage::Job(queued)) => self.admit(queued, &mut pending, &mut dependencies, &handle).await,
+                    Some(SchedulerMessage::Wake) => {}
+                    Some(SchedulerMessage::Shutdown(ack, timeout)) => {
+                        info!("Shutdown hook triggered, draining queue...");
+                        self.receiver.close();
+                        shutdown_ack = Some(ack);
+                        deadline = timeout.map(|timeout| Instant::now() + timeout);
                     }
-                }
-                SchedulerMessage::Shutdown(ack) => {
-                    info!("Shutdown hook triggered, draining queue...");
-                    self.receiver.close();
-                    while let Ok(msg) = self.receiver.try_recv() {
-                        if let SchedulerMessage::Job(job) = msg {
-                            let job_name = job.job_type();
-                            let span = tracing::info_span!("worker-cleanup", job_name = job_name);
-                            span.follows_from(tracing::Span::current());
-                            let job_result = job.execute(handle.clone()).instrument(span).await;
-                            if job_result.is_err() {
-                                let e = job_result.unwrap_err();
-                                error!("Failed to execute job: {:?}", e);
-                            }
+                    None => receiving = false,
+                },
+                Some(finished) = running.join_next_with_id(), if !running.is_empty() => {
+                    let (task_id, settled) = match finished {
+                        Ok((task_id, (_, settled))) => (task_id, settled),
+                        Err(e) => {
+                            error!("Worker panicked while executing a job: {}", e);
+                            (e.id(), Some(Settled::Failed))
                         }
+                    };
+                    let job_id = running_jobs.remove(&task_id).map(|(job_id, _)| job_id);
+                    if let Some((job_id, settled)) = job_id.zip(settled) {
+                        self.settle(job_id, settled, &mut pending, &mut dependencies, &handle).await;
                     }
-                    let _ = ack.send(());
-                    info!("Scheduler shutdown ack sent");
-                    break;
+                }
+                _ = cancellation_check.tick(), if self.store.is_some() => handle.notice_cancellations().await,
+                _ = tokio::time::sleep_until(deadline_passed.unwrap_or_else(Instant::now)), if deadline_passed.is_some() => {
+                    warn!("Shutdown deadline passed, cancelling {} running job(s)", running.len());
+                    handle.shutdown_token.cancel();
                 }
             }
         }
+        Self::abandon(dependencies.waiting.into_values(), &handle);
+        if let Some(ack) = shutdown_ack {
+            let _ = ack.send(());
+            info!("Scheduler shutdown ack sent");
+        }
         let _ = self.shutdown_tx.send(true);
         info!("Scheduler stopped");
     }
@@ -115,8 +1093,19 @@ impl Scheduler {
 
 #[cfg(test)]
 mod tests {
-    use crate::job_execution::job_base::{Job, Scheduler, SchedulerHandle};
+    use crate::job_execution::job_base::{
+        DependencyFailure, Job, JobContext, JobId, JobOutput, Priority, Scheduler, SchedulerLimits, CANCELLATION_CHECK,
+    };
+    use crate::job_execution::job_registry::JobRegistry;
+    use crate::job_execution::job_store::{now, JobStore};
+    use crate::job_execution::retry::{Permanent, RetryPolicy};
+    use anyhow::Context;
     use async_trait::async_trait;
+    use persistence::data_model::JobStatus;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use persistence::repository::Repositories;
     use serde::{Deserialize, Serialize};
     use serde_json::Value;
     use tracing::{info, instrument};
@@ -134,7 +1123,7 @@ mod tests {
             serde_json::to_value(self).unwrap()
         }
         #[instrument]
-        async fn execute(&self, _: SchedulerHandle) -> anyhow::Result<()> {
+        async fn execute(&self, _: JobContext) -> anyhow::Result<()> {
             info!("Running job {}", self.id);
             tokio::time::sleep(std::time::Duration::from_millis(200)).await;
             info!("Job {} done", self.id);
@@ -159,4 +1148,740 @@ mod tests {
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         handle.shutdown().await;
     }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct FailingJob {}
+    #[async_trait]
+    impl Job for FailingJob {
+        fn job_type(&self) -> &'static str {
+            "failing-job"
+        }
+        fn payload(&self) -> Value {
+            serde_json::to_value(self).unwrap()
+        }
+        async fn execute(&self, _: JobContext) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("failing on purpose"))
+        }
+    }
+
+    /// Sqlite repositories in `dir` and the job repository of the scheduler under test.
+    fn persistent(dir: &tempfile::TempDir) -> (Repositories, JobStore) {
+        let repositories = Repositories::sqlite(dir.path().to_str().unwrap());
+        repositories.schema.migrate().unwrap();
+        let store = repositories.jobs.clone();
+        (repositories, store)
+    }
+
+    #[tokio::test]
+    async fn test_jobs_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        handle.submit(PrintJob { id: 1 }).await.unwrap();
+        handle.submit(FailingJob {}).await.unwrap();
+        handle.shutdown().await;
+
+        let done = store.get(1).unwrap().unwrap();
+        assert_eq!(done.job_type, "print-job");
+        assert_eq!(done.status, JobStatus::Succeeded);
+        assert_eq!(done.attempts, 1);
+        let failed = store.get(2).unwrap().unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert!(failed.last_error.unwrap().contains("failing on purpose"));
+        assert!(store.unfinished().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unfinished_jobs_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let interrupted = store.insert_at("print-job", &serde_json::json!({"id": 7}), None).unwrap();
+        store.mark_running(interrupted).unwrap();
+        let queued = store.insert_at("print-job", &serde_json::json!({"id": 8}), None).unwrap();
+        let unknown = store.insert_at("unknown-job", &serde_json::json!({}), None).unwrap();
+        let broken = store.insert_at("print-job", &serde_json::json!({"id": "x"}), None).unwrap();
+
+        let mut registry = JobRegistry::new();
+        registry.register_deserializable::<PrintJob>("print-job");
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        assert_eq!(handle.recover(&registry).await.unwrap(), 2);
+        handle.shutdown().await;
+
+        let interrupted = store.get(interrupted).unwrap().unwrap();
+        assert_eq!(interrupted.status, JobStatus::Succeeded);
+        assert_eq!(interrupted.attempts, 2);
+        assert_eq!(store.get(queued).unwrap().unwrap().status, JobStatus::Succeeded);
+        assert_eq!(store.get(unknown).unwrap().unwrap().status, JobStatus::Failed);
+        assert_eq!(store.get(broken).unwrap().unwrap().status, JobStatus::Failed);
+    }
+
+    #[test]
+    fn test_registry_knows_startup_jobs() {
+        let registry = crate::acme_jobs::job_registry();
+        let job = registry
+            .reconstruct(
+                "directory-update-job",
+                serde_json::json!({"base_url": "https://localhost/dir", "user_id": "u"}),
+            )
+            .unwrap();
+        assert_eq!(job.payload()["user_id"], "u");
+        assert!(registry.reconstruct("db-initialization-job", serde_json::json!({})).is_ok());
+        assert!(registry
+            .reconstruct(
+                "initialize-local-user-job",
+                serde_json::json!({"path": "/tmp", "key_type": "ec-p256", "user_id": "u"}),
+            )
+            .is_ok());
+    }
+
+    struct FlakyJob {
+        failures: u32,
+        runs: Arc<AtomicU32>,
+        permanent: bool,
+    }
+    #[async_trait]
+    impl Job for FlakyJob {
+        fn job_type(&self) -> &'static str {
+            "flaky-job"
+        }
+        fn payload(&self) -> Value {
+            serde_json::json!({ "failures": self.failures })
+        }
+        async fn execute(&self, _: JobContext) -> anyhow::Result<()> {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.permanent {
+                return Err(anyhow::anyhow!("rejected")).context(Permanent);
+            }
+            if run <= self.failures {
+                return Err(anyhow::anyhow!("flaky failure {}", run));
+            }
+            Ok(())
+        }
+        fn retry_policy(&self) -> RetryPolicy {
+            RetryPolicy::exponential(3, Duration::from_millis(10))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_jobs_are_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let recovering = Arc::new(AtomicU32::new(0));
+        let exhausted = Arc::new(AtomicU32::new(0));
+        let permanent = Arc::new(AtomicU32::new(0));
+        handle.submit(FlakyJob { failures: 2, runs: recovering.clone(), permanent: false }).await.unwrap();
+        handle.submit(FlakyJob { failures: 5, runs: exhausted.clone(), permanent: false }).await.unwrap();
+        handle.submit(FlakyJob { failures: 0, runs: permanent.clone(), permanent: true }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // a failing job doesn't stop the queue anymore
+        handle.submit(PrintJob { id: 4 }).await.unwrap();
+        handle.shutdown().await;
+
+        assert_eq!(recovering.load(Ordering::SeqCst), 3);
+        assert_eq!(exhausted.load(Ordering::SeqCst), 3);
+        assert_eq!(permanent.load(Ordering::SeqCst), 1);
+        let recovered = store.get(1).unwrap().unwrap();
+        assert_eq!(recovered.status, JobStatus::Succeeded);
+        assert_eq!(recovered.attempts, 3);
+        let dead_letters = store.dead_letters().unwrap();
+        assert_eq!(dead_letters.iter().map(|r| r.job_id).collect::<Vec<i64>>(), vec![2, 3]);
+        assert!(dead_letters[0].last_error.as_ref().unwrap().contains("flaky failure 3"));
+        assert_eq!(store.get(4).unwrap().unwrap().status, JobStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_can_be_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let job_id = store.insert_at("print-job", &serde_json::json!({"id": 1}), None).unwrap();
+        store.mark_running(job_id).unwrap();
+        store.mark_failed(job_id, "boom").unwrap();
+        assert!(store.replay(store.insert_at("print-job", &serde_json::json!({"id": 2}), None).unwrap()).is_err());
+
+        let replayed = store.replay(job_id).unwrap();
+        assert_eq!(replayed.status, JobStatus::Queued);
+        assert_eq!(replayed.attempts, 0);
+        assert!(replayed.last_error.is_none());
+        assert!(store.dead_letters().unwrap().is_empty());
+
+        let mut registry = JobRegistry::new();
+        registry.register_deserializable::<PrintJob>("print-job");
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        assert_eq!(handle.recover(&registry).await.unwrap(), 2);
+        handle.shutdown().await;
+        assert_eq!(store.get(job_id).unwrap().unwrap().status, JobStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_dead_letters_run_without_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let mut registry = JobRegistry::new();
+        registry.register_deserializable::<GreetingJob>("greeting-job");
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let job_id = JobId(store.insert_at("greeting-job", &serde_json::json!({"text": "again", "fail": false}), None).unwrap());
+        store.mark_failed(job_id.0, "boom").unwrap();
+
+        handle.replay(job_id, &registry).await.unwrap();
+        assert_eq!(handle.completion(job_id).await.unwrap(), JobStatus::Succeeded);
+        let (_, output) = store.output(job_id.0).unwrap().unwrap();
+        assert_eq!(output["text"], "again");
+        assert!(handle.replay(job_id, &registry).await.is_err());
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_pending_retries_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let job_id = store.insert_at("print-job", &serde_json::json!({"id": 1}), None).unwrap();
+        store.mark_running(job_id).unwrap();
+        store.mark_retrying(job_id, "boom", 0).unwrap();
+        assert_eq!(store.unfinished().unwrap().len(), 1);
+
+        let mut registry = JobRegistry::new();
+        registry.register_deserializable::<PrintJob>("print-job");
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        assert_eq!(handle.recover(&registry).await.unwrap(), 1);
+        handle.shutdown().await;
+        let record = store.get(job_id).unwrap().unwrap();
+        assert_eq!(record.status, JobStatus::Succeeded);
+        assert_eq!(record.attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_delayed_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let runs = Arc::new(AtomicU32::new(0));
+        handle
+            .submit_after(Duration::from_millis(300), FlakyJob { failures: 0, runs: runs.clone(), permanent: false })
+            .await
+            .unwrap();
+        let past = std::time::SystemTime::now() - Duration::from_secs(60);
+        handle.submit_at(past, PrintJob { id: 2 }).await.unwrap();
+
+        let delayed = store.get(1).unwrap().unwrap();
+        assert_eq!(delayed.status, JobStatus::Queued);
+        assert!(delayed.run_at.unwrap() > now());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(store.get(2).unwrap().unwrap().status, JobStatus::Succeeded);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_recovered_delayed_jobs_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let job_id = store.insert_at("print-job", &serde_json::json!({"id": 1}), Some(now() + 3600)).unwrap();
+
+        let mut registry = JobRegistry::new();
+        registry.register_deserializable::<PrintJob>("print-job");
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        assert_eq!(handle.recover(&registry).await.unwrap(), 1);
+        handle.shutdown().await;
+        assert_eq!(store.get(job_id).unwrap().unwrap().status, JobStatus::Queued);
+    }
+
+    /// Tracks how many jobs are inside `execute` at once.
+    struct SlowJob {
+        key: Option<String>,
+        ca: bool,
+        active: Arc<AtomicU32>,
+        peak: Arc<AtomicU32>,
+        order: Arc<std::sync::Mutex<Vec<u32>>>,
+        id: u32,
+    }
+    #[async_trait]
+    impl Job for SlowJob {
+        fn job_type(&self) -> &'static str {
+            "slow-job"
+        }
+        fn payload(&self) -> Value {
+            serde_json::json!({ "id": self.id })
+        }
+        async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+            let _permit = if self.ca { Some(context.handle.ca_permit().await) } else { None };
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(active, Ordering::SeqCst);
+            self.order.lock().unwrap().push(self.id);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+        fn concurrency_key(&self) -> Option<String> {
+            self.key.clone()
+        }
+    }
+
+    /// Runs `jobs` as (concurrency key, uses the CA) and returns the peak concurrency and start order.
+    async fn run_slow_jobs(limits: SchedulerLimits, jobs: Vec<(Option<&str>, bool)>) -> (u32, Vec<u32>) {
+        let (scheduler, handle) = Scheduler::with_limits(32, None, limits);
+        tokio::spawn(scheduler.run(handle.clone()));
+        let active = Arc::new(AtomicU32::new(0));
+        let peak = Arc::new(AtomicU32::new(0));
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        for (id, (key, ca)) in jobs.into_iter().enumerate() {
+            let job = SlowJob {
+                key: key.map(|k| k.to_string()),
+                ca,
+                active: active.clone(),
+                peak: peak.clone(),
+                order: order.clone(),
+                id: id as u32,
+            };
+            handle.submit(job).await.unwrap();
+        }
+        handle.shutdown().await;
+        let order = order.lock().unwrap().clone();
+        (peak.load(Ordering::SeqCst), order)
+    }
+
+    #[tokio::test]
+    async fn test_jobs_run_concurrently() {
+        let limits = SchedulerLimits { workers: 3, ca_requests: 3 };
+        let started = std::time::Instant::now();
+        let (peak, order) = run_slow_jobs(limits, vec![(None, false); 6]).await;
+        assert_eq!(peak, 3);
+        assert_eq!(order.len(), 6);
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_jobs_sharing_a_key_run_serially() {
+        let limits = SchedulerLimits { workers: 4, ca_requests: 4 };
+        let jobs = vec![(Some("user-a"), false), (Some("user-a"), false), (Some("user-b"), false), (Some("user-a"), false)];
+        let (peak, order) = run_slow_jobs(limits, jobs).await;
+        assert_eq!(peak, 2);
+        let user_a = order.iter().filter(|id| **id != 2).copied().collect::<Vec<u32>>();
+        assert_eq!(user_a, vec![0, 1, 3]);
+        // user-b doesn't wait for the user-a jobs queued in front of it
+        assert!(order.iter().position(|id| *id == 2).unwrap() < 2);
+    }
+
+    #[tokio::test]
+    async fn test_ca_requests_are_limited() {
+        let limits = SchedulerLimits { workers: 4, ca_requests: 1 };
+        let (peak, order) = run_slow_jobs(limits, vec![(None, true); 4]).await;
+        assert_eq!(peak, 1);
+        assert_eq!(order.len(), 4);
+    }
+
+    /// Sleeps for `millis`, bails out early when it notices its token.
+    #[derive(Serialize, Deserialize, Debug)]
+    struct SleepyJob {
+        millis: u64,
+        timeout: Option<u64>,
+    }
+    #[async_trait]
+    impl Job for SleepyJob {
+        fn job_type(&self) -> &'static str {
+            "sleepy-job"
+        }
+        fn payload(&self) -> Value {
+            serde_json::to_value(self).unwrap()
+        }
+        async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(self.millis)) => Ok(()),
+                _ = context.cancel.cancelled() => Err(anyhow::anyhow!("job {} noticed the cancellation", context.job_id)),
+            }
+        }
+        fn timeout(&self) -> Option<Duration> {
+            self.timeout.map(Duration::from_millis)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jobs_time_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let slow = handle.submit(SleepyJob { millis: 5_000, timeout: Some(50) }).await.unwrap();
+        let fast = handle.submit(SleepyJob { millis: 10, timeout: Some(1_000) }).await.unwrap();
+        handle.shutdown().await;
+
+        let slow = store.get(slow.0).unwrap().unwrap();
+        assert_eq!(slow.status, JobStatus::Failed);
+        assert!(slow.last_error.unwrap().contains("timed out"));
+        assert_eq!(store.get(fast.0).unwrap().unwrap().status, JobStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn test_jobs_can_be_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let limits = SchedulerLimits { workers: 1, ca_requests: 1 };
+        let (scheduler, handle) = Scheduler::with_limits(32, Some(repositories.clone()), limits);
+        tokio::spawn(scheduler.run(handle.clone()));
+        let running = handle.submit(SleepyJob { millis: 5_000, timeout: None }).await.unwrap();
+        let queued = handle.submit(SleepyJob { millis: 4_000, timeout: None }).await.unwrap();
+        let next = handle.submit(PrintJob { id: 3 }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(handle.cancel(queued).await.unwrap());
+        assert!(handle.cancel(running).await.unwrap());
+        assert!(!handle.cancel(JobId(42)).await.unwrap());
+        let started = std::time::Instant::now();
+        handle.clone().shutdown().await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        assert_eq!(store.get(running.0).unwrap().unwrap().status, JobStatus::Cancelled);
+        let queued = store.get(queued.0).unwrap().unwrap();
+        assert_eq!(queued.status, JobStatus::Cancelled);
+        assert_eq!(queued.attempts, 0);
+        assert_eq!(store.get(next.0).unwrap().unwrap().status, JobStatus::Succeeded);
+        assert!(!handle.cancel(next).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_jobs_are_cancelled_from_another_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let limits = SchedulerLimits { workers: 1, ca_requests: 1 };
+        let (scheduler, daemon) = Scheduler::with_limits(32, Some(repositories.clone()), limits);
+        tokio::spawn(scheduler.run(daemon.clone()));
+        let running = daemon.submit(SleepyJob { millis: 10_000, timeout: None }).await.unwrap();
+        let queued = daemon.submit(SleepyJob { millis: 9_000, timeout: None }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // a second scheduler on the same repository doesn't run the jobs itself
+        let (_, cli) = Scheduler::new_persistent(32, repositories.clone());
+        assert!(cli.cancel(queued).await.unwrap());
+        assert!(cli.cancel(running).await.unwrap());
+        assert!(!cli.cancel(JobId(42)).await.unwrap());
+        let started = std::time::Instant::now();
+        assert_eq!(daemon.completion(running).await.unwrap(), JobStatus::Cancelled);
+        assert_eq!(daemon.completion(queued).await.unwrap(), JobStatus::Cancelled);
+        assert!(started.elapsed() < CANCELLATION_CHECK * 2);
+        daemon.shutdown().await;
+
+        let queued = store.get(queued.0).unwrap().unwrap();
+        assert_eq!(queued.status, JobStatus::Cancelled);
+        assert_eq!(queued.attempts, 0);
+        assert!(!cli.cancel(running).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_jobs_get_ids() {
+        let (scheduler, handle) = Scheduler::new(32);
+        tokio::spawn(scheduler.run(handle.clone()));
+        let first = handle.submit(PrintJob { id: 1 }).await.unwrap();
+        let second = handle.submit(PrintJob { id: 2 }).await.unwrap();
+        assert_ne!(first, second);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_deadline_leaves_jobs_for_the_next_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let limits = SchedulerLimits { workers: 1, ca_requests: 1 };
+        let (scheduler, handle) = Scheduler::with_limits(32, Some(repositories.clone()), limits);
+        tokio::spawn(scheduler.run(handle.clone()));
+        let quick = handle.submit(SleepyJob { millis: 10, timeout: None }).await.unwrap();
+        let interrupted = handle.submit(SleepyJob { millis: 5_000, timeout: None }).await.unwrap();
+        let waiting = handle.submit(PrintJob { id: 3 }).await.unwrap();
+        let started = std::time::Instant::now();
+        handle.shutdown_with_deadline(Duration::from_millis(200)).await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        assert_eq!(store.get(quick.0).unwrap().unwrap().status, JobStatus::Succeeded);
+        assert_eq!(store.get(interrupted.0).unwrap().unwrap().status, JobStatus::Queued);
+        assert_eq!(store.get(waiting.0).unwrap().unwrap().status, JobStatus::Queued);
+
+        let mut registry = JobRegistry::new();
+        registry.register_deserializable::<PrintJob>("print-job");
+        registry.register_deserializable::<SleepyJob>("sleepy-job");
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        assert_eq!(handle.recover(&registry).await.unwrap(), 2);
+        handle.shutdown_with_deadline(Duration::from_millis(1_000)).await;
+        assert_eq!(store.get(waiting.0).unwrap().unwrap().status, JobStatus::Succeeded);
+        assert_eq!(store.get(interrupted.0).unwrap().unwrap().status, JobStatus::Queued);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Greeting {
+        text: String,
+    }
+    impl JobOutput for Greeting {
+        const OUTPUT_TYPE: &'static str = "greeting";
+    }
+
+    /// Hands a [`Greeting`] to its dependents, or fails permanently.
+    #[derive(Serialize, Deserialize, Debug)]
+    struct GreetingJob {
+        text: String,
+        fail: bool,
+    }
+    #[async_trait]
+    impl Job for GreetingJob {
+        fn job_type(&self) -> &'static str {
+            "greeting-job"
+        }
+        fn payload(&self) -> Value {
+            serde_json::to_value(self).unwrap()
+        }
+        async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if self.fail {
+                return Err(anyhow::anyhow!("no greeting today")).context(Permanent);
+            }
+            context.set_output(&Greeting { text: self.text.clone() }).await
+        }
+    }
+
+    /// Records the greeting handed over by the job it depends on.
+    struct ListenerJob {
+        heard: Arc<std::sync::Mutex<Vec<String>>>,
+        on_failure: DependencyFailure,
+    }
+    #[async_trait]
+    impl Job for ListenerJob {
+        fn job_type(&self) -> &'static str {
+            "listener-job"
+        }
+        fn payload(&self) -> Value {
+            serde_json::json!({})
+        }
+        async fn execute(&self, context: JobContext) -> anyhow::Result<()> {
+            let greeting = context.upstream::<Greeting>().await?;
+            self.heard.lock().unwrap().push(greeting.text);
+            Ok(())
+        }
+        fn on_dependency_failure(&self) -> DependencyFailure {
+            self.on_failure
+        }
+    }
+
+    fn listener(heard: &Arc<std::sync::Mutex<Vec<String>>>, on_failure: DependencyFailure) -> ListenerJob {
+        ListenerJob {
+            heard: heard.clone(),
+            on_failure,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dependents_get_the_upstream_output() {
+        let (scheduler, handle) = Scheduler::new(32);
+        tokio::spawn(scheduler.run(handle.clone()));
+        let heard = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let greeting = handle.submit(GreetingJob { text: "hello".to_string(), fail: false }).await.unwrap();
+        // submitted first, but has to wait for the greeting
+        handle.submit_depending_on(&[greeting], listener(&heard, DependencyFailure::Skip)).await.unwrap();
+        handle.shutdown().await;
+        assert_eq!(*heard.lock().unwrap(), vec!["hello".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_upstream_skips_or_fails_dependents() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let heard = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let failing = handle.submit(GreetingJob { text: "hello".to_string(), fail: true }).await.unwrap();
+        let skipped = handle.submit_depending_on(&[failing], listener(&heard, DependencyFailure::Skip)).await.unwrap();
+        let failed = handle.submit_depending_on(&[failing], listener(&heard, DependencyFailure::Fail)).await.unwrap();
+        let transitive = handle.submit_depending_on(&[skipped], listener(&heard, DependencyFailure::Skip)).await.unwrap();
+        handle.shutdown().await;
+        // submitted after its upstream already failed
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let late = handle.submit_depending_on(&[failing], listener(&heard, DependencyFailure::Skip)).await.unwrap();
+        handle.shutdown().await;
+
+        assert!(heard.lock().unwrap().is_empty());
+        assert_eq!(store.get(failing.0).unwrap().unwrap().status, JobStatus::Failed);
+        let skipped = store.get(skipped.0).unwrap().unwrap();
+        assert_eq!(skipped.status, JobStatus::Skipped);
+        assert_eq!(skipped.attempts, 0);
+        assert!(skipped.last_error.unwrap().contains(format!("Job {}", failing).as_str()));
+        assert_eq!(store.get(failed.0).unwrap().unwrap().status, JobStatus::Failed);
+        assert_eq!(store.get(transitive.0).unwrap().unwrap().status, JobStatus::Skipped);
+        assert_eq!(store.get(late.0).unwrap().unwrap().status, JobStatus::Skipped);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_dependents_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let greeting = store
+            .insert_at("greeting-job", &serde_json::json!({"text": "again", "fail": false}), None)
+            .unwrap();
+        let dependent = store
+            .insert_with_dependencies("print-job", &serde_json::json!({"id": 1}), None, &[greeting])
+            .unwrap();
+        assert_eq!(store.dependencies(dependent).unwrap(), vec![greeting]);
+
+        let mut registry = JobRegistry::new();
+        registry.register_deserializable::<PrintJob>("print-job");
+        registry.register_deserializable::<GreetingJob>("greeting-job");
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        assert_eq!(handle.recover(&registry).await.unwrap(), 2);
+        handle.shutdown().await;
+
+        let greeting = store.get(greeting).unwrap().unwrap();
+        let dependent = store.get(dependent).unwrap().unwrap();
+        assert_eq!(dependent.status, JobStatus::Succeeded);
+        assert!(greeting.updated_at <= dependent.updated_at);
+        let (output_type, output) = store.output(greeting.job_id).unwrap().unwrap();
+        assert_eq!(output_type, Greeting::OUTPUT_TYPE);
+        assert_eq!(output["text"], "again");
+    }
+
+    #[tokio::test]
+    async fn test_waiting_jobs_can_be_cancelled() {
+        let (scheduler, handle) = Scheduler::new(32);
+        tokio::spawn(scheduler.run(handle.clone()));
+        let heard = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let slow = handle.submit(SleepyJob { millis: 5_000, timeout: None }).await.unwrap();
+        let waiting = handle.submit_depending_on(&[slow], listener(&heard, DependencyFailure::Skip)).await.unwrap();
+        let after = handle.submit_depending_on(&[waiting], listener(&heard, DependencyFailure::Skip)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(handle.cancel(waiting).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the job after it is settled as well
+        assert!(!handle.cancel(after).await.unwrap());
+        assert!(handle.cancel(slow).await.unwrap());
+        handle.shutdown().await;
+        assert!(heard.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_job_events_and_status() {
+        let (scheduler, handle) = Scheduler::new(32);
+        tokio::spawn(scheduler.run(handle.clone()));
+        let mut events = handle.subscribe();
+        let runs = Arc::new(AtomicU32::new(0));
+        let flaky = handle.submit(FlakyJob { failures: 1, runs: runs.clone(), permanent: false }).await.unwrap();
+        assert_eq!(handle.completion(flaky).await.unwrap(), JobStatus::Succeeded);
+        assert_eq!(handle.status(flaky).await.unwrap(), Some(JobStatus::Succeeded));
+        assert_eq!(handle.status(JobId(42)).await.unwrap(), None);
+        assert!(handle.completion(JobId(42)).await.is_err());
+        handle.shutdown().await;
+
+        let mut statuses = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(event.job_id, flaky);
+            assert_eq!(event.job_type, "flaky-job");
+            statuses.push(event.status);
+        }
+        assert_eq!(
+            statuses,
+            vec![
+                JobStatus::Queued,
+                JobStatus::Running,
+                JobStatus::Retrying,
+                JobStatus::Running,
+                JobStatus::Succeeded
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_completion_of_persisted_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let heard = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let failing = handle.submit(GreetingJob { text: "hello".to_string(), fail: true }).await.unwrap();
+        let skipped = handle.submit_depending_on(&[failing], listener(&heard, DependencyFailure::Skip)).await.unwrap();
+        let slow = handle.submit(SleepyJob { millis: 5_000, timeout: None }).await.unwrap();
+        assert_eq!(handle.completion(skipped).await.unwrap(), JobStatus::Skipped);
+        assert_eq!(handle.completion(failing).await.unwrap(), JobStatus::Failed);
+        assert_eq!(handle.status(slow).await.unwrap(), Some(JobStatus::Running));
+
+        let waiting = handle.clone();
+        let completion = tokio::spawn(async move { waiting.completion(slow).await });
+        handle.shutdown_with_deadline(Duration::from_millis(50)).await;
+        assert!(completion.await.unwrap().is_err());
+        assert_eq!(store.get(slow.0).unwrap().unwrap().status, JobStatus::Queued);
+    }
+
+    /// Records its name when it starts.
+    struct RankedJob {
+        name: &'static str,
+        priority: Priority,
+        started: Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+    #[async_trait]
+    impl Job for RankedJob {
+        fn job_type(&self) -> &'static str {
+            "ranked-job"
+        }
+        fn payload(&self) -> Value {
+            serde_json::json!({ "name": self.name })
+        }
+        async fn execute(&self, _: JobContext) -> anyhow::Result<()> {
+            self.started.lock().unwrap().push(self.name);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(())
+        }
+        fn priority(&self) -> Priority {
+            self.priority
+        }
+    }
+
+    #[tokio::test]
+    async fn test_higher_priorities_jump_the_queue() {
+        let limits = SchedulerLimits { workers: 1, ca_requests: 1 };
+        let (scheduler, handle) = Scheduler::with_limits(32, None, limits);
+        let started = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let jobs = [
+            ("running", Priority::Low),
+            ("refresh-1", Priority::Low),
+            ("renewal-1", Priority::Normal),
+            ("refresh-2", Priority::Low),
+            ("revocation", Priority::Critical),
+            ("renewal-2", Priority::Normal),
+            ("setup", Priority::High),
+        ];
+        // the first job takes the only worker, the rest queues up behind it
+        for (name, priority) in jobs {
+            handle.submit(RankedJob { name, priority, started: started.clone() }).await.unwrap();
+        }
+        tokio::spawn(scheduler.run(handle.clone()));
+        handle.shutdown().await;
+        assert_eq!(
+            *started.lock().unwrap(),
+            vec!["running", "revocation", "setup", "renewal-1", "renewal-2", "refresh-1", "refresh-2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identical_jobs_are_coalesced() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repositories, store) = persistent(&dir);
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let first = handle.submit(SleepyJob { millis: 100, timeout: None }).await.unwrap();
+        let duplicate = handle.submit(SleepyJob { millis: 100, timeout: None }).await.unwrap();
+        let other = handle.submit(SleepyJob { millis: 50, timeout: None }).await.unwrap();
+        assert_eq!(first, duplicate);
+        assert_ne!(first, other);
+        assert_eq!(handle.completion(first).await.unwrap(), JobStatus::Succeeded);
+        // finished jobs don't swallow new submissions
+        let again = handle.submit(SleepyJob { millis: 100, timeout: None }).await.unwrap();
+        assert_ne!(first, again);
+        handle.shutdown().await;
+        assert_eq!(again, JobId(3));
+        assert!(store.get(4).unwrap().is_none());
+    }
 }
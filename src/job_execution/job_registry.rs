@@ -1,8 +1,11 @@
 use std::collections::HashMap;
+use anyhow::anyhow;
 use crate::job_execution::job_base::Job;
 
-type JobFactory = Box<dyn Fn(serde_json::Value) -> Box<dyn Job> + Send + Sync>;
+type JobFactory = Box<dyn Fn(serde_json::Value) -> anyhow::Result<Box<dyn Job>> + Send + Sync>;
 
+/// Rebuilds persisted jobs from their `job_type()` and `payload()`.
+#[derive(Default)]
 pub struct JobRegistry {
     factories: HashMap<&'static str, JobFactory>,
 }
@@ -14,14 +17,25 @@ impl JobRegistry {
     pub fn register<J, F>(&mut self, job_type: &'static str, factory: F)
     where
         J: Job,
-        F: Fn(serde_json::Value) -> J + Send + Sync + 'static,
+        F: Fn(serde_json::Value) -> anyhow::Result<J> + Send + Sync + 'static,
     {
         self.factories.insert(
             job_type,
-            Box::new(move |payload| Box::new(factory(payload))),
+            Box::new(move |payload| Ok(Box::new(factory(payload)?) as Box<dyn Job>)),
         );
     }
-    pub fn reconstruct(&self, job_type: &str, payload: serde_json::Value) -> Option<Box<dyn Job>> {
-        self.factories.get(job_type).map(|f| f(payload))
+    /// Shorthand for jobs whose payload is just their serde representation.
+    pub fn register_deserializable<J>(&mut self, job_type: &'static str)
+    where
+        J: Job + serde::de::DeserializeOwned,
+    {
+        self.register(job_type, |payload| Ok(serde_json::from_value::<J>(payload)?));
     }
-}
\ No newline at end of file
+    pub fn reconstruct(&self, job_type: &str, payload: serde_json::Value) -> anyhow::Result<Box<dyn Job>> {
+        let factory = self
+            .factories
+            .get(job_type)
+            .ok_or_else(|| anyhow!("No factory registered for job type: {}", job_type))?;
+        factory(payload)
+    }
+}
@@ -0,0 +1,8 @@
+use persistence::repository::JobRepository;
+use std::sync::Arc;
+
+pub use persistence::repository::now;
+
+/// Record of every job submitted to a persistent [`Scheduler`](super::job_base::Scheduler),
+/// shared by the scheduler, every handle and the [`RecurringRunner`](super::recurring::RecurringRunner).
+pub type JobStore = Arc<dyn JobRepository>;
@@ -0,0 +1,339 @@
+use crate::job_execution::job_base::{blocking, SchedulerHandle};
+use crate::job_execution::job_registry::JobRegistry;
+use crate::job_execution::job_store::{now, JobStore};
+use anyhow::anyhow;
+use chrono::DateTime;
+use persistence::data_model::RecurringJobRecord;
+use serde_json::Value;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{error, info, instrument, warn};
+
+/// When a recurring job runs, either a cron expression (with seconds, e.g. `0 0 */6 * * *`)
+/// or a fixed interval written as `90s`, `15m`, `6h` or `1d`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(String),
+}
+
+impl Schedule {
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let spec = spec.trim();
+        if let Some(interval) = parse_interval(spec)? {
+            if interval.is_zero() {
+                return Err(anyhow!("Interval of schedule {:?} has to be greater than zero", spec));
+            }
+            return Ok(Schedule::Interval(interval));
+        }
+        cron::Schedule::from_str(spec).map_err(|e| anyhow!("Invalid schedule {:?}: {}", spec, e))?;
+        Ok(Schedule::Cron(spec.to_string()))
+    }
+    /// The form stored in `recurring_jobs.schedule`, parses back into the same schedule.
+    pub fn spec(&self) -> String {
+        match self {
+            Schedule::Interval(interval) => format!("{}s", interval.as_secs()),
+            Schedule::Cron(expression) => expression.clone(),
+        }
+    }
+    /// First run strictly after `after` (unix seconds).
+    pub fn next_after(&self, after: i64) -> anyhow::Result<i64> {
+        match self {
+            Schedule::Interval(interval) => (interval.as_secs().max(1) as i64)
+                .checked_add(after)
+                .ok_or_else(|| anyhow!("Next run of schedule {:?} is out of range", self.spec())),
+            Schedule::Cron(expression) => {
+                let schedule = cron::Schedule::from_str(expression)?;
+                let after = DateTime::from_timestamp(after, 0).ok_or_else(|| anyhow!("Timestamp out of range"))?;
+                schedule
+                    .after(&after)
+                    .next()
+                    .map(|next| next.timestamp())
+                    .ok_or_else(|| anyhow!("Schedule {:?} has no upcoming runs", expression))
+            }
+        }
+    }
+}
+
+/// `None` if `spec` isn't written like an interval, an error if it is but is too long to
+/// be added to a timestamp.
+pub(crate) fn parse_interval(spec: &str) -> anyhow::Result<Option<Duration>> {
+    let (value, unit) = match spec.find(|c: char| !c.is_ascii_digit()) {
+        Some(0) | None => return Ok(None),
+        Some(index) => spec.split_at(index),
+    };
+    let unit_seconds: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Ok(None),
+    };
+    let seconds = value
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(unit_seconds))
+        .filter(|seconds| *seconds <= i64::MAX as u64)
+        .ok_or_else(|| anyhow!("Interval {:?} is too long", spec))?;
+    Ok(Some(Duration::from_secs(seconds)))
+}
+
+/// What happens to runs that fell into a time the daemon wasn't running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissedRunPolicy {
+    /// Run once right away, several missed runs are coalesced into one.
+    CatchUp,
+    /// Drop the missed runs and wait for the next regular one.
+    Skip,
+}
+
+impl MissedRunPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MissedRunPolicy::CatchUp => "catch-up",
+            MissedRunPolicy::Skip => "skip",
+        }
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "catch-up" => Ok(MissedRunPolicy::CatchUp),
+            "skip" => Ok(MissedRunPolicy::Skip),
+            other => Err(anyhow!("Unknown missed run policy: {} (expected catch-up or skip)", other)),
+        }
+    }
+}
+
+/// Submits the entries of the `recurring_jobs` table once they're due.
+///
+/// The jobs themselves are rebuilt through the [`JobRegistry`], the same way recovered jobs are.
+pub struct RecurringRunner {
+    store: JobStore,
+    registry: JobRegistry,
+    /// Upper bound for how long newly registered entries go unnoticed.
+    poll_interval: Duration,
+}
+
+impl RecurringRunner {
+    pub fn new(store: JobStore, registry: JobRegistry) -> Self {
+        RecurringRunner {
+            store,
+            registry,
+            poll_interval: Duration::from_secs(30),
+        }
+    }
+    #[instrument(level = "trace", name = "recurring", skip_all)]
+    pub async fn run(self, mut handle: SchedulerHandle) {
+        if let Err(e) = self.apply_missed_run_policies().await {
+            error!("Failed to apply missed run policies: {:?}", e);
+        }
+        loop {
+            let wait = match self.submit_due(&handle).await {
+                Ok(next_run_at) => next_run_at
+                    .map(|next| Duration::from_secs((next - now()).max(0) as u64))
+                    .unwrap_or(self.poll_interval)
+                    .min(self.poll_interval),
+                Err(e) => {
+                    error!("Failed to submit recurring jobs: {:?}", e);
+                    self.poll_interval
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = handle.wait_for_shutdown() => break,
+            }
+        }
+        info!("Recurring job runner stopped");
+    }
+    /// Moves the missed runs of entries with [`MissedRunPolicy::Skip`] to their next regular
+    /// slot, catch-up entries stay due and are submitted by the first [`Self::submit_due`].
+    async fn apply_missed_run_policies(&self) -> anyhow::Result<()> {
+        let now = now();
+        for record in self.recurring().await? {
+            if record.next_run_at >= now || MissedRunPolicy::from_str(record.missed_runs.as_str())? == MissedRunPolicy::CatchUp {
+                continue;
+            }
+            let next_run_at = Schedule::parse(record.schedule.as_str())?.next_after(now)?;
+            info!("Skipping missed run(s) of recurring job {}", record.name);
+            self.reschedule(record.recurring_id, None, next_run_at).await?;
+        }
+        Ok(())
+    }
+    /// Submits every due entry and returns when the next one is due.
+    async fn submit_due(&self, handle: &SchedulerHandle) -> anyhow::Result<Option<i64>> {
+        let now = now();
+        let mut next_due = None;
+        for record in self.recurring().await? {
+            let next_run_at = if record.next_run_at <= now {
+                self.submit(handle, &record, now).await?
+            } else {
+                record.next_run_at
+            };
+            next_due = Some(next_due.map_or(next_run_at, |due: i64| due.min(next_run_at)));
+        }
+        Ok(next_due)
+    }
+    async fn submit(&self, handle: &SchedulerHandle, record: &RecurringJobRecord, now: i64) -> anyhow::Result<i64> {
+        let next_run_at = Schedule::parse(record.schedule.as_str())?.next_after(now)?;
+        let job = serde_json::from_str::<Value>(record.payload.as_str())
+            .map_err(anyhow::Error::from)
+            .and_then(|payload| self.registry.reconstruct(record.job_type.as_str(), payload));
+        match job {
+            Ok(job) => {
+                info!("Submitting recurring job {} ({})", record.name, record.job_type);
+                handle.submit_boxed(job).await.map_err(|e| anyhow!(e))?;
+            }
+            Err(e) => warn!("Recurring job {} can't be rebuilt, skipping this run: {:?}", record.name, e),
+        }
+        self.reschedule(record.recurring_id, Some(now), next_run_at).await?;
+        Ok(next_run_at)
+    }
+    async fn recurring(&self) -> anyhow::Result<Vec<RecurringJobRecord>> {
+        let store = self.store.clone();
+        blocking(move || store.recurring().map_err(|e| anyhow!(e.to_string()))).await
+    }
+    async fn reschedule(&self, recurring_id: i64, last_run_at: Option<i64>, next_run_at: i64) -> anyhow::Result<()> {
+        let store = self.store.clone();
+        blocking(move || {
+            store
+                .reschedule_recurring(recurring_id, last_run_at, next_run_at)
+                .map_err(|e| anyhow!(e.to_string()))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::job_execution::job_base::{Job, JobContext, Scheduler};
+    use crate::job_execution::job_registry::JobRegistry;
+    use crate::job_execution::job_store::{now, JobStore};
+    use crate::job_execution::recurring::{MissedRunPolicy, RecurringRunner, Schedule};
+    use async_trait::async_trait;
+    use persistence::repository::Repositories;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct CountJob {
+        runs: Arc<AtomicU32>,
+    }
+    #[async_trait]
+    impl Job for CountJob {
+        fn job_type(&self) -> &'static str {
+            "count-job"
+        }
+        fn payload(&self) -> Value {
+            serde_json::json!({})
+        }
+        async fn execute(&self, _: JobContext) -> anyhow::Result<()> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Registers `name` as overdue by a day and runs the recurring runner for a moment.
+    async fn run_overdue(missed_runs: MissedRunPolicy) -> (u32, JobStore) {
+        let repositories = Repositories::in_memory();
+        let store = repositories.jobs.clone();
+        let runs = Arc::new(AtomicU32::new(0));
+        store
+            .upsert_recurring("refresh", "count-job", &serde_json::json!({}), "1h", missed_runs.as_str(), now() - 86_400)
+            .unwrap();
+
+        let mut registry = JobRegistry::new();
+        let counter = runs.clone();
+        registry.register("count-job", move |_| Ok(CountJob { runs: counter.clone() }));
+        let (scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        tokio::spawn(scheduler.run(handle.clone()));
+        let mut runner = RecurringRunner::new(store.clone(), registry);
+        runner.poll_interval = Duration::from_millis(50);
+        let runner = tokio::spawn(runner.run(handle.clone()));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        handle.shutdown().await;
+        runner.await.unwrap();
+        (runs.load(Ordering::SeqCst), store)
+    }
+
+    #[tokio::test]
+    async fn test_missed_runs_are_caught_up_once() {
+        let (runs, store) = run_overdue(MissedRunPolicy::CatchUp).await;
+        assert_eq!(runs, 1);
+        let record = store.recurring().unwrap().remove(0);
+        assert!(record.last_run_at.is_some());
+        assert!(record.next_run_at > now());
+    }
+
+    #[tokio::test]
+    async fn test_missed_runs_are_skipped() {
+        let (runs, store) = run_overdue(MissedRunPolicy::Skip).await;
+        assert_eq!(runs, 0);
+        let record = store.recurring().unwrap().remove(0);
+        assert!(record.last_run_at.is_none());
+        assert!(record.next_run_at > now());
+    }
+
+    #[tokio::test]
+    async fn test_registration_keeps_next_run() {
+        let repositories = Repositories::in_memory();
+        let store = repositories.jobs.clone();
+        let (_scheduler, handle) = Scheduler::new_persistent(32, repositories.clone());
+        let job = CountJob { runs: Arc::new(AtomicU32::new(0)) };
+        let hourly = Schedule::parse("1h").unwrap();
+        handle.register_recurring("refresh", &hourly, MissedRunPolicy::Skip, &job).await.unwrap();
+        let first = store.recurring().unwrap().remove(0);
+        store.reschedule_recurring(first.recurring_id, None, 42).unwrap();
+
+        handle.register_recurring("refresh", &hourly, MissedRunPolicy::CatchUp, &job).await.unwrap();
+        let same_schedule = store.recurring().unwrap().remove(0);
+        assert_eq!(same_schedule.next_run_at, 42);
+        assert_eq!(same_schedule.missed_runs, "catch-up");
+
+        handle.register_recurring("refresh", &Schedule::parse("2h").unwrap(), MissedRunPolicy::CatchUp, &job).await.unwrap();
+        let records = store.recurring().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].schedule, "7200s");
+        assert!(records[0].next_run_at > now());
+
+        let (_scheduler, in_memory) = Scheduler::new(32);
+        assert!(in_memory.register_recurring("refresh", &hourly, MissedRunPolicy::Skip, &job).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_schedules() {
+        assert_eq!(Schedule::parse("90s").unwrap(), Schedule::Interval(Duration::from_secs(90)));
+        assert_eq!(Schedule::parse("15m").unwrap(), Schedule::Interval(Duration::from_secs(900)));
+        assert_eq!(Schedule::parse("6h").unwrap().spec(), "21600s");
+        assert_eq!(Schedule::parse("1d").unwrap(), Schedule::Interval(Duration::from_secs(86_400)));
+        assert_eq!(
+            Schedule::parse("0 0 */6 * * *").unwrap(),
+            Schedule::Cron("0 0 */6 * * *".to_string())
+        );
+        assert!(Schedule::parse("0s").is_err());
+        let error = Schedule::parse("213503982334601d").unwrap_err();
+        assert!(error.to_string().contains("too long"), "{}", error);
+        assert!(Schedule::parse("99999999999999999999s").unwrap_err().to_string().contains("too long"));
+        assert_eq!(Schedule::parse("106751991167300d").unwrap().spec(), "9223372036854720000s");
+        assert!(Schedule::parse("6 hours").is_err());
+        assert!(Schedule::parse("not a schedule").is_err());
+    }
+
+    #[test]
+    fn test_next_run() {
+        assert_eq!(Schedule::parse("1h").unwrap().next_after(1000).unwrap(), 4600);
+        assert!(Schedule::parse("106751991167300d").unwrap().next_after(100_000).is_err());
+        // 2024-01-01T00:00:00Z, the next 6 hour slot is at 06:00
+        let midnight = 1_704_067_200;
+        let schedule = Schedule::parse("0 0 */6 * * *").unwrap();
+        assert_eq!(schedule.next_after(midnight).unwrap(), midnight + 6 * 3600);
+        assert_eq!(schedule.next_after(midnight + 1).unwrap(), midnight + 6 * 3600);
+    }
+
+    #[test]
+    fn test_missed_run_policy() {
+        assert_eq!(MissedRunPolicy::from_str("skip").unwrap(), MissedRunPolicy::Skip);
+        assert_eq!(MissedRunPolicy::from_str(MissedRunPolicy::CatchUp.as_str()).unwrap(), MissedRunPolicy::CatchUp);
+        assert!(MissedRunPolicy::from_str("sometimes").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// How often and how fast a failed job is retried by the scheduler.
+///
+/// The delay before attempt `n + 1` is `base_delay * 2^(n - 1)`, capped at `max_delay` and
+/// spread by `jitter` so jobs that failed together don't hit the CA together again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, 1 means the job is never retried.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of the delay that's randomized, 0.2 spreads retries over ±20%.
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            jitter: 0.0,
+        }
+    }
+    pub fn exponential(max_attempts: u32, base_delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts,
+            base_delay,
+            max_delay: Duration::from_secs(60 * 60),
+            jitter: 0.2,
+        }
+    }
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+    /// `attempts` is the number of attempts made so far.
+    pub fn should_retry(&self, attempts: u32) -> bool {
+        attempts < self.max_attempts
+    }
+    /// Delay after the `attempts`-th failed attempt.
+    pub fn delay_for(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + self.jitter * (fastrand::f64() * 2.0 - 1.0);
+        delay.mul_f64(factor)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::none()
+    }
+}
+
+/// Context marker for errors that won't go away by retrying, e.g. a request the CA rejected.
+///
+/// `Err(e).context(Permanent)` makes the scheduler dead-letter the job right away.
+#[derive(Debug)]
+pub struct Permanent;
+
+impl Display for Permanent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "permanent failure")
+    }
+}
+
+pub fn is_permanent(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<Permanent>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::job_execution::retry::{is_permanent, Permanent, RetryPolicy};
+    use anyhow::Context;
+    use std::time::Duration;
+
+    #[test]
+    fn test_exponential_delays() {
+        let mut policy = RetryPolicy::exponential(5, Duration::from_secs(2)).with_max_delay(Duration::from_secs(10));
+        policy.jitter = 0.0;
+        assert_eq!(policy.delay_for(1), Duration::from_secs(2));
+        assert_eq!(policy.delay_for(2), Duration::from_secs(4));
+        assert_eq!(policy.delay_for(3), Duration::from_secs(8));
+        assert_eq!(policy.delay_for(4), Duration::from_secs(10));
+        assert_eq!(policy.delay_for(400), Duration::from_secs(10));
+        assert!(policy.should_retry(4));
+        assert!(!policy.should_retry(5));
+        assert!(!RetryPolicy::none().should_retry(1));
+    }
+
+    #[test]
+    fn test_jitter_stays_in_bounds() {
+        let mut policy = RetryPolicy::exponential(3, Duration::from_secs(10));
+        policy.jitter = 0.5;
+        for _ in 0..100 {
+            let delay = policy.delay_for(1);
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15));
+        }
+    }
+
+    #[test]
+    fn test_permanent_marker() {
+        let error = Err::<(), _>(anyhow::anyhow!("rejected")).context(Permanent).unwrap_err();
+        assert!(is_permanent(&error));
+        assert!(!is_permanent(&anyhow::anyhow!("timeout")));
+    }
+}
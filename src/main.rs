@@ -1,151 +1,246 @@
 mod acme_jobs;
+mod audit_log;
+mod backup;
+mod certificate_output;
+mod cli;
+mod config;
+mod config_check;
+mod doctor;
 mod job_execution;
 mod statics;
 
+use crate::acme_jobs::certificate_reconciliation::CertificateReconciliationJob;
 use crate::acme_jobs::db_initialization::DbInitializationJob;
 use crate::acme_jobs::directory_query::DirectoryUpdateJob;
 use crate::acme_jobs::initialize_keys_for_user::InitializeLocalUserJob;
-use crate::job_execution::job_base::Scheduler;
-use crate::statics::{Args, YamlConfig};
-use clap::{Parser, crate_version};
-use common_utils::{APPLICATION_CONFIG, ApplicationConfig, InternalIdTooling};
+use crate::cli::{Command, ConfigCommand, OutputMode};
+use crate::job_execution::job_base::{JobEvent, JobId, Scheduler, SchedulerHandle, SchedulerLimits};
+use crate::job_execution::recurring::{MissedRunPolicy, RecurringRunner, Schedule};
+use crate::statics::Args;
+use clap::{CommandFactory, FromArgMatches, crate_version};
+use common_utils::{APPLICATION_CONFIG, ApplicationConfig, CertificateAuthority, DEFAULT_CA_NAME};
 use std::error::Error;
-use std::path::PathBuf;
-use std::str::FromStr;
-use std::{env, fs};
-use tracing::{Instrument, Span, error, info, info_span};
+use std::time::Duration;
+use std::env;
+use common_utils::fs::FileSystem;
+use persistence::repository::Repositories;
+use persistence::data_model::JobStatus;
+use tokio::sync::broadcast;
+use tracing::{Instrument, Span, debug, info, info_span, warn};
 
-async fn async_main() -> Result<(), Box<dyn Error>> {
-    let (scheduler, handle) = Scheduler::new(32);
+async fn async_main(args: Args) -> Result<(), Box<dyn Error>> {
     let config = APPLICATION_CONFIG.get().unwrap();
-    DirectoryUpdateJob::validate_url(Some(config.base_url.clone()))?;
-    let scheduler_span = info_span!("scheduler", user_id = config.user_id);
-    scheduler_span.follows_from(Span::current());
-    let _ = tokio::spawn(scheduler.run(handle.clone()).instrument(scheduler_span));
-    handle.submit(DbInitializationJob::new()).await?;
-    handle
-        .submit(InitializeLocalUserJob::new(
-            config.output_dir.to_string(),
-            config.key_type.to_string(),
-            config.user_id.clone(),
-        ))
-        .await?;
-    handle
-        .submit(DirectoryUpdateJob::new(
-            config.base_url.to_string(),
-            config.user_id.clone(),
-        )?)
-        .await?;
+    // a restore needs the database absent, nothing may touch it before
+    if let Some(report) = args.command.as_ref().map(|command| cli::run_backup(command, config)).transpose()?.flatten() {
+        return report.print(args.output);
+    }
+    let exposed_keys = FileSystem::new(config.base_dir.as_str())?.audit_key_permissions(config.fix_key_permissions)?;
+    if !exposed_keys.is_empty() && !config.fix_key_permissions {
+        warn!("{} key file(s) are readable by other users, restart with --fix-key-permissions to restrict them", exposed_keys.len());
+    }
+    // the jobs table has to exist before the first job is persisted
+    let repositories = Repositories::sqlite(config.base_dir.as_str());
+    repositories.schema.migrate()?;
+    doctor::startup_check(config, &repositories);
+    audit_log::record_config_change(config, &repositories.audit)?;
+    if let Some(command) = args.command.as_ref().filter(|command| !matches!(command, Command::Daemon)) {
+        let report = match cli::run_offline(command, config, &repositories)? {
+            Some(report) => report,
+            None => {
+                let certificate_authorities = cli::certificate_authorities(command, config)?;
+                let (handle, directory_updates) = start_scheduler(config, &repositories, &certificate_authorities, false).await?;
+                let report = cli::run_scheduled(command, config, &repositories, &handle, &directory_updates).await;
+                handle.shutdown().await;
+                report?
+            }
+        };
+        return report.print(args.output);
+    }
+    let certificate_authorities: Vec<_> = config.certificate_authorities.iter().collect();
+    let (handle, directory_updates) = start_scheduler(config, &repositories, &certificate_authorities, true).await?;
     if config.application_mode {
         info!("Application mode has been enabled, monitoring input signals.");
+        register_recurring_jobs(config, &handle).await?;
+        if !config.certificates.is_empty() {
+            let directories: Vec<JobId> = directory_updates.iter().map(|(_, job_id)| *job_id).collect();
+            handle
+                .submit_depending_on(&directories, CertificateReconciliationJob::new(config.user_id.clone()))
+                .await?;
+        }
+        let runner = RecurringRunner::new(repositories.jobs.clone(), acme_jobs::job_registry());
+        tokio::spawn(runner.run(handle.clone()).instrument(info_span!("recurring")));
         let mut h = handle.clone();
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => { h.shutdown().await; }
+            _ = tokio::signal::ctrl_c() => {
+                h.shutdown_with_deadline(Duration::from_secs(config.shutdown_timeout)).await;
+            }
             _ = h.wait_for_shutdown() => {}
         }
         Ok(())
     } else {
         info!("Single shot mode enabled - application will shut down right now!");
+        let mut failed = Vec::new();
+        for (ca, directory_update) in directory_updates {
+            let status = handle.completion(directory_update).await?;
+            if status != JobStatus::Succeeded {
+                failed.push(format!("{} ({})", ca, status.as_str()));
+            }
+        }
         handle.shutdown().await;
+        if !failed.is_empty() {
+            return Err(format!("Directory update did not succeed for CA {}", failed.join(", ")).into());
+        }
         Ok(())
     }
 }
 
+/// Starts the scheduler and submits what every run begins with: the database, the local user
+/// and a directory refresh of each CA in `certificate_authorities`. Jobs left unfinished by an
+/// earlier run are only picked up again when `recover` is set, a subcommand leaves them to
+/// the daemon.
+async fn start_scheduler<'a>(
+    config: &ApplicationConfig,
+    repositories: &Repositories,
+    certificate_authorities: &[&'a CertificateAuthority],
+    recover: bool,
+) -> Result<(SchedulerHandle, Vec<(&'a str, JobId)>), Box<dyn Error>> {
+    let limits = SchedulerLimits {
+        workers: config.workers,
+        ca_requests: config.max_ca_requests,
+    };
+    let (scheduler, handle) = Scheduler::with_limits(32, Some(repositories.clone()), limits);
+    let scheduler_span = info_span!("scheduler", user_id = config.user_id);
+    scheduler_span.follows_from(Span::current());
+    tokio::spawn(scheduler.run(handle.clone()).instrument(scheduler_span));
+    tokio::spawn(log_job_events(handle.subscribe()));
+    if recover {
+        let recovered = handle.recover(&acme_jobs::job_registry()).await?;
+        if recovered > 0 {
+            info!("{} unfinished job(s) from a previous run have been re-enqueued", recovered);
+        }
+    }
+    let db_initialization = handle.submit(DbInitializationJob::new()).await?;
+    let local_user = handle
+        .submit_depending_on(
+            &[db_initialization],
+            InitializeLocalUserJob::new(
+                config.output_dir.to_string(),
+                config.key_type.to_string(),
+                config.user_id.clone(),
+            ),
+        )
+        .await?;
+    let mut directory_updates = Vec::new();
+    for ca in certificate_authorities {
+        let job = DirectoryUpdateJob::new(ca, config.user_id.clone())?;
+        directory_updates.push((ca.name.as_str(), handle.submit_depending_on(&[local_user], job).await?));
+    }
+    Ok((handle, directory_updates))
+}
+
 fn main() {
-    let args = Args::parse();
-    splash(args.clone().version);
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if args.output == OutputMode::Text {
+        splash(args.clone().version);
+    }
     if args.version {
         return;
     }
-    let _ = write_application_config(args.clone()).unwrap();
+    if matches!(args.command, Some(Command::Daemon)) {
+        args.application_mode = true;
+    }
+    if let Some(Command::Config(ConfigCommand::Check)) = &args.command {
+        let report = config::yaml_file(&args, &matches, &|name| env::var(name).ok()).and_then(|file| cli::check_config(file.as_deref()));
+        if let Err(e) = report.and_then(|report| report.print(args.output)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let (config, settings) = match config::resolve(&args, &matches, &|name| env::var(name).ok()) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::Config(ConfigCommand::Show)) = &args.command {
+        if let Err(e) = cli::show_config(&settings).print(args.output) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    APPLICATION_CONFIG.set(config).unwrap();
     let conf = APPLICATION_CONFIG.get().unwrap();
+    // stdout is left to the output of the subcommands
     tracing_subscriber::fmt()
         .with_max_level(conf.logging_level.unwrap())
+        .with_writer(std::io::stderr)
         .init();
     let user_id = conf.user_id.clone();
     let span = info_span!("main", user_id = user_id);
     tokio::runtime::Runtime::new()
         .unwrap()
-        .block_on(async_main().instrument(span))
+        .block_on(async_main(args).instrument(span))
         .expect("Tokio runtime panicked with error:");
 }
 
-fn handle_mode(args: Args) -> Result<(bool, bool), Box<dyn Error>> {
-    if args.application_mode {
-        if args.yaml_config.is_none() {
-            error!("YAML configuration option is required for this mode");
-            return Err("Yaml config parameter, not present in input parameter".into());
-        }
-        return Ok((true, true));
-    }
-    if args.yaml {
-        if args.yaml_config.is_none() {
-            error!("YAML configuration option is required for this mode");
-            return Err("Yaml config parameter, not present in input parameter".into());
+/// Lifecycle of every job at debug level, for everything in between the job's own logs.
+async fn log_job_events(mut events: broadcast::Receiver<JobEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => match event.error {
+                Some(error) => debug!("Job {} ({}) is {}: {}", event.job_id, event.job_type, event.status.as_str(), error),
+                None => debug!("Job {} ({}) is {}", event.job_id, event.job_type, event.status.as_str()),
+            },
+            Err(broadcast::error::RecvError::Lagged(missed)) => debug!("{} job event(s) were not logged", missed),
+            Err(broadcast::error::RecvError::Closed) => break,
         }
-        return Ok((true, false));
     }
-    Ok((false, false))
 }
 
-fn write_application_config(args: Args) -> Result<(), Box<dyn Error>> {
-    let (yaml_mode, application_mode) = handle_mode(args.clone())?;
-    if yaml_mode {
-        let yaml_file = fs::read_to_string(PathBuf::from_str(args.yaml_config.unwrap().as_str())?)?;
-        let yaml_config: YamlConfig = serde_yaml::from_str(&yaml_file)?;
-        let config = ApplicationConfig {
-            application_mode,
-            user_id: yaml_config
-                .acme_sentry_configuration
-                .user
-                .id
-                .unwrap_or(InternalIdTooling::new_compact_id()),
-            user_email: yaml_config.acme_sentry_configuration.user.email,
-            key_type: yaml_config.acme_sentry_configuration.user.key_type,
-            logging_level: yaml_config.acme_sentry_configuration.logging.logging_level,
-            base_dir: yaml_config.acme_sentry_configuration.fs.base_dir.clone(),
-            output_dir: PathBuf::from_str(
-                yaml_config.acme_sentry_configuration.fs.base_dir.as_str(),
-            )?
-            .join(yaml_config.acme_sentry_configuration.fs.output_dir.as_str())
-            .to_str()
-            .unwrap()
-            .to_string(),
-            base_url: yaml_config.acme_sentry_configuration.base_url,
-        };
-        APPLICATION_CONFIG.set(config).unwrap();
-    } else {
-        let email = args.with_email;
-        let config = ApplicationConfig {
-            application_mode,
-            user_id: args
-                .with_user_id
-                .unwrap_or(InternalIdTooling::new_compact_id()),
-            user_email: email.unwrap(),
-            key_type: args.requested_login_key_type,
-            logging_level: Some(args.logging_level),
-            base_dir: args.base_dir.clone(),
-            output_dir: PathBuf::from_str(args.base_dir.as_str())?
-                .join(args.output_dir.as_str())
-                .to_str()
-                .unwrap()
-                .to_string(),
-            base_url: args.acme_base_url.unwrap(),
-        };
-        APPLICATION_CONFIG.set(config).unwrap();
+async fn register_recurring_jobs(config: &ApplicationConfig, handle: &SchedulerHandle) -> Result<(), Box<dyn Error>> {
+    let missed_runs = MissedRunPolicy::from_str(config.missed_runs.as_str())?;
+    if let Some(directory_refresh) = &config.directory_refresh {
+        let schedule = Schedule::parse(directory_refresh)?;
+        for ca in &config.certificate_authorities {
+            // the default CA keeps the name it was registered under before CAs had names
+            let name = match ca.name.as_str() {
+                DEFAULT_CA_NAME => "directory-refresh".to_string(),
+                other => format!("directory-refresh:{}", other),
+            };
+            handle.register_recurring(
+                name.as_str(),
+                &schedule,
+                missed_runs,
+                &DirectoryUpdateJob::new(ca, config.user_id.clone())?,
+            )
+            .await?;
+        }
+    }
+    if !config.certificates.is_empty() {
+        handle.register_recurring(
+            "certificate-reconciliation",
+            &Schedule::parse(config.reconciliation.as_str())?,
+            missed_runs,
+            &CertificateReconciliationJob::new(config.user_id.clone()),
+        )
+        .await?;
     }
     Ok(())
 }
 
 fn splash(print_version: bool) {
-    print!(
-        "{}\n",
+    println!(
+        "{}",
         String::from_utf8_lossy(include_bytes!("assets/ico.bin"))
     );
     if print_version {
         println!("Version: {}", crate_version!());
-        print!(
-            "{}\n",
+        println!(
+            "{}",
             String::from_utf8_lossy(include_bytes!("assets/creators.bin"))
         );
     }
@@ -1,25 +1,40 @@
+use crate::certificate_output::OutputFormat;
+use crate::cli::{Command, OutputMode, output_parse};
+use crate::job_execution::recurring::parse_interval;
+use acme_client::crypto::SupportedKey;
+use acme_client::csr::CsrIdentifier;
 use clap::Parser;
+use common_utils::{CertificateAuthority, CertificateDefinition, CertificateHooks, ChallengeMethod, DEFAULT_CA_NAME};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
 use std::option::Option;
 use tracing::Level;
 
 #[derive(Parser, Debug, Clone)]
 #[command(about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(long, global = true, default_value = "text", value_parser = output_parse, help = "Output of the subcommands, text or json")]
+    pub output: OutputMode,
     #[arg(short, long)]
     pub version: bool,
     #[arg(short, long, default_value = "info", value_parser = log_level_parse, help = "Enable verbose logging")]
     pub logging_level: Level,
     #[arg(long, default_value_t = false, help = "Enable application-mode (input is required from user to terminate application)")]
     pub application_mode: bool,
-    #[arg(long, default_value_t = false, help = "Enable yaml config (enabled by default if application mode is on)")]
+    #[arg(long, default_value_t = false, help = "Require a yaml config, it is read whenever --yaml-config is given")]
     pub yaml: bool,
-    #[arg(long, help = "Location of the acme-sentry config file")]
+    #[arg(long, global = true, help = "Location of the acme-sentry config file, CLI flags and ACME_SENTRY_* variables take precedence over it")]
     pub yaml_config: Option<String>,
     #[arg(long, default_value = "ec-p256", help = "Specify what key type, that acme-sentry should use to log in to the CA with")]
     pub requested_login_key_type: String,
-    #[arg(long, help = "ACME system base url")]
+    #[arg(long, help = "ACME system base url, the CA named default")]
     pub acme_base_url: Option<String>,
+    #[arg(long = "ca", value_parser = ca_parse, help = "Named CA as NAME=DIRECTORY_URL, can be repeated")]
+    pub certificate_authorities: Vec<CertificateAuthority>,
     #[arg(long, default_value = "/opt/acme-sentry", help = "Application base directory")]
     pub base_dir: String,
     #[arg(long, default_value = "out", help = "Output directory of generated files")]
@@ -28,6 +43,32 @@ pub struct Args {
     pub with_user_id: Option<String>,
     #[arg(long, help = "Email that acme-sentry shall try to connect with the user")]
     pub with_email: Option<String>,
+    #[arg(long, help = "Preferred certificate chain, issuer CN of the chain root or its SHA-256 fingerprint")]
+    pub preferred_chain: Option<String>,
+    #[arg(long, default_value_t = false, help = "Don't verify the TLS certificate of the CA, only meant for test CAs like Pebble")]
+    pub accept_invalid_certs: bool,
+    #[arg(long = "output-format", help = "Additional certificate output format (haproxy, pkcs12, der), can be repeated")]
+    pub output_formats: Vec<String>,
+    #[arg(long, help = "Password of the PKCS#12 output")]
+    pub pkcs12_password: Option<String>,
+    #[arg(long, help = "Owner (name or uid) of the written certificate files")]
+    pub file_owner: Option<String>,
+    #[arg(long, help = "Group (name or gid) of the written certificate files")]
+    pub file_group: Option<String>,
+    #[arg(long, default_value_t = false, help = "Restrict key files readable by other users to 0600 on startup")]
+    pub fix_key_permissions: bool,
+    #[arg(long, help = "Schedule of the recurring directory refresh in application mode, a cron expression or an interval like 6h")]
+    pub directory_refresh: Option<String>,
+    #[arg(long, default_value = "catch-up", help = "What to do with recurring runs missed while acme-sentry was down (catch-up, skip)")]
+    pub missed_runs: String,
+    #[arg(long, default_value_t = 4, help = "Number of jobs that may run concurrently")]
+    pub workers: usize,
+    #[arg(long, default_value_t = 2, help = "Maximum number of requests in flight to the CA")]
+    pub max_ca_requests: usize,
+    #[arg(long, default_value_t = 30, help = "Seconds to wait for queued jobs on shutdown, unfinished ones are resumed on the next start")]
+    pub shutdown_timeout: u64,
+    #[arg(long, default_value = "1h", help = "Schedule the daemon reconciles the declared certificates at, a cron expression or an interval")]
+    pub reconciliation: String,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct YamlConfig {
@@ -35,32 +76,127 @@ pub struct YamlConfig {
     pub acme_sentry_configuration: AcmeSentryConfiguration
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct AcmeSentryConfiguration {
-    #[serde(rename = "base-url")]
-    pub base_url: String,
+    #[serde(default, rename = "base-url")]
+    pub base_url: Option<String>,
+    #[serde(default, rename = "certificate-authorities")]
+    pub certificate_authorities: Vec<CaConfig>,
+    #[serde(default, rename = "preferred-chain")]
+    pub preferred_chain: Option<String>,
+    #[serde(default, rename = "accept-invalid-certs")]
+    pub accept_invalid_certs: Option<bool>,
+    #[serde(default)]
     pub fs: FsConfig,
+    #[serde(default)]
     pub user: UserConfig,
+    #[serde(default)]
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
+    #[serde(default)]
+    pub certificates: Vec<CertificateConfig>,
 }
 #[derive(Debug, Serialize, Deserialize)]
-pub struct FsConfig {
-    #[serde(rename = "base-dir")]
-    pub base_dir: String,
-    #[serde(rename = "output-dir")]
-    pub output_dir: String,
+pub struct CaConfig {
+    pub name: String,
+    #[serde(rename = "directory-url")]
+    pub directory_url: String,
+}
+impl From<CaConfig> for CertificateAuthority {
+    fn from(value: CaConfig) -> Self {
+        CertificateAuthority {
+            name: value.name,
+            directory_url: value.directory_url,
+        }
+    }
+}
+/// Unset keys of the YAML sections fall through to the defaults of [`Args`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SchedulerConfig {
+    #[serde(default, rename = "directory-refresh")]
+    pub directory_refresh: Option<String>,
+    #[serde(default, rename = "missed-runs")]
+    pub missed_runs: Option<String>,
+    #[serde(default)]
+    pub workers: Option<usize>,
+    #[serde(default, rename = "max-ca-requests")]
+    pub max_ca_requests: Option<usize>,
+    #[serde(default, rename = "shutdown-timeout")]
+    pub shutdown_timeout: Option<u64>,
+    #[serde(default)]
+    pub reconciliation: Option<String>,
 }
 #[derive(Debug, Serialize, Deserialize)]
+pub struct CertificateConfig {
+    pub name: String,
+    pub identifiers: Vec<String>,
+    #[serde(default = "default_certificate_key_type", rename = "key-type")]
+    pub key_type: String,
+    pub challenge: ChallengeConfig,
+    #[serde(default)]
+    pub ca: Option<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub renewal: RenewalConfig,
+    #[serde(default, rename = "output-formats")]
+    pub output_formats: Option<Vec<String>>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+}
+fn default_certificate_key_type() -> String {
+    "ec-p256".to_string()
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeConfig {
+    pub method: String,
+    #[serde(flatten)]
+    pub settings: BTreeMap<String, String>,
+}
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RenewalConfig {
+    #[serde(default, rename = "before-expiry")]
+    pub before_expiry: Option<String>,
+}
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HooksConfig {
+    #[serde(default)]
+    pub pre: Option<String>,
+    #[serde(default)]
+    pub post: Option<String>,
+    #[serde(default)]
+    pub deploy: Option<String>,
+}
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FsConfig {
+    #[serde(default, rename = "base-dir")]
+    pub base_dir: Option<String>,
+    #[serde(default, rename = "output-dir")]
+    pub output_dir: Option<String>,
+    #[serde(default, rename = "output-formats")]
+    pub output_formats: Vec<String>,
+    #[serde(default, rename = "pkcs12-password")]
+    pub pkcs12_password: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default, rename = "fix-key-permissions")]
+    pub fix_key_permissions: Option<bool>,
+}
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct UserConfig {
     #[serde(default, rename = "id")]
     pub id: Option<String>,
-    pub email: String,
-    #[serde(rename = "login-key-type")]
-    pub key_type: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default, rename = "login-key-type")]
+    pub key_type: Option<String>,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct LoggingConfig {
-    #[serde(rename = "logging-level", with = "level_serde")]
+    #[serde(default, rename = "logging-level", with = "level_serde")]
     pub logging_level: Option<Level>,
 }
 
@@ -83,7 +219,184 @@ mod level_serde {
     }
 }
 
-fn log_level_parse(s: &str) -> Result<Level, String> {
+pub fn ca_parse(s: &str) -> Result<CertificateAuthority, String> {
+    match s.split_once('=') {
+        Some((name, directory_url)) if !name.is_empty() => Ok(CertificateAuthority {
+            name: name.to_string(),
+            directory_url: directory_url.to_string(),
+        }),
+        _ => Err(format!("Expected NAME=DIRECTORY_URL, got: {}", s)),
+    }
+}
+
+/// The CAs to hold accounts with, a bare base url is the CA named `default` with its
+/// directory under `/dir`.
+pub fn certificate_authorities(
+    base_url: Option<String>,
+    named: Vec<CertificateAuthority>,
+) -> Result<Vec<CertificateAuthority>, Box<dyn Error>> {
+    let mut certificate_authorities = Vec::new();
+    if let Some(base_url) = base_url {
+        certificate_authorities.push(CertificateAuthority {
+            name: DEFAULT_CA_NAME.to_string(),
+            directory_url: base_url + "/dir",
+        });
+    }
+    certificate_authorities.extend(named);
+    if certificate_authorities.is_empty() {
+        return Err("No CA configured, set a base url or at least one named CA".into());
+    }
+    let mut names = HashSet::new();
+    for ca in &certificate_authorities {
+        if !names.insert(ca.name.as_str()) {
+            return Err(format!("CA {} is configured more than once", ca.name).into());
+        }
+        Url::parse(ca.directory_url.as_str()).map_err(|e| format!("Directory url of CA {} is invalid: {}", ca.name, e))?;
+    }
+    Ok(certificate_authorities)
+}
+
+/// Settings each challenge method knows, the first ones listed are required.
+fn challenge_settings(method: ChallengeMethod) -> (&'static [&'static str], &'static [&'static str]) {
+    match method {
+        ChallengeMethod::Http01 => (&["webroot"], &[]),
+        ChallengeMethod::Dns01 => (&["provider"], &["propagation-timeout"]),
+        ChallengeMethod::TlsAlpn01 => (&[], &["port"]),
+    }
+}
+
+/// Problems of one declared certificate as the key of the field they are about and a
+/// message, names and CAs are checked against `certificate_authorities`.
+pub fn certificate_problems(
+    certificate: &CertificateConfig,
+    certificate_authorities: &[CertificateAuthority],
+) -> Vec<(&'static str, String)> {
+    let mut problems = Vec::new();
+    let name = certificate.name.as_str();
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        problems.push(("name", format!("{:?} can't be used as a directory name", name)));
+    }
+    let challenge = ChallengeMethod::from_str(certificate.challenge.method.as_str());
+    match &challenge {
+        Ok(method) => {
+            let (required, optional) = challenge_settings(*method);
+            for setting in required {
+                if !certificate.challenge.settings.contains_key(*setting) {
+                    problems.push(("challenge", format!("{} needs the setting {}", method.as_str(), setting)));
+                }
+            }
+            for (setting, value) in &certificate.challenge.settings {
+                if !required.contains(&setting.as_str()) && !optional.contains(&setting.as_str()) {
+                    problems.push(("challenge", format!("{} has no setting {}", method.as_str(), setting)));
+                } else if setting == "propagation-timeout" {
+                    if let Some(problem) = interval_problem("propagation-timeout", value, "120s") {
+                        problems.push(("challenge", problem));
+                    }
+                } else if setting == "port" && value.parse::<u16>().is_err() {
+                    problems.push(("challenge", format!("port {:?} isn't a port number", value)));
+                }
+            }
+        }
+        Err(e) => problems.push(("challenge", e.clone())),
+    }
+    if certificate.identifiers.is_empty() {
+        problems.push(("identifiers", "no identifiers are declared".to_string()));
+    }
+    for value in &certificate.identifiers {
+        match CsrIdentifier::parse(value.trim()) {
+            CsrIdentifier::Ip(_) if challenge == Ok(ChallengeMethod::Dns01) => {
+                problems.push(("identifiers", format!("ip address {} can't be validated with dns-01", value)));
+            }
+            CsrIdentifier::Dns(dns) if dns.contains('*') => {
+                if !dns.starts_with("*.") || dns[2..].contains('*') {
+                    problems.push(("identifiers", format!("wildcard {} has to start with *. and have no other *", value)));
+                } else if challenge.is_ok() && challenge != Ok(ChallengeMethod::Dns01) {
+                    problems.push(("identifiers", format!("wildcard {} can only be validated with dns-01", value)));
+                }
+            }
+            CsrIdentifier::Dns(dns) if dns.is_empty() || dns.contains(|c: char| c.is_whitespace() || c == '/') => {
+                problems.push(("identifiers", format!("{:?} isn't a DNS name or ip address", value)));
+            }
+            _ => {}
+        }
+    }
+    if let Err(e) = SupportedKey::from_str(certificate.key_type.as_str()) {
+        problems.push(("key-type", e.to_string()));
+    }
+    match &certificate.ca {
+        Some(ca) if !certificate_authorities.iter().any(|known| &known.name == ca) => {
+            problems.push(("ca", format!("CA {} isn't configured", ca)));
+        }
+        None if certificate_authorities.len() > 1 => {
+            problems.push(("ca", "has to be named, several CAs are configured".to_string()));
+        }
+        _ => {}
+    }
+    let before_expiry = certificate.renewal.before_expiry.as_deref();
+    if let Some(problem) = before_expiry.and_then(|before_expiry| interval_problem("before-expiry", before_expiry, "30d")) {
+        problems.push(("renewal", problem));
+    }
+    for format in certificate.output_formats.iter().flatten() {
+        if let Err(e) = OutputFormat::from_str(format) {
+            problems.push(("output-formats", e.to_string()));
+        }
+    }
+    problems
+}
+
+fn interval_problem(setting: &str, value: &str, example: &str) -> Option<String> {
+    match parse_interval(value) {
+        Ok(Some(_)) => None,
+        Ok(None) => Some(format!("{} {:?} isn't a duration like {}", setting, value, example)),
+        Err(e) => Some(format!("{}: {}", setting, e)),
+    }
+}
+
+/// The declared certificates, checked against each other and the configured CAs. A
+/// certificate without `ca` is ordered from the only CA there is.
+pub fn certificate_definitions(
+    declared: Vec<CertificateConfig>,
+    certificate_authorities: &[CertificateAuthority],
+) -> Result<Vec<CertificateDefinition>, Box<dyn Error>> {
+    let mut names = HashSet::new();
+    let mut definitions = Vec::new();
+    for certificate in declared {
+        if let Some((_, problem)) = certificate_problems(&certificate, certificate_authorities).into_iter().next() {
+            return Err(format!("Certificate {}: {}", certificate.name, problem).into());
+        }
+        let name = certificate.name;
+        if !names.insert(name.clone()) {
+            return Err(format!("Certificate {} is declared more than once", name).into());
+        }
+        let challenge = ChallengeMethod::from_str(certificate.challenge.method.as_str())?;
+        let identifiers = certificate.identifiers.iter().map(|i| CsrIdentifier::parse(i.trim()).value()).collect();
+        let ca = certificate.ca.unwrap_or_else(|| certificate_authorities[0].name.clone());
+        let renew_before = certificate
+            .renewal
+            .before_expiry
+            .and_then(|before_expiry| parse_interval(before_expiry.as_str()).ok().flatten())
+            .map(|interval| interval.as_secs());
+        definitions.push(CertificateDefinition {
+            name,
+            identifiers,
+            key_type: certificate.key_type.to_lowercase(),
+            challenge,
+            challenge_settings: certificate.challenge.settings,
+            ca,
+            profile: certificate.profile,
+            renew_before,
+            output_formats: certificate.output_formats,
+            hooks: CertificateHooks {
+                pre: certificate.hooks.pre,
+                post: certificate.hooks.post,
+                deploy: certificate.hooks.deploy,
+            },
+        });
+    }
+    Ok(definitions)
+}
+
+pub fn log_level_parse(s: &str) -> Result<Level, String> {
     match s.to_lowercase().as_str() {
         "trace" => Ok(Level::TRACE),
         "debug" => Ok(Level::DEBUG),
@@ -92,4 +405,142 @@ fn log_level_parse(s: &str) -> Result<Level, String> {
         "error" => Ok(Level::ERROR),
         _ => Ok(Level::INFO),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CertificateConfig, YamlConfig, certificate_authorities, certificate_definitions};
+    use common_utils::{CertificateAuthority, ChallengeMethod};
+
+    fn ca(name: &str, directory_url: &str) -> CertificateAuthority {
+        CertificateAuthority {
+            name: name.to_string(),
+            directory_url: directory_url.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_named_cas_are_read_from_yaml() {
+        let yaml = r#"
+acme-sentry:
+  base-url: https://localhost:14000
+  certificate-authorities:
+    - name: letsencrypt
+      directory-url: https://acme-v02.api.letsencrypt.org/directory
+    - name: letsencrypt-staging
+      directory-url: https://acme-staging-v02.api.letsencrypt.org/directory
+  fs:
+    base-dir: /opt/acme-sentry
+    output-dir: out
+  user:
+    email: admin@example.org
+    login-key-type: ec-p256
+  logging:
+    logging-level: info
+"#;
+        let config: YamlConfig = serde_yaml::from_str(yaml).unwrap();
+        let configuration = config.acme_sentry_configuration;
+        let named = configuration.certificate_authorities.into_iter().map(Into::into).collect();
+        let resolved = certificate_authorities(configuration.base_url, named).unwrap();
+        assert_eq!(
+            resolved,
+            vec![
+                ca("default", "https://localhost:14000/dir"),
+                ca("letsencrypt", "https://acme-v02.api.letsencrypt.org/directory"),
+                ca("letsencrypt-staging", "https://acme-staging-v02.api.letsencrypt.org/directory"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ca_configuration_is_validated() {
+        assert!(certificate_authorities(None, vec![]).is_err());
+        let duplicate = vec![ca("default", "https://ca/directory")];
+        assert!(certificate_authorities(Some("https://ca".to_string()), duplicate).is_err());
+        assert!(certificate_authorities(None, vec![ca("broken", "not a url")]).is_err());
+        assert_eq!(super::ca_parse("staging=https://ca/directory").unwrap(), ca("staging", "https://ca/directory"));
+        assert!(super::ca_parse("https://ca/directory").is_err());
+    }
+
+    #[test]
+    fn test_certificates_are_declared_in_yaml() {
+        let yaml = r#"
+acme-sentry:
+  certificate-authorities:
+    - name: letsencrypt
+      directory-url: https://acme-v02.api.letsencrypt.org/directory
+    - name: letsencrypt-staging
+      directory-url: https://acme-staging-v02.api.letsencrypt.org/directory
+  fs:
+    base-dir: /opt/acme-sentry
+    output-dir: out
+  user:
+    email: admin@example.org
+    login-key-type: ec-p256
+  logging:
+    logging-level: info
+  certificates:
+    - name: example
+      identifiers: [example.org, "*.Example.org"]
+      key-type: rsa-2048
+      challenge:
+        method: dns-01
+        provider: rfc2136
+        propagation-timeout: 120s
+      ca: letsencrypt
+      profile: tlsserver
+      renewal:
+        before-expiry: 30d
+      output-formats: [pkcs12]
+      hooks:
+        deploy: systemctl reload nginx
+    - name: internal
+      identifiers: [192.0.2.1]
+      challenge:
+        method: http-01
+        webroot: /var/www/html
+      ca: letsencrypt-staging
+"#;
+        let config: YamlConfig = serde_yaml::from_str(yaml).unwrap();
+        let configuration = config.acme_sentry_configuration;
+        let named: Vec<_> = configuration.certificate_authorities.into_iter().map(Into::into).collect();
+        let definitions = certificate_definitions(configuration.certificates, &named).unwrap();
+        assert_eq!(definitions.len(), 2);
+        let example = &definitions[0];
+        assert_eq!(example.identifiers, vec!["example.org", "*.example.org"]);
+        assert_eq!(example.challenge, ChallengeMethod::Dns01);
+        assert_eq!(example.challenge_settings.get("provider").map(String::as_str), Some("rfc2136"));
+        assert_eq!(example.renew_before, Some(30 * 24 * 60 * 60));
+        assert_eq!(example.output_formats, Some(vec!["pkcs12".to_string()]));
+        assert_eq!(example.hooks.deploy.as_deref(), Some("systemctl reload nginx"));
+        let internal = &definitions[1];
+        assert_eq!((internal.key_type.as_str(), internal.ca.as_str()), ("ec-p256", "letsencrypt-staging"));
+        assert_eq!(internal.renew_before, None);
+    }
+
+    #[test]
+    fn test_certificate_declarations_are_validated() {
+        let declare = |name: &str, identifiers: &str, method: &str, ca: Option<&str>| -> CertificateConfig {
+            let ca = ca.map(|ca| format!("ca: {}", ca)).unwrap_or_default();
+            let settings = match method {
+                "http-01" => "  webroot: /var/www/html\n",
+                "dns-01" => "  provider: rfc2136\n",
+                _ => "",
+            };
+            let yaml = format!("name: {}\nidentifiers: [{}]\nchallenge:\n  method: {}\n{}{}", name, identifiers, method, settings, ca);
+            serde_yaml::from_str(yaml.as_str()).unwrap()
+        };
+        let single = vec![ca("default", "https://ca/dir")];
+        let several = vec![ca("default", "https://ca/dir"), ca("staging", "https://staging/dir")];
+        assert_eq!(certificate_definitions(vec![declare("www", "example.org", "http-01", None)], &single).unwrap()[0].ca, "default");
+        assert!(certificate_definitions(vec![declare("www", "example.org", "http-01", None)], &several).is_err());
+        assert!(certificate_definitions(vec![declare("www", "example.org", "http-01", Some("unknown"))], &several).is_err());
+        assert!(certificate_definitions(vec![declare("www", "\"*.example.org\"", "http-01", None)], &single).is_err());
+        assert!(certificate_definitions(vec![declare("www", "\"a.*.example.org\"", "dns-01", None)], &single).is_err());
+        assert!(certificate_definitions(vec![declare("www", "192.0.2.1", "dns-01", None)], &single).is_err());
+        assert!(certificate_definitions(vec![declare("www", "example.org", "dns-02", None)], &single).is_err());
+        assert!(certificate_definitions(vec![declare("../www", "example.org", "http-01", None)], &single).is_err());
+        let twice = vec![declare("www", "example.org", "http-01", None), declare("www", "example.com", "http-01", None)];
+        assert!(certificate_definitions(twice, &single).is_err());
+    }
+}